@@ -0,0 +1,100 @@
+//! Calls a `dmailfi_core` canister from off-chain gateways (SMTP submission, JMAP) through
+//! `dfx canister call`, using the current dfx identity. That identity has to be registered
+//! on the canister with `add_mail_transfer_agent`.
+use std::{
+    env, fs,
+    process::{self, Command},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use candid::{utils::ArgumentEncoder, CandidType};
+use dmailfi_types::MailError;
+use serde::de::DeserializeOwned;
+
+// Ingress messages to a canister are limited to 2MiB
+pub const MAX_ARGUMENT_BYTES: usize = 2 * 1024 * 1024;
+
+// names the argument files of concurrent calls
+static CALLS: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Clone)]
+pub struct DfxCanister {
+    pub canister_id: String,
+    pub network: String,
+}
+
+impl DfxCanister {
+    pub fn new(canister_id: String, network: String) -> Self {
+        DfxCanister {
+            canister_id,
+            network,
+        }
+    }
+
+    // For endpoints returning `Result<R, MailError>`
+    pub fn query<A: ArgumentEncoder, R: CandidType + DeserializeOwned>(
+        &self,
+        method: &str,
+        args: A,
+    ) -> Result<R, String> {
+        self.call(method, args, true)
+    }
+
+    pub fn update<A: ArgumentEncoder, R: CandidType + DeserializeOwned>(
+        &self,
+        method: &str,
+        args: A,
+    ) -> Result<R, String> {
+        self.call(method, args, false)
+    }
+
+    fn call<A: ArgumentEncoder, R: CandidType + DeserializeOwned>(
+        &self,
+        method: &str,
+        args: A,
+        query: bool,
+    ) -> Result<R, String> {
+        let args = candid::encode_args(args).map_err(|err| err.to_string())?;
+        let reply = self.call_raw(method, args, query)?;
+        let result: Result<R, MailError> =
+            candid::decode_one(&reply).map_err(|err| err.to_string())?;
+        result.map_err(|err| err.to_string())
+    }
+
+    // The arguments go through a file, on the command line they would hit the size limit of
+    // a single `execve` argument long before the ingress limit.
+    fn call_raw(&self, method: &str, args: Vec<u8>, query: bool) -> Result<Vec<u8>, String> {
+        if args.len() > MAX_ARGUMENT_BYTES {
+            return Err(format!(
+                "The call is larger than the {} byte ingress limit",
+                MAX_ARGUMENT_BYTES
+            ));
+        }
+        let argument_file = env::temp_dir().join(format!(
+            "dmailfi_call_{}_{}.hex",
+            process::id(),
+            CALLS.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::write(&argument_file, hex::encode(args))
+            .map_err(|err| format!("Could not write the call arguments: {}", err))?;
+
+        let mut command = Command::new("dfx");
+        command.args(["canister", "call", "--network", &self.network]);
+        if query {
+            command.arg("--query");
+        }
+        command.args(["--type", "raw", "--output", "raw", "--argument-file"]);
+        command.arg(&argument_file);
+        command.args([&self.canister_id, method]);
+
+        let output = command.output();
+        let _ = fs::remove_file(&argument_file);
+        let output = output.map_err(|err| format!("Could not run dfx: {}", err))?;
+        if !output.status.success() {
+            return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+        }
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        hex::decode(stdout.trim()).map_err(|_| "dfx returned a non hex reply".to_string())
+    }
+}
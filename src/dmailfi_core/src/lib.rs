@@ -1,10 +1,22 @@
-use std::str::FromStr;
+use std::{str::FromStr, time::Duration};
 
 use candid::{candid_method, Nat, Principal};
 use dmailfi_types::{
-    EcdsaKeyIds, InboxData, Ledger, LedgerConfiguration, LedgerInfo, Mail, MailError, MailReply,
-    Newsletter, OutgoingMail, RegistryError, SenderChannel, CORELATION_ID, EMAIL_ADDRESS,
-    LOOKUP_DOMAIN_CALL_PAYMENT, MAIL_ID, NEWSLETTER_ID, SUBMIT_CALL_PAYMENT,
+    archive::{self, Issue, IssueSummary},
+    http::{self, percent_decode, HttpRequest, HttpResponse as GatewayResponse},
+    icrc::{
+        self, Account, TokenLedger, TransferArg, TransferError, TransferFromArgs, TransferFromError,
+    },
+    is_canister_id, rfc5322,
+    rules::{MailRule, RuleDisposition},
+    sha256, AppPasswordInfo, AuditEntry, AutoReply, Destination, EcdsaKeyIds, EcdsaPublicKeyInfo,
+    FeeSchedule, ForwardingRule, GrantScope, InboundMail, InboundRecord, InboxData, IssueMetrics,
+    Ledger, LedgerConfiguration, LedgerInfo, Mail, MailError, MailFolder, MailHeader, MailReply,
+    MailboxAccess, MailboxAction, MailboxGrant, MailboxRole, MboxChunk, MboxImportReport,
+    Newsletter, NewsletterPlan, NewsletterState, OutgoingMail, PostagePayment, RateBucketInfo,
+    RateKey, RateLimits, RegistryError, Renewal, SendJobInfo, SenderChannel, SenderLists,
+    Subscriber, SubscriberDelivery, SubscriberGrowth, Tier, CORELATION_ID, ECDSA_DERIVATION_PATH,
+    EMAIL_ADDRESS, LOOKUP_DOMAIN_CALL_PAYMENT, MAIL_ID, MAX_SEND_BATCH_MAILS, NEWSLETTER_ID,
 };
 use email_address::EmailAddress;
 use ic_cdk::{
@@ -14,15 +26,19 @@ use ic_cdk::{
         is_controller,
         management_canister::{
             self,
-            ecdsa::{sign_with_ecdsa, SignWithEcdsaArgument},
+            ecdsa::{
+                ecdsa_public_key, sign_with_ecdsa, EcdsaKeyId, EcdsaPublicKeyArgument,
+                SignWithEcdsaArgument,
+            },
             http_request::{
-                self, http_request, CanisterHttpRequestArgument, HttpHeader, HttpMethod,
-                HttpResponse, TransformArgs, TransformContext, TransformFunc,
+                self, CanisterHttpRequestArgument, HttpHeader, HttpMethod, HttpResponse,
+                TransformArgs, TransformContext, TransformFunc,
             },
         },
     },
-    caller, id, init, query, update,
+    caller, id, init, post_upgrade, pre_upgrade, query, storage, update,
 };
+use serde_bytes::ByteBuf;
 
 pub mod ledger {
     use std::cell::RefCell;
@@ -50,7 +66,37 @@ fn init() {
         if args.is_some() {
             ledger.init(args.unwrap())
         }
-    })
+    });
+    start_timers();
+}
+
+#[pre_upgrade]
+fn pre_upgrade() {
+    let state = ledger::with_mut(|ledger| ledger.take_newsletter_state());
+    storage::stable_save((state,)).expect("failed to save the newsletter state");
+}
+
+#[post_upgrade]
+fn post_upgrade() {
+    // nothing is saved by a canister that was installed before the newsletter state was kept
+    if let Ok((state,)) = storage::stable_restore::<(NewsletterState,)>() {
+        ledger::with_mut(|ledger| ledger.restore_newsletter_state(state));
+    }
+    start_timers();
+}
+
+// Timers do not survive an upgrade, so this runs after every install.
+fn start_timers() {
+    ic_cdk_timers::set_timer_interval(Duration::from_secs(60 * 60), || {
+        ledger::with_mut(|ledger| ledger.prune_pending_subscriptions(api::time()))
+    });
+    ic_cdk_timers::set_timer_interval(
+        Duration::from_secs(60),
+        || ic_cdk::spawn(resume_send_jobs()),
+    );
+    ic_cdk_timers::set_timer_interval(Duration::from_secs(60 * 60), || {
+        ic_cdk::spawn(renew_subscriptions())
+    });
 }
 
 #[query]
@@ -68,14 +114,14 @@ async fn set_info(info: LedgerInfo) {
 #[update]
 #[candid_method(update)]
 async fn submit_reply(corelation_id: CORELATION_ID, reply: MailReply) -> Result<(), MailError> {
-    check_payment(SUBMIT_CALL_PAYMENT).unwrap();
-    let email_addr_rslt = EmailAddress::from_str(&reply.sender_address).or(Err(
-        MailError::GeneralError("Bad email address".to_string()),
-    ))?;
+    check_sender_domain(&reply.sender_address)?;
+    let fee = ledger::with(|ledger| ledger.get_fee_schedule().fee_for(reply_size(&reply)));
+    check_payment(fee)?;
+    let email_addr_rslt = EmailAddress::from_str(&reply.sender_address)
+        .or(Err(MailError::InvalidAddress(reply.sender_address.clone())))?;
 
     let domain = email_addr_rslt.domain();
-    let register_id =
-        Principal::from_text(ledger::with(|ledger| ledger.get_registry_address())).unwrap();
+    let register_id = registry_principal()?;
     let (lookup_response,): (Result<String, RegistryError>,) =
         ic_cdk::api::call::call_with_payment(
             register_id,
@@ -84,69 +130,600 @@ async fn submit_reply(corelation_id: CORELATION_ID, reply: MailReply) -> Result<
             LOOKUP_DOMAIN_CALL_PAYMENT,
         )
         .await
-        .unwrap();
-    if lookup_response.is_err() {
-        return Err(MailError::GeneralError(
-            "Could not verify domain name".to_string(),
-        ));
-    }
+        .map_err(|(_, mssg)| {
+            MailError::GeneralError(format!("Could not reach the registry: {}", mssg))
+        })?;
+    let domain_canister = lookup_response
+        .map_err(|_| MailError::GeneralError("Could not verify domain name".to_string()))?;
 
-    let domain_principal = Principal::from_text(lookup_response.unwrap()).unwrap();
+    let domain_principal = Principal::from_text(&domain_canister)
+        .map_err(|_| MailError::GeneralError("Could not verify domain name".to_string()))?;
 
     if caller() != domain_principal {
         return Err(MailError::NotAuthorized);
     }
 
     ledger::with_mut(|ledger| {
-        let rslt = ledger.store_reply(corelation_id, reply);
-        accept_payment(SUBMIT_CALL_PAYMENT);
-        rslt
+        ledger.store_reply(corelation_id, reply)?;
+        accept_payment(fee);
+        Ok(())
     })
 }
 
 // Should be called by a caister on user behalf.
 #[update]
 #[candid_method(update)]
-async fn submit_mail(mut mail: Mail) -> Result<(), MailError> {
-    let custodian_rslt = is_custodian();
-    if custodian_rslt.is_err() && ic_cdk::api::call::msg_cycles_available() < SUBMIT_CALL_PAYMENT {
-        return Err(MailError::GeneralError("Not Enough Cycles".to_string()));
+async fn submit_mail(mail: Mail) -> Result<(), MailError> {
+    let mail_id = generate_random_id().await?;
+    receive_mail(mail, mail_id).await?;
+    ic_cdk::spawn(send_pending_mail());
+    Ok(())
+}
+
+// Several mails in one call, e.g. a newsletter issue for every subscriber on this domain. Each
+// mail is checked and charged like a `submit_mail` call, the results are in the same order.
+#[update]
+#[candid_method(update)]
+async fn submit_mail_batch(mails: Vec<Mail>) -> Result<Vec<Result<(), MailError>>, MailError> {
+    if mails.len() > MAX_SEND_BATCH_MAILS {
+        return Err(MailError::GeneralError(format!(
+            "At most {} mails can be sent in one batch",
+            MAX_SEND_BATCH_MAILS
+        )));
     }
+    let seed = generate_random_id().await?;
+    let mut results = vec![];
+    for (index, mail) in mails.into_iter().enumerate() {
+        let mail_id = hex::encode(sha256(&format!("{}:{}", seed, index)));
+        results.push(receive_mail(mail, mail_id).await);
+    }
+    ic_cdk::spawn(send_pending_mail());
+    Ok(results)
+}
 
-    if custodian_rslt.is_err() {
+// Checks, charges and delivers one mail submitted by another canister.
+async fn receive_mail(mut mail: Mail, mail_id: MAIL_ID) -> Result<(), MailError> {
+    let is_custodian = is_custodian().is_ok();
+    // priced before the receiving canister fills in its own header fields
+    let fee = ledger::with(|ledger| ledger.get_fee_schedule().fee_for(mail.size()));
+    if !is_custodian {
+        check_sender_domain(&mail.header.from)?;
+        check_payment(fee)?;
+        let mut keys = vec![
+            RateKey::Caller(caller()),
+            RateKey::Sender(mail.header.from.clone()),
+        ];
+        keys.extend(ledger::with(|ledger| {
+            ledger.receipient_rate_keys(&mail.header)
+        }));
+        ledger::with_mut(|ledger| ledger.take_rate_tokens(&keys, api::time()))?;
         mail.header.sender_channel = Some(SenderChannel::ICP.to_string());
         mail.header.sender_canister_id = Some(caller().to_text());
         mail.header.receipient_canister_id = Some(id().to_text())
     }
 
-    let (mail_id_hex,) = ic_cdk::api::management_canister::main::raw_rand()
-        .await
-        .or(Err(MailError::FailedToGenerateMailId))?;
+    // A canister relaying for its users would pay from its own allowance for any of them, so only
+    // a sender calling in person is charged. Relayed mail that owes postage is refused.
+    let payer = Some(caller()).filter(|caller| !is_canister_id(caller));
+    let due = collect_postage(payer, &mail).await?;
+    let sender = mail.header.from.clone();
+    let result = ledger::with_mut(|ledger| {
+        ledger.submit_mail(mail, mail_id.clone(), api::time())?;
+        if !is_custodian {
+            ic_cdk::api::call::msg_cycles_accept(fee);
+        }
+        if let Some(payer) = payer {
+            ledger.record_postage(&mail_id, payer, &sender, due.clone());
+        }
+        Ok::<(), MailError>(())
+    });
+    if let Err(err) = result {
+        if let Some(payer) = payer {
+            return_postage(payer, due).await;
+        }
+        return Err(err);
+    }
+    Ok(())
+}
 
-    let mail_id = hex::encode(mail_id_hex);
+// Called by a registered MTA for mail arriving from the web2 network.
+#[update(guard = "is_mail_transfer_agent")]
+#[candid_method(update)]
+async fn submit_inbound_mail(mut inbound: InboundMail) -> Result<(), MailError> {
+    check_sender_domain(&inbound.envelope.mail_from)?;
+    check_sender_domain(&inbound.mail.header.from)?;
+    let mut keys = vec![RateKey::Sender(inbound.envelope.mail_from.clone())];
+    keys.extend(ledger::with(|ledger| {
+        ledger.receipient_rate_keys(&MailHeader {
+            to: inbound.envelope.rcpt_to.clone(),
+            ..Default::default()
+        })
+    }));
+    ledger::with_mut(|ledger| ledger.take_rate_tokens(&keys, api::time()))?;
+    inbound.mail.header.receipient_canister_id = Some(id().to_text());
+    let mail_id = generate_random_id().await?;
+    ledger::with_mut(|ledger| ledger.submit_inbound_mail(inbound, mail_id, caller(), api::time()))?;
+    ic_cdk::spawn(send_pending_mail());
+    Ok(())
+}
+
+// Submission gateways (SMTP, JMAP) send on a user's behalf after checking an app password.
+#[update(guard = "is_mail_transfer_agent")]
+#[candid_method(update)]
+async fn send_mail_as(
+    email_address: EMAIL_ADDRESS,
+    app_password: String,
+    mail: Mail,
+) -> Result<(), MailError> {
+    ledger::with(|ledger| ledger.verify_app_password(&email_address, &app_password))?;
+    // the gateway sends for many users, only the address is limited
+    take_rate_tokens(vec![RateKey::Sender(email_address.clone())])?;
+    // the gateway can not approve tokens for the user, priced receipients refuse the mail
+    send_mail_from(email_address, mail, None).await
+}
+
+#[query(guard = "is_mail_transfer_agent")]
+#[candid_method(query)]
+async fn get_folder_mails_as(
+    email_address: EMAIL_ADDRESS,
+    app_password: String,
+    folder: MailFolder,
+    page: Option<usize>,
+) -> Result<Vec<InboxData>, MailError> {
+    ledger::with(|ledger| {
+        ledger.verify_app_password(&email_address, &app_password)?;
+        ledger.get_folder_mails(&email_address, folder, page)
+    })
+}
+
+#[update(guard = "is_mail_transfer_agent")]
+#[candid_method(update)]
+async fn get_mail_as(
+    email_address: EMAIL_ADDRESS,
+    app_password: String,
+    mail_id: MAIL_ID,
+) -> Result<Mail, MailError> {
     ledger::with_mut(|ledger| {
-        ledger.submit_mail(mail, mail_id)?;
-        ic_cdk::api::call::msg_cycles_accept(SUBMIT_CALL_PAYMENT);
-        Ok(())
+        ledger.verify_app_password(&email_address, &app_password)?;
+        ledger.get_mail_for(&email_address, mail_id)
+    })
+}
+
+#[update(guard = "is_mail_transfer_agent")]
+#[candid_method(update)]
+async fn delete_mail_as(
+    email_address: EMAIL_ADDRESS,
+    app_password: String,
+    mail_id: MAIL_ID,
+) -> Result<(), MailError> {
+    ledger::with_mut(|ledger| {
+        ledger.verify_app_password(&email_address, &app_password)?;
+        ledger.delete_mail_for(&email_address, mail_id)
+    })
+}
+
+#[update(guard = "is_mail_transfer_agent")]
+#[candid_method(update)]
+async fn restore_mail_as(
+    email_address: EMAIL_ADDRESS,
+    app_password: String,
+    mail_id: MAIL_ID,
+) -> Result<(), MailError> {
+    ledger::with_mut(|ledger| {
+        ledger.verify_app_password(&email_address, &app_password)?;
+        ledger.restore_mail_for(&email_address, mail_id)
     })
 }
 
+#[query(guard = "is_mail_transfer_agent")]
+#[candid_method(query)]
+async fn verify_app_password(
+    email_address: EMAIL_ADDRESS,
+    app_password: String,
+) -> Result<(), MailError> {
+    ledger::with(|ledger| ledger.verify_app_password(&email_address, &app_password))
+}
+
+// The password is only returned here, the canister keeps its hash.
+#[update(guard = "is_one_of_user")]
+#[candid_method(update)]
+async fn create_app_password(
+    label: String,
+    mailbox: Option<EMAIL_ADDRESS>,
+) -> Result<String, MailError> {
+    let email = authorize(mailbox, MailboxAction::Manage)?;
+    let password = generate_random_id().await?;
+    ledger::with_mut(|ledger| ledger.create_app_password(&email, label, &password))?;
+    Ok(password)
+}
+
+#[update(guard = "is_one_of_user")]
+#[candid_method(update)]
+async fn revoke_app_password(
+    label: String,
+    mailbox: Option<EMAIL_ADDRESS>,
+) -> Result<(), MailError> {
+    let email = authorize(mailbox, MailboxAction::Manage)?;
+    ledger::with_mut(|ledger| ledger.revoke_app_password(&email, label))
+}
+
+#[query(guard = "is_one_of_user")]
+#[candid_method(query)]
+async fn get_app_passwords(
+    mailbox: Option<EMAIL_ADDRESS>,
+) -> Result<Vec<AppPasswordInfo>, MailError> {
+    let email = authorize(mailbox, MailboxAction::Manage)?;
+    ledger::with(|ledger| ledger.get_app_passwords(&email))
+}
+
+#[update(guard = "is_one_of_user")]
+#[candid_method(update)]
+async fn get_inbound_record(
+    mail_id: MAIL_ID,
+    mailbox: Option<EMAIL_ADDRESS>,
+) -> Result<InboundRecord, MailError> {
+    let email = authorize(mailbox, MailboxAction::ReadMail(mail_id.clone()))?;
+    ledger::with(|ledger| ledger.get_inbound_record(&email, mail_id))
+}
+
+#[update(guard = "is_custodian")]
+#[candid_method(update)]
+async fn add_mail_transfer_agent(mta: Principal) {
+    ledger::with_mut(|ledger| ledger.add_mail_transfer_agent(mta))
+}
+
+#[update(guard = "is_custodian")]
+#[candid_method(update)]
+async fn remove_mail_transfer_agent(mta: Principal) -> Result<(), MailError> {
+    ledger::with_mut(|ledger| ledger.remove_mail_transfer_agent(mta))
+}
+
+#[query(guard = "is_custodian")]
+#[candid_method(query)]
+async fn get_mail_transfer_agents() -> Vec<Principal> {
+    ledger::with(|ledger| ledger.get_mail_transfer_agents())
+}
+
+#[update(guard = "is_custodian")]
+#[candid_method(update)]
+async fn add_alias(alias: EMAIL_ADDRESS, mailbox: EMAIL_ADDRESS) -> Result<(), MailError> {
+    ledger::with_mut(|ledger| ledger.add_alias(alias, mailbox))
+}
+
+#[update(guard = "is_custodian")]
+#[candid_method(update)]
+async fn remove_alias(alias: EMAIL_ADDRESS) -> Result<(), MailError> {
+    ledger::with_mut(|ledger| ledger.remove_alias(alias))
+}
+
+#[query(guard = "is_custodian")]
+#[candid_method(query)]
+async fn get_aliases() -> Vec<(EMAIL_ADDRESS, EMAIL_ADDRESS)> {
+    ledger::with(|ledger| ledger.get_aliases())
+}
+
+// Unknown addresses in our domain are delivered to the catch-all mailbox, None turns it off.
+#[update(guard = "is_custodian")]
+#[candid_method(update)]
+async fn set_catch_all(mailbox: Option<EMAIL_ADDRESS>) -> Result<(), MailError> {
+    ledger::with_mut(|ledger| ledger.set_catch_all(mailbox))
+}
+
+#[query(guard = "is_custodian")]
+#[candid_method(query)]
+async fn get_catch_all() -> Option<EMAIL_ADDRESS> {
+    ledger::with(|ledger| ledger.get_catch_all())
+}
+
+// None restores the defaults.
+#[update(guard = "is_custodian")]
+#[candid_method(update)]
+async fn set_rate_limits(limits: Option<RateLimits>) -> Result<(), MailError> {
+    ledger::with_mut(|ledger| ledger.set_rate_limits(limits.unwrap_or_default()))
+}
+
+#[query(guard = "is_custodian")]
+#[candid_method(query)]
+async fn get_rate_limits() -> RateLimits {
+    ledger::with(|ledger| ledger.get_rate_limits())
+}
+
+#[query(guard = "is_custodian")]
+#[candid_method(query)]
+async fn get_rate_buckets() -> Vec<RateBucketInfo> {
+    ledger::with(|ledger| ledger.get_rate_buckets(api::time()))
+}
+
+// Refills one bucket, or every bucket when no key is given.
+#[update(guard = "is_custodian")]
+#[candid_method(update)]
+async fn reset_rate_buckets(key: Option<RateKey>) -> Result<(), MailError> {
+    ledger::with_mut(|ledger| ledger.reset_rate_buckets(key))
+}
+
+// Mail from the domain and its subdomains is refused before any cycles are taken.
+#[update(guard = "is_custodian")]
+#[candid_method(update)]
+async fn add_blocked_domain(domain: String) -> Result<(), MailError> {
+    ledger::with_mut(|ledger| ledger.add_blocked_domain(domain))
+}
+
+#[update(guard = "is_custodian")]
+#[candid_method(update)]
+async fn remove_blocked_domain(domain: String) -> Result<(), MailError> {
+    ledger::with_mut(|ledger| ledger.remove_blocked_domain(domain))
+}
+
+#[query(guard = "is_custodian")]
+#[candid_method(query)]
+async fn get_blocked_domains() -> Vec<String> {
+    ledger::with(|ledger| ledger.get_blocked_domains())
+}
+
+// Senders the mailbox does not want mail from, None clears both lists.
+#[update(guard = "is_one_of_user")]
+#[candid_method(update)]
+async fn set_sender_lists(
+    lists: Option<SenderLists>,
+    mailbox: Option<EMAIL_ADDRESS>,
+) -> Result<(), MailError> {
+    let email = authorize(mailbox, MailboxAction::Manage)?;
+    ledger::with_mut(|ledger| ledger.set_sender_lists(&email, lists))
+}
+
+#[query(guard = "is_one_of_user")]
+#[candid_method(query)]
+async fn get_sender_lists(
+    mailbox: Option<EMAIL_ADDRESS>,
+) -> Result<Option<SenderLists>, MailError> {
+    let email = authorize(mailbox, MailboxAction::Manage)?;
+    ledger::with(|ledger| Ok(ledger.get_sender_lists(&email)))
+}
+
+#[update(guard = "is_one_of_user")]
+#[candid_method(update)]
+async fn get_my_aliases(mailbox: Option<EMAIL_ADDRESS>) -> Result<Vec<EMAIL_ADDRESS>, MailError> {
+    let email = authorize(mailbox, MailboxAction::ReadAll)?;
+    ledger::with(|ledger| Ok(ledger.get_aliases_of(&email)))
+}
+
+// Replaces the rules incoming mail runs through, in order.
+#[update(guard = "is_one_of_user")]
+#[candid_method(update)]
+async fn set_mail_rules(
+    rules: Vec<MailRule>,
+    mailbox: Option<EMAIL_ADDRESS>,
+) -> Result<(), MailError> {
+    let email = authorize(mailbox, MailboxAction::Organize)?;
+    ledger::with_mut(|ledger| ledger.set_mail_rules(&email, rules))
+}
+
+#[update(guard = "is_one_of_user")]
+#[candid_method(update)]
+async fn get_mail_rules(mailbox: Option<EMAIL_ADDRESS>) -> Result<Vec<MailRule>, MailError> {
+    let email = authorize(mailbox, MailboxAction::Organize)?;
+    ledger::with(|ledger| Ok(ledger.get_mail_rules(&email)))
+}
+
+// Shows which rules would fire for `mail` and what delivery would do with it.
+#[update(guard = "is_one_of_user")]
+#[candid_method(update)]
+async fn dry_run_mail_rules(
+    mail: Mail,
+    mailbox: Option<EMAIL_ADDRESS>,
+) -> Result<RuleDisposition, MailError> {
+    let email = authorize(mailbox, MailboxAction::Organize)?;
+    ledger::with(|ledger| ledger.dry_run_mail_rules(&email, &mail))
+}
+
+#[update(guard = "is_one_of_user")]
+#[candid_method(update)]
+async fn get_labels(mailbox: Option<EMAIL_ADDRESS>) -> Result<Vec<String>, MailError> {
+    let email = authorize(mailbox, MailboxAction::ReadAll)?;
+    ledger::with(|ledger| Ok(ledger.get_labels(&email)))
+}
+
+#[update(guard = "is_one_of_user")]
+#[candid_method(update)]
+async fn get_label_mails(
+    label: String,
+    page: Option<usize>,
+    mailbox: Option<EMAIL_ADDRESS>,
+) -> Result<Vec<InboxData>, MailError> {
+    let email = authorize(mailbox, MailboxAction::ReadAll)?;
+    ledger::with(|ledger| ledger.get_label_mails(&email, &label, page))
+}
+
+#[update(guard = "is_one_of_user")]
+#[candid_method(update)]
+async fn get_starred_mails(
+    page: Option<usize>,
+    mailbox: Option<EMAIL_ADDRESS>,
+) -> Result<Vec<InboxData>, MailError> {
+    let email = authorize(mailbox, MailboxAction::ReadAll)?;
+    ledger::with(|ledger| ledger.get_starred_mails(&email, page))
+}
+
+#[update(guard = "is_one_of_user")]
+#[candid_method(update)]
+async fn set_starred(
+    mail_id: MAIL_ID,
+    starred: bool,
+    mailbox: Option<EMAIL_ADDRESS>,
+) -> Result<(), MailError> {
+    let email = authorize(mailbox, MailboxAction::Organize)?;
+    ledger::with_mut(|ledger| ledger.set_starred(&email, mail_id, starred))
+}
+
+// Vacation reply, None turns it off.
+#[update(guard = "is_one_of_user")]
+#[candid_method(update)]
+async fn set_auto_reply(
+    auto_reply: Option<AutoReply>,
+    mailbox: Option<EMAIL_ADDRESS>,
+) -> Result<(), MailError> {
+    let email = authorize(mailbox, MailboxAction::Manage)?;
+    ledger::with_mut(|ledger| ledger.set_auto_reply(&email, auto_reply))
+}
+
+#[query(guard = "is_one_of_user")]
+#[candid_method(query)]
+async fn get_auto_reply(mailbox: Option<EMAIL_ADDRESS>) -> Result<Option<AutoReply>, MailError> {
+    let email = authorize(mailbox, MailboxAction::Manage)?;
+    ledger::with(|ledger| Ok(ledger.get_auto_reply(&email)))
+}
+
+// None stops forwarding.
+#[update(guard = "is_one_of_user")]
+#[candid_method(update)]
+async fn set_forwarding(
+    rule: Option<ForwardingRule>,
+    mailbox: Option<EMAIL_ADDRESS>,
+) -> Result<(), MailError> {
+    let email = authorize(mailbox, MailboxAction::Manage)?;
+    ledger::with_mut(|ledger| ledger.set_forwarding(&email, rule))
+}
+
+#[query(guard = "is_one_of_user")]
+#[candid_method(query)]
+async fn get_forwarding(
+    mailbox: Option<EMAIL_ADDRESS>,
+) -> Result<Option<ForwardingRule>, MailError> {
+    let email = authorize(mailbox, MailboxAction::Manage)?;
+    ledger::with(|ledger| Ok(ledger.get_forwarding(&email)))
+}
+
 #[update]
 #[candid_method(update)]
-async fn get_mail(mail_id: MAIL_ID) -> Result<Mail, MailError> {
-    ledger::with_mut(|ledger| ledger.get_mail(mail_id))
+async fn get_mail(mail_id: MAIL_ID, mailbox: Option<EMAIL_ADDRESS>) -> Result<Mail, MailError> {
+    let email = authorize(mailbox, MailboxAction::ReadMail(mail_id.clone()))?;
+    let (mail, first_read) = ledger::with_mut(|ledger| ledger.open_mail(&email, mail_id))?;
+    if first_read {
+        report_newsletter_read_of(&email, &mail);
+    }
+    Ok(mail)
+}
+
+// Tells the canister a newsletter copy came from that its subscriber opened it. Copies from
+// this canister are recorded directly.
+fn report_newsletter_read_of(address: &EMAIL_ADDRESS, mail: &Mail) {
+    let (n_id, number) = match mail.header.newsletter_issue() {
+        Some(issue) => issue,
+        None => return,
+    };
+    let sender = mail
+        .header
+        .sender_canister_id
+        .as_deref()
+        .and_then(|canister| Principal::from_text(canister).ok())
+        .filter(|canister| *canister != id());
+    match sender {
+        None => {
+            let _ = ledger::with_mut(|ledger| {
+                ledger.record_issue_read(&n_id, number, address, Destination::Local, api::time())
+            });
+        }
+        Some(canister) => {
+            let address = address.clone();
+            ic_cdk::spawn(async move {
+                let _: Result<(Result<(), MailError>,), (RejectionCode, String)> =
+                    call::call(canister, "report_newsletter_read", (n_id, number, address)).await;
+            });
+        }
+    }
+}
+
+// Called by the canister a newsletter copy was delivered to once its recipient opened it, or
+// by the principal that subscribed the address.
+#[update(guard = "not_anonymous")]
+#[candid_method(update)]
+async fn report_newsletter_read(
+    n_id: NEWSLETTER_ID,
+    number: u32,
+    address: EMAIL_ADDRESS,
+) -> Result<(), MailError> {
+    ledger::with_mut(|ledger| {
+        ledger.report_issue_read(&n_id, number, &address, caller(), api::time())
+    })
+}
+
+#[update(guard = "is_one_of_user")]
+#[candid_method(update)]
+async fn get_mail_raw(
+    mail_id: MAIL_ID,
+    mailbox: Option<EMAIL_ADDRESS>,
+) -> Result<ByteBuf, MailError> {
+    let email = authorize(mailbox, MailboxAction::ReadMail(mail_id.clone()))?;
+    ledger::with(|ledger| ledger.get_mail_raw(&email, mail_id).map(ByteBuf::from))
+}
+
+// Imports an RFC 5322 message, e.g. exported from another provider, into the caller's mailbox.
+#[update(guard = "is_one_of_user")]
+#[candid_method(update)]
+async fn import_raw_mail(
+    raw: ByteBuf,
+    mailbox: Option<EMAIL_ADDRESS>,
+) -> Result<MAIL_ID, MailError> {
+    let email = authorize(mailbox, MailboxAction::Organize)?;
+    let mail = rfc5322::from_rfc5322(&raw).map_err(MailError::GeneralError)?;
+    let mail_id = generate_random_id().await?;
+    ledger::with_mut(|ledger| ledger.import_mail(&email, mail, mail_id.clone()))?;
+    Ok(mail_id)
+}
+
+// Pages through the caller's inbox, sent and trash as mbox, start with no cursor and pass the
+// returned one back until it is None.
+#[update(guard = "is_one_of_user")]
+#[candid_method(update)]
+async fn export_mailbox(
+    cursor: Option<String>,
+    mailbox: Option<EMAIL_ADDRESS>,
+) -> Result<MboxChunk, MailError> {
+    let email = authorize(mailbox, MailboxAction::ReadAll)?;
+    ledger::with(|ledger| ledger.export_mailbox(&email, cursor))
+}
+
+// Imports an mbox uploaded in chunks of any size, set `last` on the final chunk.
+#[update(guard = "is_one_of_user")]
+#[candid_method(update)]
+async fn import_mailbox(
+    chunk: ByteBuf,
+    last: bool,
+    mailbox: Option<EMAIL_ADDRESS>,
+) -> Result<MboxImportReport, MailError> {
+    let email = authorize(mailbox, MailboxAction::Manage)?;
+    let seed = generate_random_id().await?;
+    ledger::with_mut(|ledger| ledger.import_mbox_chunk(&email, &chunk, last, &seed))
 }
 
 #[query]
 #[candid_method(query)]
 async fn get_users() -> Result<std::vec::Vec<std::string::String>, MailError> {
-    ledger::with(|ledger| ledger.get_users())
+    if !ledger::with(|ledger| ledger.is_permissioned()) {
+        is_custodian().map_err(|_| MailError::NotAuthorized)?;
+    }
+    ledger::with(|ledger| Ok(ledger.get_users()))
 }
 
-#[query]
-#[candid_method(query)]
-async fn get_mails(page: Option<usize>) -> Result<std::vec::Vec<InboxData>, MailError> {
-    ledger::with(|ledger| ledger.get_mails(page))
+#[update]
+#[candid_method(update)]
+async fn get_mails(
+    page: Option<usize>,
+    mailbox: Option<EMAIL_ADDRESS>,
+) -> Result<std::vec::Vec<InboxData>, MailError> {
+    let email = authorize(mailbox, MailboxAction::ListFolder(MailFolder::Inbox))?;
+    ledger::with(|ledger| ledger.get_folder_mails(&email, MailFolder::Inbox, page))
+}
+
+#[update(guard = "is_one_of_user")]
+#[candid_method(update)]
+async fn get_folder_mails(
+    folder: MailFolder,
+    page: Option<usize>,
+    mailbox: Option<EMAIL_ADDRESS>,
+) -> Result<std::vec::Vec<InboxData>, MailError> {
+    let email = authorize(mailbox, MailboxAction::ListFolder(folder))?;
+    ledger::with(|ledger| ledger.get_folder_mails(&email, folder, page))
 }
 
 #[query]
@@ -170,22 +747,25 @@ async fn delete_user(email_address: EMAIL_ADDRESS) -> Result<(), MailError> {
     ledger::with_mut(|ledger| ledger.delete_user(email_address))
 }
 
-#[query]
-#[candid_method[query]]
-async fn get_mail_count() -> Result<(u32, u32), MailError> {
-    ledger::with(|ledger| ledger.get_mail_count())
+#[update]
+#[candid_method(update)]
+async fn get_mail_count(mailbox: Option<EMAIL_ADDRESS>) -> Result<(u32, u32), MailError> {
+    let email = authorize(mailbox, MailboxAction::ListFolder(MailFolder::Inbox))?;
+    ledger::with(|ledger| ledger.get_mail_count(&email))
 }
 
 #[update]
 #[candid_method[update]]
-async fn delete_mail(mail_id: MAIL_ID) -> Result<(), MailError> {
-    ledger::with_mut(|ledger| ledger.delete_mail(mail_id))
+async fn delete_mail(mail_id: MAIL_ID, mailbox: Option<EMAIL_ADDRESS>) -> Result<(), MailError> {
+    let email = authorize(mailbox, MailboxAction::Organize)?;
+    ledger::with_mut(|ledger| ledger.delete_mail_for(&email, mail_id))
 }
 
 #[update]
 #[candid_method[update]]
-async fn restore_mail(mail_id: MAIL_ID) -> Result<(), MailError> {
-    ledger::with_mut(|ledger| ledger.restore_mail(mail_id))
+async fn restore_mail(mail_id: MAIL_ID, mailbox: Option<EMAIL_ADDRESS>) -> Result<(), MailError> {
+    let email = authorize(mailbox, MailboxAction::Organize)?;
+    ledger::with_mut(|ledger| ledger.restore_mail_for(&email, mail_id))
 }
 
 #[query]
@@ -202,9 +782,16 @@ async fn get_token_name() -> std::string::String {
 
 #[update(guard = "is_one_of_user")]
 #[candid_method(update)]
-async fn send_reply(correlation_id: CORELATION_ID, mut reply: MailReply) -> Result<(), MailError> {
-    let user_email = ledger::with(|ledger| ledger.get_user_address(caller()))
-        .ok_or(MailError::NoUserAddressFound)?;
+async fn send_reply(
+    correlation_id: CORELATION_ID,
+    mut reply: MailReply,
+    mailbox: Option<EMAIL_ADDRESS>,
+) -> Result<(), MailError> {
+    let user_email = authorize(mailbox, MailboxAction::Send)?;
+    take_rate_tokens(vec![
+        RateKey::Caller(caller()),
+        RateKey::Sender(user_email.clone()),
+    ])?;
 
     reply.sender_address = user_email.clone();
 
@@ -221,14 +808,15 @@ async fn send_reply(correlation_id: CORELATION_ID, mut reply: MailReply) -> Resu
                 .to
                 .iter()
                 .map(|f| {
-                    let binding = EmailAddress::from_str(f).unwrap();
-                    let domain = binding.domain();
-                    return domain.to_string();
+                    let binding = EmailAddress::from_str(f)
+                        .map_err(|_| MailError::InvalidAddress(f.clone()))?;
+                    Ok(binding.domain().to_string())
                 })
-                .collect();
+                .collect::<Result<Vec<String>, MailError>>()?;
             domain_vec.extend(local_vec)
         } else if mail.header.to.contains(&user_email) {
-            let email_addr = EmailAddress::from_str(&user_email).unwrap();
+            let email_addr = EmailAddress::from_str(&user_email)
+                .map_err(|_| MailError::InvalidAddress(user_email.clone()))?;
 
             domain_vec.push(email_addr.domain().to_string())
         }
@@ -241,9 +829,7 @@ async fn send_reply(correlation_id: CORELATION_ID, mut reply: MailReply) -> Resu
     })?;
 
     let platform_domain = ledger::with(|ledger| ledger.get_domain_name());
-    let registry_id = ledger::with(|ledger| ledger.get_registry_address());
-
-    let registry_id = Principal::from_text(registry_id).unwrap();
+    let registry_id = registry_principal()?;
 
     for domain in domain_vec {
         if domain == platform_domain {
@@ -270,13 +856,17 @@ async fn send_reply(correlation_id: CORELATION_ID, mut reply: MailReply) -> Resu
             continue;
         }
 
-        let dmailfi_canister = Principal::from_text(l_reply.unwrap()).unwrap();
+        let dmailfi_canister = match Principal::from_text(l_reply.unwrap()) {
+            Ok(canister) => canister,
+            Err(_) => continue,
+        };
+        let fee = submission_fee(dmailfi_canister, reply_size(&reply)).await;
         let dmailfi_response: Result<(Result<(), MailError>,), (RejectionCode, String)> =
             ic_cdk::api::call::call_with_payment(
                 dmailfi_canister,
                 "submit_reply",
                 (correlation_id.clone(), reply.clone()),
-                SUBMIT_CALL_PAYMENT,
+                fee,
             )
             .await;
         if dmailfi_response.is_err() {
@@ -289,50 +879,110 @@ async fn send_reply(correlation_id: CORELATION_ID, mut reply: MailReply) -> Resu
 
 #[update(guard = "is_one_of_user")]
 #[candid_method(update)]
-async fn send_mail(mut mail: Mail) -> Result<(), MailError> {
-    ledger::with(|ledger| {
-        // this has already been checked by "is_one_of_user" guard function
-        let user_address = ledger.get_user_address(caller()).unwrap();
-        mail.header.from = user_address;
-    });
-    let platform_domain = ledger::with(|ledger| ledger.get_domain_name());
-
-    let registry_id = ledger::with(|ledger| ledger.get_registry_address());
+async fn send_mail(mail: Mail, mailbox: Option<EMAIL_ADDRESS>) -> Result<(), MailError> {
+    let user_address = authorize(mailbox, MailboxAction::Send)?;
+    take_rate_tokens(vec![
+        RateKey::Caller(caller()),
+        RateKey::Sender(user_address.clone()),
+    ])?;
+    send_mail_from(user_address, mail, Some(caller())).await
+}
 
-    let registry_id = Principal::from_text(registry_id).unwrap();
-
-    let domain_vec = Ledger::get_receipients_domains(&mail);
-    let mut failed_domain = vec![];
+// `payer` covers postage for local receipients, it is None when nobody approved any.
+async fn send_mail_from(
+    user_address: EMAIL_ADDRESS,
+    mut mail: Mail,
+    payer: Option<Principal>,
+) -> Result<(), MailError> {
+    mail.header.from = user_address.clone();
+    // nothing is stored for a mail with a malformed receipient
+    Ledger::get_receipients_domains(&mail)?;
     let correlation_id = generate_random_id().await?;
 
     ledger::with_mut(|ledger| {
         mail.correlation_id = Some(correlation_id.clone());
         // Correlation Id serves as the Mail ID in this CASE.
-        ledger.store_mail(mail.clone(), correlation_id.clone());
-        let user_addr = ledger.get_user_address(caller());
-        if user_addr.is_none() {
-            return;
+        ledger.store_mail(mail.clone(), correlation_id.clone())?;
+        ledger.add_to_sent(correlation_id, user_address.clone());
+        // people the mailbox writes to can answer without postage
+        let mut receipients = mail.header.to.clone();
+        receipients.extend(mail.header.cc.iter().flatten().cloned());
+        receipients.extend(mail.header.bcc.iter().flatten().cloned());
+        let _ = ledger.add_contacts(&user_address, &receipients);
+        Ok::<(), MailError>(())
+    })?;
+
+    let result = dispatch_mail(mail, payer).await;
+    ic_cdk::spawn(send_pending_mail());
+    result
+}
+
+// Sends forwarded copies and auto replies queued by deliveries, delivering them to a local
+// mailbox can queue more. `MAX_FORWARD_HOPS` bounds forwarding, auto replies are never answered.
+async fn send_pending_mail() {
+    loop {
+        let outbound = ledger::with_mut(|ledger| ledger.take_pending_outbound());
+        if outbound.is_empty() {
+            break;
         }
+        for (_mailbox, mail) in outbound {
+            // not retried, the mail is still in the inbox when a forwarding rule keeps a copy
+            let _ = dispatch_mail(mail, None).await;
+        }
+    }
+}
 
-        ledger.add_to_sent(correlation_id, user_addr.unwrap());
-    });
+// Delivers `mail` to every receipient domain, local, dmail canisters through the registry and
+// web2 through the MTA.
+async fn dispatch_mail(mut mail: Mail, payer: Option<Principal>) -> Result<(), MailError> {
+    let platform_domain = ledger::with(|ledger| ledger.get_domain_name());
+
+    let mut domain_vec = Ledger::get_receipients_domains(&mail)?;
+    domain_vec.sort();
+    domain_vec.dedup();
+    let mut failed_domain = vec![];
+    let mut web2_domains = vec![];
+    let mut postage_error = None;
 
     for domain in domain_vec {
         // let mx = mail.clone();
 
         if domain == platform_domain {
             let mail_id = generate_random_id().await?;
-            ledger::with_mut(|ledger| {
+            let due = match collect_postage(payer, &mail).await {
+                Ok(due) => due,
+                Err(err) => {
+                    failed_domain.push(format!("Domain: {} with error: {}", domain.clone(), err));
+                    postage_error = Some(err);
+                    continue;
+                }
+            };
+            let delivered = ledger::with_mut(|ledger| {
                 mail.header.receipient_canister_id = Some(id().to_text());
-                let result = ledger.submit_mail(mail.clone(), mail_id);
+                let result = ledger.submit_mail(mail.clone(), mail_id.clone(), api::time());
                 if result.is_err() {
-                    failed_domain.push(domain.clone())
+                    failed_domain.push(domain.clone());
+                } else if let Some(payer) = payer {
+                    ledger.record_postage(&mail_id, payer, &mail.header.from, due.clone());
                 }
+                result.is_ok()
             });
+            if !delivered {
+                if let Some(payer) = payer {
+                    return_postage(payer, due).await;
+                }
+            }
 
             continue;
         }
 
+        let registry_id = match registry_principal() {
+            Ok(registry_id) => registry_id,
+            Err(err) => {
+                failed_domain.push(format!("Domain: {} with error: {}", domain.clone(), err));
+                continue;
+            }
+        };
         let lookup_response: Result<(Result<String, String>,), (RejectionCode, String)> =
             ic_cdk::api::call::call_with_payment(
                 registry_id,
@@ -350,24 +1000,25 @@ async fn send_mail(mut mail: Mail) -> Result<(), MailError> {
         let (reply,) = lookup_response.unwrap();
 
         if reply.is_err() {
-            let mail_id = generate_random_id().await?;
-            let out_mail = OutgoingMail {
-                id: mail_id,
-                header: mail.header.clone(),
-                body: mail.body.clone(),
-            };
-            send_http_mail(out_mail).await?;
-            //TODO send HTTP MTA
+            // not a dmail domain, handed to the MTA in a single request below
+            web2_domains.push(domain.clone());
             continue;
         }
 
-        let dmailfi_canister = Principal::from_text(reply.unwrap()).unwrap();
+        let dmailfi_canister = match Principal::from_text(reply.unwrap()) {
+            Ok(canister) => canister,
+            Err(_) => {
+                failed_domain.push(domain.clone());
+                continue;
+            }
+        };
+        let fee = submission_fee(dmailfi_canister, mail.size()).await;
         let dmailfi_response: Result<(Result<(), MailError>,), (RejectionCode, String)> =
             ic_cdk::api::call::call_with_payment(
                 dmailfi_canister,
                 "submit_mail",
                 (mail.clone(),),
-                SUBMIT_CALL_PAYMENT,
+                fee,
             )
             .await;
         if dmailfi_response.is_err() {
@@ -382,6 +1033,26 @@ async fn send_mail(mut mail: Mail) -> Result<(), MailError> {
         }
     }
 
+    if !web2_domains.is_empty() {
+        let out_mail = OutgoingMail {
+            id: generate_random_id().await?,
+            rcpt_to: Ledger::get_receipients_in_domains(&mail, &web2_domains),
+            header: mail.header.clone(),
+            body: mail.body.clone(),
+        };
+        if let Err(err) = send_http_mail(out_mail).await {
+            failed_domain.push(format!(
+                "Domains: {} with error: {}",
+                web2_domains.join(","),
+                err
+            ));
+        }
+    }
+
+    // the sender can approve the amount and try again
+    if let (Some(err), 1) = (postage_error, failed_domain.len()) {
+        return Err(err);
+    }
     if failed_domain.len() > 0 {
         let domains = failed_domain.join(",");
         return Err(MailError::MailTransferError(format!(
@@ -392,23 +1063,15 @@ async fn send_mail(mut mail: Mail) -> Result<(), MailError> {
     Ok(())
 }
 
+// Replicas must agree on the response, headers such as Date differ between them so only
+// the status and body are kept. `send_http_mail` decides what the status means.
 #[query]
 fn transform(args: TransformArgs) -> HttpResponse {
-    let mut res = http_request::HttpResponse {
-        status: args.response.status.clone(),
-        body: args.response.body.clone(),
+    http_request::HttpResponse {
+        status: args.response.status,
+        body: args.response.body,
         headers: vec![],
-    };
-
-    if res.status == Nat::from(200u32) {
-        res.body = args.response.body;
-    } else {
-        ic_cdk::api::print(format!(
-            "Received an error from http server: err = {:?}",
-            args
-        ));
     }
-    res
 }
 
 async fn generate_random_id() -> Result<String, MailError> {
@@ -420,8 +1083,18 @@ async fn generate_random_id() -> Result<String, MailError> {
 }
 
 async fn send_http_mail(out: OutgoingMail) -> Result<(), MailError> {
-    let mta_url = ledger::with(|ledger| ledger.get_mail_transfer_agent_url());
-    let sig = sign_data(&out.id)
+    let (mta_url, max_response_bytes, cycles) = ledger::with(|ledger| {
+        (
+            ledger.get_mail_transfer_agent_url(),
+            ledger.get_mta_max_response_bytes(),
+            ledger.get_mta_request_cycles(),
+        )
+    });
+    if ledger::with(|ledger| ledger.get_ecdsa_public_key()).is_none() {
+        load_ecdsa_public_key().await?;
+    }
+    let digest = out.signing_digest().map_err(MailError::HttpSendMail)?;
+    let sig = sign_data(digest)
         .await
         .map_err(|err| MailError::HttpSendMail(err))?;
     let canister_id = api::id().to_text();
@@ -434,11 +1107,21 @@ async fn send_http_mail(out: OutgoingMail) -> Result<(), MailError> {
             name: "x-principal".to_string(),
             value: canister_id,
         },
+        // Every replica makes the same request, the MTA must only relay one of them.
+        HttpHeader {
+            name: "Idempotency-Key".to_string(),
+            value: hex::encode(sha256(&out.id)),
+        },
+        HttpHeader {
+            name: "Content-Type".to_string(),
+            value: "application/json".to_string(),
+        },
     ];
-    let out_json = serde_json::to_string(&out).unwrap();
+    let out_json =
+        serde_json::to_string(&out).map_err(|err| MailError::HttpSendMail(err.to_string()))?;
     let request = CanisterHttpRequestArgument {
         url: mta_url,
-        max_response_bytes: Some(256),
+        max_response_bytes: Some(max_response_bytes),
         method: HttpMethod::POST,
         headers,
         body: Some(out_json.into_bytes()),
@@ -451,27 +1134,33 @@ async fn send_http_mail(out: OutgoingMail) -> Result<(), MailError> {
         }),
     };
 
-    match http_request(request, 20_000_000_000).await {
-        Ok((resp,)) => Ok(()),
-        Err((code, mssg)) => Err(MailError::HttpSendMail(mssg)),
+    match http_request::http_request(request, cycles).await {
+        Ok((resp,))
+            if u16::try_from(&resp.status.0).is_ok_and(|status| (200..300).contains(&status)) =>
+        {
+            Ok(())
+        }
+        Ok((resp,)) => Err(MailError::HttpSendMail(format!(
+            "MTA responded with status {}: {}",
+            resp.status,
+            String::from_utf8_lossy(&resp.body)
+        ))),
+        Err((code, mssg)) => Err(MailError::HttpSendMail(format!("{:?}: {}", code, mssg))),
     }
 }
 
-fn sha256(input: &str) -> [u8; 32] {
-    use sha2::Digest;
-    let mut hasher = sha2::Sha256::new();
-    hasher.update(input);
-    hasher.finalize().into()
+fn ecdsa_key_id() -> EcdsaKeyId {
+    #[cfg(network = "ic")]
+    return EcdsaKeyIds::ProductionKey1.to_key_id();
+    #[cfg(network = "local")]
+    return EcdsaKeyIds::TestKeyLocalDevelopment.to_key_id();
 }
 
-async fn sign_data(mssg_hash: &String) -> Result<String, String> {
+async fn sign_data(message_hash: [u8; 32]) -> Result<String, String> {
     let data = management_canister::ecdsa::sign_with_ecdsa(SignWithEcdsaArgument {
-        message_hash: sha256(mssg_hash).to_vec(),
-        derivation_path: vec![b"asymetric".to_vec()],
-        #[cfg(network = "ic")]
-        key_id: EcdsaKeyIds::ProductionKey1.to_key_id(),
-        #[cfg(network = "local")]
-        key_id: EcdsaKeyIds::TestKeyLocalDevelopment.to_key_id(),
+        message_hash: message_hash.to_vec(),
+        derivation_path: vec![ECDSA_DERIVATION_PATH.to_vec()],
+        key_id: ecdsa_key_id(),
     })
     .await;
 
@@ -482,25 +1171,323 @@ async fn sign_data(mssg_hash: &String) -> Result<String, String> {
     }
 }
 
+// Fetches the public key matching `sign_data` from the management canister and caches it.
+async fn load_ecdsa_public_key() -> Result<EcdsaPublicKeyInfo, MailError> {
+    let key_id = ecdsa_key_id();
+    let (resp,) = ecdsa_public_key(EcdsaPublicKeyArgument {
+        canister_id: None,
+        derivation_path: vec![ECDSA_DERIVATION_PATH.to_vec()],
+        key_id: key_id.clone(),
+    })
+    .await
+    .map_err(|(_, mssg)| MailError::GeneralError(mssg))?;
+
+    let info = EcdsaPublicKeyInfo {
+        public_key: ByteBuf::from(resp.public_key),
+        chain_code: ByteBuf::from(resp.chain_code),
+        derivation_path: vec![ByteBuf::from(ECDSA_DERIVATION_PATH.to_vec())],
+        key_name: key_id.name,
+    };
+    ledger::with_mut(|ledger| ledger.set_ecdsa_public_key(info.clone()));
+    Ok(info)
+}
+
+// MTAs use this key to verify the `x-sig` header of mail sent over HTTP outcalls.
+#[query]
+#[candid_method(query)]
+async fn get_ecdsa_public_key() -> Result<EcdsaPublicKeyInfo, MailError> {
+    ledger::with(|ledger| ledger.get_ecdsa_public_key()).ok_or(MailError::NotFound)
+}
+
 #[update(guard = "is_custodian")]
 #[candid_method(update)]
-async fn send_newsletter(n_id: NEWSLETTER_ID, mail: Mail) -> Result<(), MailError> {
-    let emails = ledger::with(|ledger| ledger.get_newsletter_subscribers(n_id))?;
+async fn refresh_ecdsa_public_key() -> Result<EcdsaPublicKeyInfo, MailError> {
+    load_ecdsa_public_key().await
+}
 
-    for addr in emails {
-        let mut mx = mail.clone();
-        mx.header.to = vec![addr];
-        send_mail(mx).await;
+// Starts sending the issue to every subscriber and returns the send job, which
+// `get_send_jobs` reports on. A paid issue goes to the active paid subscribers only.
+#[update(guard = "is_custodian")]
+#[candid_method(update)]
+async fn send_newsletter(
+    n_id: NEWSLETTER_ID,
+    mail: Mail,
+    tier: Option<Tier>,
+) -> Result<u64, MailError> {
+    ledger::with(|ledger| ledger.check_newsletter_owner(&n_id, caller()))?;
+    if !ledger::with(|ledger| ledger.has_newsletter_secret()) {
+        let (secret,) = ic_cdk::api::management_canister::main::raw_rand()
+            .await
+            .or(Err(MailError::FailedToGenerateMailId))?;
+        let secret: [u8; 32] = secret
+            .try_into()
+            .or(Err(MailError::FailedToGenerateMailId))?;
+        ledger::with_mut(|ledger| ledger.set_newsletter_secret(secret));
     }
+    let job_id = ledger::with_mut(|ledger| {
+        ledger.create_send_job(&n_id, mail, tier.unwrap_or_default(), caller(), api::time())
+    })?;
+    ic_cdk::spawn(run_send_job(job_id));
+    Ok(job_id)
+}
 
-    Ok(())
+#[query(guard = "not_anonymous")]
+#[candid_method(query)]
+async fn get_send_jobs(n_id: NEWSLETTER_ID) -> Result<Vec<SendJobInfo>, MailError> {
+    ledger::with(|ledger| ledger.get_send_jobs(&n_id, caller()))
+}
+
+#[query(guard = "not_anonymous")]
+#[candid_method(query)]
+async fn get_send_job_deliveries(job_id: u64) -> Result<Vec<SubscriberDelivery>, MailError> {
+    ledger::with(|ledger| ledger.get_send_job_deliveries(job_id, caller()))
+}
+
+// Sends a job batch by batch, writing the results of each one before the next. Subscribers on
+// this canister are delivered directly, other dmail canisters get one `submit_mail_batch` call
+// per batch and web2 addresses go through the MTA.
+async fn run_send_job(job_id: u64) {
+    if !ledger::with_mut(|ledger| ledger.claim_send_job(job_id, api::time())) {
+        return;
+    }
+    let base_url = gateway_url();
+    loop {
+        let batch =
+            ledger::with_mut(|ledger| ledger.next_send_batch(job_id, &base_url, api::time()));
+        let (domain, mails) = match batch {
+            Some(batch) => batch,
+            None => break,
+        };
+        let addresses: Vec<EMAIL_ADDRESS> = mails
+            .iter()
+            .map(|mail| mail.header.to.first().cloned().unwrap_or_default())
+            .collect();
+
+        let results = match send_job_destination(job_id, &domain).await {
+            Ok(destination) => deliver_batch(destination, mails).await,
+            Err(err) => {
+                let message = err.to_string();
+                addresses
+                    .iter()
+                    .map(|_| Err(MailError::MailTransferError(message.clone())))
+                    .collect()
+            }
+        };
+        let retry_after = results
+            .iter()
+            .filter_map(|result| match result {
+                Err(MailError::RateLimited { retry_after }) => Some(*retry_after),
+                _ => None,
+            })
+            .max();
+        ledger::with_mut(|ledger| {
+            ledger.record_deliveries(
+                job_id,
+                addresses.into_iter().zip(results).collect(),
+                api::time(),
+            )
+        });
+        // the timer picks the job up again once the receiving side has tokens
+        if let Some(retry_after) = retry_after {
+            let until = api::time() + retry_after.max(1) * 1_000_000_000;
+            ledger::with_mut(|ledger| ledger.release_send_job(job_id, until));
+            return;
+        }
+    }
+}
+
+async fn send_job_destination(job_id: u64, domain: &str) -> Result<Destination, MailError> {
+    if let Some(destination) =
+        ledger::with(|ledger| ledger.get_send_job_destination(job_id, domain))
+    {
+        return Ok(destination);
+    }
+    let destination = if domain == ledger::with(|ledger| ledger.get_domain_name()) {
+        Destination::Local
+    } else {
+        let lookup_response: Result<(Result<String, String>,), (RejectionCode, String)> =
+            ic_cdk::api::call::call_with_payment(
+                registry_principal()?,
+                "lookup_domain_name",
+                (domain.to_string(),),
+                LOOKUP_DOMAIN_CALL_PAYMENT,
+            )
+            .await;
+        match lookup_response {
+            Ok((Ok(canister),)) => Destination::Canister(
+                Principal::from_text(canister).map_err(|_| MailError::DomainNotFound)?,
+            ),
+            // not a dmail domain
+            Ok((Err(_),)) => Destination::Web2,
+            Err((_, mssg)) => {
+                return Err(MailError::GeneralError(format!(
+                    "Could not reach the registry: {}",
+                    mssg
+                )))
+            }
+        }
+    };
+    ledger::with_mut(|ledger| ledger.set_send_job_destination(job_id, domain, destination.clone()));
+    Ok(destination)
+}
+
+// What `submit_mail_batch` answers, one result per mail.
+type BatchReply = Result<Vec<Result<(), MailError>>, MailError>;
+
+async fn deliver_batch(destination: Destination, mails: Vec<Mail>) -> Vec<Result<(), MailError>> {
+    let mut results = vec![];
+    match destination {
+        Destination::Local => {
+            for mut mail in mails {
+                let result = match generate_random_id().await {
+                    Ok(mail_id) => ledger::with_mut(|ledger| {
+                        mail.header.receipient_canister_id = Some(id().to_text());
+                        ledger.submit_mail(mail, mail_id, api::time())
+                    }),
+                    Err(err) => Err(err),
+                };
+                results.push(result);
+            }
+            ic_cdk::spawn(send_pending_mail());
+        }
+        Destination::Canister(canister) => {
+            let count = mails.len();
+            let schedule = remote_fee_schedule(canister).await;
+            let fee = mails
+                .iter()
+                .map(|mail| schedule.fee_for(mail.size()))
+                .fold(0u64, |total, fee| total.saturating_add(fee));
+            let response: call::CallResult<(BatchReply,)> =
+                ic_cdk::api::call::call_with_payment(canister, "submit_mail_batch", (mails,), fee)
+                    .await;
+            match response {
+                Ok((Ok(batch_results),)) if batch_results.len() == count => results = batch_results,
+                Ok((Ok(_),)) => {
+                    for _ in 0..count {
+                        results.push(Err(MailError::MailTransferError(
+                            "Incomplete batch result".to_string(),
+                        )));
+                    }
+                }
+                Ok((Err(err),)) => {
+                    let message = err.to_string();
+                    for _ in 0..count {
+                        results.push(Err(MailError::MailTransferError(message.clone())));
+                    }
+                }
+                Err((_, mssg)) => {
+                    for _ in 0..count {
+                        results.push(Err(MailError::MailTransferError(mssg.clone())));
+                    }
+                }
+            }
+        }
+        // copies differ by their unsubscribe link, so each one is its own SMTP message
+        Destination::Web2 => {
+            for mail in mails {
+                let result = match generate_random_id().await {
+                    Ok(id) => {
+                        send_http_mail(OutgoingMail {
+                            id,
+                            rcpt_to: mail.header.to.clone(),
+                            header: mail.header,
+                            body: mail.body,
+                        })
+                        .await
+                    }
+                    Err(err) => Err(err),
+                };
+                results.push(result);
+            }
+        }
+    }
+    results
+}
+
+// Resumes send jobs left unfinished by a trap or a rate limit.
+async fn resume_send_jobs() {
+    let job_ids = ledger::with(|ledger| ledger.stale_send_jobs(api::time()));
+    for job_id in job_ids {
+        run_send_job(job_id).await;
+    }
+}
+
+// Where the HTTP gateway serves this canister.
+fn gateway_url() -> String {
+    format!("https://{}.icp0.io", id().to_text())
+}
+
+// The token comes from a newsletter's List-Unsubscribe link, it removes the address it was made
+// for whoever calls. Returns the newsletter's title.
+#[update]
+#[candid_method(update)]
+async fn unsubscribe_with_token(token: String) -> Result<String, MailError> {
+    ledger::with_mut(|ledger| ledger.unsubscribe_with_token(&token, api::time()))
 }
 
 #[query]
 #[candid_method(query)]
-async fn exchange_key() -> String {
-    //TODO (IMplement Exchange Key)
-    todo!()
+fn http_request(request: HttpRequest) -> GatewayResponse {
+    match (request.method.as_str(), request.path()) {
+        ("GET", "/unsubscribe" | "/confirm") => http::token_form(&request),
+        ("POST", "/unsubscribe" | "/confirm") => GatewayResponse::upgrade(),
+        ("GET", path) if path.starts_with("/newsletters/") => {
+            archive_response(&path["/newsletters/".len()..])
+                .unwrap_or_else(GatewayResponse::not_found)
+        }
+        _ => GatewayResponse::not_found(),
+    }
+}
+
+// Serves the archive of a newsletter below /newsletters/{id}: the index, /issues/{number},
+// /rss.xml and /atom.xml.
+fn archive_response(path: &str) -> Option<GatewayResponse> {
+    let mut segments = path.trim_end_matches('/').split('/');
+    let n_id = percent_decode(segments.next()?);
+    let page: Vec<&str> = segments.collect();
+    let base_url = gateway_url();
+    ledger::with(|ledger| {
+        let newsletter = ledger.get_newsletter(n_id.clone()).ok()?;
+        let issues = ledger.get_issues(&n_id).ok()?;
+        let response = match page.as_slice() {
+            [] => GatewayResponse::html(
+                200,
+                archive::render_index(&base_url, &n_id, &newsletter, &issues),
+            ),
+            ["issues", number] => {
+                let issue = ledger.get_issue(&n_id, number.parse().ok()?).ok()?;
+                archive::issue_response(&base_url, &n_id, &newsletter, &issue)
+            }
+            ["rss.xml"] => GatewayResponse::new(
+                200,
+                "application/rss+xml; charset=utf-8",
+                archive::render_rss(&base_url, &n_id, &newsletter, &issues),
+            ),
+            ["atom.xml"] => GatewayResponse::new(
+                200,
+                "application/atom+xml; charset=utf-8",
+                archive::render_atom(&base_url, &n_id, &newsletter, &issues),
+            ),
+            _ => return None,
+        };
+        Some(response)
+    })
+}
+
+// One-click unsubscribe (RFC 8058) and the unsubscribe and confirmation forms post here.
+#[update]
+#[candid_method(update)]
+fn http_request_update(request: HttpRequest) -> GatewayResponse {
+    ledger::with_mut(|ledger| http::http_request_update(ledger, &request, api::time()))
+}
+
+#[query]
+#[candid_method(query)]
+async fn exchange_key() -> Result<String, MailError> {
+    // the key other parties verify this canister's signatures with, hex encoded
+    ledger::with(|ledger| ledger.get_ecdsa_public_key())
+        .map(|info| hex::encode(info.public_key.as_slice()))
+        .ok_or(MailError::NotFound)
 }
 
 #[update]
@@ -509,7 +1496,20 @@ async fn subscribe_to_newsletter(
     addr: EMAIL_ADDRESS,
     n_id: NEWSLETTER_ID,
 ) -> Result<(), MailError> {
-    ledger::with_mut(|ledger| ledger.subscribe_to_newsletter(n_id, addr, caller()))
+    take_rate_tokens(vec![RateKey::Caller(caller())])?;
+    let token = generate_random_id().await?;
+    ledger::with_mut(|ledger| {
+        ledger.subscribe_to_newsletter(n_id, addr, caller(), &token, &gateway_url(), api::time())
+    })?;
+    ic_cdk::spawn(send_pending_mail());
+    Ok(())
+}
+
+// Called with the token from the confirmation mail, returns the newsletter it subscribed to.
+#[update]
+#[candid_method(update)]
+async fn confirm_subscription(token: String) -> Result<NEWSLETTER_ID, MailError> {
+    ledger::with_mut(|ledger| ledger.confirm_subscription(&token, api::time()))
 }
 
 #[update]
@@ -518,7 +1518,7 @@ async fn unsubscribe_to_newsletter(
     addr: EMAIL_ADDRESS,
     n_id: NEWSLETTER_ID,
 ) -> Result<(), MailError> {
-    ledger::with_mut(|ledger| ledger.unsubscribe_to_newsletter(n_id, addr, caller()))
+    ledger::with_mut(|ledger| ledger.unsubscribe_to_newsletter(n_id, addr, caller(), api::time()))
 }
 
 #[update(guard = "is_custodian")]
@@ -529,7 +1529,26 @@ async fn create_newsletter(n: Newsletter) -> Result<(), MailError> {
         .or(Err(MailError::FailedToGenerateMailId))?;
     let n_id = hex::encode(n_id_hex);
 
-    ledger::with_mut(|ledger| ledger.create_newletter(n_id, n))
+    ledger::with_mut(|ledger| ledger.create_newletter(n_id, n, caller()))
+}
+
+#[update(guard = "not_anonymous")]
+#[candid_method(update)]
+async fn update_newsletter(n_id: NEWSLETTER_ID, n: Newsletter) -> Result<(), MailError> {
+    ledger::with_mut(|ledger| ledger.update_newsletter(&n_id, n, caller()))
+}
+
+#[update(guard = "not_anonymous")]
+#[candid_method(update)]
+async fn delete_newsletter(n_id: NEWSLETTER_ID) -> Result<(), MailError> {
+    ledger::with_mut(|ledger| ledger.delete_newsletter(&n_id, caller()))
+}
+
+// Only the newsletter's owner sees who subscribed.
+#[query(guard = "not_anonymous")]
+#[candid_method(query)]
+async fn get_newsletter_subscribers(n_id: NEWSLETTER_ID) -> Result<Vec<Subscriber>, MailError> {
+    ledger::with(|ledger| ledger.get_subscribers(&n_id, caller()))
 }
 
 #[query]
@@ -544,23 +1563,428 @@ async fn get_newsletter(n_id: NEWSLETTER_ID) -> Result<Newsletter, MailError> {
     ledger::with(|ledger| ledger.get_newsletter(n_id))
 }
 
+// The issues sent so far, oldest first. The archive is public like the newsletter itself.
+#[query]
+#[candid_method(query)]
+async fn get_newsletter_issues(n_id: NEWSLETTER_ID) -> Result<Vec<IssueSummary>, MailError> {
+    ledger::with(|ledger| ledger.get_issue_summaries(&n_id))
+}
+
+#[query]
+#[candid_method(query)]
+async fn get_newsletter_issue(n_id: NEWSLETTER_ID, number: u32) -> Result<Issue, MailError> {
+    ledger::with(|ledger| ledger.get_issue(&n_id, number))
+}
+
+#[query(guard = "not_anonymous")]
+#[candid_method(query)]
+async fn get_issue_metrics(n_id: NEWSLETTER_ID) -> Result<Vec<IssueMetrics>, MailError> {
+    ledger::with(|ledger| ledger.get_issue_metrics(&n_id, caller()))
+}
+
+// Daily subscriptions and unsubscribes, `since` in nanoseconds.
+#[query(guard = "not_anonymous")]
+#[candid_method(query)]
+async fn get_subscriber_growth(
+    n_id: NEWSLETTER_ID,
+    since: Option<u64>,
+) -> Result<Vec<SubscriberGrowth>, MailError> {
+    ledger::with(|ledger| ledger.get_subscriber_growth(&n_id, since, caller()))
+}
+
+#[query]
+#[candid_method(query)]
+async fn get_newsletter_plan(n_id: NEWSLETTER_ID) -> Option<NewsletterPlan> {
+    ledger::with(|ledger| ledger.get_newsletter_plan(&n_id))
+}
+
+#[update(guard = "not_anonymous")]
+#[candid_method(update)]
+async fn set_newsletter_plan(
+    n_id: NEWSLETTER_ID,
+    plan: Option<NewsletterPlan>,
+) -> Result<(), MailError> {
+    ledger::with_mut(|ledger| ledger.set_newsletter_plan(&n_id, plan, caller()))
+}
+
+// Moves a confirmed subscription to the paid tier. The caller pays the first period now and
+// every following one from the allowance it approved for this canister with `icrc2_approve`.
+#[update(guard = "not_anonymous")]
+#[candid_method(update)]
+async fn start_paid_subscription(
+    n_id: NEWSLETTER_ID,
+    address: EMAIL_ADDRESS,
+) -> Result<(), MailError> {
+    let renewal = ledger::with_mut(|ledger| {
+        ledger.begin_paid_subscription(&n_id, &address, caller(), api::time())
+    })?;
+    let renewal = match renewal {
+        Some(renewal) => renewal,
+        None => return Ok(()),
+    };
+    let result = charge_subscription(&renewal).await;
+    ledger::with_mut(|ledger| {
+        ledger.finish_billing(
+            &renewal,
+            result.as_ref().map(|_| ()).map_err(|err| err.to_string()),
+            api::time(),
+        )
+    });
+    result
+}
+
+#[update(guard = "not_anonymous")]
+#[candid_method(update)]
+async fn cancel_paid_subscription(
+    n_id: NEWSLETTER_ID,
+    address: EMAIL_ADDRESS,
+) -> Result<(), MailError> {
+    ledger::with_mut(|ledger| ledger.cancel_paid_subscription(&n_id, &address, caller()))
+}
+
+// Charges every paid subscription whose period is over, the ones that can not be charged are
+// suspended.
+async fn renew_subscriptions() {
+    let renewals = ledger::with_mut(|ledger| ledger.begin_renewals(api::time()));
+    for renewal in renewals {
+        let result = charge_subscription(&renewal)
+            .await
+            .map_err(|err| err.to_string());
+        ledger::with_mut(|ledger| ledger.finish_billing(&renewal, result, api::time()));
+    }
+}
+
+// Takes one period's price from the payer's allowance and pays it to the newsletter's owner.
+async fn charge_subscription(renewal: &Renewal) -> Result<(), MailError> {
+    icrc::charge_subscription(
+        &token_ledger()?,
+        renewal.payer,
+        renewal.owner,
+        renewal.plan.price.clone(),
+    )
+    .await
+}
+
 #[update]
 #[candid_method(update)]
 async fn public_create_user(email_address: EMAIL_ADDRESS) -> Result<(), MailError> {
-    ledger::with_mut(|ledger| ledger.public_create_user(email_address))
+    ledger::with_mut(|ledger| ledger.public_create_user(email_address, caller()))
 }
 
 #[update]
 #[candid_method(update)]
-async fn delete_self() -> Result<(), MailError> {
-    ledger::with_mut(|ledger| ledger.delete_self())
+async fn delete_self(mailbox: Option<EMAIL_ADDRESS>) -> Result<(), MailError> {
+    let email = authorize(mailbox, MailboxAction::Manage)?;
+    ledger::with_mut(|ledger| ledger.delete_mailbox(&email))
+}
+
+#[query(guard = "is_one_of_user")]
+#[candid_method(query)]
+async fn get_my_mailboxes() -> Vec<MailboxAccess> {
+    ledger::with(|ledger| ledger.get_mailboxes(caller()))
+}
+
+// The mailbox used by calls that do not name one.
+#[update(guard = "is_one_of_user")]
+#[candid_method(update)]
+async fn set_primary_mailbox(mailbox: EMAIL_ADDRESS) -> Result<(), MailError> {
+    ledger::with_mut(|ledger| ledger.set_primary_mailbox(caller(), mailbox))
+}
+
+#[query(guard = "is_one_of_user")]
+#[candid_method(query)]
+async fn get_mailbox_members(
+    mailbox: Option<EMAIL_ADDRESS>,
+) -> Result<Vec<(Principal, MailboxRole)>, MailError> {
+    let email = authorize(mailbox, MailboxAction::Manage)?;
+    ledger::with(|ledger| Ok(ledger.get_mailbox_members(&email)))
+}
+
+// Shares a mailbox, granting again changes the role.
+#[update(guard = "is_one_of_user")]
+#[candid_method(update)]
+async fn grant_mailbox_access(
+    principal: Principal,
+    role: MailboxRole,
+    mailbox: Option<EMAIL_ADDRESS>,
+) -> Result<(), MailError> {
+    let email = authorize(mailbox, MailboxAction::Manage)?;
+    ledger::with_mut(|ledger| ledger.grant_mailbox_access(&email, principal, role))
+}
+
+#[update(guard = "is_one_of_user")]
+#[candid_method(update)]
+async fn revoke_mailbox_access(
+    principal: Principal,
+    mailbox: Option<EMAIL_ADDRESS>,
+) -> Result<(), MailError> {
+    let email = authorize(mailbox, MailboxAction::Manage)?;
+    ledger::with_mut(|ledger| ledger.revoke_mailbox_access(&email, principal))
+}
+
+// Lets another principal act on the mailbox until `expires_at` (nanoseconds) within `scopes`.
+#[update(guard = "is_one_of_user")]
+#[candid_method(update)]
+async fn create_grant(
+    grantee: Principal,
+    scopes: Vec<GrantScope>,
+    expires_at: u64,
+    mailbox: Option<EMAIL_ADDRESS>,
+) -> Result<u64, MailError> {
+    let email = authorize(mailbox, MailboxAction::Manage)?;
+    ledger::with_mut(|ledger| {
+        ledger.create_grant(&email, caller(), grantee, scopes, expires_at, api::time())
+    })
+}
+
+#[update(guard = "is_one_of_user")]
+#[candid_method(update)]
+async fn revoke_grant(grant_id: u64, mailbox: Option<EMAIL_ADDRESS>) -> Result<(), MailError> {
+    let email = authorize(mailbox, MailboxAction::Manage)?;
+    ledger::with_mut(|ledger| ledger.revoke_grant(&email, grant_id))
+}
+
+#[query(guard = "is_one_of_user")]
+#[candid_method(query)]
+async fn get_grants(mailbox: Option<EMAIL_ADDRESS>) -> Result<Vec<MailboxGrant>, MailError> {
+    let email = authorize(mailbox, MailboxAction::Manage)?;
+    ledger::with(|ledger| Ok(ledger.get_grants(&email)))
+}
+
+#[query(guard = "not_anonymous")]
+#[candid_method(query)]
+async fn get_my_grants() -> Vec<MailboxGrant> {
+    ledger::with(|ledger| ledger.get_grants_of(caller(), api::time()))
+}
+
+#[query(guard = "is_one_of_user")]
+#[candid_method(query)]
+async fn get_audit_log(mailbox: Option<EMAIL_ADDRESS>) -> Result<Vec<AuditEntry>, MailError> {
+    let email = authorize(mailbox, MailboxAction::Manage)?;
+    ledger::with(|ledger| Ok(ledger.get_audit_log(&email)))
+}
+
+// None stops charging postage.
+#[update(guard = "is_one_of_user")]
+#[candid_method(update)]
+async fn set_postage(price: Option<Nat>, mailbox: Option<EMAIL_ADDRESS>) -> Result<(), MailError> {
+    let email = authorize(mailbox, MailboxAction::Manage)?;
+    ledger::with_mut(|ledger| ledger.set_postage_price(&email, price))
+}
+
+// What `sender` has to approve before writing to `receipient`.
+#[query]
+#[candid_method(query)]
+fn get_postage(receipient: EMAIL_ADDRESS, sender: EMAIL_ADDRESS) -> Nat {
+    ledger::with(|ledger| ledger.get_postage(&receipient, &sender))
+}
+
+#[update(guard = "is_one_of_user")]
+#[candid_method(update)]
+async fn add_contacts(
+    addresses: Vec<EMAIL_ADDRESS>,
+    mailbox: Option<EMAIL_ADDRESS>,
+) -> Result<(), MailError> {
+    let email = authorize(mailbox, MailboxAction::Manage)?;
+    ledger::with_mut(|ledger| ledger.add_contacts(&email, &addresses))
+}
+
+#[update(guard = "is_one_of_user")]
+#[candid_method(update)]
+async fn remove_contact(
+    address: EMAIL_ADDRESS,
+    mailbox: Option<EMAIL_ADDRESS>,
+) -> Result<(), MailError> {
+    let email = authorize(mailbox, MailboxAction::Manage)?;
+    ledger::with_mut(|ledger| ledger.remove_contact(&email, &address))
 }
 
-fn check_payment<'a>(payment: u64) -> Result<(), &'a str> {
+#[query(guard = "is_one_of_user")]
+#[candid_method(query)]
+async fn get_contacts(mailbox: Option<EMAIL_ADDRESS>) -> Result<Vec<EMAIL_ADDRESS>, MailError> {
+    let email = authorize(mailbox, MailboxAction::Manage)?;
+    ledger::with(|ledger| Ok(ledger.get_contacts(&email)))
+}
+
+// Sends the postage paid for a mail back to the payer and adds the sender to the contacts.
+#[update(guard = "is_one_of_user")]
+#[candid_method(update)]
+async fn refund_postage(
+    mail_id: MAIL_ID,
+    mailbox: Option<EMAIL_ADDRESS>,
+) -> Result<Nat, MailError> {
+    let email = authorize(mailbox, MailboxAction::Manage)?;
+    let payment = ledger::with_mut(|ledger| ledger.begin_postage_refund(&email, &mail_id))?;
+    let result = pay_out(Account::from(payment.payer), payment.amount).await;
+    if result.is_err() {
+        ledger::with_mut(|ledger| ledger.cancel_postage_refund(&email, &mail_id));
+    }
+    result
+}
+
+#[update(guard = "is_one_of_user")]
+#[candid_method(update)]
+async fn withdraw_postage(
+    to: Account,
+    amount: Nat,
+    mailbox: Option<EMAIL_ADDRESS>,
+) -> Result<Nat, MailError> {
+    let email = authorize(mailbox, MailboxAction::Manage)?;
+    ledger::with_mut(|ledger| ledger.debit_postage(&email, &amount))?;
+    let result = pay_out(to, amount.clone()).await;
+    if result.is_err() {
+        ledger::with_mut(|ledger| ledger.credit_postage(&email, amount));
+    }
+    result
+}
+
+#[query(guard = "is_one_of_user")]
+#[candid_method(query)]
+async fn get_postage_balance(mailbox: Option<EMAIL_ADDRESS>) -> Result<Nat, MailError> {
+    let email = authorize(mailbox, MailboxAction::Manage)?;
+    ledger::with(|ledger| Ok(ledger.get_postage_balance(&email)))
+}
+
+#[query(guard = "is_one_of_user")]
+#[candid_method(query)]
+async fn get_postage_payments(
+    mailbox: Option<EMAIL_ADDRESS>,
+) -> Result<Vec<PostagePayment>, MailError> {
+    let email = authorize(mailbox, MailboxAction::Manage)?;
+    ledger::with(|ledger| Ok(ledger.get_postage_payments(&email)))
+}
+
+fn registry_principal() -> Result<Principal, MailError> {
+    let registry_id = ledger::with(|ledger| ledger.get_registry_address());
+    Principal::from_text(registry_id)
+        .map_err(|_| MailError::GeneralError("No registry canister is configured".to_string()))
+}
+
+// Other canisters look this up to attach the cycles a submission costs.
+#[query]
+#[candid_method(query)]
+fn get_fee_schedule() -> FeeSchedule {
+    ledger::with(|ledger| ledger.get_fee_schedule())
+}
+
+// None restores the default schedule. The registry sets it when it changes the domain's fees.
+#[update(guard = "is_custodian_or_controller")]
+#[candid_method(update)]
+async fn set_fee_schedule(schedule: Option<FeeSchedule>) {
+    ledger::with_mut(|ledger| ledger.set_fee_schedule(schedule))
+}
+
+// What `canister` charges for a submission of `size` bytes. Canisters from before fee schedules
+// charge the default base fee.
+async fn submission_fee(canister: Principal, size: u64) -> u64 {
+    remote_fee_schedule(canister).await.fee_for(size)
+}
+
+async fn remote_fee_schedule(canister: Principal) -> FeeSchedule {
+    let response: Result<(FeeSchedule,), (RejectionCode, String)> =
+        call::call(canister, "get_fee_schedule", ()).await;
+    response.map(|(schedule,)| schedule).unwrap_or_default()
+}
+
+fn reply_size(reply: &MailReply) -> u64 {
+    (reply.content.0.len() + reply.sender_address.len()) as u64
+}
+
+fn check_payment(payment: u64) -> Result<(), MailError> {
     if ic_cdk::api::call::msg_cycles_available() < payment {
-        return Err("Insufficient Fund");
+        return Err(MailError::InsufficientCycles { required: payment });
+    }
+
+    Ok(())
+}
+
+// Custodians are not limited, newsletters are sent by them.
+fn take_rate_tokens(keys: Vec<RateKey>) -> Result<(), MailError> {
+    if is_custodian().is_ok() {
+        return Ok(());
+    }
+    ledger::with_mut(|ledger| ledger.take_rate_tokens(&keys, api::time()))
+}
+
+// Takes the postage the local receipients of `mail` charge its sender from `payer` with
+// `icrc2_transfer_from`, in one transfer. Custodians do not pay.
+async fn collect_postage(
+    payer: Option<Principal>,
+    mail: &Mail,
+) -> Result<Vec<(EMAIL_ADDRESS, Nat)>, MailError> {
+    if is_custodian().is_ok() {
+        return Ok(vec![]);
+    }
+    let due = ledger::with(|ledger| ledger.postage_due(&mail.header));
+    if due.is_empty() {
+        return Ok(due);
+    }
+    let amount = due
+        .iter()
+        .fold(Nat::from(0u64), |total, (_, price)| total + price.clone());
+    let payer = match payer {
+        Some(payer) => payer,
+        None => return Err(MailError::PostageRequired { amount }),
+    };
+
+    icrc::collect_postage(&token_ledger()?, payer, id(), amount).await?;
+    Ok(due)
+}
+
+// Gives back postage taken for a mail that was not delivered.
+async fn return_postage(payer: Principal, due: Vec<(EMAIL_ADDRESS, Nat)>) {
+    let amount = due
+        .into_iter()
+        .fold(Nat::from(0u64), |total, (_, price)| total + price);
+    if amount > 0u64 {
+        let _ = pay_out(Account::from(payer), amount).await;
+    }
+}
+
+async fn pay_out(to: Account, amount: Nat) -> Result<Nat, MailError> {
+    icrc::pay_out(&token_ledger()?, to, amount).await
+}
+
+// The configured token ledger, called from this canister.
+struct CanisterLedger(Principal);
+
+impl TokenLedger for CanisterLedger {
+    async fn fee(&self) -> Result<Nat, String> {
+        let response: Result<(Nat,), (RejectionCode, String)> =
+            call::call(self.0, "icrc1_fee", ()).await;
+        response.map(|(fee,)| fee).map_err(|(_, message)| message)
     }
 
+    async fn transfer(&self, args: TransferArg) -> Result<Result<Nat, TransferError>, String> {
+        let response: Result<(Result<Nat, TransferError>,), (RejectionCode, String)> =
+            call::call(self.0, "icrc1_transfer", (args,)).await;
+        response
+            .map(|(result,)| result)
+            .map_err(|(_, message)| message)
+    }
+
+    async fn transfer_from(
+        &self,
+        args: TransferFromArgs,
+    ) -> Result<Result<Nat, TransferFromError>, String> {
+        let response: Result<(Result<Nat, TransferFromError>,), (RejectionCode, String)> =
+            call::call(self.0, "icrc2_transfer_from", (args,)).await;
+        response
+            .map(|(result,)| result)
+            .map_err(|(_, message)| message)
+    }
+}
+
+fn token_ledger() -> Result<CanisterLedger, MailError> {
+    ledger::with(|ledger| ledger.get_token_ledger()).map(CanisterLedger)
+}
+
+fn check_sender_domain(address: &str) -> Result<(), MailError> {
+    if ledger::with(|ledger| ledger.is_domain_blocked(address)) {
+        return Err(MailError::GeneralError(
+            "The sender's domain is blocked".to_string(),
+        ));
+    }
     Ok(())
 }
 
@@ -578,8 +2002,7 @@ fn not_anonymous() -> Result<(), String> {
 
 fn is_one_of_user() -> Result<(), String> {
     not_anonymous()?;
-    let address = ledger::with(|ledger| ledger.get_user_address(caller()));
-    if address.is_none() {
+    if !ledger::with(|ledger| ledger.has_mailbox(caller(), api::time())) {
         return Err("There's no email address associated with this prinicpal".to_string());
     } else {
         return Ok(());
@@ -593,6 +2016,27 @@ fn export_candid() -> String {
     __export_service()
 }
 
+// The mailbox a user call acts on, the caller's primary one unless it selected another, once the
+// caller may do `action` there. Grants reach every action but `Manage` and their use is written to
+// the audit log, so endpoints below `Manage` are update calls, a query would drop the entry.
+fn authorize(
+    mailbox: Option<EMAIL_ADDRESS>,
+    action: MailboxAction,
+) -> Result<EMAIL_ADDRESS, MailError> {
+    ledger::with_mut(|ledger| ledger.authorize(caller(), mailbox, &action, api::time()))
+}
+
 fn is_custodian() -> Result<(), String> {
     ledger::with(|ledger| ledger.is_custodian(caller()))
 }
+
+fn is_custodian_or_controller() -> Result<(), String> {
+    if is_controller(&caller()) {
+        return Ok(());
+    }
+    is_custodian()
+}
+
+fn is_mail_transfer_agent() -> Result<(), String> {
+    ledger::with(|ledger| ledger.is_mail_transfer_agent(caller()))
+}
@@ -1,6 +1,6 @@
 
 use candid::{candid_method, encode_args, Principal};
-use dmailfi_types::{LedgerConfiguration, MailError, Rcbytes, RegistryError, LOOKUP_DOMAIN_CALL_PAYMENT};
+use dmailfi_types::{FeeSchedule, LedgerConfiguration, MailError, Rcbytes, RegistryError, LOOKUP_DOMAIN_CALL_PAYMENT};
 use ic_cdk::{
     api::{is_controller, management_canister::{
         self, main::{CanisterInstallMode, CreateCanisterArgument, InstallCodeArgument}, provisional::CanisterSettings
@@ -41,7 +41,7 @@ async fn lookup_domain_name(
     domain_name: DOMAIN_NAME,
 ) -> Result<std::string::String, RegistryError> {
     if ic_cdk::api::call::msg_cycles_available() < LOOKUP_DOMAIN_CALL_PAYMENT {
-        return Err(RegistryError::GeneralError("Not Enough Cycles".to_string()));
+        return Err(RegistryError::InsufficientCycles { required: LOOKUP_DOMAIN_CALL_PAYMENT });
     }
     let result = ledger::with(|ledger| ledger.lookup_domain_name(domain_name));
     ic_cdk::api::call::msg_cycles_accept(LOOKUP_DOMAIN_CALL_PAYMENT);
@@ -51,11 +51,19 @@ async fn lookup_domain_name(
 #[update(guard = "is_custodian")]
 #[candid_method(update)]
 async fn create_dmail_canister(domain_name: DOMAIN_NAME, controller_principal: String, config : Option<LedgerConfiguration>) -> Result<String, RegistryError> {
+    // everything is checked before the cycles for the new canister are spent
+    let controller = Principal::from_text(&controller_principal).map_err(|_| RegistryError::InvalidPrincipal(controller_principal.clone()))?;
+    if ledger::with(|ledger| ledger.lookup_domain_name(domain_name.clone())).is_ok() {
+        return Err(RegistryError::GeneralError(format!("{} is already registered", domain_name)));
+    }
+    let fee_schedule = config.as_ref().map(|config| config.get_fee_schedule()).unwrap_or_default();
+    let install_arg = encode_args((config, )).map_err(|err| RegistryError::GeneralError(err.to_string()))?;
+
     let registry_id = ic_cdk::api::id();
     let arg = CreateCanisterArgument {
         settings: Some(CanisterSettings {
             controllers: Some(vec![
-                Principal::from_text(controller_principal.clone()).unwrap(),
+                controller,
                 registry_id,
             ]),
             ..CanisterSettings::default()
@@ -71,18 +79,19 @@ async fn create_dmail_canister(domain_name: DOMAIN_NAME, controller_principal: S
         mode: ic_cdk::api::management_canister::main::CanisterInstallMode::Install,
         canister_id: cr.canister_id,
         wasm_module: wasm.to_vec(),
-        arg: encode_args((config, )).unwrap(),
+        arg: install_arg,
     }).await;
 
     if installation_result.is_err() {
         ledger::with_mut(|ledger|{
-            ledger.add_to_pending_canister(cr.canister_id, Principal::from_text(controller_principal).unwrap());
+            ledger.add_to_pending_canister(cr.canister_id, controller);
         });
 
         return Err(RegistryError::FailedToInstallCode(cr.canister_id.to_string()));
     }
 
     ledger::with_mut(|ledger| {
+        ledger.set_fee_schedule(domain_name.clone(), fee_schedule);
         ledger.add_domain(domain_name, cr.canister_id.to_text())
     });
 
@@ -100,7 +109,13 @@ async fn upgrade_all_dmail_canisters() -> Result<(), RegistryError> {
     let Rcbytes(wasm) = DMAILFI_WASM.with_borrow(|f| f.clone());
     let mut failed_domains = vec![];
     for canister_id in canister_ids {
-        let canister_principal = Principal::from_text(canister_id.clone()).unwrap();
+        let canister_principal = match Principal::from_text(canister_id.clone()) {
+            Ok(canister_principal) => canister_principal,
+            Err(_) => {
+                failed_domains.push(canister_id);
+                continue;
+            }
+        };
         let arg = InstallCodeArgument { mode: CanisterInstallMode::Upgrade, canister_id: canister_principal, wasm_module: wasm.to_vec(), arg: vec![] };
         let reslt = management_canister::main::install_code(arg).await;
         if reslt.is_err() {
@@ -122,6 +137,27 @@ async fn get_domain_details(domain_name: DOMAIN_NAME) -> Result<std::string::Str
     ledger::with(|ledger| ledger.get_domain_details(domain_name))
 }
 
+// The cycles the domain's canister takes per mail, also available from its own `get_fee_schedule`.
+#[query]
+#[candid_method(query)]
+async fn get_fee_schedule(domain_name: DOMAIN_NAME) -> Result<FeeSchedule, RegistryError> {
+    ledger::with(|ledger| ledger.get_fee_schedule(&domain_name))
+}
+
+// Changes the domain's fees here and on its canister, which the registry controls.
+#[update(guard = "is_custodian")]
+#[candid_method(update)]
+async fn set_fee_schedule(domain_name: DOMAIN_NAME, schedule: FeeSchedule) -> Result<(), RegistryError> {
+    let canister_id = ledger::with(|ledger| ledger.lookup_domain_name(domain_name.clone()))?;
+    let canister_id = Principal::from_text(canister_id).map_err(|_| RegistryError::NotFound)?;
+    let response: Result<(), _> = ic_cdk::api::call::call(canister_id, "set_fee_schedule", (Some(schedule.clone()),)).await;
+    if let Err((_, message)) = response {
+        return Err(RegistryError::GeneralError(format!("Could not update the domain's canister: {}", message)));
+    }
+    ledger::with_mut(|ledger| ledger.set_fee_schedule(domain_name, schedule));
+    Ok(())
+}
+
 #[query(guard = "not_anonymous")]
 #[candid_method(query)]
 async fn lookup_user() -> Result<Vec<String>, RegistryError> {
@@ -1,7 +1,7 @@
 use std::collections::{HashMap, HashSet};
 
 use candid::{CandidType, Deserialize, Principal};
-use dmailfi_types::RegistryError;
+use dmailfi_types::{FeeSchedule, RegistryError};
 
 pub type DOMAIN_NAME = String;
 pub type CANISTER_ID = Principal;
@@ -11,7 +11,9 @@ pub struct Ledger {
     // Principal
     customers : HashMap<Principal, HashSet<CANISTER_ID>>,
     custodians: HashSet<String>,
-    pending_canister: HashMap<CANISTER_ID, Principal>
+    pending_canister: HashMap<CANISTER_ID, Principal>,
+    // domains without an entry use `FeeSchedule::default()`
+    fee_schedules: HashMap<DOMAIN_NAME, FeeSchedule>
 }
 
 
@@ -41,6 +43,17 @@ impl Ledger {
         self.domains.insert(domain_name, principal_str);
     }
 
+    pub fn get_fee_schedule(&self, domain_name : &DOMAIN_NAME) -> Result<FeeSchedule, RegistryError> {
+        if !self.domains.contains_key(domain_name) {
+            return Err(RegistryError::NotFound);
+        }
+        Ok(self.fee_schedules.get(domain_name).cloned().unwrap_or_default())
+    }
+
+    pub fn set_fee_schedule(&mut self, domain_name : DOMAIN_NAME, schedule : FeeSchedule) {
+        self.fee_schedules.insert(domain_name, schedule);
+    }
+
     pub fn get_all_domain_canisters(&self) -> Vec<String> {
         self.domains.values().cloned().collect()
     }
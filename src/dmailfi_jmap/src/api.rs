@@ -0,0 +1,1215 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use dmailfi_types::{InboxData, Mail, MailFolder, MailHeader, Rcbytes, UtcDateTime};
+use serde_json::{json, Map, Value};
+
+use crate::{
+    state::{self, Snapshot, StateHistory},
+    store::MailStore,
+};
+
+pub const CORE: &str = "urn:ietf:params:jmap:core";
+pub const MAIL: &str = "urn:ietf:params:jmap:mail";
+pub const SUBMISSION: &str = "urn:ietf:params:jmap:submission";
+
+// (id, name, role)
+pub const MAILBOXES: [(&str, &str, &str); 4] = [
+    ("inbox", "Inbox", "inbox"),
+    ("sent", "Sent", "sent"),
+    ("trash", "Trash", "trash"),
+    ("drafts", "Drafts", "drafts"),
+];
+
+pub struct Account {
+    pub email_address: String,
+    pub app_password: String,
+}
+
+// Drafts live in the gateway only, until EmailSubmission/set hands them to `send_mail_as`
+#[derive(Default)]
+pub struct Drafts {
+    next_id: u64,
+    mails: HashMap<String, HashMap<String, Mail>>,
+}
+
+pub struct Gateway<S: MailStore> {
+    pub store: S,
+    pub history: Mutex<StateHistory>,
+    pub drafts: Mutex<Drafts>,
+}
+
+struct MethodError(&'static str, Option<String>);
+
+impl MethodError {
+    fn to_value(&self) -> Value {
+        match &self.1 {
+            Some(description) => json!({ "type": self.0, "description": description }),
+            None => json!({ "type": self.0 }),
+        }
+    }
+}
+
+// Per request view of the account, folders are fetched once and reused by every method call
+struct Context<'a, S: MailStore> {
+    gateway: &'a Gateway<S>,
+    account: &'a Account,
+    mails: Option<Vec<(MailFolder, InboxData)>>,
+    created_ids: HashMap<String, String>,
+}
+
+impl<S: MailStore> Gateway<S> {
+    pub fn new(store: S) -> Arc<Self> {
+        Arc::new(Gateway {
+            store,
+            history: Mutex::new(StateHistory::default()),
+            drafts: Mutex::new(Drafts::default()),
+        })
+    }
+
+    pub fn session(&self, account: &Account, base_url: &str) -> Result<Value, String> {
+        let mut context = Context::new(self, account);
+        let state = context.state()?;
+        let id = &account.email_address;
+        Ok(json!({
+            "capabilities": {
+                CORE: {
+                    "maxSizeUpload": 2_000_000,
+                    "maxConcurrentUpload": 1,
+                    "maxSizeRequest": 2_000_000,
+                    "maxConcurrentRequests": 4,
+                    "maxCallsInRequest": 16,
+                    "maxObjectsInGet": 500,
+                    "maxObjectsInSet": 100,
+                    "collationAlgorithms": []
+                },
+                MAIL: {},
+                SUBMISSION: {}
+            },
+            "accounts": {
+                id: {
+                    "name": id,
+                    "isPersonal": true,
+                    "isReadOnly": false,
+                    "accountCapabilities": {
+                        MAIL: {
+                            "maxMailboxesPerEmail": 1,
+                            "maxMailboxDepth": 1,
+                            "maxSizeMailboxName": 64,
+                            "maxSizeAttachmentsPerEmail": 0,
+                            "emailQuerySortOptions": ["receivedAt"],
+                            "mayCreateTopLevelMailbox": false
+                        },
+                        SUBMISSION: {
+                            "maxDelayedSend": 0,
+                            "submissionExtensions": {}
+                        }
+                    }
+                }
+            },
+            "primaryAccounts": { MAIL: id, SUBMISSION: id },
+            "username": id,
+            "apiUrl": format!("{}/jmap/api", base_url),
+            "downloadUrl": format!("{}/jmap/download/{{accountId}}/{{blobId}}/{{name}}?accept={{type}}", base_url),
+            "uploadUrl": format!("{}/jmap/upload/{{accountId}}/", base_url),
+            "eventSourceUrl": format!("{}/jmap/eventsource?types={{types}}&closeafter={{closeafter}}&ping={{ping}}", base_url),
+            "state": state
+        }))
+    }
+
+    pub fn download(&self, account: &Account, blob_id: &str) -> Result<Vec<u8>, String> {
+        if let Some(draft) = self.draft(account, blob_id) {
+            return Ok(draft.body.0.to_vec());
+        }
+        let mail = self
+            .store
+            .get_mail(&account.email_address, &account.app_password, blob_id)?;
+        Ok(mail.body.0.to_vec())
+    }
+
+    // RFC 8620 section 3.3
+    pub fn api(&self, account: &Account, request: Value) -> Result<Value, String> {
+        let calls = request
+            .get("methodCalls")
+            .and_then(|c| c.as_array())
+            .ok_or("methodCalls is missing")?;
+
+        let mut context = Context::new(self, account);
+        let mut responses: Vec<Value> = vec![];
+        for call in calls {
+            let (name, args, call_id) = match call.as_array().map(|c| c.as_slice()) {
+                Some([Value::String(name), Value::Object(args), Value::String(call_id)]) => {
+                    (name.clone(), args.clone(), call_id.clone())
+                }
+                _ => return Err("Invalid method call".to_string()),
+            };
+
+            let result =
+                resolve_references(args, &responses).and_then(|args| context.invoke(&name, args));
+            match result {
+                Ok(response) => responses.push(json!([name, response, call_id])),
+                Err(err) => responses.push(json!(["error", err.to_value(), call_id])),
+            }
+        }
+
+        let session_state = context.state()?;
+        Ok(json!({ "methodResponses": responses, "sessionState": session_state }))
+    }
+
+    fn draft(&self, account: &Account, id: &str) -> Option<Mail> {
+        let drafts = self.drafts.lock().unwrap();
+        drafts.mails.get(&account.email_address)?.get(id).cloned()
+    }
+}
+
+impl<'a, S: MailStore> Context<'a, S> {
+    fn new(gateway: &'a Gateway<S>, account: &'a Account) -> Self {
+        Context {
+            gateway,
+            account,
+            mails: None,
+            created_ids: HashMap::new(),
+        }
+    }
+
+    fn invoke(&mut self, name: &str, args: Map<String, Value>) -> Result<Value, MethodError> {
+        if let Some(account_id) = args.get("accountId").and_then(|a| a.as_str()) {
+            if account_id != self.account.email_address {
+                return Err(MethodError("accountNotFound", None));
+            }
+        }
+
+        match name {
+            "Core/echo" => Ok(Value::Object(args)),
+            "Mailbox/get" => self.mailbox_get(&args),
+            "Mailbox/query" => self.mailbox_query(),
+            "Mailbox/changes" => self.mailbox_changes(&args),
+            "Email/get" => self.email_get(&args),
+            "Email/query" => self.email_query(&args),
+            "Email/changes" | "Thread/changes" => self.email_changes(&args),
+            "Email/set" => self.email_set(&args),
+            "Thread/get" => self.thread_get(&args),
+            "EmailSubmission/set" => self.submission_set(&args),
+            _ => Err(MethodError("unknownMethod", None)),
+        }
+    }
+
+    fn mails(&mut self) -> Result<&Vec<(MailFolder, InboxData)>, MethodError> {
+        if self.mails.is_none() {
+            let store = &self.gateway.store;
+            let account = self.account;
+            let mut mails = vec![];
+            for folder in [MailFolder::Inbox, MailFolder::Sent, MailFolder::Trash] {
+                let batch = store
+                    .folder(&account.email_address, &account.app_password, folder)
+                    .map_err(|err| MethodError("serverFail", Some(err)))?;
+                mails.extend(batch.into_iter().map(|data| (folder, data)));
+            }
+            self.mails = Some(mails);
+        }
+        Ok(self.mails.as_ref().unwrap())
+    }
+
+    // Anything that changes the canister invalidates the folders fetched so far
+    fn invalidate(&mut self) {
+        self.mails = None;
+    }
+
+    fn snapshot(&mut self) -> Result<Snapshot, MethodError> {
+        let mut snapshot: Snapshot = self
+            .mails()?
+            .iter()
+            .map(|(folder, data)| {
+                (
+                    data.mail_id.clone(),
+                    (mailbox_id(*folder).to_string(), data.read),
+                )
+            })
+            .collect();
+        for id in self.draft_ids() {
+            snapshot.insert(id, ("drafts".to_string(), true));
+        }
+        Ok(snapshot)
+    }
+
+    fn state(&mut self) -> Result<String, String> {
+        let snapshot = self
+            .snapshot()
+            .map_err(|err| err.1.unwrap_or(err.0.to_string()))?;
+        let mut history = self.gateway.history.lock().unwrap();
+        Ok(history.record(&self.account.email_address, snapshot))
+    }
+
+    fn method_state(&mut self) -> Result<String, MethodError> {
+        self.state()
+            .map_err(|err| MethodError("serverFail", Some(err)))
+    }
+
+    fn draft_ids(&self) -> Vec<String> {
+        let drafts = self.gateway.drafts.lock().unwrap();
+        drafts
+            .mails
+            .get(&self.account.email_address)
+            .map(|mails| mails.keys().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    fn mailbox_get(&mut self, args: &Map<String, Value>) -> Result<Value, MethodError> {
+        let ids = string_list(args.get("ids"));
+        let draft_count = self.draft_ids().len() as u32;
+        let mails = self.mails()?;
+        let mut list = vec![];
+        for (sort_order, (id, name, role)) in MAILBOXES.iter().enumerate() {
+            if ids
+                .as_ref()
+                .map(|ids| !ids.contains(&id.to_string()))
+                .unwrap_or(false)
+            {
+                continue;
+            }
+            let (total, unread) = if *id == "drafts" {
+                (draft_count, 0)
+            } else {
+                let in_mailbox = mails
+                    .iter()
+                    .filter(|(folder, _)| mailbox_id(*folder) == *id);
+                let total = in_mailbox.clone().count() as u32;
+                let unread = in_mailbox.filter(|(_, data)| !data.read).count() as u32;
+                (total, unread)
+            };
+            list.push(json!({
+                "id": id,
+                "name": name,
+                "parentId": null,
+                "role": role,
+                "sortOrder": sort_order,
+                "totalEmails": total,
+                "unreadEmails": unread,
+                "totalThreads": total,
+                "unreadThreads": unread,
+                "myRights": {
+                    "mayReadItems": true,
+                    "mayAddItems": *id != "sent",
+                    "mayRemoveItems": true,
+                    "maySetSeen": false,
+                    "maySetKeywords": false,
+                    "mayCreateChild": false,
+                    "mayRename": false,
+                    "mayDelete": false,
+                    "maySubmit": true
+                },
+                "isSubscribed": true
+            }));
+        }
+
+        let not_found: Vec<String> = ids
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|id| !MAILBOXES.iter().any(|(m, _, _)| m == id))
+            .collect();
+        let state = self.method_state()?;
+        Ok(json!({
+            "accountId": self.account.email_address,
+            "state": state,
+            "list": list,
+            "notFound": not_found
+        }))
+    }
+
+    fn mailbox_query(&mut self) -> Result<Value, MethodError> {
+        let state = self.method_state()?;
+        let ids: Vec<&str> = MAILBOXES.iter().map(|(id, _, _)| *id).collect();
+        Ok(json!({
+            "accountId": self.account.email_address,
+            "queryState": state,
+            "canCalculateChanges": false,
+            "position": 0,
+            "ids": ids,
+            "total": ids.len()
+        }))
+    }
+
+    fn mailbox_changes(&mut self, args: &Map<String, Value>) -> Result<Value, MethodError> {
+        let since_state = args
+            .get("sinceState")
+            .and_then(|s| s.as_str())
+            .ok_or(MethodError(
+                "invalidArguments",
+                Some("sinceState is required".to_string()),
+            ))?;
+        let new_state = self.method_state()?;
+        let current = self.snapshot()?;
+        let history = self.gateway.history.lock().unwrap();
+        let old = history
+            .get(&self.account.email_address, since_state)
+            .ok_or(MethodError("cannotCalculateChanges", None))?;
+
+        Ok(json!({
+            "accountId": self.account.email_address,
+            "oldState": since_state,
+            "newState": new_state,
+            "hasMoreChanges": false,
+            "created": [],
+            "updated": state::mailbox_diff(old, &current),
+            "destroyed": [],
+            "updatedProperties": ["totalEmails", "unreadEmails", "totalThreads", "unreadThreads"]
+        }))
+    }
+
+    fn email_get(&mut self, args: &Map<String, Value>) -> Result<Value, MethodError> {
+        let ids = string_list(args.get("ids"));
+        let properties = string_list(args.get("properties"));
+        let fetch_body = args
+            .get("fetchTextBodyValues")
+            .or(args.get("fetchAllBodyValues"))
+            .or(args.get("fetchHTMLBodyValues"))
+            .and_then(|f| f.as_bool())
+            .unwrap_or(false)
+            || properties
+                .as_ref()
+                .map(|p| p.iter().any(|p| p == "bodyValues"))
+                .unwrap_or(false);
+
+        let mut found: Vec<(String, Value)> = self
+            .mails()?
+            .iter()
+            .filter(|(_, data)| {
+                ids.as_ref()
+                    .map(|ids| ids.contains(&data.mail_id))
+                    .unwrap_or(true)
+            })
+            .map(|(folder, data)| {
+                (
+                    data.mail_id.clone(),
+                    email_object(
+                        &data.mail_id,
+                        &data.header,
+                        data.read,
+                        mailbox_id(*folder),
+                        data.content.as_ref().map(|c| c.as_slice()),
+                    ),
+                )
+            })
+            .collect();
+        for id in self.draft_ids() {
+            if ids.as_ref().map(|ids| ids.contains(&id)).unwrap_or(true) {
+                let draft = self.gateway.draft(self.account, &id).unwrap();
+                found.push((
+                    id.clone(),
+                    email_object(&id, &draft.header, true, "drafts", Some(&draft.body.0)),
+                ));
+            }
+        }
+
+        let mut list = vec![];
+        for (id, mut email) in found {
+            if fetch_body {
+                let body = match self.gateway.draft(self.account, &id) {
+                    Some(draft) => draft.body.0.to_vec(),
+                    None => self
+                        .gateway
+                        .store
+                        .get_mail(&self.account.email_address, &self.account.app_password, &id)
+                        .map_err(|err| MethodError("serverFail", Some(err)))?
+                        .body
+                        .0
+                        .to_vec(),
+                };
+                email["bodyValues"] = json!({
+                    "1": { "value": String::from_utf8_lossy(&body), "isEncodingProblem": false, "isTruncated": false }
+                });
+            }
+            if let Some(properties) = &properties {
+                if let Value::Object(map) = &mut email {
+                    map.retain(|key, _| key == "id" || properties.contains(key));
+                }
+            }
+            list.push(email);
+        }
+
+        let not_found: Vec<String> = ids
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|id| !list.iter().any(|email| email["id"] == *id))
+            .collect();
+        let state = self.method_state()?;
+        Ok(json!({
+            "accountId": self.account.email_address,
+            "state": state,
+            "list": list,
+            "notFound": not_found
+        }))
+    }
+
+    fn email_query(&mut self, args: &Map<String, Value>) -> Result<Value, MethodError> {
+        let filter = args.get("filter").cloned().unwrap_or(Value::Null);
+        let in_mailbox = filter
+            .get("inMailbox")
+            .and_then(|m| m.as_str())
+            .map(|m| m.to_string());
+        let text = |key: &str| {
+            filter
+                .get(key)
+                .and_then(|v| v.as_str())
+                .map(|v| v.to_lowercase())
+        };
+        let (from, to, subject, any_text) =
+            (text("from"), text("to"), text("subject"), text("text"));
+        let ascending = args
+            .get("sort")
+            .and_then(|s| s.get(0))
+            .and_then(|s| s.get("isAscending"))
+            .and_then(|a| a.as_bool())
+            .unwrap_or(false);
+        let position = args.get("position").and_then(|p| p.as_u64()).unwrap_or(0) as usize;
+        let limit = args
+            .get("limit")
+            .and_then(|l| l.as_u64())
+            .map(|l| l as usize);
+
+        let draft_ids = self.draft_ids();
+        let drafts: Vec<(String, MailHeader)> = draft_ids
+            .into_iter()
+            .filter_map(|id| {
+                self.gateway
+                    .draft(self.account, &id)
+                    .map(|d| (id, d.header))
+            })
+            .collect();
+        let mut matches: Vec<(u64, String)> = self
+            .mails()?
+            .iter()
+            .map(|(folder, data)| (mailbox_id(*folder), &data.mail_id, &data.header))
+            .chain(drafts.iter().map(|(id, header)| ("drafts", id, header)))
+            .filter(|(mailbox, _, header)| {
+                let contains = |value: &str, needle: &Option<String>| {
+                    needle
+                        .as_ref()
+                        .map(|n| value.to_lowercase().contains(n))
+                        .unwrap_or(true)
+                };
+                let subject_text = header.subject.clone().unwrap_or_default();
+                in_mailbox.as_ref().map(|m| m == mailbox).unwrap_or(true)
+                    && contains(&header.from, &from)
+                    && contains(&header.to.join(","), &to)
+                    && contains(&subject_text, &subject)
+                    && contains(
+                        &format!("{} {} {}", header.from, header.to.join(","), subject_text),
+                        &any_text,
+                    )
+            })
+            .map(|(_, id, header)| (header.timestamp, id.clone()))
+            .collect();
+        matches.sort();
+        if !ascending {
+            matches.reverse();
+        }
+
+        let total = matches.len();
+        let ids: Vec<String> = matches
+            .into_iter()
+            .skip(position)
+            .take(limit.unwrap_or(usize::MAX))
+            .map(|(_, id)| id)
+            .collect();
+        let state = self.method_state()?;
+        Ok(json!({
+            "accountId": self.account.email_address,
+            "queryState": state,
+            "canCalculateChanges": false,
+            "position": position,
+            "ids": ids,
+            "total": total
+        }))
+    }
+
+    fn email_changes(&mut self, args: &Map<String, Value>) -> Result<Value, MethodError> {
+        let since_state = args
+            .get("sinceState")
+            .and_then(|s| s.as_str())
+            .ok_or(MethodError(
+                "invalidArguments",
+                Some("sinceState is required".to_string()),
+            ))?;
+        let new_state = self.method_state()?;
+        let current = self.snapshot()?;
+        let history = self.gateway.history.lock().unwrap();
+        let old = history
+            .get(&self.account.email_address, since_state)
+            .ok_or(MethodError("cannotCalculateChanges", None))?;
+        let changes = state::diff(old, &current);
+
+        Ok(json!({
+            "accountId": self.account.email_address,
+            "oldState": since_state,
+            "newState": new_state,
+            "hasMoreChanges": false,
+            "created": changes.created,
+            "updated": changes.updated,
+            "destroyed": changes.destroyed
+        }))
+    }
+
+    // Every email is its own thread
+    fn thread_get(&mut self, args: &Map<String, Value>) -> Result<Value, MethodError> {
+        let snapshot = self.snapshot()?;
+        let ids = string_list(args.get("ids")).unwrap_or(snapshot.keys().cloned().collect());
+        let (found, not_found): (Vec<String>, Vec<String>) =
+            ids.into_iter().partition(|id| snapshot.contains_key(id));
+        let list: Vec<Value> = found
+            .iter()
+            .map(|id| json!({ "id": id, "emailIds": [id] }))
+            .collect();
+        let state = self.method_state()?;
+        Ok(json!({
+            "accountId": self.account.email_address,
+            "state": state,
+            "list": list,
+            "notFound": not_found
+        }))
+    }
+
+    fn email_set(&mut self, args: &Map<String, Value>) -> Result<Value, MethodError> {
+        let old_state = self.method_state()?;
+        if let Some(if_in_state) = args.get("ifInState").and_then(|s| s.as_str()) {
+            if if_in_state != old_state {
+                return Err(MethodError("stateMismatch", None));
+            }
+        }
+
+        let mut created = Map::new();
+        let mut not_created = Map::new();
+        if let Some(Value::Object(create)) = args.get("create") {
+            for (creation_id, object) in create {
+                match draft_from_object(object) {
+                    Ok(mail) => {
+                        let id = self.add_draft(mail);
+                        self.created_ids.insert(creation_id.clone(), id.clone());
+                        created.insert(
+                            creation_id.clone(),
+                            json!({ "id": id, "blobId": id, "threadId": id }),
+                        );
+                    }
+                    Err(err) => {
+                        not_created.insert(creation_id.clone(), err.to_value());
+                    }
+                }
+            }
+        }
+
+        let mut updated = Map::new();
+        let mut not_updated = Map::new();
+        if let Some(Value::Object(update)) = args.get("update") {
+            for (id, patch) in update {
+                match self.move_email(id, patch) {
+                    Ok(()) => {
+                        updated.insert(id.clone(), Value::Null);
+                    }
+                    Err(err) => {
+                        not_updated.insert(id.clone(), err.to_value());
+                    }
+                }
+            }
+        }
+
+        let mut destroyed = vec![];
+        let mut not_destroyed = Map::new();
+        for id in string_list(args.get("destroy")).unwrap_or_default() {
+            if self.remove_draft(&id).is_some() {
+                destroyed.push(id);
+            } else {
+                let err = MethodError(
+                    "forbidden",
+                    Some("Mail can only be moved to the Trash".to_string()),
+                );
+                not_destroyed.insert(id, err.to_value());
+            }
+        }
+
+        self.invalidate();
+        let new_state = self.method_state()?;
+        Ok(json!({
+            "accountId": self.account.email_address,
+            "oldState": old_state,
+            "newState": new_state,
+            "created": created,
+            "notCreated": not_created,
+            "updated": updated,
+            "notUpdated": not_updated,
+            "destroyed": destroyed,
+            "notDestroyed": not_destroyed
+        }))
+    }
+
+    // Only Inbox <-> Trash moves exist on the canister, through delete_mail and restore_mail
+    fn move_email(&mut self, id: &str, patch: &Value) -> Result<(), MethodError> {
+        let current = self
+            .snapshot()?
+            .get(id)
+            .map(|(mailbox, _)| mailbox.clone())
+            .ok_or(MethodError("notFound", None))?;
+
+        let mut target: Option<String> = None;
+        if let Value::Object(patch) = patch {
+            for (key, value) in patch {
+                if key == "mailboxIds" {
+                    let ids: Vec<String> = value
+                        .as_object()
+                        .map(|m| m.keys().cloned().collect())
+                        .unwrap_or_default();
+                    if ids.len() != 1 {
+                        return Err(MethodError(
+                            "invalidProperties",
+                            Some("Exactly one mailbox is supported".to_string()),
+                        ));
+                    }
+                    target = ids.into_iter().next();
+                } else if let Some(mailbox) = key.strip_prefix("mailboxIds/") {
+                    if value.as_bool() == Some(true) {
+                        target = Some(mailbox.to_string());
+                    }
+                } else {
+                    return Err(MethodError(
+                        "forbidden",
+                        Some(format!("{} can not be changed", key)),
+                    ));
+                }
+            }
+        }
+
+        let target = match target {
+            Some(target) if target != current => target,
+            _ => return Ok(()),
+        };
+        let store = &self.gateway.store;
+        let account = self.account;
+        let result = match (current.as_str(), target.as_str()) {
+            ("inbox", "trash") => {
+                store.delete_mail(&account.email_address, &account.app_password, id)
+            }
+            ("trash", "inbox") => {
+                store.restore_mail(&account.email_address, &account.app_password, id)
+            }
+            _ => {
+                return Err(MethodError(
+                    "forbidden",
+                    Some(format!("Can not move from {} to {}", current, target)),
+                ))
+            }
+        };
+        result.map_err(|err| MethodError("serverFail", Some(err)))
+    }
+
+    fn submission_set(&mut self, args: &Map<String, Value>) -> Result<Value, MethodError> {
+        let mut created = Map::new();
+        let mut not_created = Map::new();
+        let mut sent = vec![];
+        if let Some(Value::Object(create)) = args.get("create") {
+            for (creation_id, object) in create {
+                let email_id = object.get("emailId").and_then(|e| e.as_str()).unwrap_or("");
+                let email_id = match email_id.strip_prefix('#') {
+                    Some(reference) => self.created_ids.get(reference).cloned().unwrap_or_default(),
+                    None => email_id.to_string(),
+                };
+                let mail = match self.gateway.draft(self.account, &email_id) {
+                    Some(mail) => mail,
+                    None => {
+                        let err = MethodError(
+                            "invalidProperties",
+                            Some("emailId must be a draft".to_string()),
+                        );
+                        not_created.insert(creation_id.clone(), err.to_value());
+                        continue;
+                    }
+                };
+
+                let account = self.account;
+                match self.gateway.store.send_mail(
+                    &account.email_address,
+                    &account.app_password,
+                    mail,
+                ) {
+                    Ok(()) => {
+                        let submission_id = format!("sub-{}", email_id);
+                        created.insert(
+                            creation_id.clone(),
+                            json!({ "id": submission_id, "undoStatus": "final" }),
+                        );
+                        sent.push(email_id);
+                    }
+                    Err(err) => {
+                        not_created.insert(
+                            creation_id.clone(),
+                            MethodError("forbiddenToSend", Some(err)).to_value(),
+                        );
+                    }
+                }
+            }
+        }
+
+        // the canister keeps its own copy in Sent, the draft is not needed anymore
+        for email_id in sent {
+            self.remove_draft(&email_id);
+        }
+        self.invalidate();
+        let state = self.method_state()?;
+        Ok(json!({
+            "accountId": self.account.email_address,
+            "newState": state,
+            "created": created,
+            "notCreated": not_created
+        }))
+    }
+
+    fn add_draft(&mut self, mail: Mail) -> String {
+        let mut drafts = self.gateway.drafts.lock().unwrap();
+        drafts.next_id += 1;
+        let id = format!("draft-{}", drafts.next_id);
+        drafts
+            .mails
+            .entry(self.account.email_address.clone())
+            .or_default()
+            .insert(id.clone(), mail);
+        id
+    }
+
+    fn remove_draft(&mut self, id: &str) -> Option<Mail> {
+        let mut drafts = self.gateway.drafts.lock().unwrap();
+        drafts
+            .mails
+            .get_mut(&self.account.email_address)?
+            .remove(id)
+    }
+}
+
+fn mailbox_id(folder: MailFolder) -> &'static str {
+    match folder {
+        MailFolder::Inbox => "inbox",
+        MailFolder::Sent => "sent",
+        MailFolder::Trash => "trash",
+    }
+}
+
+fn string_list(value: Option<&Value>) -> Option<Vec<String>> {
+    value?.as_array().map(|ids| {
+        ids.iter()
+            .filter_map(|id| id.as_str().map(|id| id.to_string()))
+            .collect()
+    })
+}
+
+fn email_object(
+    id: &str,
+    header: &MailHeader,
+    read: bool,
+    mailbox: &str,
+    content: Option<&[u8]>,
+) -> Value {
+    let addresses = |list: &Vec<String>| -> Vec<Value> {
+        list.iter()
+            .map(|email| json!({ "name": null, "email": email }))
+            .collect()
+    };
+    let preview: String = content
+        .map(|c| String::from_utf8_lossy(c).chars().take(256).collect())
+        .unwrap_or_default();
+    let keywords = if read {
+        json!({ "$seen": true })
+    } else {
+        json!({})
+    };
+
+    json!({
+        "id": id,
+        "blobId": id,
+        "threadId": id,
+        "mailboxIds": { mailbox: true },
+        "keywords": keywords,
+        "size": content.map(|c| c.len()).unwrap_or(0),
+        "receivedAt": UtcDateTime::from_nanos(header.timestamp).to_rfc3339(),
+        "sentAt": UtcDateTime::from_nanos(header.timestamp).to_rfc3339(),
+        "from": [{ "name": header.sender_name, "email": header.from }],
+        "to": addresses(&header.to),
+        "cc": header.cc.as_ref().map(addresses),
+        "bcc": header.bcc.as_ref().map(addresses),
+        "subject": header.subject,
+        "preview": preview,
+        "hasAttachment": false,
+        "textBody": [{ "partId": "1", "blobId": id, "type": header.content_type.clone().unwrap_or("text/plain".to_string()) }],
+        "htmlBody": [],
+        "attachments": []
+    })
+}
+
+fn draft_from_object(object: &Value) -> Result<Mail, MethodError> {
+    let addresses = |key: &str| -> Vec<String> {
+        object
+            .get(key)
+            .and_then(|list| list.as_array())
+            .map(|list| {
+                list.iter()
+                    .filter_map(|a| {
+                        a.get("email")
+                            .and_then(|e| e.as_str())
+                            .map(|e| e.to_string())
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    };
+    let optional = |list: Vec<String>| if list.is_empty() { None } else { Some(list) };
+
+    let (part, content_type) = match object.get("textBody").and_then(|b| b.get(0)) {
+        Some(part) => (part, "text/plain; charset=utf-8"),
+        None => match object.get("htmlBody").and_then(|b| b.get(0)) {
+            Some(part) => (part, "text/html; charset=utf-8"),
+            None => {
+                return Err(MethodError(
+                    "invalidProperties",
+                    Some("textBody or htmlBody is required".to_string()),
+                ))
+            }
+        },
+    };
+    let part_id = part.get("partId").and_then(|p| p.as_str()).unwrap_or("");
+    let body = object
+        .get("bodyValues")
+        .and_then(|values| values.get(part_id))
+        .and_then(|value| value.get("value"))
+        .and_then(|value| value.as_str())
+        .ok_or(MethodError(
+            "invalidProperties",
+            Some("bodyValues has no value for the body part".to_string()),
+        ))?;
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0);
+
+    Ok(Mail {
+        correlation_id: None,
+        header: MailHeader {
+            timestamp,
+            content_type: Some(content_type.to_string()),
+            to: addresses("to"),
+            subject: object
+                .get("subject")
+                .and_then(|s| s.as_str())
+                .map(|s| s.to_string()),
+            cc: optional(addresses("cc")),
+            bcc: optional(addresses("bcc")),
+            ..MailHeader::default()
+        },
+        body: Rcbytes::new(Arc::new(serde_bytes::ByteBuf::from(
+            body.as_bytes().to_vec(),
+        ))),
+        reply_messages: None,
+    })
+}
+
+// "#ids": { "resultOf": "c0", "name": "Email/query", "path": "/ids" }, RFC 8620 section 3.7
+fn resolve_references(
+    args: Map<String, Value>,
+    responses: &[Value],
+) -> Result<Map<String, Value>, MethodError> {
+    let mut resolved = Map::new();
+    for (key, value) in args {
+        let name = match key.strip_prefix('#') {
+            Some(name) => name.to_string(),
+            None => {
+                resolved.insert(key, value);
+                continue;
+            }
+        };
+
+        let invalid = || MethodError("invalidResultReference", None);
+        let result_of = value
+            .get("resultOf")
+            .and_then(|r| r.as_str())
+            .ok_or_else(invalid)?;
+        let method = value
+            .get("name")
+            .and_then(|n| n.as_str())
+            .ok_or_else(invalid)?;
+        let path = value
+            .get("path")
+            .and_then(|p| p.as_str())
+            .ok_or_else(invalid)?;
+        let response = responses
+            .iter()
+            .find(|r| r[2] == result_of && r[0] == method)
+            .ok_or_else(invalid)?;
+        let target = evaluate_pointer(&response[1], path).ok_or_else(invalid)?;
+        resolved.insert(name, target);
+    }
+    Ok(resolved)
+}
+
+// JSON pointer with the JMAP `*` extension for mapping over arrays
+fn evaluate_pointer(value: &Value, path: &str) -> Option<Value> {
+    let path = path.strip_prefix('/').unwrap_or(path);
+    if path.is_empty() {
+        return Some(value.clone());
+    }
+    let (head, rest) = path.split_once('/').unwrap_or((path, ""));
+    let head = head.replace("~1", "/").replace("~0", "~");
+    if head == "*" {
+        let items = value.as_array()?;
+        let mut out = vec![];
+        for item in items {
+            match evaluate_pointer(item, rest)? {
+                Value::Array(inner) => out.extend(inner),
+                other => out.push(other),
+            }
+        }
+        return Some(Value::Array(out));
+    }
+    let next = match value {
+        Value::Array(items) => items.get(head.parse::<usize>().ok()?)?,
+        Value::Object(map) => map.get(&head)?,
+        _ => return None,
+    };
+    evaluate_pointer(next, rest)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_bytes::ByteBuf;
+
+    use super::*;
+
+    // Stands in for the replica with one account and its folders.
+    #[derive(Default)]
+    struct StandIn {
+        folders: Mutex<HashMap<String, MailFolder>>,
+        sent: Mutex<Vec<Mail>>,
+    }
+
+    impl StandIn {
+        fn with_inbox(ids: &[&str]) -> Self {
+            let store = StandIn::default();
+            let mut folders = store.folders.lock().unwrap();
+            for id in ids {
+                folders.insert(id.to_string(), MailFolder::Inbox);
+            }
+            drop(folders);
+            store
+        }
+
+        fn mail(&self, id: &str) -> Mail {
+            Mail {
+                correlation_id: None,
+                header: MailHeader {
+                    from: "bob@dmail.ai".to_string(),
+                    timestamp: id.len() as u64,
+                    to: vec!["alice@dmail.ai".to_string()],
+                    subject: Some(format!("About {}", id)),
+                    ..MailHeader::default()
+                },
+                body: Rcbytes::new(Arc::new(ByteBuf::from(format!("Body of {}", id)))),
+                reply_messages: None,
+            }
+        }
+
+        fn check(&self, email_address: &str, app_password: &str) -> Result<(), String> {
+            if email_address == "alice@dmail.ai" && app_password == "secret" {
+                Ok(())
+            } else {
+                Err("Not authorized".to_string())
+            }
+        }
+
+        fn set_folder(&self, id: &str, from: MailFolder, to: MailFolder) -> Result<(), String> {
+            let mut folders = self.folders.lock().unwrap();
+            match folders.get_mut(id) {
+                Some(folder) if *folder == from => {
+                    *folder = to;
+                    Ok(())
+                }
+                _ => Err("Mail not found".to_string()),
+            }
+        }
+    }
+
+    impl MailStore for StandIn {
+        fn verify(&self, email_address: &str, app_password: &str) -> Result<(), String> {
+            self.check(email_address, app_password)
+        }
+
+        fn folder(
+            &self,
+            email_address: &str,
+            app_password: &str,
+            folder: MailFolder,
+        ) -> Result<Vec<InboxData>, String> {
+            self.check(email_address, app_password)?;
+            let folders = self.folders.lock().unwrap();
+            Ok(folders
+                .iter()
+                .filter(|(_, f)| **f == folder)
+                .map(|(id, _)| InboxData {
+                    header: self.mail(id).header,
+                    read: false,
+                    mail_id: id.clone(),
+                    content: None,
+                })
+                .collect())
+        }
+
+        fn get_mail(
+            &self,
+            email_address: &str,
+            app_password: &str,
+            mail_id: &str,
+        ) -> Result<Mail, String> {
+            self.check(email_address, app_password)?;
+            match self.folders.lock().unwrap().contains_key(mail_id) {
+                true => Ok(self.mail(mail_id)),
+                false => Err("Mail not found".to_string()),
+            }
+        }
+
+        fn delete_mail(
+            &self,
+            email_address: &str,
+            app_password: &str,
+            mail_id: &str,
+        ) -> Result<(), String> {
+            self.check(email_address, app_password)?;
+            self.set_folder(mail_id, MailFolder::Inbox, MailFolder::Trash)
+        }
+
+        fn restore_mail(
+            &self,
+            email_address: &str,
+            app_password: &str,
+            mail_id: &str,
+        ) -> Result<(), String> {
+            self.check(email_address, app_password)?;
+            self.set_folder(mail_id, MailFolder::Trash, MailFolder::Inbox)
+        }
+
+        fn send_mail(
+            &self,
+            email_address: &str,
+            app_password: &str,
+            mail: Mail,
+        ) -> Result<(), String> {
+            self.check(email_address, app_password)?;
+            let mut sent = self.sent.lock().unwrap();
+            self.folders
+                .lock()
+                .unwrap()
+                .insert(format!("sent-{}", sent.len()), MailFolder::Sent);
+            sent.push(mail);
+            Ok(())
+        }
+    }
+
+    fn account() -> Account {
+        Account {
+            email_address: "alice@dmail.ai".to_string(),
+            app_password: "secret".to_string(),
+        }
+    }
+
+    fn call(gateway: &Gateway<StandIn>, calls: Value) -> Vec<Value> {
+        let response = gateway
+            .api(
+                &account(),
+                json!({ "using": [CORE, MAIL], "methodCalls": calls }),
+            )
+            .unwrap();
+        response["methodResponses"].as_array().unwrap().clone()
+    }
+
+    #[test]
+    fn queries_and_reads_the_inbox() {
+        let gateway = Gateway::new(StandIn::with_inbox(&["m1", "m22"]));
+        let responses = call(
+            &gateway,
+            json!([
+                ["Email/query", { "filter": { "inMailbox": "inbox" } }, "c0"],
+                ["Email/get", {
+                    "#ids": { "resultOf": "c0", "name": "Email/query", "path": "/ids" },
+                    "properties": ["subject", "bodyValues"]
+                }, "c1"]
+            ]),
+        );
+
+        assert_eq!(responses[0][1]["ids"], json!(["m22", "m1"]));
+        let list = responses[1][1]["list"].as_array().unwrap();
+        assert_eq!(list.len(), 2);
+        let email = list.iter().find(|email| email["id"] == "m22").unwrap();
+        assert_eq!(email["subject"], "About m22");
+        assert_eq!(email["bodyValues"]["1"]["value"], "Body of m22");
+    }
+
+    #[test]
+    fn moves_mail_to_the_trash_and_reports_the_change() {
+        let gateway = Gateway::new(StandIn::with_inbox(&["m1"]));
+        let state = call(&gateway, json!([["Email/get", { "ids": [] }, "c0"]]))[0][1]["state"]
+            .as_str()
+            .unwrap()
+            .to_string();
+
+        let responses = call(
+            &gateway,
+            json!([
+                ["Email/set", { "update": { "m1": { "mailboxIds": { "trash": true } } } }, "c0"],
+                ["Email/changes", { "sinceState": state }, "c1"]
+            ]),
+        );
+
+        assert_eq!(responses[0][1]["updated"], json!({ "m1": null }));
+        assert_eq!(responses[1][1]["updated"], json!(["m1"]));
+        let folders = gateway.store.folders.lock().unwrap();
+        assert_eq!(folders.get("m1"), Some(&MailFolder::Trash));
+    }
+
+    #[test]
+    fn submits_a_draft_through_the_canister() {
+        let gateway = Gateway::new(StandIn::default());
+        let responses = call(
+            &gateway,
+            json!([
+                ["Email/set", { "create": { "d": {
+                    "to": [{ "email": "bob@dmail.ai" }],
+                    "subject": "Hello",
+                    "textBody": [{ "partId": "1" }],
+                    "bodyValues": { "1": { "value": "Hi Bob" } }
+                } } }, "c0"],
+                ["EmailSubmission/set", { "create": { "s": { "emailId": "#d" } } }, "c1"],
+                ["Email/query", {}, "c2"]
+            ]),
+        );
+
+        assert!(responses[1][1]["created"]["s"].is_object());
+        // the draft is gone and the canister's copy is in Sent
+        assert_eq!(responses[2][1]["ids"], json!(["sent-0"]));
+        let sent = gateway.store.sent.lock().unwrap();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].header.to, vec!["bob@dmail.ai".to_string()]);
+        assert_eq!(sent[0].header.subject.as_deref(), Some("Hello"));
+        assert_eq!(sent[0].body.0.as_slice(), b"Hi Bob");
+    }
+
+    #[test]
+    fn refuses_other_accounts_and_unknown_methods() {
+        let gateway = Gateway::new(StandIn::default());
+        let responses = call(
+            &gateway,
+            json!([
+                ["Email/get", { "accountId": "bob@dmail.ai" }, "c0"],
+                ["Calendar/get", {}, "c1"]
+            ]),
+        );
+
+        assert_eq!(responses[0][1]["type"], "accountNotFound");
+        assert_eq!(responses[1][1]["type"], "unknownMethod");
+    }
+}
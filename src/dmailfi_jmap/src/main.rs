@@ -0,0 +1,140 @@
+//! JMAP (RFC 8620 / RFC 8621) gateway over a `dmailfi_core` canister. Clients log in with
+//! HTTP Basic auth using their mail address and an app password from `create_app_password`.
+//!
+//! Configuration comes from the environment:
+//! - `DMAILFI_JMAP_LISTEN` address to listen on, defaults to `127.0.0.1:8081`
+//! - `DMAILFI_JMAP_URL` public base url advertised in the session, defaults to `http://<listen>`
+//! - `DMAILFI_CANISTER_ID` core canister the accounts belong to
+//! - `DMAILFI_NETWORK` dfx network, defaults to `local`
+use std::{env, sync::Arc, thread};
+
+use api::{Account, Gateway};
+use base64::Engine;
+use dmailfi_client::DfxCanister;
+use store::MailStore;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+mod api;
+mod state;
+mod store;
+
+fn main() {
+    let canister_id = env::var("DMAILFI_CANISTER_ID").unwrap_or_else(|_| {
+        eprintln!("DMAILFI_CANISTER_ID is not set");
+        std::process::exit(1);
+    });
+    let listen = env::var("DMAILFI_JMAP_LISTEN").unwrap_or("127.0.0.1:8081".to_string());
+    let base_url = Arc::new(env::var("DMAILFI_JMAP_URL").unwrap_or(format!("http://{}", listen)));
+    let network = env::var("DMAILFI_NETWORK").unwrap_or("local".to_string());
+    let gateway = Gateway::new(DfxCanister::new(canister_id, network));
+
+    let server = Server::http(&listen).unwrap_or_else(|err| {
+        eprintln!("Could not listen on {}: {}", listen, err);
+        std::process::exit(1);
+    });
+    println!("dmailfi jmap listening on {}", listen);
+
+    for mut request in server.incoming_requests() {
+        let gateway = gateway.clone();
+        let base_url = base_url.clone();
+        thread::spawn(move || {
+            let response = handle(&gateway, &base_url, &mut request);
+            let _ = request.respond(response);
+        });
+    }
+}
+
+type HttpResponse = Response<std::io::Cursor<Vec<u8>>>;
+
+fn handle<S: MailStore>(
+    gateway: &Gateway<S>,
+    base_url: &str,
+    request: &mut Request,
+) -> HttpResponse {
+    let account = match authenticate(gateway, request) {
+        Some(account) => account,
+        None => {
+            return Response::from_string("Unauthorized")
+                .with_status_code(401)
+                .with_header(header("WWW-Authenticate", "Basic realm=\"dmailfi\""))
+        }
+    };
+
+    let url = request.url().to_string();
+    let path = url.split('?').next().unwrap_or("");
+    let result = match (request.method(), path) {
+        (Method::Get, "/.well-known/jmap") | (Method::Get, "/jmap/session") => {
+            gateway.session(&account, base_url).map(json_response)
+        }
+        (Method::Post, "/jmap/api") => {
+            let mut body = String::new();
+            if request.as_reader().read_to_string(&mut body).is_err() {
+                return problem(400, "urn:ietf:params:jmap:error:notJSON");
+            }
+            let body = match serde_json::from_str(&body) {
+                Ok(body) => body,
+                Err(_) => return problem(400, "urn:ietf:params:jmap:error:notJSON"),
+            };
+            match gateway.api(&account, body) {
+                Ok(response) => Ok(json_response(response)),
+                Err(_) => return problem(400, "urn:ietf:params:jmap:error:notRequest"),
+            }
+        }
+        (Method::Get, path) if path.starts_with("/jmap/download/") => {
+            // /jmap/download/{accountId}/{blobId}/{name}
+            let blob_id = path.split('/').nth(4).unwrap_or("");
+            gateway.download(&account, blob_id).map(|bytes| {
+                Response::from_data(bytes)
+                    .with_header(header("Content-Type", "application/octet-stream"))
+            })
+        }
+        _ => return Response::from_string("Not Found").with_status_code(404),
+    };
+
+    match result {
+        Ok(response) => response,
+        Err(err) => Response::from_string(err).with_status_code(500),
+    }
+}
+
+fn authenticate<S: MailStore>(gateway: &Gateway<S>, request: &Request) -> Option<Account> {
+    let authorization = request
+        .headers()
+        .iter()
+        .find(|h| {
+            h.field
+                .as_str()
+                .as_str()
+                .eq_ignore_ascii_case("authorization")
+        })?
+        .value
+        .as_str()
+        .to_string();
+    let encoded = authorization.strip_prefix("Basic ")?;
+    let decoded = base64::engine::general_purpose::STANDARD
+        .decode(encoded.trim())
+        .ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let (email_address, app_password) = decoded.split_once(':')?;
+
+    gateway.store.verify(email_address, app_password).ok()?;
+    Some(Account {
+        email_address: email_address.to_string(),
+        app_password: app_password.to_string(),
+    })
+}
+
+fn json_response(value: serde_json::Value) -> HttpResponse {
+    Response::from_data(value.to_string().into_bytes())
+        .with_header(header("Content-Type", "application/json"))
+}
+
+// RFC 7807 problem details, used for request level errors
+fn problem(status: u16, kind: &str) -> HttpResponse {
+    let body = serde_json::json!({ "type": kind, "status": status });
+    json_response(body).with_status_code(status)
+}
+
+fn header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name.as_bytes(), value.as_bytes()).unwrap()
+}
@@ -0,0 +1,98 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+use sha2::Digest;
+
+// How many past states are kept per account for `/changes` calls
+const HISTORY_LEN: usize = 64;
+
+// mail id -> (mailbox id, seen)
+pub type Snapshot = BTreeMap<String, (String, bool)>;
+
+pub struct Changes {
+    pub created: Vec<String>,
+    pub updated: Vec<String>,
+    pub destroyed: Vec<String>,
+}
+
+// The canister keeps no change log, so states are hashes of what the gateway has seen.
+// A client that presents a state older than the history gets `cannotCalculateChanges`
+// and has to resync, as RFC 8620 section 5.2 allows.
+#[derive(Default)]
+pub struct StateHistory {
+    accounts: HashMap<String, VecDeque<(String, Snapshot)>>,
+}
+
+impl StateHistory {
+    pub fn record(&mut self, account: &str, snapshot: Snapshot) -> String {
+        let state = state_of(&snapshot);
+        let history = self.accounts.entry(account.to_string()).or_default();
+        if history.back().map(|(s, _)| s != &state).unwrap_or(true) {
+            history.push_back((state.clone(), snapshot));
+            if history.len() > HISTORY_LEN {
+                history.pop_front();
+            }
+        }
+        state
+    }
+
+    pub fn get(&self, account: &str, state: &str) -> Option<&Snapshot> {
+        self.accounts
+            .get(account)?
+            .iter()
+            .find(|(s, _)| s == state)
+            .map(|(_, snapshot)| snapshot)
+    }
+}
+
+pub fn state_of(snapshot: &Snapshot) -> String {
+    let mut hasher = sha2::Sha256::new();
+    for (id, (mailbox, seen)) in snapshot {
+        hasher.update(id);
+        hasher.update(mailbox);
+        hasher.update([*seen as u8]);
+    }
+    let hash: [u8; 32] = hasher.finalize().into();
+    hex::encode(&hash[..8])
+}
+
+pub fn diff(old: &Snapshot, new: &Snapshot) -> Changes {
+    let mut changes = Changes {
+        created: vec![],
+        updated: vec![],
+        destroyed: vec![],
+    };
+    for (id, value) in new {
+        match old.get(id) {
+            None => changes.created.push(id.clone()),
+            Some(old_value) if old_value != value => changes.updated.push(id.clone()),
+            _ => {}
+        }
+    }
+    for id in old.keys() {
+        if !new.contains_key(id) {
+            changes.destroyed.push(id.clone());
+        }
+    }
+    changes
+}
+
+// Mailboxes whose counts differ between two snapshots
+pub fn mailbox_diff(old: &Snapshot, new: &Snapshot) -> Vec<String> {
+    let counts = |snapshot: &Snapshot| {
+        let mut counts: BTreeMap<String, (u32, u32)> = BTreeMap::new();
+        for (mailbox, seen) in snapshot.values() {
+            let entry = counts.entry(mailbox.clone()).or_default();
+            entry.0 += 1;
+            if !seen {
+                entry.1 += 1;
+            }
+        }
+        counts
+    };
+    let (old, new) = (counts(old), counts(new));
+    crate::api::MAILBOXES
+        .iter()
+        .map(|(id, _, _)| id.to_string())
+        .filter(|id| old.get(id) != new.get(id))
+        .collect()
+}
@@ -0,0 +1,91 @@
+use dmailfi_client::DfxCanister;
+use dmailfi_types::{InboxData, Mail, MailFolder};
+
+// Core canister calls made on behalf of the account authenticated with an app password
+pub trait MailStore {
+    fn verify(&self, email_address: &str, app_password: &str) -> Result<(), String>;
+    fn folder(
+        &self,
+        email_address: &str,
+        app_password: &str,
+        folder: MailFolder,
+    ) -> Result<Vec<InboxData>, String>;
+    fn get_mail(
+        &self,
+        email_address: &str,
+        app_password: &str,
+        mail_id: &str,
+    ) -> Result<Mail, String>;
+    fn delete_mail(
+        &self,
+        email_address: &str,
+        app_password: &str,
+        mail_id: &str,
+    ) -> Result<(), String>;
+    fn restore_mail(
+        &self,
+        email_address: &str,
+        app_password: &str,
+        mail_id: &str,
+    ) -> Result<(), String>;
+    fn send_mail(&self, email_address: &str, app_password: &str, mail: Mail) -> Result<(), String>;
+}
+
+impl MailStore for DfxCanister {
+    fn verify(&self, email_address: &str, app_password: &str) -> Result<(), String> {
+        self.query("verify_app_password", (email_address, app_password))
+    }
+
+    fn folder(
+        &self,
+        email_address: &str,
+        app_password: &str,
+        folder: MailFolder,
+    ) -> Result<Vec<InboxData>, String> {
+        let mut mails = vec![];
+        let mut page: usize = 0;
+        loop {
+            let batch: Vec<InboxData> = self.query(
+                "get_folder_mails_as",
+                (email_address, app_password, folder, Some(page)),
+            )?;
+            let done = batch.len() < 50;
+            mails.extend(batch);
+            if done {
+                return Ok(mails);
+            }
+            page += 1;
+        }
+    }
+
+    fn get_mail(
+        &self,
+        email_address: &str,
+        app_password: &str,
+        mail_id: &str,
+    ) -> Result<Mail, String> {
+        self.update("get_mail_as", (email_address, app_password, mail_id))
+    }
+
+    fn delete_mail(
+        &self,
+        email_address: &str,
+        app_password: &str,
+        mail_id: &str,
+    ) -> Result<(), String> {
+        self.update("delete_mail_as", (email_address, app_password, mail_id))
+    }
+
+    fn restore_mail(
+        &self,
+        email_address: &str,
+        app_password: &str,
+        mail_id: &str,
+    ) -> Result<(), String> {
+        self.update("restore_mail_as", (email_address, app_password, mail_id))
+    }
+
+    fn send_mail(&self, email_address: &str, app_password: &str, mail: Mail) -> Result<(), String> {
+        self.update("send_mail_as", (email_address, app_password, mail))
+    }
+}
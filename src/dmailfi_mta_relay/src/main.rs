@@ -0,0 +1,424 @@
+//! Receives the JSON `OutgoingMail` that `dmailfi_core` POSTs to `mta_url`, checks the
+//! `x-sig` header against the canister public key and relays the mail to an SMTP smarthost.
+//!
+//! Configuration comes from the environment:
+//! - `DMAILFI_RELAY_LISTEN` address to listen on, defaults to `127.0.0.1:8080`
+//! - `DMAILFI_CANISTER_ID` principal expected in `x-principal`
+//! - `DMAILFI_PUBLIC_KEY` hex encoded key from the canister's `get_ecdsa_public_key`
+//! - `DMAILFI_SMARTHOST` SMTP server to relay to, defaults to `127.0.0.1:25`
+//! - `DMAILFI_HELO_NAME` name sent in EHLO, defaults to `localhost`
+//! - `DMAILFI_DELIVERED_LOG` file the ids of relayed mail are kept in, defaults to
+//!   `dmailfi_relay_delivered.log`
+use std::{
+    collections::HashSet,
+    env,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+};
+
+use dmailfi_types::{rfc5322, sha256, verify_signature, OutgoingMail};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+mod smtp;
+
+// Idempotency keys of every mail relayed so far. Each replica of a subnet sends the same
+// request and a captured one can be sent again later, a key is never relayed twice. The keys
+// are appended to a file so they survive restarts.
+#[derive(Default)]
+struct Delivered {
+    keys: HashSet<String>,
+    log: Option<File>,
+}
+
+impl Delivered {
+    fn open(path: &str) -> Result<Self, String> {
+        let mut keys = HashSet::new();
+        if let Ok(file) = File::open(path) {
+            for line in BufReader::new(file).lines() {
+                let line = line.map_err(|err| format!("Could not read {}: {}", path, err))?;
+                if !line.trim().is_empty() {
+                    keys.insert(line.trim().to_string());
+                }
+            }
+        }
+        let log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|err| format!("Could not open {}: {}", path, err))?;
+        Ok(Delivered {
+            keys,
+            log: Some(log),
+        })
+    }
+
+    fn contains(&self, key: &str) -> bool {
+        self.keys.contains(key)
+    }
+
+    fn insert(&mut self, key: String) {
+        if let Some(log) = self.log.as_mut() {
+            if let Err(err) = writeln!(log, "{}", key).and_then(|_| log.sync_data()) {
+                eprintln!("Could not record delivered mail {}: {}", key, err);
+            }
+        }
+        self.keys.insert(key);
+    }
+}
+
+struct RelayConfig {
+    listen: String,
+    canister_id: String,
+    public_key: Vec<u8>,
+    smarthost: String,
+    helo_name: String,
+    delivered_log: String,
+}
+
+impl RelayConfig {
+    fn from_env() -> Result<Self, String> {
+        let canister_id =
+            env::var("DMAILFI_CANISTER_ID").map_err(|_| "DMAILFI_CANISTER_ID is not set")?;
+        let public_key_hex =
+            env::var("DMAILFI_PUBLIC_KEY").map_err(|_| "DMAILFI_PUBLIC_KEY is not set")?;
+        let public_key =
+            hex::decode(public_key_hex).map_err(|_| "DMAILFI_PUBLIC_KEY is not valid hex")?;
+
+        Ok(RelayConfig {
+            listen: env::var("DMAILFI_RELAY_LISTEN").unwrap_or("127.0.0.1:8080".to_string()),
+            canister_id,
+            public_key,
+            smarthost: env::var("DMAILFI_SMARTHOST").unwrap_or("127.0.0.1:25".to_string()),
+            helo_name: env::var("DMAILFI_HELO_NAME").unwrap_or("localhost".to_string()),
+            delivered_log: env::var("DMAILFI_DELIVERED_LOG")
+                .unwrap_or("dmailfi_relay_delivered.log".to_string()),
+        })
+    }
+}
+
+fn main() {
+    let config = match RelayConfig::from_env() {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    };
+
+    let server = Server::http(&config.listen).unwrap_or_else(|err| {
+        eprintln!("Could not listen on {}: {}", config.listen, err);
+        std::process::exit(1);
+    });
+    println!("dmailfi relay listening on {}", config.listen);
+
+    let mut delivered = Delivered::open(&config.delivered_log).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    });
+    for mut request in server.incoming_requests() {
+        let (status, body) = match handle(&config, &mut delivered, &mut request) {
+            Ok(()) => (200, "ok".to_string()),
+            Err((status, err)) => {
+                eprintln!("relay error: {}", err);
+                (status, err)
+            }
+        };
+        let _ = request.respond(Response::from_string(body).with_status_code(status));
+    }
+}
+
+fn header(request: &Request, name: &str) -> Option<String> {
+    request
+        .headers()
+        .iter()
+        .find(|h: &&Header| h.field.as_str().as_str().eq_ignore_ascii_case(name))
+        .map(|h| h.value.as_str().to_string())
+}
+
+fn handle(
+    config: &RelayConfig,
+    delivered: &mut Delivered,
+    request: &mut Request,
+) -> Result<(), (u16, String)> {
+    if request.method() != &Method::Post {
+        return Err((405, "Only POST is supported".to_string()));
+    }
+
+    let principal = header(request, "x-principal");
+    let sig = header(request, "x-sig");
+    let idempotency_key = header(request, "idempotency-key");
+    let mut body = String::new();
+    request
+        .as_reader()
+        .read_to_string(&mut body)
+        .map_err(|err| (400, err.to_string()))?;
+    accept(
+        config,
+        delivered,
+        principal,
+        sig,
+        idempotency_key,
+        &body,
+        relay,
+    )
+}
+
+// Checks a request and relays the mail through `send` unless it was relayed before.
+fn accept(
+    config: &RelayConfig,
+    delivered: &mut Delivered,
+    principal: Option<String>,
+    sig: Option<String>,
+    idempotency_key: Option<String>,
+    body: &str,
+    send: impl FnOnce(&RelayConfig, &OutgoingMail) -> Result<(), String>,
+) -> Result<(), (u16, String)> {
+    let principal = principal.ok_or((401, "Missing x-principal".to_string()))?;
+    if principal != config.canister_id {
+        return Err((401, format!("Unknown canister {}", principal)));
+    }
+    let sig = sig.ok_or((401, "Missing x-sig".to_string()))?;
+    let out: OutgoingMail = serde_json::from_str(body).map_err(|err| (400, err.to_string()))?;
+
+    let digest = out.signing_digest().map_err(|err| (400, err))?;
+    verify_signature(&config.public_key, &digest, &sig).map_err(|err| (401, err))?;
+
+    let key = hex::encode(sha256(&out.id));
+    if idempotency_key.is_some_and(|k| k != key) {
+        return Err((
+            400,
+            "Idempotency-Key does not match the mail id".to_string(),
+        ));
+    }
+    if delivered.contains(&key) {
+        return Ok(());
+    }
+
+    send(config, &out).map_err(|err| (502, err))?;
+    delivered.insert(key);
+    Ok(())
+}
+
+fn relay(config: &RelayConfig, out: &OutgoingMail) -> Result<(), String> {
+    // Bcc receipients are left out of the headers, they are only in the envelope
+    let domain = out.header.from.rsplit('@').next().unwrap_or("localhost");
+    let message_id = format!("{}@{}", out.id, domain);
+    let message = rfc5322::encode(&out.header, out.body.0.as_slice(), &message_id, false);
+    let mut client = smtp::SmtpClient::connect(&config.smarthost, &config.helo_name)?;
+    client.send(&out.header.from, &out.rcpt_to, &message)?;
+    client.quit()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+        sync::mpsc,
+        thread,
+    };
+
+    use dmailfi_types::{MailHeader, Rcbytes};
+    use k256::ecdsa::{signature::hazmat::PrehashSigner, Signature, SigningKey};
+    use serde_bytes::ByteBuf;
+
+    use super::*;
+
+    struct Received {
+        mail_from: String,
+        rcpt_to: Vec<String>,
+        data: String,
+    }
+
+    // A local SMTP server that accepts one message and hands it to the test.
+    fn smtp_sink() -> (String, mpsc::Receiver<Received>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut received = Received {
+                mail_from: String::new(),
+                rcpt_to: vec![],
+                data: String::new(),
+            };
+            writer.write_all(b"220 sink ready\r\n").unwrap();
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 0 {
+                let command = line.trim_end().to_string();
+                line.clear();
+                if command.starts_with("EHLO") {
+                    writer.write_all(b"250-sink\r\n250 8BITMIME\r\n").unwrap();
+                } else if let Some(from) = command.strip_prefix("MAIL FROM:") {
+                    received.mail_from = from.trim_matches(['<', '>']).to_string();
+                    writer.write_all(b"250 OK\r\n").unwrap();
+                } else if let Some(rcpt) = command.strip_prefix("RCPT TO:") {
+                    received
+                        .rcpt_to
+                        .push(rcpt.trim_matches(['<', '>']).to_string());
+                    writer.write_all(b"250 OK\r\n").unwrap();
+                } else if command == "DATA" {
+                    writer.write_all(b"354 Go ahead\r\n").unwrap();
+                    let mut data = String::new();
+                    while reader.read_line(&mut data).unwrap() > 0 && !data.ends_with("\r\n.\r\n") {
+                    }
+                    received.data = data;
+                    writer.write_all(b"250 Queued\r\n").unwrap();
+                } else if command == "QUIT" {
+                    writer.write_all(b"221 Bye\r\n").unwrap();
+                    break;
+                }
+            }
+            sender.send(received).unwrap();
+        });
+        (address, receiver)
+    }
+
+    fn signing_key() -> SigningKey {
+        SigningKey::from_slice(&[3u8; 32]).unwrap()
+    }
+
+    fn config(smarthost: String) -> RelayConfig {
+        RelayConfig {
+            listen: String::new(),
+            canister_id: "aaaaa-aa".to_string(),
+            public_key: signing_key()
+                .verifying_key()
+                .to_encoded_point(true)
+                .as_bytes()
+                .to_vec(),
+            smarthost,
+            helo_name: "relay.test".to_string(),
+            delivered_log: String::new(),
+        }
+    }
+
+    fn outgoing_mail(body: &[u8]) -> OutgoingMail {
+        OutgoingMail {
+            id: "0123abcd".to_string(),
+            rcpt_to: vec![
+                "bob@example.com".to_string(),
+                "carol@example.com".to_string(),
+            ],
+            header: MailHeader {
+                from: "alice@dmail.ai".to_string(),
+                to: vec!["bob@example.com".to_string()],
+                bcc: Some(vec!["carol@example.com".to_string()]),
+                subject: Some("Lunch".to_string()),
+                content_type: Some("text/plain; charset=utf-8".to_string()),
+                ..Default::default()
+            },
+            body: Rcbytes::new(std::sync::Arc::new(ByteBuf::from(body.to_vec()))),
+        }
+    }
+
+    fn sign(out: &OutgoingMail) -> String {
+        let signature: Signature = signing_key()
+            .sign_prehash(&out.signing_digest().unwrap())
+            .unwrap();
+        hex::encode(signature.to_bytes())
+    }
+
+    #[test]
+    fn relays_to_the_smarthost() {
+        let (smarthost, received) = smtp_sink();
+        let out = outgoing_mail(b"Noon?");
+        relay(&config(smarthost), &out).unwrap();
+
+        let received = received.recv().unwrap();
+        assert_eq!(received.mail_from, "alice@dmail.ai");
+        assert_eq!(received.rcpt_to, out.rcpt_to);
+        assert!(received.data.contains("Subject: Lunch\r\n"));
+        assert!(received.data.contains("Message-ID: <0123abcd@dmail.ai>"));
+        // Bcc receipients only get the envelope
+        assert!(!received.data.contains("carol@example.com"));
+        assert!(received.data.ends_with("\r\n.\r\n"));
+    }
+
+    #[test]
+    fn stuffs_leading_dots() {
+        let (smarthost, received) = smtp_sink();
+        let mut client = smtp::SmtpClient::connect(&smarthost, "relay.test").unwrap();
+        client
+            .send(
+                "alice@dmail.ai",
+                &["bob@example.com".to_string()],
+                b"Subject: Dots\r\n\r\n.\r\n.hidden\r\n",
+            )
+            .unwrap();
+        client.quit().unwrap();
+        assert_eq!(
+            received.recv().unwrap().data,
+            "Subject: Dots\r\n\r\n..\r\n..hidden\r\n.\r\n"
+        );
+    }
+
+    #[test]
+    fn relays_a_signed_mail_once() {
+        let (smarthost, received) = smtp_sink();
+        let config = config(smarthost);
+        let mut delivered = Delivered::default();
+        let out = outgoing_mail(b"Noon?");
+        let body = serde_json::to_string(&out).unwrap();
+        let principal = Some(config.canister_id.clone());
+        let key = Some(hex::encode(sha256(&out.id)));
+
+        accept(
+            &config,
+            &mut delivered,
+            principal.clone(),
+            Some(sign(&out)),
+            key.clone(),
+            &body,
+            relay,
+        )
+        .unwrap();
+        assert_eq!(received.recv().unwrap().rcpt_to, out.rcpt_to);
+
+        // the other replicas and later replays are answered without relaying again
+        let result = accept(
+            &config,
+            &mut delivered,
+            principal,
+            Some(sign(&out)),
+            key,
+            &body,
+            |_, _| panic!("relayed twice"),
+        );
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn rejects_a_signature_for_other_content() {
+        let config = config(String::new());
+        let signed = outgoing_mail(b"Noon?");
+        let sig = sign(&signed);
+        let mut tampered = outgoing_mail(b"Noon?");
+        tampered.rcpt_to.push("eve@example.com".to_string());
+        let body = serde_json::to_string(&tampered).unwrap();
+
+        let result = accept(
+            &config,
+            &mut Delivered::default(),
+            Some(config.canister_id.clone()),
+            Some(sig),
+            None,
+            &body,
+            |_, _| panic!("relayed a forged mail"),
+        );
+        assert_eq!(result.unwrap_err().0, 401);
+    }
+
+    #[test]
+    fn remembers_relayed_ids_across_restarts() {
+        let path = std::env::temp_dir().join(format!("dmailfi_relay_{}.log", std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = std::fs::remove_file(path);
+
+        Delivered::open(path).unwrap().insert("key-1".to_string());
+        assert!(Delivered::open(path).unwrap().contains("key-1"));
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,114 @@
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::TcpStream,
+    time::Duration,
+};
+
+pub struct SmtpClient {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl SmtpClient {
+    pub fn connect(smarthost: &str, helo_name: &str) -> Result<Self, String> {
+        let stream = TcpStream::connect(smarthost)
+            .map_err(|err| format!("Could not connect to {}: {}", smarthost, err))?;
+        stream
+            .set_read_timeout(Some(Duration::from_secs(60)))
+            .map_err(|err| err.to_string())?;
+        let writer = stream.try_clone().map_err(|err| err.to_string())?;
+        let mut client = SmtpClient {
+            reader: BufReader::new(stream),
+            writer,
+        };
+
+        client.expect(220)?;
+        client.command(&format!("EHLO {}", helo_name), 250)?;
+        Ok(client)
+    }
+
+    // Sends one message. `message` must already be a complete RFC 5322 document with CRLF line endings.
+    pub fn send(
+        &mut self,
+        mail_from: &str,
+        rcpt_to: &[String],
+        message: &[u8],
+    ) -> Result<(), String> {
+        self.command(&format!("MAIL FROM:<{}>", mail_from), 250)?;
+        for rcpt in rcpt_to {
+            let code = self.command_any(&format!("RCPT TO:<{}>", rcpt))?;
+            if code != 250 && code != 251 {
+                return Err(format!("Receipient {} rejected with {}", rcpt, code));
+            }
+        }
+        self.command("DATA", 354)?;
+
+        let mut data = Vec::with_capacity(message.len() + 5);
+        let message = message.strip_suffix(b"\r\n").unwrap_or(message);
+        for line in message.split(|b| *b == b'\n') {
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            // dot stuffing, RFC 5321 section 4.5.2
+            if line.starts_with(b".") {
+                data.push(b'.');
+            }
+            data.extend_from_slice(line);
+            data.extend_from_slice(b"\r\n");
+        }
+        data.extend_from_slice(b".\r\n");
+        self.writer
+            .write_all(&data)
+            .map_err(|err| err.to_string())?;
+        self.expect(250)
+    }
+
+    pub fn quit(mut self) -> Result<(), String> {
+        self.command("QUIT", 221)
+    }
+
+    fn command(&mut self, line: &str, expected: u16) -> Result<(), String> {
+        let code = self.command_any(line)?;
+        if code != expected {
+            return Err(format!("`{}` failed with {}", line, code));
+        }
+        Ok(())
+    }
+
+    fn command_any(&mut self, line: &str) -> Result<u16, String> {
+        self.writer
+            .write_all(format!("{}\r\n", line).as_bytes())
+            .map_err(|err| err.to_string())?;
+        self.read_reply()
+    }
+
+    fn expect(&mut self, expected: u16) -> Result<(), String> {
+        let code = self.read_reply()?;
+        if code != expected {
+            return Err(format!(
+                "Expected {} from smarthost but got {}",
+                expected, code
+            ));
+        }
+        Ok(())
+    }
+
+    // Replies can span several lines, "250-..." continues and "250 ..." ends.
+    fn read_reply(&mut self) -> Result<u16, String> {
+        loop {
+            let mut line = String::new();
+            let read = self
+                .reader
+                .read_line(&mut line)
+                .map_err(|err| err.to_string())?;
+            if read == 0 || line.len() < 3 {
+                return Err("Smarthost closed the connection".to_string());
+            }
+
+            let code = line[0..3]
+                .parse::<u16>()
+                .map_err(|_| format!("Bad reply from smarthost: {}", line.trim_end()))?;
+            if line.as_bytes().get(3) != Some(&b'-') {
+                return Ok(code);
+            }
+        }
+    }
+}
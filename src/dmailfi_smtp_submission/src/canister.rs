@@ -0,0 +1,18 @@
+use dmailfi_client::DfxCanister;
+use dmailfi_types::Mail;
+
+// The calls the submission server makes on the user's core canister
+pub trait MailCanister {
+    fn verify_app_password(&self, email_address: &str, app_password: &str) -> Result<(), String>;
+    fn send_mail_as(&self, email_address: &str, app_password: &str, mail: Mail) -> Result<(), String>;
+}
+
+impl MailCanister for DfxCanister {
+    fn verify_app_password(&self, email_address: &str, app_password: &str) -> Result<(), String> {
+        self.query("verify_app_password", (email_address, app_password))
+    }
+
+    fn send_mail_as(&self, email_address: &str, app_password: &str, mail: Mail) -> Result<(), String> {
+        self.update("send_mail_as", (email_address, app_password, mail))
+    }
+}
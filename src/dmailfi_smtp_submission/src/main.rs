@@ -0,0 +1,52 @@
+//! SMTP submission server (RFC 6409) for desktop mail clients. Users authenticate with an
+//! app password from `create_app_password` and their mail is sent through `send_mail_as`.
+//!
+//! Configuration comes from the environment:
+//! - `DMAILFI_SUBMISSION_LISTEN` address to listen on, defaults to `127.0.0.1:2587`
+//! - `DMAILFI_CANISTER_ID` core canister the users belong to
+//! - `DMAILFI_NETWORK` dfx network, defaults to `local`
+//! - `DMAILFI_HOSTNAME` name used in the greeting, defaults to `localhost`
+//!
+//! There is no STARTTLS, run it on localhost or behind a TLS terminating proxy.
+use std::{env, net::TcpListener, sync::Arc, thread};
+
+use dmailfi_client::DfxCanister;
+use session::Session;
+
+mod canister;
+mod mime;
+mod session;
+
+fn main() {
+    let canister_id = env::var("DMAILFI_CANISTER_ID").unwrap_or_else(|_| {
+        eprintln!("DMAILFI_CANISTER_ID is not set");
+        std::process::exit(1);
+    });
+    let listen = env::var("DMAILFI_SUBMISSION_LISTEN").unwrap_or("127.0.0.1:2587".to_string());
+    let hostname = Arc::new(env::var("DMAILFI_HOSTNAME").unwrap_or("localhost".to_string()));
+    let canister = Arc::new(DfxCanister::new(
+        canister_id,
+        env::var("DMAILFI_NETWORK").unwrap_or("local".to_string()),
+    ));
+
+    let listener = TcpListener::bind(&listen).unwrap_or_else(|err| {
+        eprintln!("Could not listen on {}: {}", listen, err);
+        std::process::exit(1);
+    });
+    println!("dmailfi submission listening on {}", listen);
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(_) => continue,
+        };
+        let canister = canister.clone();
+        let hostname = hostname.clone();
+        thread::spawn(move || {
+            let result = Session::new(canister.as_ref(), &hostname, stream).and_then(|s| s.run());
+            if let Err(err) = result {
+                eprintln!("session error: {}", err);
+            }
+        });
+    }
+}
@@ -0,0 +1,27 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use dmailfi_types::{rfc5322, Mail};
+
+// Builds a `Mail` from a submitted message. Envelope receipients that are not
+// in To or Cc end up in Bcc, which is how clients submit blind copies.
+pub fn to_mail(message: &[u8], rcpt_to: &[String]) -> Result<Mail, String> {
+    let mut mail = rfc5322::from_rfc5322(message)?;
+    let header = &mut mail.header;
+
+    let cc = header.cc.clone().unwrap_or_default();
+    let bcc: Vec<String> = rcpt_to
+        .iter()
+        .filter(|rcpt| !header.to.contains(rcpt) && !cc.contains(rcpt))
+        .cloned()
+        .collect();
+    header.bcc = if bcc.is_empty() { None } else { Some(bcc) };
+
+    // the canister sends as the authenticated user, whatever the From header says
+    header.from = String::new();
+    header.timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0);
+
+    Ok(mail)
+}
@@ -0,0 +1,434 @@
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::TcpStream,
+};
+
+use base64::Engine;
+use dmailfi_client::MAX_ARGUMENT_BYTES;
+
+use crate::{canister::MailCanister, mime};
+
+// What the client can hand to the canister in one call, less room for the Candid encoding of
+// the `Mail` and the credentials sent with it
+pub const MAX_MESSAGE_SIZE: usize = MAX_ARGUMENT_BYTES - 64 * 1024;
+
+struct Credentials {
+    email_address: String,
+    app_password: String,
+}
+
+pub struct Session<'a, C: MailCanister> {
+    canister: &'a C,
+    hostname: &'a str,
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    credentials: Option<Credentials>,
+    mail_from: Option<String>,
+    rcpt_to: Vec<String>,
+}
+
+impl<'a, C: MailCanister> Session<'a, C> {
+    pub fn new(canister: &'a C, hostname: &'a str, stream: TcpStream) -> std::io::Result<Self> {
+        let writer = stream.try_clone()?;
+        Ok(Session {
+            canister,
+            hostname,
+            reader: BufReader::new(stream),
+            writer,
+            credentials: None,
+            mail_from: None,
+            rcpt_to: vec![],
+        })
+    }
+
+    pub fn run(mut self) -> std::io::Result<()> {
+        self.reply(&format!("220 {} dmailfi submission ready", self.hostname))?;
+        loop {
+            let line = match self.read_line()? {
+                Some(line) => line,
+                None => return Ok(()),
+            };
+            let (verb, arg) = match line.split_once(' ') {
+                Some((verb, arg)) => (verb.to_ascii_uppercase(), arg.trim().to_string()),
+                None => (line.to_ascii_uppercase(), String::new()),
+            };
+
+            match verb.as_str() {
+                "EHLO" => self.reply(&format!(
+                    "250-{}\r\n250-AUTH PLAIN LOGIN\r\n250-8BITMIME\r\n250 SIZE {}",
+                    self.hostname, MAX_MESSAGE_SIZE
+                ))?,
+                "HELO" => self.reply(&format!("250 {}", self.hostname))?,
+                "AUTH" => self.auth(&arg)?,
+                "MAIL" => self.mail_from(&arg)?,
+                "RCPT" => self.rcpt_to(&arg)?,
+                "DATA" => self.data()?,
+                "RSET" => {
+                    self.reset();
+                    self.reply("250 2.0.0 OK")?
+                }
+                "NOOP" => self.reply("250 2.0.0 OK")?,
+                "QUIT" => {
+                    self.reply("221 2.0.0 Bye")?;
+                    return Ok(());
+                }
+                _ => self.reply("502 5.5.2 Command not implemented")?,
+            }
+        }
+    }
+
+    fn auth(&mut self, arg: &str) -> std::io::Result<()> {
+        if self.credentials.is_some() {
+            return self.reply("503 5.5.1 Already authenticated");
+        }
+
+        let (mechanism, initial) = match arg.split_once(' ') {
+            Some((mechanism, initial)) => {
+                (mechanism.to_ascii_uppercase(), Some(initial.to_string()))
+            }
+            None => (arg.to_ascii_uppercase(), None),
+        };
+
+        let credentials = match mechanism.as_str() {
+            "PLAIN" => {
+                let response = match initial {
+                    Some(initial) => initial,
+                    None => self.challenge("")?,
+                };
+                // authzid \0 authcid \0 password, RFC 4616
+                decode(&response).and_then(|plain| {
+                    let mut parts = plain.split('\0').skip(1);
+                    Some((parts.next()?.to_string(), parts.next()?.to_string()))
+                })
+            }
+            "LOGIN" => {
+                let username = self.challenge("VXNlcm5hbWU6")?;
+                let password = self.challenge("UGFzc3dvcmQ6")?;
+                decode(&username).zip(decode(&password))
+            }
+            _ => return self.reply("504 5.5.4 Unrecognized authentication type"),
+        };
+
+        let (email_address, app_password) = match credentials {
+            Some(credentials) => credentials,
+            None => return self.reply("501 5.5.2 Cannot decode response"),
+        };
+
+        match self
+            .canister
+            .verify_app_password(&email_address, &app_password)
+        {
+            Ok(()) => {
+                self.credentials = Some(Credentials {
+                    email_address,
+                    app_password,
+                });
+                self.reply("235 2.7.0 Authentication successful")
+            }
+            Err(_) => self.reply("535 5.7.8 Authentication credentials invalid"),
+        }
+    }
+
+    fn mail_from(&mut self, arg: &str) -> std::io::Result<()> {
+        let email_address = match &self.credentials {
+            Some(credentials) => credentials.email_address.clone(),
+            None => return self.reply("530 5.7.0 Authentication required"),
+        };
+        let from = match path(arg, "FROM:") {
+            Some(from) => from,
+            None => return self.reply("501 5.5.4 Syntax: MAIL FROM:<address>"),
+        };
+        if !from.eq_ignore_ascii_case(&email_address) {
+            return self.reply("553 5.7.1 Sender address does not belong to you");
+        }
+
+        self.reset();
+        self.mail_from = Some(from);
+        self.reply("250 2.1.0 OK")
+    }
+
+    fn rcpt_to(&mut self, arg: &str) -> std::io::Result<()> {
+        if self.mail_from.is_none() {
+            return self.reply("503 5.5.1 Need MAIL before RCPT");
+        }
+        match path(arg, "TO:") {
+            Some(rcpt) if !rcpt.is_empty() => {
+                self.rcpt_to.push(rcpt);
+                self.reply("250 2.1.5 OK")
+            }
+            _ => self.reply("501 5.5.4 Syntax: RCPT TO:<address>"),
+        }
+    }
+
+    fn data(&mut self) -> std::io::Result<()> {
+        if self.rcpt_to.is_empty() {
+            return self.reply("503 5.5.1 Need RCPT before DATA");
+        }
+        self.reply("354 End data with <CR><LF>.<CR><LF>")?;
+
+        let mut message: Vec<u8> = vec![];
+        let mut too_large = false;
+        loop {
+            let line = match self.read_line()? {
+                Some(line) => line,
+                None => return Ok(()),
+            };
+            if line == "." {
+                break;
+            }
+            let line = line.strip_prefix('.').unwrap_or(&line);
+            if message.len() + line.len() + 2 > MAX_MESSAGE_SIZE {
+                too_large = true;
+                continue;
+            }
+            message.extend_from_slice(line.as_bytes());
+            message.extend_from_slice(b"\r\n");
+        }
+
+        let rcpt_to = std::mem::take(&mut self.rcpt_to);
+        self.mail_from = None;
+        if too_large {
+            return self.reply("552 5.3.4 Message size exceeds fixed limit");
+        }
+
+        let mail = match mime::to_mail(&message, &rcpt_to) {
+            Ok(mail) => mail,
+            Err(err) => return self.reply(&format!("554 5.6.0 {}", err)),
+        };
+        let credentials = self.credentials.as_ref().unwrap();
+        match self.canister.send_mail_as(
+            &credentials.email_address,
+            &credentials.app_password,
+            mail,
+        ) {
+            Ok(()) => self.reply("250 2.0.0 Message accepted"),
+            Err(err) => self.reply(&format!("554 5.0.0 {}", err.replace(['\r', '\n'], " "))),
+        }
+    }
+
+    fn reset(&mut self) {
+        self.mail_from = None;
+        self.rcpt_to.clear();
+    }
+
+    fn challenge(&mut self, prompt: &str) -> std::io::Result<String> {
+        self.reply(&format!("334 {}", prompt))?;
+        Ok(self.read_line()?.unwrap_or_default())
+    }
+
+    fn read_line(&mut self) -> std::io::Result<Option<String>> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        Ok(Some(line.trim_end_matches(['\r', '\n']).to_string()))
+    }
+
+    fn reply(&mut self, line: &str) -> std::io::Result<()> {
+        self.writer.write_all(format!("{}\r\n", line).as_bytes())
+    }
+}
+
+fn decode(value: &str) -> Option<String> {
+    let bytes = base64::engine::general_purpose::STANDARD
+        .decode(value.trim())
+        .ok()?;
+    String::from_utf8(bytes).ok()
+}
+
+// "FROM:<a@b.c> SIZE=12" -> "a@b.c"
+fn path(arg: &str, prefix: &str) -> Option<String> {
+    if arg.len() < prefix.len() || !arg[..prefix.len()].eq_ignore_ascii_case(prefix) {
+        return None;
+    }
+    let rest = arg[prefix.len()..].trim();
+    let rest = rest.split_whitespace().next().unwrap_or("");
+    Some(
+        rest.trim_start_matches('<')
+            .trim_end_matches('>')
+            .to_string(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::{TcpListener, TcpStream},
+        sync::Mutex,
+        thread,
+    };
+
+    use base64::Engine;
+    use dmailfi_types::Mail;
+
+    use super::*;
+
+    // Stands in for the replica, it knows one app password and keeps what is sent.
+    #[derive(Default)]
+    struct StandIn {
+        sent: Mutex<Vec<(String, Mail)>>,
+    }
+
+    impl MailCanister for StandIn {
+        fn verify_app_password(
+            &self,
+            email_address: &str,
+            app_password: &str,
+        ) -> Result<(), String> {
+            if email_address == "alice@dmail.ai" && app_password == "secret" {
+                Ok(())
+            } else {
+                Err("Not authorized".to_string())
+            }
+        }
+
+        fn send_mail_as(
+            &self,
+            email_address: &str,
+            app_password: &str,
+            mail: Mail,
+        ) -> Result<(), String> {
+            self.verify_app_password(email_address, app_password)?;
+            self.sent
+                .lock()
+                .unwrap()
+                .push((email_address.to_string(), mail));
+            Ok(())
+        }
+    }
+
+    struct Client {
+        reader: BufReader<TcpStream>,
+        writer: TcpStream,
+    }
+
+    impl Client {
+        // Sends a line and returns the reply.
+        fn send(&mut self, line: &str) -> (u16, String) {
+            self.writer
+                .write_all(format!("{}\r\n", line).as_bytes())
+                .unwrap();
+            self.reply()
+        }
+
+        fn reply(&mut self) -> (u16, String) {
+            let mut text = String::new();
+            loop {
+                let mut line = String::new();
+                self.reader.read_line(&mut line).unwrap();
+                text.push_str(&line);
+                if line.as_bytes().get(3) != Some(&b'-') {
+                    return (line[..3].parse().unwrap(), text);
+                }
+            }
+        }
+
+        fn login(&mut self) {
+            let plain =
+                base64::engine::general_purpose::STANDARD.encode("\0alice@dmail.ai\0secret");
+            assert_eq!(self.send(&format!("AUTH PLAIN {}", plain)).0, 235);
+        }
+
+        fn data(&mut self, message: &str) -> (u16, String) {
+            assert_eq!(self.send("DATA").0, 354);
+            self.writer.write_all(message.as_bytes()).unwrap();
+            self.send(".")
+        }
+    }
+
+    // Runs one session against the stand-in and hands the client side to `test`.
+    fn with_session(canister: &StandIn, test: impl FnOnce(&mut Client)) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                let (stream, _) = listener.accept().unwrap();
+                Session::new(canister, "submission.test", stream)
+                    .unwrap()
+                    .run()
+                    .unwrap();
+            });
+            let stream = TcpStream::connect(address).unwrap();
+            let mut client = Client {
+                reader: BufReader::new(stream.try_clone().unwrap()),
+                writer: stream,
+            };
+            assert_eq!(client.reply().0, 220);
+            test(&mut client);
+            client.send("QUIT");
+        });
+    }
+
+    #[test]
+    fn submits_mail_through_the_canister() {
+        let canister = StandIn::default();
+        with_session(&canister, |client| {
+            assert_eq!(client.send("EHLO client.test").0, 250);
+            client.login();
+            assert_eq!(client.send("MAIL FROM:<alice@dmail.ai>").0, 250);
+            assert_eq!(client.send("RCPT TO:<bob@dmail.ai>").0, 250);
+            assert_eq!(client.send("RCPT TO:<carol@dmail.ai>").0, 250);
+            let message = "From: Alice <alice@dmail.ai>\r\nTo: bob@dmail.ai\r\nSubject: Hi\r\n\r\n..dotted\r\nHello Bob\r\n";
+            assert_eq!(client.data(message).0, 250);
+        });
+
+        let sent = canister.sent.lock().unwrap();
+        assert_eq!(sent.len(), 1);
+        let (sender, mail) = &sent[0];
+        assert_eq!(sender, "alice@dmail.ai");
+        assert_eq!(mail.header.subject.as_deref(), Some("Hi"));
+        assert_eq!(mail.header.to, vec!["bob@dmail.ai".to_string()]);
+        assert_eq!(mail.header.bcc, Some(vec!["carol@dmail.ai".to_string()]));
+        assert_eq!(
+            String::from_utf8_lossy(mail.body.0.as_slice()),
+            ".dotted\r\nHello Bob\r\n"
+        );
+    }
+
+    #[test]
+    fn refuses_bad_credentials_and_foreign_senders() {
+        let canister = StandIn::default();
+        with_session(&canister, |client| {
+            assert_eq!(client.send("MAIL FROM:<alice@dmail.ai>").0, 530);
+            let plain = base64::engine::general_purpose::STANDARD.encode("\0alice@dmail.ai\0wrong");
+            assert_eq!(client.send(&format!("AUTH PLAIN {}", plain)).0, 535);
+            client.login();
+            assert_eq!(client.send("MAIL FROM:<mallory@dmail.ai>").0, 553);
+        });
+        assert!(canister.sent.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn accepts_messages_up_to_the_advertised_size() {
+        let canister = StandIn::default();
+        let line = format!("{}\r\n", "a".repeat(998));
+        let header = "From: alice@dmail.ai\r\nTo: bob@dmail.ai\r\nSubject: Big\r\n\r\n";
+        let fits = format!(
+            "{}{}",
+            header,
+            line.repeat((MAX_MESSAGE_SIZE - header.len()) / line.len())
+        );
+        let too_large = format!("{}{}", fits, line.repeat(2));
+
+        with_session(&canister, |client| {
+            let (code, ehlo) = client.send("EHLO client.test");
+            assert_eq!(code, 250);
+            assert!(ehlo.contains(&format!("SIZE {}", MAX_MESSAGE_SIZE)));
+            client.login();
+            assert_eq!(client.send("MAIL FROM:<alice@dmail.ai>").0, 250);
+            assert_eq!(client.send("RCPT TO:<bob@dmail.ai>").0, 250);
+            let (code, text) = client.data(&fits);
+            assert_eq!(code, 250, "{}", text);
+            assert_eq!(client.send("MAIL FROM:<alice@dmail.ai>").0, 250);
+            assert_eq!(client.send("RCPT TO:<bob@dmail.ai>").0, 250);
+            assert_eq!(client.data(&too_large).0, 552);
+        });
+
+        let sent = canister.sent.lock().unwrap();
+        assert_eq!(sent.len(), 1);
+        // the whole message still fits in one call to the canister
+        let encoded = candid::encode_args(("alice@dmail.ai", "secret", &sent[0].1)).unwrap();
+        assert!(encoded.len() <= MAX_ARGUMENT_BYTES);
+    }
+}
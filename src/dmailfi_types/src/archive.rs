@@ -0,0 +1,338 @@
+//! The public archive of newsletter issues, served by `http_request` as HTML pages and as RSS 2.0
+//! and Atom (RFC 4287) feeds.
+use candid::CandidType;
+use serde::Deserialize;
+use serde_bytes::ByteBuf;
+
+use crate::{
+    http::{escape_html, percent_encode, HttpResponse},
+    Newsletter, Tier, UtcDateTime, NEWSLETTER_ID,
+};
+
+// feeds list the latest issues only
+pub const FEED_ISSUES: usize = 20;
+
+// HTML issues are the sender's markup and this origin also serves /unsubscribe. The sandbox puts
+// the page in an origin of its own, without scripts, forms or plugins.
+pub const ISSUE_CONTENT_SECURITY_POLICY: &str =
+    "sandbox; default-src 'none'; img-src https: data:; \
+     style-src 'unsafe-inline'; form-action 'none'; base-uri 'none'";
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Issue {
+    // starts at 1 for each newsletter
+    pub number: u32,
+    pub subject: String,
+    pub published_at: u64,
+    pub content_type: Option<String>,
+    pub body: ByteBuf,
+    // paid issues went to paid subscribers only and are left out of the archive
+    pub tier: Tier,
+}
+
+impl Issue {
+    pub fn summary(&self) -> IssueSummary {
+        IssueSummary {
+            number: self.number,
+            subject: self.subject.clone(),
+            published_at: self.published_at,
+        }
+    }
+
+    fn is_html(&self) -> bool {
+        self.content_type
+            .as_deref()
+            .is_some_and(|content_type| content_type.to_ascii_lowercase().starts_with("text/html"))
+    }
+
+    // The body as HTML, plain text is escaped and keeps its line breaks.
+    fn html_body(&self) -> String {
+        let text = String::from_utf8_lossy(&self.body);
+        if self.is_html() {
+            text.into_owned()
+        } else {
+            format!(
+                "<pre style=\"white-space: pre-wrap\">{}</pre>",
+                escape_html(&text)
+            )
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct IssueSummary {
+    pub number: u32,
+    pub subject: String,
+    pub published_at: u64,
+}
+
+pub fn newsletter_url(base_url: &str, newsletter_id: &NEWSLETTER_ID) -> String {
+    format!("{}/newsletters/{}", base_url, percent_encode(newsletter_id))
+}
+
+pub fn issue_url(base_url: &str, newsletter_id: &NEWSLETTER_ID, number: u32) -> String {
+    format!(
+        "{}/issues/{}",
+        newsletter_url(base_url, newsletter_id),
+        number
+    )
+}
+
+// Newest issue first.
+pub fn render_index(
+    base_url: &str,
+    newsletter_id: &NEWSLETTER_ID,
+    newsletter: &Newsletter,
+    issues: &[&Issue],
+) -> String {
+    let url = newsletter_url(base_url, newsletter_id);
+    let title = escape_html(&newsletter.title);
+    let items: String = issues
+        .iter()
+        .rev()
+        .map(|issue| {
+            format!(
+                "<li><a href=\"{}\">{}</a> <time>{}</time></li>",
+                escape_html(&issue_url(base_url, newsletter_id, issue.number)),
+                escape_html(&issue.subject),
+                UtcDateTime::from_nanos(issue.published_at).to_rfc2822()
+            )
+        })
+        .collect();
+    format!(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>{title}</title>\
+         <link rel=\"alternate\" type=\"application/rss+xml\" href=\"{url}/rss.xml\">\
+         <link rel=\"alternate\" type=\"application/atom+xml\" href=\"{url}/atom.xml\">\
+         </head><body><h1>{title}</h1><p>{description}</p><ul>{items}</ul>\
+         <p><a href=\"{url}/rss.xml\">RSS</a> <a href=\"{url}/atom.xml\">Atom</a></p></body></html>",
+        title = title,
+        url = escape_html(&url),
+        description = escape_html(&newsletter.desciption),
+        items = items
+    )
+}
+
+pub fn render_issue(
+    base_url: &str,
+    newsletter_id: &NEWSLETTER_ID,
+    newsletter: &Newsletter,
+    issue: &Issue,
+) -> String {
+    format!(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>{subject}</title></head>\
+         <body><p><a href=\"{url}\">{title}</a></p><h1>{subject}</h1><p><time>{date}</time></p>\
+         <article>{body}</article></body></html>",
+        subject = escape_html(&issue.subject),
+        url = escape_html(&newsletter_url(base_url, newsletter_id)),
+        title = escape_html(&newsletter.title),
+        date = UtcDateTime::from_nanos(issue.published_at).to_rfc2822(),
+        body = issue.html_body()
+    )
+}
+
+pub fn issue_response(
+    base_url: &str,
+    newsletter_id: &NEWSLETTER_ID,
+    newsletter: &Newsletter,
+    issue: &Issue,
+) -> HttpResponse {
+    let mut response = HttpResponse::html(
+        200,
+        render_issue(base_url, newsletter_id, newsletter, issue),
+    );
+    response.headers.push((
+        "Content-Security-Policy".to_string(),
+        ISSUE_CONTENT_SECURITY_POLICY.to_string(),
+    ));
+    response
+}
+
+pub fn render_rss(
+    base_url: &str,
+    newsletter_id: &NEWSLETTER_ID,
+    newsletter: &Newsletter,
+    issues: &[&Issue],
+) -> String {
+    let items: String = issues
+        .iter()
+        .rev()
+        .take(FEED_ISSUES)
+        .map(|issue| {
+            let link = escape_html(&issue_url(base_url, newsletter_id, issue.number));
+            format!(
+                "<item><title>{}</title><link>{}</link><guid isPermaLink=\"true\">{}</guid>\
+                 <pubDate>{}</pubDate><description>{}</description></item>",
+                escape_html(&issue.subject),
+                link,
+                link,
+                UtcDateTime::from_nanos(issue.published_at).to_rfc2822(),
+                escape_html(&issue.html_body())
+            )
+        })
+        .collect();
+    format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?><rss version=\"2.0\"><channel>\
+         <title>{}</title><link>{}</link><description>{}</description>{}</channel></rss>",
+        escape_html(&newsletter.title),
+        escape_html(&newsletter_url(base_url, newsletter_id)),
+        escape_html(&newsletter.desciption),
+        items
+    )
+}
+
+pub fn render_atom(
+    base_url: &str,
+    newsletter_id: &NEWSLETTER_ID,
+    newsletter: &Newsletter,
+    issues: &[&Issue],
+) -> String {
+    let url = escape_html(&newsletter_url(base_url, newsletter_id));
+    // a feed without entries was last updated at the epoch
+    let updated = issues.last().map_or(0, |issue| issue.published_at);
+    let entries: String = issues
+        .iter()
+        .rev()
+        .take(FEED_ISSUES)
+        .map(|issue| {
+            let link = escape_html(&issue_url(base_url, newsletter_id, issue.number));
+            format!(
+                "<entry><id>{}</id><title>{}</title><link href=\"{}\"/><updated>{}</updated>\
+                 <content type=\"html\">{}</content></entry>",
+                link,
+                escape_html(&issue.subject),
+                link,
+                UtcDateTime::from_nanos(issue.published_at).to_rfc3339(),
+                escape_html(&issue.html_body())
+            )
+        })
+        .collect();
+    format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?><feed xmlns=\"http://www.w3.org/2005/Atom\">\
+         <id>{url}</id><title>{}</title><subtitle>{}</subtitle><link href=\"{url}\"/>\
+         <link rel=\"self\" href=\"{url}/atom.xml\"/><updated>{}</updated>\
+         <author><name>{}</name></author>{}</feed>",
+        escape_html(&newsletter.title),
+        escape_html(&newsletter.desciption),
+        UtcDateTime::from_nanos(updated).to_rfc3339(),
+        escape_html(&newsletter.title),
+        entries,
+        url = url
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE_URL: &str = "https://aaaaa-aa.icp0.io";
+
+    fn newsletter() -> Newsletter {
+        Newsletter::new(
+            "Tom & Jerry's <News>".to_string(),
+            "Cats \"and\" mice".to_string(),
+        )
+    }
+
+    fn issue(number: u32, subject: &str, content_type: Option<&str>, body: &str) -> Issue {
+        Issue {
+            number,
+            subject: subject.to_string(),
+            // 2023-11-14 22:13:20 UTC, a day apart
+            published_at: (1_700_000_000 + 86_400 * number as u64) * 1_000_000_000,
+            content_type: content_type.map(str::to_string),
+            body: ByteBuf::from(body.as_bytes().to_vec()),
+            tier: Tier::Free,
+        }
+    }
+
+    #[test]
+    fn the_index_lists_issues_newest_first_and_escapes_them() {
+        let (first, second) = (
+            issue(1, "First", None, ""),
+            issue(2, "<b>Second</b>", None, ""),
+        );
+        let page = render_index(
+            BASE_URL,
+            &"a b".to_string(),
+            &newsletter(),
+            &[&first, &second],
+        );
+        assert!(page.contains("<title>Tom &amp; Jerry&#39;s &lt;News&gt;</title>"));
+        assert!(page.contains("<p>Cats &quot;and&quot; mice</p>"));
+        assert!(page.contains("href=\"https://aaaaa-aa.icp0.io/newsletters/a%20b/rss.xml\""));
+        let second_at = page.find("&lt;b&gt;Second&lt;/b&gt;").unwrap();
+        let first_at = page.find("/issues/1\">First</a>").unwrap();
+        assert!(second_at < first_at);
+        assert!(page.contains("<time>Thu, 16 Nov 2023 22:13:20 +0000</time>"));
+        assert!(!page.contains("<b>"));
+    }
+
+    #[test]
+    fn plain_text_issues_are_escaped() {
+        let issue = issue(
+            1,
+            "Hi",
+            Some("text/plain"),
+            "<script>alert(1)</script>\nbye",
+        );
+        let page = render_issue(BASE_URL, &"news".to_string(), &newsletter(), &issue);
+        assert!(page.contains(
+            "<pre style=\"white-space: pre-wrap\">&lt;script&gt;alert(1)&lt;/script&gt;\nbye</pre>"
+        ));
+        assert!(!page.contains("<script>"));
+    }
+
+    #[test]
+    fn html_issues_are_served_in_a_sandbox() {
+        let issue = issue(
+            1,
+            "Hi",
+            Some("Text/HTML; charset=utf-8"),
+            "<p>Hello</p><script>alert(1)</script>",
+        );
+        let response = issue_response(BASE_URL, &"news".to_string(), &newsletter(), &issue);
+        let page = String::from_utf8(response.body.to_vec()).unwrap();
+        assert!(page.contains("<article><p>Hello</p><script>alert(1)</script></article>"));
+        let policy = response
+            .headers
+            .iter()
+            .find(|(name, _)| name == "Content-Security-Policy")
+            .map(|(_, value)| value.as_str())
+            .unwrap();
+        assert!(policy.starts_with("sandbox;"));
+        assert!(policy.contains("default-src 'none'"));
+        assert!(!policy.contains("allow-"));
+    }
+
+    #[test]
+    fn feeds_carry_the_latest_issues_escaped() {
+        let issues: Vec<Issue> = (1..=FEED_ISSUES as u32 + 1)
+            .map(|number| {
+                issue(
+                    number,
+                    &format!("Issue {}", number),
+                    Some("text/html"),
+                    "<p>a & b</p>",
+                )
+            })
+            .collect();
+        let issues: Vec<&Issue> = issues.iter().collect();
+        let id = "news".to_string();
+
+        let rss = render_rss(BASE_URL, &id, &newsletter(), &issues);
+        assert_eq!(rss.matches("<item>").count(), FEED_ISSUES);
+        assert!(!rss.contains("<title>Issue 1</title>"));
+        assert!(rss.contains("<title>Tom &amp; Jerry&#39;s &lt;News&gt;</title>"));
+        assert!(rss.contains("<description>&lt;p&gt;a &amp; b&lt;/p&gt;</description>"));
+        assert!(rss.contains("<pubDate>Tue, 05 Dec 2023 22:13:20 +0000</pubDate>"));
+
+        let atom = render_atom(BASE_URL, &id, &newsletter(), &issues);
+        assert_eq!(atom.matches("<entry>").count(), FEED_ISSUES);
+        assert!(atom.contains("<content type=\"html\">&lt;p&gt;a &amp; b&lt;/p&gt;</content>"));
+        // the feed was updated by its newest issue
+        let updated = "<updated>2023-12-05T22:13:20Z</updated>";
+        assert!(atom.find(updated).unwrap() < atom.find("<entry>").unwrap());
+        assert!(render_atom(BASE_URL, &id, &newsletter(), &[])
+            .contains("<updated>1970-01-01T00:00:00Z</updated>"));
+    }
+}
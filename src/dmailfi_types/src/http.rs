@@ -0,0 +1,357 @@
+//! The HTTP gateway interface a canister implements to be reachable from browsers and mail
+//! clients, `http_request` as a query and `http_request_update` for requests that change state.
+use candid::CandidType;
+use serde::Deserialize;
+use serde_bytes::ByteBuf;
+
+use crate::Ledger;
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct HttpRequest {
+    pub method: String,
+    // path and query, e.g. "/unsubscribe?token=..."
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: ByteBuf,
+}
+
+impl HttpRequest {
+    pub fn path(&self) -> &str {
+        self.url.split('?').next().unwrap_or("")
+    }
+
+    // The first value of a query parameter, percent decoded. Form bodies of POST requests are
+    // searched as well.
+    pub fn param(&self, name: &str) -> Option<String> {
+        let query = self
+            .url
+            .split_once('?')
+            .map(|(_, query)| query)
+            .unwrap_or("");
+        let body = std::str::from_utf8(&self.body).unwrap_or("");
+        query
+            .split('&')
+            .chain(body.split('&'))
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| percent_decode(value))
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct HttpResponse {
+    pub status_code: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: ByteBuf,
+    // asks the gateway to repeat the request as `http_request_update`
+    pub upgrade: Option<bool>,
+}
+
+impl HttpResponse {
+    pub fn new(status_code: u16, content_type: &str, body: String) -> Self {
+        HttpResponse {
+            status_code,
+            headers: vec![("Content-Type".to_string(), content_type.to_string())],
+            body: ByteBuf::from(body.into_bytes()),
+            upgrade: None,
+        }
+    }
+
+    pub fn html(status_code: u16, body: String) -> Self {
+        HttpResponse::new(status_code, "text/html; charset=utf-8", body)
+    }
+
+    pub fn not_found() -> Self {
+        HttpResponse::new(404, "text/plain; charset=utf-8", "Not found".to_string())
+    }
+
+    pub fn upgrade() -> Self {
+        HttpResponse {
+            status_code: 200,
+            headers: vec![],
+            body: ByteBuf::new(),
+            upgrade: Some(true),
+        }
+    }
+}
+
+fn page(status_code: u16, title: &str, content: &str) -> HttpResponse {
+    HttpResponse::html(
+        status_code,
+        format!(
+            "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>{}</title></head><body>{}</body></html>",
+            title, content
+        ),
+    )
+}
+
+// A GET of an unsubscribe or confirmation link only asks, link scanners in mail clients must not
+// act on it. The form posts the token back to the same path.
+pub fn token_form(request: &HttpRequest) -> HttpResponse {
+    let (title, button) = match request.path() {
+        "/unsubscribe" => ("Unsubscribe", "Unsubscribe"),
+        "/confirm" => ("Confirm subscription", "Confirm"),
+        _ => return HttpResponse::not_found(),
+    };
+    let token = escape_html(&request.param("token").unwrap_or_default());
+    page(
+        200,
+        title,
+        &format!(
+            "<form method=\"post\" action=\"{}\"><input type=\"hidden\" name=\"token\" value=\"{}\"><button type=\"submit\">{}</button></form>",
+            request.path(),
+            token,
+            button
+        ),
+    )
+}
+
+// The requests `http_request` upgrades because they change state: one-click unsubscribe
+// (RFC 8058), which carries the token in the URL, and the forms above, which post it.
+pub fn http_request_update(ledger: &mut Ledger, request: &HttpRequest, now: u64) -> HttpResponse {
+    if request.method != "POST" {
+        return HttpResponse::not_found();
+    }
+    let token = request.param("token").unwrap_or_default();
+    let (title, result) = match request.path() {
+        "/unsubscribe" => (
+            "Unsubscribe",
+            ledger
+                .unsubscribe_with_token(&token, now)
+                .map(|title| format!("You are unsubscribed from {}.", escape_html(&title))),
+        ),
+        "/confirm" => (
+            "Confirm subscription",
+            ledger.confirm_subscription(&token, now).map(|n_id| {
+                let title = ledger
+                    .get_newsletter(n_id.clone())
+                    .map(|newsletter| newsletter.title)
+                    .unwrap_or(n_id);
+                format!("You are subscribed to {}.", escape_html(&title))
+            }),
+        ),
+        _ => return HttpResponse::not_found(),
+    };
+    match result {
+        Ok(message) => page(200, title, &format!("<p>{}</p>", message)),
+        Err(err) => page(
+            400,
+            title,
+            &format!("<p>{}</p>", escape_html(&err.to_string())),
+        ),
+    }
+}
+
+pub fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).ok();
+                match hex.and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                    Some(byte) => {
+                        decoded.push(byte);
+                        i += 2;
+                    }
+                    None => decoded.push(b'%'),
+                }
+            }
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+pub fn percent_encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+// Escapes text for HTML element content and attribute values.
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use candid::Principal;
+
+    use super::*;
+    use crate::Newsletter;
+
+    fn request(method: &str, url: &str, body: &str) -> HttpRequest {
+        HttpRequest {
+            method: method.to_string(),
+            url: url.to_string(),
+            headers: vec![],
+            body: ByteBuf::from(body.as_bytes().to_vec()),
+        }
+    }
+
+    // "news" with bob and carol subscribed
+    fn ledger() -> Ledger {
+        let mut ledger = Ledger::default();
+        let owner = Principal::from_slice(&[1]);
+        ledger.set_newsletter_secret([9u8; 32]);
+        for id in ["news", "other"] {
+            ledger
+                .create_newletter(
+                    id.to_string(),
+                    Newsletter::new("News".to_string(), "Weekly news".to_string()),
+                    owner,
+                )
+                .unwrap();
+        }
+        for address in ["bob@example.com", "carol@example.com"] {
+            ledger
+                .subscribe_to_newsletter(
+                    "news".to_string(),
+                    address.to_string(),
+                    owner,
+                    address,
+                    "https://news.example",
+                    1,
+                )
+                .unwrap();
+            ledger.confirm_subscription(address, 2).unwrap();
+        }
+        ledger
+    }
+
+    fn subscribers(ledger: &Ledger) -> Vec<String> {
+        let mut subscribers = ledger
+            .get_newsletter_subscribers("news".to_string())
+            .unwrap();
+        subscribers.sort();
+        subscribers
+    }
+
+    fn token(ledger: &Ledger, address: &str) -> String {
+        ledger
+            .unsubscribe_token(&"news".to_string(), &address.to_string())
+            .unwrap()
+    }
+
+    #[test]
+    fn one_click_and_form_posts_unsubscribe() {
+        let mut ledger = ledger();
+        // RFC 8058, the token stays in the List-Unsubscribe URL
+        let one_click = request(
+            "POST",
+            &format!("/unsubscribe?token={}", token(&ledger, "bob@example.com")),
+            "List-Unsubscribe=One-Click",
+        );
+        let response = http_request_update(&mut ledger, &one_click, 3);
+        assert_eq!(response.status_code, 200);
+        assert!(String::from_utf8_lossy(&response.body).contains("unsubscribed from News"));
+        assert_eq!(subscribers(&ledger), vec!["carol@example.com"]);
+
+        let form = request(
+            "POST",
+            "/unsubscribe",
+            &format!(
+                "token={}",
+                percent_encode(&token(&ledger, "carol@example.com"))
+            ),
+        );
+        assert_eq!(http_request_update(&mut ledger, &form, 4).status_code, 200);
+        assert!(subscribers(&ledger).is_empty());
+    }
+
+    #[test]
+    fn a_get_only_shows_the_form() {
+        let mut ledger = ledger();
+        let token = token(&ledger, "bob@example.com");
+        let get = request("GET", &format!("/unsubscribe?token={}", token), "");
+        let form = String::from_utf8(token_form(&get).body.into_vec()).unwrap();
+        assert!(form.contains(&format!("name=\"token\" value=\"{}\"", token)));
+        assert_eq!(http_request_update(&mut ledger, &get, 3).status_code, 404);
+        assert_eq!(subscribers(&ledger).len(), 2);
+    }
+
+    #[test]
+    fn tampered_tokens_are_rejected() {
+        let mut ledger = ledger();
+        let token = token(&ledger, "bob@example.com");
+        let parts: Vec<&str> = token.split('.').collect();
+        let mut signature = hex::decode(parts[2]).unwrap();
+        signature[0] ^= 1;
+        let tampered = [
+            // carol's address under bob's signature
+            format!(
+                "{}.{}.{}",
+                parts[0],
+                hex::encode("carol@example.com"),
+                parts[2]
+            ),
+            // the same subscriber of another newsletter
+            format!("{}.{}.{}", hex::encode("other"), parts[1], parts[2]),
+            format!("{}.{}.{}", parts[0], parts[1], hex::encode(signature)),
+            format!("{}.{}.{}", parts[0], parts[1], &parts[2][..32]),
+            format!("{}.{}", parts[0], parts[1]),
+        ];
+        for token in tampered {
+            let post = request("POST", &format!("/unsubscribe?token={}", token), "");
+            assert_eq!(http_request_update(&mut ledger, &post, 3).status_code, 400);
+        }
+        assert_eq!(subscribers(&ledger).len(), 2);
+    }
+
+    #[test]
+    fn the_confirmation_link_confirms_on_post() {
+        let mut ledger = ledger();
+        ledger.take_pending_outbound();
+        ledger
+            .subscribe_to_newsletter(
+                "news".to_string(),
+                "dave@example.org".to_string(),
+                Principal::from_slice(&[2]),
+                "a+b/c",
+                "https://news.example",
+                3,
+            )
+            .unwrap();
+        let mails = ledger.take_pending_outbound();
+        let body = String::from_utf8_lossy(mails[0].1.body.0.as_slice()).into_owned();
+        let link = "https://news.example/confirm?token=a%2Bb%2Fc";
+        assert!(body.contains(link));
+
+        let url = &link["https://news.example".len()..];
+        let form = token_form(&request("GET", url, ""));
+        assert!(String::from_utf8_lossy(&form.body).contains(
+            "action=\"/confirm\"><input type=\"hidden\" name=\"token\" value=\"a+b/c\">"
+        ));
+        assert_eq!(subscribers(&ledger).len(), 2);
+
+        let post = request("POST", "/confirm", "token=a%2Bb%2Fc");
+        let response = http_request_update(&mut ledger, &post, 4);
+        assert_eq!(response.status_code, 200);
+        assert!(String::from_utf8_lossy(&response.body).contains("subscribed to News"));
+        assert!(subscribers(&ledger).contains(&"dave@example.org".to_string()));
+        // the token works once
+        assert_eq!(http_request_update(&mut ledger, &post, 5).status_code, 400);
+    }
+}
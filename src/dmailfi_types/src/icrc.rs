@@ -0,0 +1,188 @@
+//! The parts of the ICRC-1 and ICRC-2 ledger interfaces used to collect and pay out postage.
+use std::future::Future;
+
+use candid::{CandidType, Nat, Principal};
+use serde::Deserialize;
+use serde_bytes::ByteBuf;
+
+use crate::MailError;
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct Account {
+    pub owner: Principal,
+    pub subaccount: Option<ByteBuf>,
+}
+
+impl From<Principal> for Account {
+    fn from(owner: Principal) -> Self {
+        Account {
+            owner,
+            subaccount: None,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TransferArg {
+    pub from_subaccount: Option<ByteBuf>,
+    pub to: Account,
+    pub amount: Nat,
+    pub fee: Option<Nat>,
+    pub memo: Option<ByteBuf>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum TransferError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    TemporarilyUnavailable,
+    Duplicate { duplicate_of: Nat },
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TransferFromArgs {
+    pub spender_subaccount: Option<ByteBuf>,
+    pub from: Account,
+    pub to: Account,
+    pub amount: Nat,
+    pub fee: Option<Nat>,
+    pub memo: Option<ByteBuf>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum TransferFromError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    InsufficientAllowance { allowance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+/// The calls made to the configured token ledger. The canister makes them as inter-canister
+/// calls, a failed call comes back as its reject message.
+pub trait TokenLedger {
+    fn fee(&self) -> impl Future<Output = Result<Nat, String>>;
+    fn transfer(
+        &self,
+        args: TransferArg,
+    ) -> impl Future<Output = Result<Result<Nat, TransferError>, String>>;
+    fn transfer_from(
+        &self,
+        args: TransferFromArgs,
+    ) -> impl Future<Output = Result<Result<Nat, TransferFromError>, String>>;
+}
+
+/// Takes `amount` from what `payer` approved for `canister` with `icrc2_approve`. A missing
+/// allowance or balance is `PostageRequired`.
+pub async fn collect_postage(
+    token: &impl TokenLedger,
+    payer: Principal,
+    canister: Principal,
+    amount: Nat,
+) -> Result<(), MailError> {
+    transfer_from(token, payer, canister, amount.clone())
+        .await
+        .map_err(|err| match err {
+            Shortfall::Insufficient => MailError::PostageRequired { amount },
+            Shortfall::Failed(err) => {
+                MailError::GeneralError(format!("Postage transfer failed: {:?}", err))
+            }
+            Shortfall::Unreachable(err) => err,
+        })
+}
+
+/// Takes one billing period's `amount` from what `payer` approved for this canister and pays it
+/// to the newsletter's `owner`. A missing allowance or balance is `PaymentRequired`.
+pub async fn charge_subscription(
+    token: &impl TokenLedger,
+    payer: Principal,
+    owner: Principal,
+    amount: Nat,
+) -> Result<(), MailError> {
+    transfer_from(token, payer, owner, amount.clone())
+        .await
+        .map_err(|err| match err {
+            Shortfall::Insufficient => MailError::PaymentRequired { amount },
+            Shortfall::Failed(err) => {
+                MailError::GeneralError(format!("Subscription payment failed: {:?}", err))
+            }
+            Shortfall::Unreachable(err) => err,
+        })
+}
+
+// Why a `transfer_from` took nothing.
+enum Shortfall {
+    // the allowance or the balance is too small
+    Insufficient,
+    Failed(TransferFromError),
+    Unreachable(MailError),
+}
+
+async fn transfer_from(
+    token: &impl TokenLedger,
+    from: Principal,
+    to: Principal,
+    amount: Nat,
+) -> Result<(), Shortfall> {
+    let args = TransferFromArgs {
+        spender_subaccount: None,
+        from: Account::from(from),
+        to: Account::from(to),
+        amount,
+        fee: None,
+        memo: None,
+        created_at_time: None,
+    };
+    match token.transfer_from(args).await {
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err(TransferFromError::InsufficientAllowance { .. }))
+        | Ok(Err(TransferFromError::InsufficientFunds { .. })) => Err(Shortfall::Insufficient),
+        Ok(Err(err)) => Err(Shortfall::Failed(err)),
+        Err(message) => Err(Shortfall::Unreachable(MailError::GeneralError(format!(
+            "Token ledger call failed: {}",
+            message
+        )))),
+    }
+}
+
+/// Transfers `amount` out of the canister's account, the ledger fee comes off the amount.
+/// Returns the block index.
+pub async fn pay_out(token: &impl TokenLedger, to: Account, amount: Nat) -> Result<Nat, MailError> {
+    let fee = token.fee().await.map_err(|message| {
+        MailError::GeneralError(format!("Token ledger call failed: {}", message))
+    })?;
+    if amount <= fee {
+        return Err(MailError::GeneralError(
+            "The amount does not cover the transfer fee".to_string(),
+        ));
+    }
+    let args = TransferArg {
+        from_subaccount: None,
+        to,
+        amount: amount - fee.clone(),
+        fee: Some(fee),
+        memo: None,
+        created_at_time: None,
+    };
+    match token.transfer(args).await {
+        Ok(Ok(block)) => Ok(block),
+        Ok(Err(err)) => Err(MailError::GeneralError(format!(
+            "Transfer failed: {:?}",
+            err
+        ))),
+        Err(message) => Err(MailError::GeneralError(format!(
+            "Token ledger call failed: {}",
+            message
+        ))),
+    }
+}
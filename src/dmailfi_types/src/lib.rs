@@ -1,15 +1,64 @@
 use std::{
-    borrow::Borrow, cell::RefCell, cmp, collections::{HashMap, HashSet}, default, fmt::{Debug, Display}, ops::Deref, str::FromStr, sync::Arc
+    cell::RefCell, cmp, collections::{BTreeMap, HashMap, HashSet, VecDeque}, default, fmt::{Debug, Display}, ops::Deref, str::FromStr, sync::Arc
 };
 
-use candid::{types::TypeInner, CandidType, Principal};
+use candid::{types::TypeInner, CandidType, Nat, Principal};
 use email_address::EmailAddress;
-use ic_cdk::{api::{management_canister::ecdsa::{EcdsaCurve, EcdsaKeyId}, time}, caller};
+use hmac::{Hmac, Mac};
+use ic_cdk::api::{management_canister::ecdsa::{EcdsaCurve, EcdsaKeyId}, time};
 use serde::{de::Visitor, Deserialize, Serialize};
 use serde_bytes::ByteBuf;
 
+use crate::{archive::{Issue, IssueSummary}, rules::{MailRule, RuleAction, RuleDisposition}};
+
+pub mod archive;
+pub mod http;
+pub mod icrc;
+pub mod mbox;
+pub mod rfc5322;
+pub mod rules;
+
+// base fee of `FeeSchedule::default()`
 pub const SUBMIT_CALL_PAYMENT : u64 = 1_000_000_000;
+// owners opt in to charging by size with `set_fee_schedule`
+pub const DEFAULT_PER_KB_FEE : u64 = 0;
 pub const LOOKUP_DOMAIN_CALL_PAYMENT : u64 = 1_000_000_000;
+pub const DEFAULT_MTA_MAX_RESPONSE_BYTES : u64 = 256;
+pub const DEFAULT_MTA_REQUEST_CYCLES : u128 = 20_000_000_000;
+// forwarding stops once a mail has been forwarded this many times, which breaks forwarding loops
+pub const MAX_FORWARD_HOPS : u32 = 5;
+pub const MAX_FORWARD_ADDRESSES : usize = 10;
+// mailboxes a principal can claim through `public_create_user`
+pub const MAX_MAILBOXES_PER_PRINCIPAL : usize = 10;
+pub const FORWARD_HOPS_HEADER : &str = "X-Dmail-Hops";
+// name the newsletter and issue a copy belongs to, so reads can be reported back to the sender
+pub const NEWSLETTER_HEADER : &str = "X-Dmail-Newsletter";
+pub const NEWSLETTER_ISSUE_HEADER : &str = "X-Dmail-Issue";
+pub const MAX_AUTO_REPLY_BYTES : usize = 16_000;
+pub const MAX_MAIL_RULES : usize = 100;
+pub const MAX_LABEL_LENGTH : usize = 64;
+pub const MAX_SENDER_LIST_ENTRIES : usize = 1000;
+pub const MAX_CONTACTS : usize = 5000;
+pub const MAX_NEWSLETTER_TITLE_BYTES : usize = 200;
+pub const MAX_NEWSLETTER_DESCRIPTION_BYTES : usize = 10_000;
+// the shortest billing period a paid newsletter can have
+pub const MIN_BILLING_PERIOD_SECONDS : u64 = 24 * 60 * 60;
+// a newsletter send hands at most this much to one destination per call
+pub const MAX_SEND_BATCH_MAILS : usize = 100;
+pub const MAX_SEND_BATCH_BYTES : u64 = 1_500_000;
+// a send job nobody worked on for this long is picked up again by the timer
+pub const SEND_JOB_LEASE_NANOS : u64 = 10 * 60 * 1_000_000_000;
+// buckets are pruned once there are this many, full ones are the same as no bucket
+const RATE_BUCKETS_PRUNE_AT : usize = 10_000;
+const NANOS_PER_DAY : u64 = 86_400_000_000_000;
+// how long a subscription waits for the address to confirm it
+pub const SUBSCRIPTION_CONFIRMATION_NANOS : u64 = 2 * NANOS_PER_DAY;
+// keeps an export chunk under the 2MiB reply limit
+pub const MBOX_EXPORT_CHUNK_BYTES : usize = 1_800_000;
+// an unfinished message carried between import calls
+pub const MBOX_IMPORT_PENDING_LIMIT : usize = 8_000_000;
+// delegated actions kept per mailbox, the oldest are dropped first
+pub const MAX_AUDIT_ENTRIES : usize = 1000;
 
 pub type EMAIL_ADDRESS = String;
 pub type MAIL_ID = String;
@@ -163,7 +212,7 @@ impl FromStr for SenderChannel {
     }
 }
 
-#[derive(CandidType, Deserialize, Clone, Default, Serialize)]
+#[derive(CandidType, Deserialize, Clone, Default, Serialize, Debug)]
 pub struct MailHeader {
     pub from: String,
     pub timestamp: u64,
@@ -175,7 +224,33 @@ pub struct MailHeader {
     pub sender_name: Option<String>,
     pub sender_canister_id: Option<String>,
     pub sender_channel : Option<String>,
-    pub receipient_canister_id: Option<String>
+    pub receipient_canister_id: Option<String>,
+    // headers without a field of their own, e.g. List-Id, in the order they appear
+    pub headers: Option<Vec<(String, String)>>
+}
+
+impl MailHeader {
+    pub fn get_header(&self, name : &str) -> Option<&str> {
+        self.headers.as_ref()?.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+    }
+
+    // replaces every existing header of that name
+    pub fn set_header(&mut self, name : &str, value : String) {
+        let headers = self.headers.get_or_insert(vec![]);
+        headers.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+        headers.push((name.to_string(), value));
+    }
+
+    pub fn forward_hops(&self) -> u32 {
+        self.get_header(FORWARD_HOPS_HEADER).and_then(|hops| hops.trim().parse().ok()).unwrap_or(0)
+    }
+
+    // The newsletter and issue number of a newsletter copy.
+    pub fn newsletter_issue(&self) -> Option<(NEWSLETTER_ID, u32)> {
+        let newsletter_id = self.get_header(NEWSLETTER_HEADER)?.trim().to_string();
+        let number = self.get_header(NEWSLETTER_ISSUE_HEADER)?.trim().parse().ok()?;
+        Some((newsletter_id, number))
+    }
 }
 
 impl Clone for Mail {
@@ -186,10 +261,251 @@ impl Clone for Mail {
 #[derive(CandidType, Deserialize, Serialize)]
 pub struct OutgoingMail {
     pub id: MAIL_ID,
+    // SMTP envelope receipients, only the ones on web2 domains
+    pub rcpt_to: Vec<EMAIL_ADDRESS>,
     pub header: MailHeader,
     pub body:Rcbytes
 }
 
+impl OutgoingMail {
+    // What `x-sig` signs: the id, the envelope, every header field and the body's hash, so a
+    // captured signature can not be sent along with other content.
+    pub fn signing_digest(&self) -> Result<[u8; 32], String> {
+        let body_hash = sha256_bytes(self.body.0.as_slice());
+        let payload = candid::encode_args((&self.id, &self.rcpt_to, &self.header, body_hash.to_vec())).map_err(|err| err.to_string())?;
+        Ok(sha256_bytes(&payload))
+    }
+}
+
+// SMTP envelope as seen by the relaying MTA
+#[derive(CandidType, Deserialize, Clone)]
+pub struct InboundEnvelope {
+    pub mail_from: String,
+    pub rcpt_to: Vec<EMAIL_ADDRESS>,
+    pub helo: Option<String>,
+    pub remote_ip: Option<String>
+}
+
+// Result strings follow RFC 8601, e.g. "pass", "fail", "softfail", "none"
+#[derive(CandidType, Deserialize, Clone)]
+pub struct AuthenticationResults {
+    pub spf: String,
+    pub dkim: String,
+    pub dmarc: Option<String>
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct InboundMail {
+    pub envelope: InboundEnvelope,
+    pub authentication_results: AuthenticationResults,
+    pub raw: Rcbytes,
+    pub mail: Mail
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct InboundRecord {
+    pub envelope: InboundEnvelope,
+    pub authentication_results: AuthenticationResults,
+    pub raw: Rcbytes,
+    pub relayed_by: String,
+    pub received_at: u64
+}
+
+// Roles are ordered, each one includes what the ones before it allow.
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum MailboxRole {
+    Read,
+    SendAs,
+    Owner
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum MailboxAction {
+    // list the mail of one folder
+    ListFolder(MailFolder),
+    // read one mail, wherever it is filed
+    ReadMail(MAIL_ID),
+    // read the whole mailbox at once, e.g. an export
+    ReadAll,
+    // send from the mailbox address
+    Send,
+    // move mail between folders, delete and restore, file it with rules and import single messages
+    Organize,
+    // members, grants, app passwords, forwarding, postage, mbox import and deleting the mailbox
+    Manage
+}
+
+impl MailboxRole {
+    pub fn allows(&self, action : &MailboxAction) -> bool {
+        match action {
+            MailboxAction::ListFolder(_) | MailboxAction::ReadMail(_) | MailboxAction::ReadAll => true,
+            MailboxAction::Send | MailboxAction::Organize => *self >= MailboxRole::SendAs,
+            MailboxAction::Manage => *self == MailboxRole::Owner
+        }
+    }
+}
+
+// What a grant lets its holder do. Managing the mailbox is never delegated.
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum GrantScope {
+    ReadInbox,
+    ReadAll,
+    Send,
+    Organize
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct MailboxGrant {
+    pub id: u64,
+    pub mailbox: EMAIL_ADDRESS,
+    pub grantee: Principal,
+    pub scopes: Vec<GrantScope>,
+    // nanoseconds, the grant stops working at this time
+    pub expires_at: u64,
+    pub granted_by: Principal,
+    pub created_at: u64
+}
+
+impl MailboxGrant {
+    pub fn is_active(&self, now : u64) -> bool {
+        now < self.expires_at
+    }
+}
+
+// One action taken on a mailbox through a grant.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct AuditEntry {
+    pub timestamp: u64,
+    pub principal: Principal,
+    pub grant_id: u64,
+    pub action: String
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct MailboxAccess {
+    pub mailbox: EMAIL_ADDRESS,
+    pub role: MailboxRole,
+    // used when a call does not name a mailbox
+    pub primary: bool
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct ForwardingRule {
+    pub forward_to: Vec<EMAIL_ADDRESS>,
+    // leave a copy in the inbox as well
+    pub keep_copy: bool
+}
+
+#[derive(CandidType, Deserialize, Clone, PartialEq, Debug)]
+pub enum SenderPattern {
+    Address(EMAIL_ADDRESS),
+    // the domain and its subdomains
+    Domain(String),
+    // the dmail canister that submitted the mail
+    Canister(Principal)
+}
+
+impl SenderPattern {
+    pub fn matches(&self, header : &MailHeader) -> bool {
+        match self {
+            SenderPattern::Address(address) => header.from.eq_ignore_ascii_case(address),
+            SenderPattern::Domain(domain) => {
+                let from_domain = header.from.rsplit_once('@').map_or("", |(_, domain)| domain).to_ascii_lowercase();
+                let domain = domain.trim_start_matches('@').to_ascii_lowercase();
+                from_domain == domain || from_domain.ends_with(&format!(".{}", domain))
+            },
+            SenderPattern::Canister(canister) => header.sender_canister_id.as_deref() == Some(canister.to_text().as_str())
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum BlockMode {
+    // accept the mail and throw it away, the sender is not told
+    Drop,
+    // refuse the mail with an error
+    Reject
+}
+
+// Allowed senders get through even when a blocked pattern matches them as well.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct SenderLists {
+    pub blocked: Vec<SenderPattern>,
+    pub allowed: Vec<SenderPattern>,
+    pub mode: BlockMode
+}
+
+impl SenderLists {
+    pub fn is_blocked(&self, header : &MailHeader) -> bool {
+        self.blocked.iter().any(|pattern| pattern.matches(header))
+            && !self.allowed.iter().any(|pattern| pattern.matches(header))
+    }
+}
+
+// Postage one sender paid for one mail.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct PostagePayment {
+    pub mail_id: MAIL_ID,
+    pub sender: EMAIL_ADDRESS,
+    // the principal the tokens came from, refunds go back to it
+    pub payer: Principal,
+    pub amount: Nat,
+    pub paid_at: u64,
+    pub refunded: bool
+}
+
+// Vacation reply sent to people who write to the mailbox.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct AutoReply {
+    pub enabled: bool,
+    // nanoseconds, the reply is only sent between them when set
+    pub start: Option<u64>,
+    pub end: Option<u64>,
+    pub subject: String,
+    pub body: String,
+    // a sender gets at most one reply in this many days
+    pub interval_days: u32
+}
+
+impl AutoReply {
+    pub fn is_active(&self, now : u64) -> bool {
+        self.enabled
+            && self.start.is_none_or(|start| start <= now)
+            && self.end.is_none_or(|end| now < end)
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct MboxChunk {
+    pub data: ByteBuf,
+    // pass back to `export_mailbox` for the next chunk, None once the mailbox is exhausted
+    pub cursor: Option<String>
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct MboxImportReport {
+    pub imported: u32,
+    pub failed: u32,
+    // bytes of an unfinished message waiting for the next chunk
+    pub pending_bytes: u64
+}
+
+// Credential a desktop client uses against a submission gateway, only the hash is stored
+#[derive(Clone)]
+pub struct AppPassword {
+    label: String,
+    hash: [u8; 32],
+    created_at: u64
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct AppPasswordInfo {
+    pub label: String,
+    pub created_at: u64
+}
+
+pub const ECDSA_DERIVATION_PATH : &[u8] = b"asymetric";
+
 pub enum EcdsaKeyIds {
     #[allow(unused)]
     TestKeyLocalDevelopment,
@@ -213,6 +529,110 @@ impl EcdsaKeyIds {
     }
 }
 
+// Public half of the key this canister signs outgoing HTTP mail with, so an MTA can check `x-sig`
+#[derive(CandidType, Deserialize, Clone)]
+pub struct EcdsaPublicKeyInfo {
+    pub public_key: ByteBuf,
+    pub chain_code: ByteBuf,
+    pub derivation_path: Vec<ByteBuf>,
+    pub key_name: String
+}
+
+// Calendar fields of an `ic_cdk::api::time` timestamp, in UTC
+pub struct UtcDateTime {
+    pub year: i64,
+    pub month: u32,
+    pub day: u32,
+    pub hour: u32,
+    pub minute: u32,
+    pub second: u32,
+    // 0 is Sunday
+    pub weekday: u32
+}
+
+impl UtcDateTime {
+    pub fn from_nanos(timestamp : u64) -> Self {
+        let secs = timestamp / 1_000_000_000;
+        let days = (secs / 86_400) as i64;
+        let rem = (secs % 86_400) as u32;
+
+        // civil from days, http://howardhinnant.github.io/date_algorithms.html
+        let z = days + 719_468;
+        let era = z.div_euclid(146_097);
+        let doe = z.rem_euclid(146_097);
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+        let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+        UtcDateTime {
+            year,
+            month,
+            day,
+            hour: rem / 3600,
+            minute: (rem % 3600) / 60,
+            second: rem % 60,
+            weekday: (days + 4).rem_euclid(7) as u32
+        }
+    }
+
+    pub fn to_secs(&self) -> i64 {
+        // days from civil, the inverse of `from_nanos`
+        let year = if self.month <= 2 { self.year - 1 } else { self.year };
+        let era = year.div_euclid(400);
+        let yoe = year.rem_euclid(400);
+        let mp = (self.month as i64 + 9) % 12;
+        let doy = (153 * mp + 2) / 5 + self.day as i64 - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        let days = era * 146_097 + doe - 719_468;
+        days * 86_400 + (self.hour * 3600 + self.minute * 60 + self.second) as i64
+    }
+
+    // e.g. "Tue, 14 Nov 2023 22:13:20 +0000"
+    pub fn to_rfc2822(&self) -> String {
+        const DAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+        const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+        format!("{}, {:02} {} {} {:02}:{:02}:{:02} +0000", DAYS[self.weekday as usize], self.day, MONTHS[(self.month - 1) as usize], self.year, self.hour, self.minute, self.second)
+    }
+
+    // e.g. "2023-11-14T22:13:20Z"
+    pub fn to_rfc3339(&self) -> String {
+        format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", self.year, self.month, self.day, self.hour, self.minute, self.second)
+    }
+}
+
+pub fn sha256(input: &str) -> [u8; 32] {
+    sha256_bytes(input.as_bytes())
+}
+
+pub fn sha256_bytes(input: &[u8]) -> [u8; 32] {
+    use sha2::Digest;
+    let mut hasher = sha2::Sha256::new();
+    hasher.update(input);
+    hasher.finalize().into()
+}
+
+// Verifies a hex encoded `x-sig` header against the SEC1 encoded public key returned by `get_ecdsa_public_key`.
+// The signed message is `OutgoingMail::signing_digest`.
+pub fn verify_signature(public_key : &[u8], digest : &[u8; 32], signature_hex : &str) -> Result<(), String> {
+    use k256::ecdsa::{signature::hazmat::PrehashVerifier, Signature, VerifyingKey};
+
+    let verifying_key = VerifyingKey::from_sec1_bytes(public_key).map_err(|_| "Invalid public key".to_string())?;
+    let signature_bytes = hex::decode(signature_hex).map_err(|_| "Signature is not valid hex".to_string())?;
+    let signature = Signature::from_slice(&signature_bytes).map_err(|_| "Invalid signature encoding".to_string())?;
+    verifying_key
+        .verify_prehash(digest, &signature)
+        .map_err(|_| "Signature does not match".to_string())
+}
+
+// Canister ids are opaque principals, eight bytes of id and the class byte 0x01 twice.
+pub fn is_canister_id(principal : &Principal) -> bool {
+    let bytes = principal.as_slice();
+    bytes.len() == 10 && bytes[8..] == [0x01, 0x01]
+}
+
 #[derive(CandidType, Deserialize)]
 pub enum MailRole {
     Sender,
@@ -234,6 +654,45 @@ pub struct Mail {
     pub reply_messages : Option<Vec<MailReply>>
 }
 
+impl Mail {
+    // The bytes a receiving canister charges for: the body and the header fields the sender
+    // fills in, not the ones the receiver adds.
+    pub fn size(&self) -> u64 {
+        let header = &self.header;
+        let addresses : usize = header.to.iter().chain(header.cc.iter().flatten()).chain(header.bcc.iter().flatten()).map(|address| address.len()).sum();
+        let extra : usize = header.headers.iter().flatten().map(|(name, value)| name.len() + value.len()).sum();
+        (self.body.0.len()
+            + header.from.len()
+            + addresses
+            + extra
+            + header.subject.as_ref().map_or(0, |subject| subject.len())
+            + header.content_type.as_ref().map_or(0, |content_type| content_type.len())
+            + header.sender_name.as_ref().map_or(0, |name| name.len())) as u64
+    }
+}
+
+// Cycles a canister takes for each mail or reply it accepts. Attached cycles above the fee are
+// not accepted and go back to the caller.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct FeeSchedule {
+    pub base_fee: u64,
+    // for every started kilobyte
+    pub per_kb_fee: u64
+}
+
+impl Default for FeeSchedule {
+    fn default() -> Self {
+        FeeSchedule { base_fee: SUBMIT_CALL_PAYMENT, per_kb_fee: DEFAULT_PER_KB_FEE }
+    }
+}
+
+impl FeeSchedule {
+    pub fn fee_for(&self, size : u64) -> u64 {
+        let kilobytes = size.div_ceil(1024);
+        self.base_fee.saturating_add(self.per_kb_fee.saturating_mul(kilobytes))
+    }
+}
+
 pub struct MailStatus {
     read : bool,
     mail_id: MAIL_ID
@@ -241,8 +700,236 @@ pub struct MailStatus {
 
 #[derive(CandidType, Deserialize, Clone)]
 pub struct Newsletter {
-    title : String,
-    desciption : String
+    pub title : String,
+    pub desciption : String
+}
+
+impl Newsletter {
+    pub fn new(title : String, description : String) -> Self {
+        Newsletter { title, desciption: description }
+    }
+
+    fn validate(&self) -> Result<(), MailError> {
+        if self.title.trim().is_empty() || self.title.len() > MAX_NEWSLETTER_TITLE_BYTES {
+            return Err(MailError::GeneralError(format!("A newsletter title needs 1 to {} bytes", MAX_NEWSLETTER_TITLE_BYTES)));
+        }
+        if self.desciption.len() > MAX_NEWSLETTER_DESCRIPTION_BYTES {
+            return Err(MailError::GeneralError(format!("A newsletter description can have at most {} bytes", MAX_NEWSLETTER_DESCRIPTION_BYTES)));
+        }
+        Ok(())
+    }
+}
+
+// The paid tier of a newsletter, charged from the subscriber's ICRC-2 allowance every period.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct NewsletterPlan {
+    // in the configured token, paid to the newsletter's owner
+    pub price : Nat,
+    pub period_seconds : u64
+}
+
+impl NewsletterPlan {
+    fn validate(&self) -> Result<(), MailError> {
+        if self.price == 0u64 {
+            return Err(MailError::GeneralError("A paid tier needs a price".to_string()));
+        }
+        if self.period_seconds < MIN_BILLING_PERIOD_SECONDS {
+            return Err(MailError::GeneralError(format!("A billing period is at least {} seconds", MIN_BILLING_PERIOD_SECONDS)));
+        }
+        Ok(())
+    }
+
+    fn period_nanos(&self) -> u64 {
+        self.period_seconds.saturating_mul(1_000_000_000)
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+pub enum Tier {
+    #[default]
+    Free,
+    Paid
+}
+
+#[derive(CandidType, Deserialize, Clone, PartialEq, Debug)]
+pub enum BillingStatus {
+    Active,
+    // not renewed, the subscription stays paid until the end of the period
+    Cancelled,
+    // the last charge failed, paid issues stop until the subscriber pays again
+    Suspended(String)
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct PaidSubscription {
+    // the account charged each period
+    pub payer : Principal,
+    pub paid_until : u64,
+    pub status : BillingStatus
+}
+
+impl PaidSubscription {
+    pub fn is_active(&self, now : u64) -> bool {
+        !matches!(self.status, BillingStatus::Suspended(_)) && self.paid_until > now
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Subscription {
+    // the principal that subscribed, it can unsubscribe the address again
+    pub principal : Principal,
+    pub subscribed_at : u64,
+    pub paid : Option<PaidSubscription>
+}
+
+// A charge the billing timer makes, `finish_billing` records how it went.
+#[derive(Clone)]
+pub struct Renewal {
+    pub newsletter_id : NEWSLETTER_ID,
+    pub address : EMAIL_ADDRESS,
+    pub payer : Principal,
+    pub owner : Principal,
+    pub plan : NewsletterPlan
+}
+
+// A subscription waiting for its confirmation token, kept under the token's hash.
+struct PendingSubscription {
+    newsletter_id : NEWSLETTER_ID,
+    address : EMAIL_ADDRESS,
+    principal : Principal,
+    expires_at : u64
+}
+
+#[derive(CandidType, Deserialize, Clone, PartialEq, Debug)]
+pub enum DeliveryStatus {
+    Pending,
+    Delivered,
+    Failed(String)
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct SubscriberDelivery {
+    pub address : EMAIL_ADDRESS,
+    pub status : DeliveryStatus,
+    pub attempted_at : Option<u64>,
+    // when the subscriber first opened the copy, as reported by its canister
+    pub read_at : Option<u64>
+}
+
+// How one issue did, counted over the subscribers its send job targeted.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct IssueMetrics {
+    pub number : u32,
+    pub subject : String,
+    pub tier : Tier,
+    pub published_at : u64,
+    pub targeted : u32,
+    pub delivered : u32,
+    pub bounced : u32,
+    pub pending : u32,
+    pub reads : u32,
+    // subscribers that left after this was the last issue they got
+    pub unsubscribes : u32
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct SubscriberGrowth {
+    // start of the UTC day
+    pub day : u64,
+    pub subscribed : u32,
+    pub unsubscribed : u32,
+    // subscribers at the end of the day
+    pub subscribers : u64
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct SendJobInfo {
+    pub id : u64,
+    pub newsletter_id : NEWSLETTER_ID,
+    pub created_at : u64,
+    pub finished_at : Option<u64>,
+    pub delivered : u32,
+    pub failed : u32,
+    pub pending : u32
+}
+
+// Where the subscribers on one domain are reached, looked up once per send job.
+#[derive(CandidType, Deserialize, Clone, PartialEq, Debug)]
+pub enum Destination {
+    Local,
+    Canister(Principal),
+    Web2
+}
+
+// One `send_newsletter` call. Results are written after every batch, so a trap or a stopped
+// timer only repeats the batch that was in flight.
+#[derive(CandidType, Deserialize)]
+struct SendJob {
+    newsletter_id : NEWSLETTER_ID,
+    issue_number : u32,
+    mail : Mail,
+    // grouped by domain, in the order they are sent
+    deliveries : Vec<(String, SubscriberDelivery)>,
+    destinations : HashMap<String, Destination>,
+    created_at : u64,
+    finished_at : Option<u64>,
+    // a runner holds the job until then
+    lease_until : u64,
+    unsubscribes : u32
+}
+
+impl SendJob {
+    fn info(&self, id : u64) -> SendJobInfo {
+        let count = |f : fn(&DeliveryStatus) -> bool| self.deliveries.iter().filter(|(_, delivery)| f(&delivery.status)).count() as u32;
+        SendJobInfo {
+            id,
+            newsletter_id: self.newsletter_id.clone(),
+            created_at: self.created_at,
+            finished_at: self.finished_at,
+            delivered: count(|status| *status == DeliveryStatus::Delivered),
+            failed: count(|status| matches!(status, DeliveryStatus::Failed(_))),
+            pending: count(|status| *status == DeliveryStatus::Pending)
+        }
+    }
+
+    fn metrics(&self, issue : &Issue) -> IssueMetrics {
+        let count = |f : &dyn Fn(&SubscriberDelivery) -> bool| self.deliveries.iter().filter(|(_, delivery)| f(delivery)).count() as u32;
+        IssueMetrics {
+            number: issue.number,
+            subject: issue.subject.clone(),
+            tier: issue.tier,
+            published_at: issue.published_at,
+            targeted: self.deliveries.len() as u32,
+            delivered: count(&|delivery| delivery.status == DeliveryStatus::Delivered),
+            bounced: count(&|delivery| matches!(delivery.status, DeliveryStatus::Failed(_))),
+            pending: count(&|delivery| delivery.status == DeliveryStatus::Pending),
+            reads: count(&|delivery| delivery.read_at.is_some()),
+            unsubscribes: self.unsubscribes
+        }
+    }
+}
+
+// Kept in stable memory over an upgrade: the send jobs and what their copies are built from, the
+// lists, their owners, the secret signing unsubscribe links and the domain in the configuration,
+// and the issue archive.
+#[derive(CandidType, Deserialize, Default)]
+pub struct NewsletterState {
+    config : LedgerConfiguration,
+    newsletter : HashMap<NEWSLETTER_ID, Newsletter>,
+    newsletter_owners : HashMap<NEWSLETTER_ID, Principal>,
+    newsletter_subscribers : HashMap<NEWSLETTER_ID, HashMap<EMAIL_ADDRESS, Subscription>>,
+    newsletter_secret : Option<[u8; 32]>,
+    send_jobs : HashMap<u64, SendJob>,
+    next_send_job_id : u64,
+    newsletter_issues : HashMap<NEWSLETTER_ID, Vec<Issue>>
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Subscriber {
+    pub address : EMAIL_ADDRESS,
+    pub principal : Principal,
+    pub subscribed_at : u64,
+    pub paid : Option<PaidSubscription>
 }
 
 #[derive(CandidType, Deserialize)]
@@ -251,7 +938,10 @@ pub enum RegistryError {
     FailedToUpgrade(String),
     FailedToCreateCanister,
     FailedToInstallCode(String),
-    GeneralError(String)
+    GeneralError(String),
+    InvalidPrincipal(String),
+    // cycles the call needs attached
+    InsufficientCycles { required: u64 }
 }
 
 impl Display for RegistryError {
@@ -262,6 +952,8 @@ impl Display for RegistryError {
             RegistryError::FailedToCreateCanister => f.write_str("Failed to create new canister"),
             RegistryError::FailedToInstallCode(_) => f.write_str("Failed to install code"),
             RegistryError::GeneralError(mssg) => f.write_str(format!("General Error: {}", mssg).as_str()),
+            RegistryError::InvalidPrincipal(text) => write!(f, "{} is not a valid principal", text),
+            RegistryError::InsufficientCycles { required } => write!(f, "{} cycles are required", required),
         }
     }
 }
@@ -274,6 +966,8 @@ impl Debug for RegistryError {
             Self::FailedToCreateCanister => write!(f, "FailedToCreateCanister"),
             Self::FailedToInstallCode(arg0) => f.debug_tuple("FailedToInstallCode").field(arg0).finish(),
             Self::GeneralError(arg0) => f.debug_tuple("GeneralError").field(arg0).finish(),
+            Self::InvalidPrincipal(arg0) => f.debug_tuple("InvalidPrincipal").field(arg0).finish(),
+            Self::InsufficientCycles { required } => f.debug_struct("InsufficientCycles").field("required", required).finish(),
         }
     }
 }
@@ -293,6 +987,54 @@ pub struct LedgerInfo {
     description: String
 }
 
+// A token bucket, each call takes a token and they come back at `refill_per_hour`.
+#[derive(CandidType, Deserialize, Clone, Copy)]
+pub struct RateLimit {
+    pub capacity: u32,
+    pub refill_per_hour: u32
+}
+
+// None leaves that kind of key unlimited.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct RateLimits {
+    pub per_caller: Option<RateLimit>,
+    pub per_sender: Option<RateLimit>,
+    pub per_receipient: Option<RateLimit>
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        RateLimits {
+            per_caller: Some(RateLimit { capacity: 100, refill_per_hour: 1000 }),
+            per_sender: Some(RateLimit { capacity: 50, refill_per_hour: 500 }),
+            per_receipient: Some(RateLimit { capacity: 100, refill_per_hour: 1000 })
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, PartialEq, Eq, Hash, Debug)]
+pub enum RateKey {
+    // the principal making the call
+    Caller(Principal),
+    // the address mail is sent from
+    Sender(EMAIL_ADDRESS),
+    // a local mailbox receiving mail
+    Receipient(EMAIL_ADDRESS)
+}
+
+struct RateBucket {
+    tokens: f64,
+    updated_at: u64
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct RateBucketInfo {
+    pub key: RateKey,
+    pub tokens: f64,
+    pub capacity: u32,
+    pub updated_at: u64
+}
+
 #[derive(CandidType, Deserialize)]
 #[derive(Default)]
 pub struct LedgerConfiguration {
@@ -302,29 +1044,92 @@ pub struct LedgerConfiguration {
     mta_url: String,
     domain_name: String,
     show_logs: bool,
-    version: String
+    version: String,
+    mta_max_response_bytes: Option<u64>,
+    mta_request_cycles: Option<u128>,
+    // `RateLimits::default()` when not set
+    rate_limits: Option<RateLimits>,
+    // `FeeSchedule::default()` when not set
+    fee_schedule: Option<FeeSchedule>
+}
+
+impl LedgerConfiguration {
+    pub fn get_fee_schedule(&self) -> FeeSchedule {
+        self.fee_schedule.clone().unwrap_or_default()
+    }
 }
 #[derive(Default)]
 pub struct Ledger {
     custodians: HashSet<Principal>,
+    // the primary mailbox of a principal, `mailbox_members` has every mailbox it can reach
     users: HashMap<Principal, EMAIL_ADDRESS>,
+    mailbox_members: HashMap<EMAIL_ADDRESS, HashMap<Principal, MailboxRole>>,
     profile: HashMap<EMAIL_ADDRESS, Profile>,
     inboxes: HashMap<EMAIL_ADDRESS, HashSet<MAIL_ID>>,
     sent: HashMap<EMAIL_ADDRESS, HashSet<MAIL_ID>>,
     mail_status: HashMap<MAIL_ID, MailStatus>,
+    // the mailboxes that opened each mail, read status is shared by every recipient on this canister
+    opened_by: HashMap<MAIL_ID, HashSet<EMAIL_ADDRESS>>,
     trash: HashMap<EMAIL_ADDRESS, HashSet<MAIL_ID>>,
     pub mails: HashMap<MAIL_ID, Mail>,
     //Corelation ID is an ID two Independent Systems share to Identify a resource
     pub corelation_map: HashMap<CORELATION_ID, MAIL_ID>,
-    // audit_logs: Vec<String>,
+    // delegated actions per mailbox, oldest first
+    audit_logs: HashMap<EMAIL_ADDRESS, VecDeque<AuditEntry>>,
     config: LedgerConfiguration,
-    newsletter_subscribers: HashMap<NEWSLETTER_ID, HashMap<EMAIL_ADDRESS, Principal>>,
+    newsletter_subscribers: HashMap<NEWSLETTER_ID, HashMap<EMAIL_ADDRESS, Subscription>>,
     newsletter: HashMap<NEWSLETTER_ID, Newsletter>,
-    info : LedgerInfo
+    // the principal that created the newsletter, only it manages it
+    newsletter_owners: HashMap<NEWSLETTER_ID, Principal>,
+    pending_subscriptions: HashMap<[u8; 32], PendingSubscription>,
+    // signs unsubscribe tokens, drawn from `raw_rand` before the first newsletter goes out
+    newsletter_secret: Option<[u8; 32]>,
+    send_jobs: HashMap<u64, SendJob>,
+    next_send_job_id: u64,
+    // every issue sent, oldest first, published in the archive
+    newsletter_issues: HashMap<NEWSLETTER_ID, Vec<Issue>>,
+    newsletter_plans: HashMap<NEWSLETTER_ID, NewsletterPlan>,
+    // (subscribed, unsubscribed) per UTC day
+    subscriber_growth: HashMap<NEWSLETTER_ID, BTreeMap<u64, (u32, u32)>>,
+    // subscriptions with a charge on its way, they are not billed twice
+    billing_in_progress: HashSet<(NEWSLETTER_ID, EMAIL_ADDRESS)>,
+    info : LedgerInfo,
+    ecdsa_public_key: Option<EcdsaPublicKeyInfo>,
+    mta_principals: HashSet<Principal>,
+    inbound_records: HashMap<MAIL_ID, InboundRecord>,
+    app_passwords: HashMap<EMAIL_ADDRESS, Vec<AppPassword>>,
+    mbox_imports: HashMap<EMAIL_ADDRESS, Vec<u8>>,
+    // alias -> mailbox it delivers to
+    aliases: HashMap<EMAIL_ADDRESS, EMAIL_ADDRESS>,
+    catch_all: Option<EMAIL_ADDRESS>,
+    forwarding: HashMap<EMAIL_ADDRESS, ForwardingRule>,
+    // forwarded copies and auto replies waiting to be sent, (mailbox sending it, mail)
+    pending_outbound: Vec<(EMAIL_ADDRESS, Mail)>,
+    auto_replies: HashMap<EMAIL_ADDRESS, AutoReply>,
+    // when each sender was last answered, per mailbox
+    auto_replied: HashMap<EMAIL_ADDRESS, HashMap<EMAIL_ADDRESS, u64>>,
+    mail_rules: HashMap<EMAIL_ADDRESS, Vec<MailRule>>,
+    // mail filed by rules, per mailbox and label
+    labels: HashMap<EMAIL_ADDRESS, HashMap<String, HashSet<MAIL_ID>>>,
+    starred: HashMap<EMAIL_ADDRESS, HashSet<MAIL_ID>>,
+    sender_lists: HashMap<EMAIL_ADDRESS, SenderLists>,
+    // no mail is accepted from these domains, lower case
+    blocked_domains: HashSet<String>,
+    rate_buckets: HashMap<RateKey, RateBucket>,
+    // what a sender outside the contacts pays, in the configured token
+    postage_prices: HashMap<EMAIL_ADDRESS, Nat>,
+    // lower case, senders that never pay postage
+    contacts: HashMap<EMAIL_ADDRESS, HashSet<EMAIL_ADDRESS>>,
+    // postage held by this canister for each mailbox
+    postage_balances: HashMap<EMAIL_ADDRESS, Nat>,
+    postage_payments: HashMap<EMAIL_ADDRESS, Vec<PostagePayment>>,
+    // grants by the principal holding them
+    grants: HashMap<Principal, Vec<MailboxGrant>>,
+    next_grant_id: u64
 }
 
 
-#[derive(CandidType, Deserialize)]
+#[derive(CandidType, Deserialize, Debug)]
 pub enum MailError {
     NoUserAddressFound,
     InternalSystemMailCollision,
@@ -337,7 +1142,16 @@ pub enum MailError {
     MailTransferError(String),
     NotFound,
     HttpSendMail(String),
-    GeneralError(String)
+    GeneralError(String),
+    // seconds until the call can be made again
+    RateLimited { retry_after: u64 },
+    // the receipients want this much of the configured token, approved with `icrc2_approve`
+    PostageRequired { amount: Nat },
+    // cycles the call needs attached
+    InsufficientCycles { required: u64 },
+    InvalidAddress(String),
+    // the price of a paid subscription, approved with `icrc2_approve`
+    PaymentRequired { amount: Nat }
 }
 
 impl std::fmt::Display for MailError {
@@ -354,7 +1168,12 @@ impl std::fmt::Display for MailError {
             MailError::MailTransferError(_) => f.write_str("Mail Transfer Error"),
             MailError::NotFound => f.write_str("Not Found"),
             MailError::HttpSendMail(_) => f.write_str("Error using internal HTTP outcall"),
-            MailError::GeneralError(mssg) => f.write_str(&mssg)
+            MailError::GeneralError(mssg) => f.write_str(&mssg),
+            MailError::RateLimited { retry_after } => write!(f, "Rate limited, retry in {} seconds", retry_after),
+            MailError::PostageRequired { amount } => write!(f, "Postage of {} is required", amount),
+            MailError::InsufficientCycles { required } => write!(f, "{} cycles are required", required),
+            MailError::InvalidAddress(address) => write!(f, "{} is not a valid address", address),
+            MailError::PaymentRequired { amount } => write!(f, "A payment of {} is required", amount)
         }
     }
 }
@@ -362,10 +1181,76 @@ impl std::fmt::Display for MailError {
 
 #[derive(CandidType, Deserialize)]
 pub struct InboxData {
-    header : MailHeader,
-    read: bool,
-    mail_id: MAIL_ID,
-    content: Option<ByteBuf>
+    pub header : MailHeader,
+    pub read: bool,
+    pub mail_id: MAIL_ID,
+    pub content: Option<ByteBuf>
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum MailFolder {
+    Inbox,
+    Sent,
+    Trash
+}
+
+impl MailFolder {
+    pub fn name(&self) -> &'static str {
+        match self {
+            MailFolder::Inbox => "Inbox",
+            MailFolder::Sent => "Sent",
+            MailFolder::Trash => "Trash",
+        }
+    }
+}
+
+// Where an imported mbox message goes and whether it was read. Our own exports say so in
+// X-Dmail-Folder and Status, Gmail takeouts in X-Gmail-Labels. The bookkeeping headers are dropped.
+fn mbox_placement(mail : &mut Mail, email : &EMAIL_ADDRESS) -> (MailFolder, bool) {
+    let headers = mail.header.headers.take().unwrap_or_default();
+    let value = |name : &str| headers.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, v)| v.clone());
+
+    let labels = value("X-Gmail-Labels").unwrap_or_default();
+    let has_label = |label : &str| labels.split(',').any(|l| l.trim().eq_ignore_ascii_case(label));
+    let folder = match value("X-Dmail-Folder").as_deref() {
+        Some("Inbox") => MailFolder::Inbox,
+        Some("Sent") => MailFolder::Sent,
+        Some("Trash") => MailFolder::Trash,
+        _ if has_label("Trash") => MailFolder::Trash,
+        _ if has_label("Sent") || mail.header.from.eq_ignore_ascii_case(email) => MailFolder::Sent,
+        _ => MailFolder::Inbox
+    };
+    let read = match value("Status") {
+        Some(status) => status.contains('R'),
+        None => !has_label("Unread")
+    };
+
+    let headers : Vec<(String, String)> = headers
+        .into_iter()
+        .filter(|(n, _)| !["X-Dmail-Folder", "Status", "X-Status"].iter().any(|h| n.eq_ignore_ascii_case(h)))
+        .collect();
+    mail.header.headers = if headers.is_empty() { None } else { Some(headers) };
+    (folder, read)
+}
+
+// Mail that no person wrote, answering it could start two responders replying to each other
+// (RFC 3834). Newsletters and mailing lists count as well.
+pub fn is_auto_generated(header : &MailHeader) -> bool {
+    let auto_submitted = header.get_header("Auto-Submitted").is_some_and(|value| !value.trim().eq_ignore_ascii_case("no"));
+    let bulk = header.get_header("Precedence").is_some_and(|value| {
+        ["bulk", "list", "junk"].iter().any(|p| value.trim().eq_ignore_ascii_case(p))
+    });
+    let list = header.get_header("List-Id").is_some() || header.get_header("List-Unsubscribe").is_some();
+    let suppressed = header.get_header("X-Auto-Response-Suppress").is_some();
+
+    let local_part = header.from.rsplit_once('@').map_or(header.from.as_str(), |(local, _)| local).to_ascii_lowercase();
+    let daemon = local_part.is_empty()
+        || ["mailer-daemon", "postmaster", "noreply", "no-reply", "do-not-reply", "donotreply", "listserv", "majordomo"].contains(&local_part.as_str())
+        || local_part.starts_with("owner-")
+        || local_part.ends_with("-request")
+        || local_part.ends_with("-bounces");
+
+    auto_submitted || bulk || list || suppressed || daemon
 }
 
 
@@ -408,26 +1293,51 @@ impl Ledger {
         self.mails.insert(intended_mail_id, mail);
         Ok(())
     }
-    pub fn submit_mail(&mut self, mail: Mail, intended_mail_id: String) -> Result<(), MailError> {
-        let mut selected_users = vec![];
-        for user in &mail.header.to {
-            if self.profile.contains_key(user) {
-                selected_users.push(user.clone());
-            }
-        }
-
+    pub fn submit_mail(&mut self, mail: Mail, intended_mail_id: String, now : u64) -> Result<(), MailError> {
+        let mut receipients = mail.header.to.clone();
         if mail.header.cc.is_some() {
-            for user in mail.header.cc.as_ref().unwrap() {
-                if self.profile.contains_key(user) {
-                    selected_users.push(user.clone());
-                }
-            }
+            receipients.extend(mail.header.cc.clone().unwrap());
         }
 
         if mail.header.bcc.is_some() {
-            for user in mail.header.bcc.as_ref().unwrap() {
-                if self.profile.contains_key(user) {
-                    selected_users.push(user.clone());
+            receipients.extend(mail.header.bcc.clone().unwrap());
+        }
+
+        let reply_to = if is_auto_generated(&mail.header) { None } else { Some(mail.header.from.clone()) };
+        self.deliver_mail(mail, intended_mail_id, &receipients, reply_to, now)
+    }
+
+    // Mail relayed by a registered MTA is delivered to its envelope receipients, not the header ones,
+    // so Bcc and mailing list copies still reach the right inbox.
+    pub fn submit_inbound_mail(&mut self, inbound : InboundMail, intended_mail_id : MAIL_ID, mta : Principal, now : u64) -> Result<(), MailError> {
+        let InboundMail { envelope, authentication_results, raw, mut mail } = inbound;
+        mail.header.sender_channel = Some(SenderChannel::Web2.to_string());
+        mail.header.sender_canister_id = None;
+
+        // bounces have an empty envelope sender, auto replies go to the envelope sender (RFC 3834)
+        let reply_to = if envelope.mail_from.is_empty() || is_auto_generated(&mail.header) {
+            None
+        } else {
+            Some(envelope.mail_from.clone())
+        };
+        self.deliver_mail(mail, intended_mail_id.clone(), &envelope.rcpt_to, reply_to, now)?;
+        self.inbound_records.insert(intended_mail_id, InboundRecord {
+            envelope,
+            authentication_results,
+            raw,
+            relayed_by: mta.to_text(),
+            received_at: now
+        });
+        Ok(())
+    }
+
+    // `reply_to` is where auto replies go, None when the mail must not be answered.
+    fn deliver_mail(&mut self, mail: Mail, intended_mail_id: MAIL_ID, receipients : &Vec<EMAIL_ADDRESS>, reply_to : Option<EMAIL_ADDRESS>, now : u64) -> Result<(), MailError> {
+        let mut selected_users = vec![];
+        for user in receipients {
+            if let Some(mailbox) = self.resolve_receipient(user) {
+                if !selected_users.contains(&mailbox) {
+                    selected_users.push(mailbox);
                 }
             }
         }
@@ -440,330 +1350,2944 @@ impl Ledger {
             return Err(MailError::InternalSystemMailCollision);
         }
 
+        let hops = mail.header.forward_hops();
+        let mut accepted = false;
+        let mut kept = false;
+        let mut rejection = None;
+        // the status is shared by every mailbox, it starts read only if all their rules mark it so
+        let mut read = true;
         for selected_user in &selected_users {
-            let inbox_set = self
-                .inboxes
-                .get_mut(selected_user)
-                .ok_or(MailError::NoUserAddressFound)?;
-            inbox_set.insert(intended_mail_id.clone());
-        }
+            if let Some(lists) = self.sender_lists.get(selected_user).filter(|lists| lists.is_blocked(&mail.header)) {
+                if lists.mode == BlockMode::Reject {
+                    rejection = Some("Sender is blocked".to_string());
+                }
+                continue;
+            }
 
-        if mail.correlation_id.is_some() && !self.corelation_map.contains_key(mail.correlation_id.as_ref().unwrap()) {
-            self.corelation_map.insert(mail.correlation_id.clone().unwrap(), intended_mail_id.clone());
-        }
+            let disposition = self.mail_rules
+                .get(selected_user)
+                .map(|rules| rules::evaluate(rules, &mail))
+                .unwrap_or_default();
+            if disposition.is_dropped() {
+                if disposition.reject.is_some() {
+                    rejection = disposition.reject;
+                }
+                continue;
+            }
+            accepted = true;
 
-        self.mail_status.insert(intended_mail_id.clone(), MailStatus { read: false, mail_id: intended_mail_id.clone() });
+            // forwarded copies are answered by the mailbox that got the original
+            if let (Some(reply_to), 0) = (&reply_to, hops) {
+                self.queue_auto_reply(selected_user, reply_to, now);
+            }
 
-        self.mails.insert(intended_mail_id, mail);
+            let mut inbox = disposition.inbox;
+            if hops < MAX_FORWARD_HOPS {
+                if !disposition.forward_to.is_empty() {
+                    let forward = Self::forward_of(&mail, selected_user, &disposition.forward_to, hops);
+                    self.pending_outbound.push((selected_user.clone(), forward));
+                }
+                let rule = self.forwarding.get(selected_user).filter(|rule| !rule.forward_to.is_empty());
+                if let Some(rule) = rule {
+                    let forward = Self::forward_of(&mail, selected_user, &rule.forward_to, hops);
+                    inbox &= rule.keep_copy;
+                    self.pending_outbound.push((selected_user.clone(), forward));
+                }
+            } else if !disposition.forward_to.is_empty() {
+                // too many hops to forward again, keep it rather than lose it
+                inbox = true;
+            }
+
+            if inbox {
+                let inbox_set = self
+                    .inboxes
+                    .get_mut(selected_user)
+                    .ok_or(MailError::NoUserAddressFound)?;
+                inbox_set.insert(intended_mail_id.clone());
+            }
+            if !disposition.labels.is_empty() {
+                let labels = self.labels.entry(selected_user.clone()).or_default();
+                for label in &disposition.labels {
+                    labels.entry(label.clone()).or_default().insert(intended_mail_id.clone());
+                }
+            }
+            if inbox || !disposition.labels.is_empty() {
+                kept = true;
+                read &= disposition.read;
+                if disposition.starred {
+                    self.starred.entry(selected_user.clone()).or_default().insert(intended_mail_id.clone());
+                }
+            }
+        }
+
+        // refused only when no mailbox took the mail
+        if !accepted {
+            if let Some(reason) = rejection {
+                return Err(MailError::GeneralError(format!("Mail rejected: {}", reason)));
+            }
+        }
+        if !kept {
+            return Ok(());
+        }
+
+        if mail.correlation_id.is_some() && !self.corelation_map.contains_key(mail.correlation_id.as_ref().unwrap()) {
+            self.corelation_map.insert(mail.correlation_id.clone().unwrap(), intended_mail_id.clone());
+        }
+
+        self.mail_status.insert(intended_mail_id.clone(), MailStatus { read, mail_id: intended_mail_id.clone() });
+
+        self.mails.insert(intended_mail_id, mail);
 
         Ok(())
     }
 
-    pub fn is_custodian(&self, principal : Principal) -> Result<(), String> {
-        if self.custodians.contains(&principal) {
-            Ok(())
-        } else {
-            Err("You are not a custodian of this canister".to_string())
+    // The forwarded copy is sent by the forwarding mailbox, replies still reach the original sender.
+    fn forward_of(mail : &Mail, mailbox : &EMAIL_ADDRESS, forward_to : &[EMAIL_ADDRESS], hops : u32) -> Mail {
+        let mut header = mail.header.clone();
+        if header.get_header("Reply-To").is_none() {
+            header.set_header("Reply-To", mail.header.from.clone());
         }
+        header.set_header(FORWARD_HOPS_HEADER, (hops + 1).to_string());
+        header.from = mailbox.clone();
+        header.sender_name = None;
+        header.to = forward_to.to_vec();
+        header.cc = None;
+        header.bcc = None;
+        header.sender_canister_id = None;
+        header.receipient_canister_id = None;
+
+        Mail { correlation_id: None, header, body: mail.body.clone(), reply_messages: None }
     }
 
-    pub fn get_mail(&mut self, mail_id : String) -> Result<Mail, MailError> {
-        let email = self.users.get(&caller()).ok_or(MailError::NoUserAddressFound)?;
-        let inbox = self.inboxes.get(email).ok_or(MailError::NoUserAddressFound)?;
-        if inbox.contains(&mail_id) {
-            let mail = self.mails.get(&mail_id).ok_or(MailError::MailNotFound)?;
-            let mailstatus = self.mail_status.get_mut(&mail_id).ok_or(MailError::MailNotFound)?;
-            mailstatus.read = true;
-            Ok(mail.clone())
-        } else {
-            Err(MailError::MailNotFound)
-        }
+    pub fn take_pending_outbound(&mut self) -> Vec<(EMAIL_ADDRESS, Mail)> {
+        std::mem::take(&mut self.pending_outbound)
     }
 
-    pub fn get_mails(&self, page : Option<usize>) -> Result<Vec<InboxData>, MailError> {
-        let email = self.users.get(&caller()).ok_or(MailError::NoUserAddressFound)?;
-        let inbox = self.inboxes.get(email).ok_or(MailError::NoUserAddressFound)?;
-        let mut inbox_data_vec = vec![];
-        let mut skip = 0;
-        if page.is_some() {
-            let page_num = page.unwrap();
-            skip = page_num * 50
+    fn queue_auto_reply(&mut self, mailbox : &EMAIL_ADDRESS, reply_to : &EMAIL_ADDRESS, now : u64) {
+        let auto_reply = match self.auto_replies.get(mailbox) {
+            Some(auto_reply) if auto_reply.is_active(now) => auto_reply,
+            _ => return
+        };
+        if reply_to.eq_ignore_ascii_case(mailbox) || self.resolve_receipient(reply_to).as_ref() == Some(mailbox) {
+            return;
         }
-        for mail_id in inbox.iter().skip(skip).take(50) {
-            let mail = self.mails.get(mail_id).ok_or(MailError::MailNotFound)?;
-
-            let status = self.mail_status.get(mail_id).ok_or(MailError::MailNotFound)?;
-            
-            let content = if !status.read {
-                if mail.body.0.len() > 1_000_000 {
-                    None
-                } else {
-                    Some(mail.body.0.clone().deref().clone())
-                }
-            } else {
-                if mail.body.0.len() > 102400 {
-                    None
-                } else {
-                    Some(mail.body.0.deref().clone())
-                }
-            };
 
-            inbox_data_vec.push(InboxData{
-                header: mail.header.clone(),
-                read: status.read,
-                mail_id: mail_id.clone(),
-                content
-            })
+        let interval = auto_reply.interval_days as u64 * NANOS_PER_DAY;
+        let replied = self.auto_replied.entry(mailbox.clone()).or_default();
+        let sender = reply_to.to_lowercase();
+        if replied.get(&sender).is_some_and(|last| now < last + interval) {
+            return;
         }
+        replied.retain(|_, last| now < *last + interval);
+        replied.insert(sender, now);
 
-        Ok(inbox_data_vec)
+        let mut header = MailHeader {
+            from: mailbox.clone(),
+            timestamp: now,
+            content_type: Some("text/plain; charset=utf-8".to_string()),
+            to: vec![reply_to.clone()],
+            subject: Some(auto_reply.subject.clone()),
+            ..Default::default()
+        };
+        header.set_header("Auto-Submitted", "auto-replied".to_string());
+        let body = Rcbytes(Arc::new(ByteBuf::from(auto_reply.body.clone().into_bytes())));
+        self.pending_outbound.push((mailbox.clone(), Mail { correlation_id: None, header, body, reply_messages: None }));
     }
 
-    pub fn get_users(&self) -> Result<Vec<EMAIL_ADDRESS>, MailError> {
-        if self.config.permissioned {
-            Ok(self.users.values().cloned().collect())
-        } else {
-            if self.is_custodian(caller()).is_err() {
-                Err(MailError::NotAuthorized)
-            } else {
-                Ok(self.users.values().cloned().collect())
+    pub fn set_auto_reply(&mut self, mailbox : &EMAIL_ADDRESS, auto_reply : Option<AutoReply>) -> Result<(), MailError> {
+        if !self.inboxes.contains_key(mailbox) {
+            return Err(MailError::NoUserAddressFound);
+        }
+        // a new vacation answers everyone again
+        self.auto_replied.remove(mailbox);
+        let auto_reply = match auto_reply {
+            Some(auto_reply) => auto_reply,
+            None => {
+                self.auto_replies.remove(mailbox);
+                return Ok(());
+            }
+        };
+
+        if auto_reply.interval_days == 0 {
+            return Err(MailError::GeneralError("The reply interval is at least one day".to_string()));
+        }
+        if auto_reply.body.len() + auto_reply.subject.len() > MAX_AUTO_REPLY_BYTES {
+            return Err(MailError::GeneralError(format!("An auto reply is at most {} bytes", MAX_AUTO_REPLY_BYTES)));
+        }
+        if let (Some(start), Some(end)) = (auto_reply.start, auto_reply.end) {
+            if end <= start {
+                return Err(MailError::GeneralError("The auto reply has to end after it starts".to_string()));
             }
         }
 
+        self.auto_replies.insert(mailbox.clone(), auto_reply);
+        Ok(())
     }
 
-    pub fn get_user_address(&self, principal : Principal) ->Option<String> {
-        self.users.get(&principal).cloned()
+    pub fn get_auto_reply(&self, mailbox : &EMAIL_ADDRESS) -> Option<AutoReply> {
+        self.auto_replies.get(mailbox).cloned()
     }
 
-    pub fn get_user_profile(&self, principal : Principal) -> Option<Profile> {
-        let address_opt = self.get_user_address(principal);
-        if address_opt.is_some() {
-            let email_addr = address_opt.unwrap();
-            let profile_opt = self.profile.get(&email_addr).cloned();
-            return profile_opt;
-        } else {
-            return None;
+    pub fn set_sender_lists(&mut self, mailbox : &EMAIL_ADDRESS, lists : Option<SenderLists>) -> Result<(), MailError> {
+        if !self.inboxes.contains_key(mailbox) {
+            return Err(MailError::NoUserAddressFound);
         }
-        
-    }
-    pub fn get_all_mail_count(&self) -> Result<(u32, u32), MailError> {
-        let mut unread = 0;
-        let mut read = 0;
-        for status in self.mail_status.values() {
-            if status.read {
-                read += 1
-            } else {
-                unread += 1
+        let lists = match lists {
+            Some(lists) => lists,
+            None => {
+                self.sender_lists.remove(mailbox);
+                return Ok(());
             }
+        };
+        if lists.blocked.len() + lists.allowed.len() > MAX_SENDER_LIST_ENTRIES {
+            return Err(MailError::GeneralError(format!("At most {} senders can be listed", MAX_SENDER_LIST_ENTRIES)));
         }
-
-        Ok((unread, read))
-    }
-
-    pub fn get_mail_count(&self) -> Result<(u32, u32), MailError> {
-        let email = self.users.get(&caller()).ok_or(MailError::NoUserAddressFound)?;
-        let inbox = self.inboxes.get(email).ok_or(MailError::NoUserAddressFound)?;
-        let mut unread = 0;
-        let mut read = 0;
-        for mail_id in inbox {
-            let status = self.mail_status.get(mail_id).ok_or(MailError::MailNotFound)?;
-            if status.read {
-                read += 1
-            } else {
-                unread += 1
+        for pattern in lists.blocked.iter().chain(lists.allowed.iter()) {
+            let valid = match pattern {
+                SenderPattern::Address(address) => EmailAddress::from_str(address).is_ok(),
+                SenderPattern::Domain(domain) => !domain.trim_start_matches('@').is_empty() && !domain.contains(char::is_whitespace),
+                SenderPattern::Canister(_) => true
+            };
+            if !valid {
+                return Err(MailError::GeneralError(format!("Invalid sender {:?}", pattern)));
             }
         }
 
-        Ok((unread, read))
+        self.sender_lists.insert(mailbox.clone(), lists);
+        Ok(())
     }
 
-    pub fn create_user(&mut self, email_address : EMAIL_ADDRESS, principal_address : String) -> Result<(), MailError> {
-        let user_p = Principal::from_text(principal_address).unwrap();
-        self.inboxes.insert(email_address.clone(), HashSet::new());
-        // self.trash.insert(email_address.clone(), HashSet::new())
-        self.users.insert(user_p, email_address);
+    pub fn get_sender_lists(&self, mailbox : &EMAIL_ADDRESS) -> Option<SenderLists> {
+        self.sender_lists.get(mailbox).cloned()
+    }
+
+    pub fn add_blocked_domain(&mut self, domain : String) -> Result<(), MailError> {
+        let domain = domain.trim().trim_start_matches('@').to_ascii_lowercase();
+        if domain.is_empty() || domain.contains(char::is_whitespace) {
+            return Err(MailError::GeneralError("Invalid domain".to_string()));
+        }
+        if domain == self.config.domain_name.to_ascii_lowercase() {
+            return Err(MailError::GeneralError("The platform domain can not be blocked".to_string()));
+        }
+        self.blocked_domains.insert(domain);
         Ok(())
     }
 
-    pub fn public_create_user(&mut self, email_address : EMAIL_ADDRESS) -> Result<(), MailError> {
-        if self.config.permissioned {
-            return Err(MailError::PermissionedSystem)
+    pub fn remove_blocked_domain(&mut self, domain : String) -> Result<(), MailError> {
+        let domain = domain.trim().trim_start_matches('@').to_ascii_lowercase();
+        if self.blocked_domains.remove(&domain) {
+            Ok(())
+        } else {
+            Err(MailError::NotFound)
         }
+    }
 
-        if self.inboxes.contains_key(&email_address) {
-            return Err(MailError::AddressExist);
+    pub fn get_blocked_domains(&self) -> Vec<String> {
+        let mut domains : Vec<String> = self.blocked_domains.iter().cloned().collect();
+        domains.sort();
+        domains
+    }
+
+    // Whether the address is in a domain, or a subdomain of one, on the custodians' blocklist.
+    pub fn is_domain_blocked(&self, address : &str) -> bool {
+        let domain = address.rsplit_once('@').map_or(address, |(_, domain)| domain).to_ascii_lowercase();
+        let mut parent = domain.as_str();
+        loop {
+            if self.blocked_domains.contains(parent) {
+                return true;
+            }
+            match parent.split_once('.') {
+                Some((_, rest)) => parent = rest,
+                None => return false
+            }
         }
+    }
 
-        self.inboxes.insert(email_address.clone(), HashSet::new());
-        self.users.insert(caller(), email_address);
+    pub fn set_postage_price(&mut self, mailbox : &EMAIL_ADDRESS, price : Option<Nat>) -> Result<(), MailError> {
+        if !self.inboxes.contains_key(mailbox) {
+            return Err(MailError::NoUserAddressFound);
+        }
+        match price {
+            Some(price) if price > 0u64 => self.postage_prices.insert(mailbox.clone(), price),
+            _ => self.postage_prices.remove(mailbox)
+        };
         Ok(())
     }
 
-    pub fn delete_user(&mut self, email_address : EMAIL_ADDRESS) -> Result<(), MailError> {
-        if !self.config.permissioned {
-            return Err(MailError::NotAuthorized);
+    // What `sender` pays to reach the address, zero for contacts and allowed senders.
+    pub fn get_postage(&self, receipient : &EMAIL_ADDRESS, sender : &EMAIL_ADDRESS) -> Nat {
+        let mailbox = match self.resolve_receipient(receipient) {
+            Some(mailbox) => mailbox,
+            None => return Nat::from(0u64)
+        };
+        match self.postage_prices.get(&mailbox) {
+            Some(price) if !self.is_contact(&mailbox, sender) => price.clone(),
+            _ => Nat::from(0u64)
         }
-        let mut px : Option<Principal> = None;
-        for (principal, email) in self.users.borrow() {
-            if &email_address == email {
-                px = Some(principal.clone());
-                break;
+    }
+
+    // The local mailboxes among the receipients that charge the sender postage, and the price.
+    pub fn postage_due(&self, header : &MailHeader) -> Vec<(EMAIL_ADDRESS, Nat)> {
+        let mut due : Vec<(EMAIL_ADDRESS, Nat)> = vec![];
+        let receipients = header.to.iter().chain(header.cc.iter().flatten()).chain(header.bcc.iter().flatten());
+        for receipient in receipients {
+            let mailbox = match self.resolve_receipient(receipient) {
+                Some(mailbox) => mailbox,
+                None => continue
+            };
+            if due.iter().any(|(m, _)| *m == mailbox) {
+                continue;
+            }
+            if let Some(price) = self.postage_prices.get(&mailbox) {
+                if !self.is_contact(&mailbox, &header.from) {
+                    due.push((mailbox, price.clone()));
+                }
             }
         }
+        due
+    }
 
-        if px.is_some() {
-            self.users.remove(&px.unwrap());
+    pub fn is_contact(&self, mailbox : &EMAIL_ADDRESS, sender : &EMAIL_ADDRESS) -> bool {
+        if sender.eq_ignore_ascii_case(mailbox) {
+            return true;
         }
+        let listed = self.contacts.get(mailbox).is_some_and(|contacts| contacts.contains(&sender.to_lowercase()));
+        let header = MailHeader { from: sender.clone(), ..Default::default() };
+        let allowed = self.sender_lists.get(mailbox).is_some_and(|lists| lists.allowed.iter().any(|pattern| pattern.matches(&header)));
+        listed || allowed
+    }
 
+    // Addresses the mailbox writes to become contacts, beyond `MAX_CONTACTS` they are not added.
+    pub fn add_contacts(&mut self, mailbox : &EMAIL_ADDRESS, addresses : &[EMAIL_ADDRESS]) -> Result<(), MailError> {
+        if !self.inboxes.contains_key(mailbox) {
+            return Err(MailError::NoUserAddressFound);
+        }
+        let contacts = self.contacts.entry(mailbox.clone()).or_default();
+        for address in addresses {
+            if contacts.len() >= MAX_CONTACTS {
+                return Err(MailError::GeneralError(format!("At most {} contacts are allowed", MAX_CONTACTS)));
+            }
+            contacts.insert(address.to_lowercase());
+        }
         Ok(())
     }
 
-    pub fn delete_self(&mut self) -> Result<(), MailError> {
-        let email = self.users.get(&caller()).ok_or(MailError::NoUserAddressFound)?;
-        self.profile.remove(email);
-        self.inboxes.remove(email);
-        self.trash.remove(email);
-        self.users.remove(&caller());
+    pub fn remove_contact(&mut self, mailbox : &EMAIL_ADDRESS, address : &EMAIL_ADDRESS) -> Result<(), MailError> {
+        let removed = self.contacts.get_mut(mailbox).is_some_and(|contacts| contacts.remove(&address.to_lowercase()));
+        if removed { Ok(()) } else { Err(MailError::NotFound) }
+    }
 
-        Ok(())
+    pub fn get_contacts(&self, mailbox : &EMAIL_ADDRESS) -> Vec<EMAIL_ADDRESS> {
+        let mut contacts : Vec<EMAIL_ADDRESS> = self.contacts.get(mailbox).map(|contacts| contacts.iter().cloned().collect()).unwrap_or_default();
+        contacts.sort();
+        contacts
     }
 
+    // Credits the mailboxes with postage collected for a delivered mail.
+    pub fn record_postage(&mut self, mail_id : &MAIL_ID, payer : Principal, sender : &EMAIL_ADDRESS, due : Vec<(EMAIL_ADDRESS, Nat)>) {
+        let now = time();
+        for (mailbox, amount) in due {
+            let balance = self.postage_balances.entry(mailbox.clone()).or_insert(Nat::from(0u64));
+            *balance += amount.clone();
+            self.postage_payments.entry(mailbox).or_insert(vec![]).push(PostagePayment {
+                mail_id: mail_id.clone(),
+                sender: sender.clone(),
+                payer,
+                amount,
+                paid_at: now,
+                refunded: false
+            });
+        }
+    }
 
+    // Marks the payment refunded and takes it off the balance, the sender becomes a contact.
+    pub fn begin_postage_refund(&mut self, mailbox : &EMAIL_ADDRESS, mail_id : &MAIL_ID) -> Result<PostagePayment, MailError> {
+        let payment = self.postage_payments
+            .get_mut(mailbox)
+            .and_then(|payments| payments.iter_mut().find(|payment| payment.mail_id == *mail_id && !payment.refunded))
+            .ok_or(MailError::NotFound)?;
+        payment.refunded = true;
+        let payment = payment.clone();
+        self.debit_postage(mailbox, &payment.amount)?;
+        let _ = self.add_contacts(mailbox, std::slice::from_ref(&payment.sender));
+        Ok(payment)
+    }
 
-    pub fn delete_mail(&mut self, mail_id : String) -> Result<(), MailError> {
-        let email = self.users.get(&caller()).ok_or(MailError::NoUserAddressFound)?;
-        let inbox = self.inboxes.get_mut(email).ok_or(MailError::NoUserAddressFound)?;
-        if inbox.contains(&mail_id) {
-            inbox.remove(&mail_id);
-            let mut trash_set = HashSet::new();
-            trash_set.insert(mail_id);
-            self.trash.insert(email.clone(),  trash_set);
+    // Undoes `begin_postage_refund` when the transfer failed.
+    pub fn cancel_postage_refund(&mut self, mailbox : &EMAIL_ADDRESS, mail_id : &MAIL_ID) {
+        let payment = self.postage_payments
+            .get_mut(mailbox)
+            .and_then(|payments| payments.iter_mut().find(|payment| payment.mail_id == *mail_id && payment.refunded));
+        if let Some(payment) = payment {
+            payment.refunded = false;
+            let amount = payment.amount.clone();
+            self.credit_postage(mailbox, amount);
         }
-
-        Ok(())
     }
 
-    pub fn restore_mail(&mut self, mail_id : MAIL_ID) -> Result<(), MailError> {
-        let email = self.users.get(&caller()).ok_or(MailError::NoUserAddressFound)?;
-        let trash_set = self.trash.get_mut(email).ok_or(MailError::MailNotFound)?;
-        if trash_set.contains(&mail_id) {
-            trash_set.remove(&mail_id);
-            let inbox_set = self.inboxes.get_mut(email).ok_or(MailError::MailNotFound)?;
-            inbox_set.insert(mail_id);
-            Ok(())
-        } else {
-            Err(MailError::MailNotFound)
+    pub fn debit_postage(&mut self, mailbox : &EMAIL_ADDRESS, amount : &Nat) -> Result<(), MailError> {
+        let balance = self.postage_balances.entry(mailbox.clone()).or_insert(Nat::from(0u64));
+        if *balance < *amount {
+            return Err(MailError::GeneralError(format!("The postage balance is only {}", balance)));
         }
+        *balance -= amount.clone();
+        Ok(())
     }
 
-    pub fn get_domain_name(&self) -> String {
-        self.config.domain_name.clone()
+    pub fn credit_postage(&mut self, mailbox : &EMAIL_ADDRESS, amount : Nat) {
+        *self.postage_balances.entry(mailbox.clone()).or_insert(Nat::from(0u64)) += amount;
     }
 
-    pub fn get_token_address(&self) -> String {
-        self.config.token_address.clone()
+    pub fn get_postage_balance(&self, mailbox : &EMAIL_ADDRESS) -> Nat {
+        self.postage_balances.get(mailbox).cloned().unwrap_or(Nat::from(0u64))
     }
 
-    pub fn get_registry_address(&self) -> String {
-        self.config.registry_canister.clone()
+    // Newest first.
+    pub fn get_postage_payments(&self, mailbox : &EMAIL_ADDRESS) -> Vec<PostagePayment> {
+        self.postage_payments.get(mailbox).map(|payments| payments.iter().rev().cloned().collect()).unwrap_or_default()
     }
 
-    // gets domains of receipients
-    pub fn get_receipients_domains(mail: &Mail) -> Vec<String> {
-        let mut selected_domains = vec![];
-        for to in &mail.header.to {
-            if !EmailAddress::is_valid(to) {
-                ic_cdk::trap(format!("{} is not valid", to).as_str())
-            }
+    pub fn get_token_ledger(&self) -> Result<Principal, MailError> {
+        Principal::from_text(&self.config.token_address).map_err(|_| MailError::GeneralError("No token ledger is configured".to_string()))
+    }
 
-            let email = EmailAddress::from_str(&to).unwrap();
-            let domain_name = email.domain().to_string();
-            selected_domains.push(domain_name)
+    // Replaces the mailbox's rules, they run in the given order.
+    pub fn set_mail_rules(&mut self, mailbox : &EMAIL_ADDRESS, rules : Vec<MailRule>) -> Result<(), MailError> {
+        if !self.inboxes.contains_key(mailbox) {
+            return Err(MailError::NoUserAddressFound);
         }
-
-        if mail.header.bcc.is_some() {
-            for to in mail.header.bcc.as_ref().unwrap() {
-                if !EmailAddress::is_valid(to) {
-                    ic_cdk::trap(format!("{} is not valid", to).as_str())
-                }
-    
-                let email = EmailAddress::from_str(&to).unwrap();
-                let domain_name = email.domain().to_string();
-                selected_domains.push(domain_name)
-            }
+        if rules.len() > MAX_MAIL_RULES {
+            return Err(MailError::GeneralError(format!("At most {} rules are allowed", MAX_MAIL_RULES)));
         }
-
-        if mail.header.cc.is_some() {
-            for to in mail.header.cc.as_ref().unwrap() {
-                if !EmailAddress::is_valid(to) {
-                    ic_cdk::trap(format!("{} is not valid", to).as_str())
+        for rule in &rules {
+            if rule.actions.is_empty() {
+                return Err(MailError::GeneralError(format!("Rule {} has no actions", rule.name)));
+            }
+            let forwards = rule.actions.iter().filter(|action| matches!(action, RuleAction::Forward(_))).count();
+            if forwards > MAX_FORWARD_ADDRESSES {
+                return Err(MailError::GeneralError(format!("At most {} forwarding addresses are allowed", MAX_FORWARD_ADDRESSES)));
+            }
+            for action in &rule.actions {
+                match action {
+                    RuleAction::FileInto(label) if label.trim().is_empty() || label.len() > MAX_LABEL_LENGTH => {
+                        return Err(MailError::GeneralError(format!("Labels are 1 to {} bytes long", MAX_LABEL_LENGTH)));
+                    },
+                    RuleAction::Forward(address) => {
+                        if EmailAddress::from_str(address).is_err() {
+                            return Err(MailError::GeneralError(format!("Invalid forwarding address {}", address)));
+                        }
+                        if self.resolve_receipient(address).as_ref() == Some(mailbox) {
+                            return Err(MailError::GeneralError("A mailbox can not forward to itself".to_string()));
+                        }
+                    },
+                    _ => {}
                 }
-    
-                let email = EmailAddress::from_str(&to).unwrap();
-                let domain_name = email.domain().to_string();
-                selected_domains.push(domain_name)
             }
         }
 
-        selected_domains
-        
+        if rules.is_empty() {
+            self.mail_rules.remove(mailbox);
+        } else {
+            self.mail_rules.insert(mailbox.clone(), rules);
+        }
+        Ok(())
     }
 
-    pub fn get_newsletter_subscribers(&self, newsletter_id : NEWSLETTER_ID) -> Result<Vec<EMAIL_ADDRESS>, MailError>{
-        let set = self.newsletter_subscribers.get(&newsletter_id).ok_or(MailError::NotFound)?;
-        Ok(set.keys().cloned().collect())
+    pub fn get_mail_rules(&self, mailbox : &EMAIL_ADDRESS) -> Vec<MailRule> {
+        self.mail_rules.get(mailbox).cloned().unwrap_or_default()
     }
 
-    pub fn subscribe_to_newsletter(&mut self, newsletter_id : NEWSLETTER_ID, email_address : EMAIL_ADDRESS, p : Principal) -> Result<(), MailError> {
-        let subscribe_set = self.newsletter_subscribers.get_mut(&newsletter_id).unwrap();
-        if subscribe_set.contains_key(&email_address) {
-            return Err(MailError::AddressExist);
+    // What the mailbox's rules would do with `mail`, nothing is delivered.
+    pub fn dry_run_mail_rules(&self, mailbox : &EMAIL_ADDRESS, mail : &Mail) -> Result<RuleDisposition, MailError> {
+        if !self.inboxes.contains_key(mailbox) {
+            return Err(MailError::NoUserAddressFound);
         }
-        subscribe_set.insert(email_address, p);
+        Ok(self.mail_rules.get(mailbox).map(|rules| rules::evaluate(rules, mail)).unwrap_or_default())
+    }
+
+    pub fn get_labels(&self, mailbox : &EMAIL_ADDRESS) -> Vec<String> {
+        let mut labels : Vec<String> = self.labels
+            .get(mailbox)
+            .map(|labels| labels.keys().cloned().collect())
+            .unwrap_or_default();
+        labels.sort();
+        labels
+    }
+
+    pub fn get_label_mails(&self, mailbox : &EMAIL_ADDRESS, label : &str, page : Option<usize>) -> Result<Vec<InboxData>, MailError> {
+        let mail_ids = self.labels
+            .get(mailbox)
+            .and_then(|labels| labels.get(label))
+            .ok_or(MailError::NotFound)?;
+        self.list_mails(mail_ids, page)
+    }
+
+    pub fn get_starred_mails(&self, mailbox : &EMAIL_ADDRESS, page : Option<usize>) -> Result<Vec<InboxData>, MailError> {
+        let empty = HashSet::new();
+        self.list_mails(self.starred.get(mailbox).unwrap_or(&empty), page)
+    }
 
+    pub fn set_starred(&mut self, mailbox : &EMAIL_ADDRESS, mail_id : MAIL_ID, starred : bool) -> Result<(), MailError> {
+        if !self.owns_mail(mailbox, &mail_id) {
+            return Err(MailError::MailNotFound);
+        }
+        let set = self.starred.entry(mailbox.clone()).or_default();
+        if starred {
+            set.insert(mail_id);
+        } else {
+            set.remove(&mail_id);
+        }
         Ok(())
     }
 
-    pub fn get_newsletters(&self) -> Vec<(NEWSLETTER_ID, Newsletter)> {
-        self.newsletter.clone().into_iter().collect()
+    // The mailbox an address delivers to, either its own, the one it is an alias of, or the
+    // catch-all for unknown addresses in our domain.
+    pub fn resolve_receipient(&self, address : &EMAIL_ADDRESS) -> Option<EMAIL_ADDRESS> {
+        if self.inboxes.contains_key(address) {
+            return Some(address.clone());
+        }
+        if let Some(mailbox) = self.aliases.get(address) {
+            return Some(mailbox.clone());
+        }
+
+        let domain = address.rsplit_once('@').map(|(_, domain)| domain)?;
+        if domain.eq_ignore_ascii_case(&self.config.domain_name) {
+            return self.catch_all.clone();
+        }
+        None
     }
 
-    pub fn unsubscribe_to_newsletter(&mut self, newsletter_id : NEWSLETTER_ID, email_address : EMAIL_ADDRESS, p : Principal) -> Result<(), MailError> {
-        let subscribe_set = self.newsletter_subscribers.get_mut(&newsletter_id).unwrap();
-        if subscribe_set.contains_key(&email_address) {
+    pub fn add_alias(&mut self, alias : EMAIL_ADDRESS, mailbox : EMAIL_ADDRESS) -> Result<(), MailError> {
+        let address = EmailAddress::from_str(&alias).map_err(|_| MailError::GeneralError("Invalid alias".to_string()))?;
+        if !address.domain().eq_ignore_ascii_case(&self.config.domain_name) {
+            return Err(MailError::DomainNotFound);
+        }
+        if self.inboxes.contains_key(&alias) || self.aliases.contains_key(&alias) {
             return Err(MailError::AddressExist);
         }
-        let b = subscribe_set.get(&email_address).unwrap().clone();
-
-        if p == b {
-            subscribe_set.remove(&email_address);
-        } else {
-            return Err(MailError::NotAuthorized);
+        if !self.inboxes.contains_key(&mailbox) {
+            return Err(MailError::NoUserAddressFound);
         }
 
+        self.aliases.insert(alias, mailbox);
         Ok(())
     }
 
-    pub fn get_newsletter(&self, mail_id : MAIL_ID) -> Result<Newsletter, MailError> {
-        let n = self.newsletter.get(&mail_id).cloned().ok_or(MailError::NotFound)?;
-        Ok(n)
+    pub fn remove_alias(&mut self, alias : EMAIL_ADDRESS) -> Result<(), MailError> {
+        self.aliases.remove(&alias).map(|_| ()).ok_or(MailError::NotFound)
+    }
+
+    pub fn get_aliases(&self) -> Vec<(EMAIL_ADDRESS, EMAIL_ADDRESS)> {
+        self.aliases.iter().map(|(alias, mailbox)| (alias.clone(), mailbox.clone())).collect()
     }
 
+    pub fn get_aliases_of(&self, mailbox : &EMAIL_ADDRESS) -> Vec<EMAIL_ADDRESS> {
+        self.aliases.iter().filter(|(_, m)| *m == mailbox).map(|(alias, _)| alias.clone()).collect()
+    }
 
-    pub fn create_newletter(&mut self, newsletter_id : NEWSLETTER_ID, letter : Newsletter) -> Result<(), MailError> {
-        self.newsletter.insert(newsletter_id.clone(), letter);
-        // self._newsletter_subscribers_addr.insert(newsletter_id.clone(), HashSet::new());
-        self.newsletter_subscribers.insert(newsletter_id, HashMap::new());
+    pub fn set_catch_all(&mut self, mailbox : Option<EMAIL_ADDRESS>) -> Result<(), MailError> {
+        if let Some(mailbox) = &mailbox {
+            if !self.inboxes.contains_key(mailbox) {
+                return Err(MailError::NoUserAddressFound);
+            }
+        }
+        self.catch_all = mailbox;
         Ok(())
     }
 
-    pub fn get_mail_transfer_agent_url(&self) -> String {
-        return self.config.mta_url.clone();
+    pub fn get_catch_all(&self) -> Option<EMAIL_ADDRESS> {
+        self.catch_all.clone()
     }
 
-    pub fn add_to_sent(&mut self, mail_id : MAIL_ID,user_addr : EMAIL_ADDRESS) {
-        let set = self.sent.entry(user_addr).or_insert(HashSet::new());
-        set.insert(mail_id);
+    pub fn set_forwarding(&mut self, mailbox : &EMAIL_ADDRESS, rule : Option<ForwardingRule>) -> Result<(), MailError> {
+        if !self.inboxes.contains_key(mailbox) {
+            return Err(MailError::NoUserAddressFound);
+        }
+        let rule = match rule {
+            Some(rule) => rule,
+            None => {
+                self.forwarding.remove(mailbox);
+                return Ok(());
+            }
+        };
+
+        if rule.forward_to.len() > MAX_FORWARD_ADDRESSES {
+            return Err(MailError::GeneralError(format!("At most {} forwarding addresses are allowed", MAX_FORWARD_ADDRESSES)));
+        }
+        for address in &rule.forward_to {
+            if EmailAddress::from_str(address).is_err() {
+                return Err(MailError::GeneralError(format!("Invalid forwarding address {}", address)));
+            }
+            if self.resolve_receipient(address).as_ref() == Some(mailbox) {
+                return Err(MailError::GeneralError("A mailbox can not forward to itself".to_string()));
+            }
+        }
+
+        self.forwarding.insert(mailbox.clone(), rule);
+        Ok(())
+    }
+
+    pub fn get_forwarding(&self, mailbox : &EMAIL_ADDRESS) -> Option<ForwardingRule> {
+        self.forwarding.get(mailbox).cloned()
     }
-}
\ No newline at end of file
+
+    // drops every route into a mailbox that is going away
+    fn remove_routes(&mut self, mailbox : &EMAIL_ADDRESS) {
+        self.aliases.retain(|_, m| m != mailbox);
+        self.forwarding.remove(mailbox);
+        if self.catch_all.as_ref() == Some(mailbox) {
+            self.catch_all = None;
+        }
+    }
+
+    pub fn get_inbound_record(&self, email : &EMAIL_ADDRESS, mail_id : MAIL_ID) -> Result<InboundRecord, MailError> {
+        let inbox = self.inboxes.get(email).ok_or(MailError::NoUserAddressFound)?;
+        if !inbox.contains(&mail_id) {
+            return Err(MailError::MailNotFound);
+        }
+
+        self.inbound_records.get(&mail_id).cloned().ok_or(MailError::NotFound)
+    }
+
+    pub fn is_mail_transfer_agent(&self, principal : Principal) -> Result<(), String> {
+        if self.mta_principals.contains(&principal) {
+            Ok(())
+        } else {
+            Err("You are not a registered mail transfer agent".to_string())
+        }
+    }
+
+    pub fn add_mail_transfer_agent(&mut self, principal : Principal) {
+        self.mta_principals.insert(principal);
+    }
+
+    pub fn remove_mail_transfer_agent(&mut self, principal : Principal) -> Result<(), MailError> {
+        if self.mta_principals.remove(&principal) {
+            Ok(())
+        } else {
+            Err(MailError::NotFound)
+        }
+    }
+
+    pub fn get_mail_transfer_agents(&self) -> Vec<Principal> {
+        self.mta_principals.iter().cloned().collect()
+    }
+
+    pub fn is_custodian(&self, principal : Principal) -> Result<(), String> {
+        if self.custodians.contains(&principal) {
+            Ok(())
+        } else {
+            Err("You are not a custodian of this canister".to_string())
+        }
+    }
+
+    pub fn is_read(&self, mail_id : &MAIL_ID) -> bool {
+        self.mail_status.get(mail_id).is_none_or(|status| status.read)
+    }
+
+    pub fn get_mail_for(&mut self, email : &EMAIL_ADDRESS, mail_id : MAIL_ID) -> Result<Mail, MailError> {
+        if !self.owns_mail(email, &mail_id) {
+            return Err(MailError::MailNotFound);
+        }
+
+        let mail = self.mails.get(&mail_id).ok_or(MailError::MailNotFound)?;
+        if let Some(mailstatus) = self.mail_status.get_mut(&mail_id) {
+            mailstatus.read = true;
+        }
+        Ok(mail.clone())
+    }
+
+    // Like `get_mail_for`, also tells whether this is the first time the mailbox opened the mail.
+    pub fn open_mail(&mut self, email : &EMAIL_ADDRESS, mail_id : MAIL_ID) -> Result<(Mail, bool), MailError> {
+        let mail = self.get_mail_for(email, mail_id.clone())?;
+        let first = self.opened_by.entry(mail_id).or_default().insert(email.clone());
+        Ok((mail, first))
+    }
+
+    // RFC 5322 form of a mail in any of the user's folders, reading it does not mark it read
+    pub fn get_mail_raw(&self, email : &EMAIL_ADDRESS, mail_id : MAIL_ID) -> Result<Vec<u8>, MailError> {
+        if !self.owns_mail(email, &mail_id) {
+            return Err(MailError::MailNotFound);
+        }
+
+        let mail = self.mails.get(&mail_id).ok_or(MailError::MailNotFound)?;
+        let message_id = format!("{}@{}", mail_id, self.config.domain_name);
+        Ok(rfc5322::to_rfc5322(mail, &message_id))
+    }
+
+    // Files a mail from another provider without delivering it. Mail the user wrote lands in
+    // sent, everything else in the inbox as read.
+    pub fn import_mail(&mut self, email : &EMAIL_ADDRESS, mail : Mail, intended_mail_id : MAIL_ID) -> Result<(), MailError> {
+        let folder = if mail.header.from.eq_ignore_ascii_case(email) { MailFolder::Sent } else { MailFolder::Inbox };
+        self.file_mail(email, mail, intended_mail_id, folder, true)
+    }
+
+    fn file_mail(&mut self, email : &EMAIL_ADDRESS, mut mail : Mail, intended_mail_id : MAIL_ID, folder : MailFolder, read : bool) -> Result<(), MailError> {
+        if !self.inboxes.contains_key(email) {
+            return Err(MailError::NoUserAddressFound);
+        }
+        if self.mails.contains_key(&intended_mail_id) {
+            return Err(MailError::InternalSystemMailCollision);
+        }
+
+        if mail.header.timestamp == 0 {
+            mail.header.timestamp = time();
+        }
+        match folder {
+            MailFolder::Sent => self.add_to_sent(intended_mail_id.clone(), email.clone()),
+            MailFolder::Inbox => {
+                self.inboxes.get_mut(email).ok_or(MailError::NoUserAddressFound)?.insert(intended_mail_id.clone());
+            },
+            MailFolder::Trash => {
+                self.trash.entry(email.clone()).or_default().insert(intended_mail_id.clone());
+            }
+        }
+        if folder != MailFolder::Sent {
+            self.mail_status.insert(intended_mail_id.clone(), MailStatus { read, mail_id: intended_mail_id.clone() });
+        }
+
+        self.mails.insert(intended_mail_id, mail);
+        Ok(())
+    }
+
+    // One chunk of the user's mailbox as mbox, folders in the order inbox, sent, trash and mail
+    // ids in ascending order within a folder. The cursor is "<folder>:<last mail id>".
+    pub fn export_mailbox(&self, email : &EMAIL_ADDRESS, cursor : Option<String>) -> Result<MboxChunk, MailError> {
+        const FOLDERS : [MailFolder; 3] = [MailFolder::Inbox, MailFolder::Sent, MailFolder::Trash];
+        if !self.inboxes.contains_key(email) {
+            return Err(MailError::NoUserAddressFound);
+        }
+
+        let (mut folder_index, mut after) = match &cursor {
+            Some(cursor) => {
+                let (folder, mail_id) = cursor.split_once(':').ok_or(MailError::GeneralError("Invalid export cursor".to_string()))?;
+                let folder = folder.parse::<usize>().ok().filter(|f| *f < FOLDERS.len()).ok_or(MailError::GeneralError("Invalid export cursor".to_string()))?;
+                (folder, Some(mail_id.to_string()))
+            },
+            None => (0, None)
+        };
+
+        let mut data = vec![];
+        while folder_index < FOLDERS.len() {
+            let folder = FOLDERS[folder_index];
+            let mut mail_ids : Vec<&MAIL_ID> = self.folder_set(email, folder)
+                .map(|set| set.iter().filter(|id| after.as_ref().is_none_or(|after| *id > after)).collect())
+                .unwrap_or_default();
+            mail_ids.sort();
+
+            for mail_id in mail_ids {
+                let mail = match self.mails.get(mail_id) {
+                    Some(mail) => mail,
+                    None => continue
+                };
+                let read = self.mail_status.get(mail_id).is_none_or(|status| status.read);
+                let mut header = mail.header.clone();
+                let headers = header.headers.get_or_insert(vec![]);
+                headers.push(("X-Dmail-Folder".to_string(), folder.name().to_string()));
+                headers.push(("Status".to_string(), if read { "RO" } else { "O" }.to_string()));
+
+                let message_id = format!("{}@{}", mail_id, self.config.domain_name);
+                let message = rfc5322::encode(&header, mail.body.0.as_slice(), &message_id, true);
+                let mut entry = vec![];
+                mbox::push_message(&mut entry, &header.from, header.timestamp, &message);
+
+                if !data.is_empty() && data.len() + entry.len() > MBOX_EXPORT_CHUNK_BYTES {
+                    return Ok(MboxChunk { data: ByteBuf::from(data), cursor: Some(format!("{}:{}", folder_index, after.unwrap_or_default())) });
+                }
+                data.extend(entry);
+                after = Some(mail_id.clone());
+            }
+
+            folder_index += 1;
+            after = None;
+        }
+
+        Ok(MboxChunk { data: ByteBuf::from(data), cursor: None })
+    }
+
+    // Imports the complete messages in `chunk`, an unfinished trailing message waits for the next
+    // chunk until `last` is set. `seed` is fresh randomness the mail ids are derived from.
+    // A message that outgrows `MBOX_IMPORT_PENDING_LIMIT` is dropped and counted as failed, the
+    // messages around it are still imported.
+    pub fn import_mbox_chunk(&mut self, email : &EMAIL_ADDRESS, chunk : &[u8], last : bool, seed : &str) -> Result<MboxImportReport, MailError> {
+        if !self.inboxes.contains_key(email) {
+            return Err(MailError::NoUserAddressFound);
+        }
+
+        let mut pending = self.mbox_imports.remove(email).unwrap_or_default();
+        pending.extend_from_slice(chunk);
+        let (messages, consumed) = mbox::split_messages(&pending, last);
+        let mut rest = pending.split_off(consumed);
+        let mut failed = 0;
+        if rest.len() > MBOX_IMPORT_PENDING_LIMIT {
+            // what follows of a dropped message has no separator, split_messages skips it once
+            // the next message starts, and it is only counted the first time
+            if rest.starts_with(b"From ") {
+                failed += 1;
+            }
+            rest.clear();
+        }
+
+        let mut report = MboxImportReport { imported: 0, failed, pending_bytes: rest.len() as u64 };
+        for (index, entry) in messages.into_iter().enumerate() {
+            let mut mail = match rfc5322::from_rfc5322(&entry.message) {
+                Ok(mail) => mail,
+                Err(_) => {
+                    report.failed += 1;
+                    continue;
+                }
+            };
+            if mail.header.timestamp == 0 {
+                mail.header.timestamp = entry.timestamp.unwrap_or(0);
+            }
+
+            let (folder, read) = mbox_placement(&mut mail, email);
+            let mail_id = hex::encode(sha256(&format!("{}{}", seed, index)));
+            match self.file_mail(email, mail, mail_id, folder, read) {
+                Ok(()) => report.imported += 1,
+                Err(_) => report.failed += 1
+            }
+        }
+
+        if !rest.is_empty() {
+            self.mbox_imports.insert(email.clone(), rest);
+        }
+        Ok(report)
+    }
+
+    fn owns_mail(&self, email : &EMAIL_ADDRESS, mail_id : &MAIL_ID) -> bool {
+        [MailFolder::Inbox, MailFolder::Sent, MailFolder::Trash]
+            .iter()
+            .any(|folder| self.folder_set(email, *folder).is_some_and(|set| set.contains(mail_id)))
+            || self.labels.get(email).is_some_and(|labels| labels.values().any(|set| set.contains(mail_id)))
+    }
+
+    fn folder_set(&self, email : &EMAIL_ADDRESS, folder : MailFolder) -> Option<&HashSet<MAIL_ID>> {
+        match folder {
+            MailFolder::Inbox => self.inboxes.get(email),
+            MailFolder::Sent => self.sent.get(email),
+            MailFolder::Trash => self.trash.get(email),
+        }
+    }
+
+    pub fn get_folder_mails(&self, email : &EMAIL_ADDRESS, folder : MailFolder, page : Option<usize>) -> Result<Vec<InboxData>, MailError> {
+        if !self.inboxes.contains_key(email) {
+            return Err(MailError::NoUserAddressFound);
+        }
+        let empty = HashSet::new();
+        self.list_mails(self.folder_set(email, folder).unwrap_or(&empty), page)
+    }
+
+    fn list_mails(&self, mail_ids : &HashSet<MAIL_ID>, page : Option<usize>) -> Result<Vec<InboxData>, MailError> {
+        let mut inbox_data_vec = vec![];
+        let mut skip = 0;
+        if page.is_some() {
+            let page_num = page.unwrap();
+            skip = page_num * 50
+        }
+        for mail_id in mail_ids.iter().skip(skip).take(50) {
+            let mail = self.mails.get(mail_id).ok_or(MailError::MailNotFound)?;
+
+            // sent mail has no status, it is read by its author
+            let read = self.mail_status.get(mail_id).is_none_or(|status| status.read);
+            
+            let content = if !read {
+                if mail.body.0.len() > 1_000_000 {
+                    None
+                } else {
+                    Some(mail.body.0.clone().deref().clone())
+                }
+            } else {
+                if mail.body.0.len() > 102400 {
+                    None
+                } else {
+                    Some(mail.body.0.deref().clone())
+                }
+            };
+
+            inbox_data_vec.push(InboxData{
+                header: mail.header.clone(),
+                read,
+                mail_id: mail_id.clone(),
+                content
+            })
+        }
+
+        Ok(inbox_data_vec)
+    }
+
+    pub fn is_permissioned(&self) -> bool {
+        self.config.permissioned
+    }
+
+    pub fn get_users(&self) -> Vec<EMAIL_ADDRESS> {
+        self.inboxes.keys().cloned().collect()
+    }
+
+    pub fn get_user_address(&self, principal : Principal) ->Option<String> {
+        self.users.get(&principal).cloned()
+    }
+
+    pub fn get_user_profile(&self, principal : Principal) -> Option<Profile> {
+        let address_opt = self.get_user_address(principal);
+        if address_opt.is_some() {
+            let email_addr = address_opt.unwrap();
+            let profile_opt = self.profile.get(&email_addr).cloned();
+            return profile_opt;
+        } else {
+            return None;
+        }
+        
+    }
+    pub fn get_all_mail_count(&self) -> Result<(u32, u32), MailError> {
+        let mut unread = 0;
+        let mut read = 0;
+        for status in self.mail_status.values() {
+            if status.read {
+                read += 1
+            } else {
+                unread += 1
+            }
+        }
+
+        Ok((unread, read))
+    }
+
+    pub fn get_mail_count(&self, email : &EMAIL_ADDRESS) -> Result<(u32, u32), MailError> {
+        let inbox = self.inboxes.get(email).ok_or(MailError::NoUserAddressFound)?;
+        let mut unread = 0;
+        let mut read = 0;
+        for mail_id in inbox {
+            let status = self.mail_status.get(mail_id).ok_or(MailError::MailNotFound)?;
+            if status.read {
+                read += 1
+            } else {
+                unread += 1
+            }
+        }
+
+        Ok((unread, read))
+    }
+
+    // Adds the principal as an owner of the mailbox, creating the mailbox if needed. The first
+    // mailbox of a principal becomes its primary one.
+    pub fn create_user(&mut self, email_address : EMAIL_ADDRESS, principal_address : String) -> Result<(), MailError> {
+        let user_p = Principal::from_text(principal_address).map_err(|_| MailError::GeneralError("Invalid principal".to_string()))?;
+        self.inboxes.entry(email_address.clone()).or_default();
+        self.add_member(email_address, user_p, MailboxRole::Owner);
+        Ok(())
+    }
+
+    pub fn public_create_user(&mut self, email_address : EMAIL_ADDRESS, principal : Principal) -> Result<(), MailError> {
+        if self.config.permissioned {
+            return Err(MailError::PermissionedSystem)
+        }
+
+        if self.inboxes.contains_key(&email_address) {
+            return Err(MailError::AddressExist);
+        }
+
+        let owned = self.get_mailboxes(principal).iter().filter(|access| access.role == MailboxRole::Owner).count();
+        if owned >= MAX_MAILBOXES_PER_PRINCIPAL {
+            return Err(MailError::GeneralError(format!("A principal can own at most {} mailboxes", MAX_MAILBOXES_PER_PRINCIPAL)));
+        }
+
+        self.inboxes.insert(email_address.clone(), HashSet::new());
+        self.add_member(email_address, principal, MailboxRole::Owner);
+        Ok(())
+    }
+
+    // Takes every principal off the mailbox, its mail stays.
+    pub fn delete_user(&mut self, email_address : EMAIL_ADDRESS) -> Result<(), MailError> {
+        if !self.config.permissioned {
+            return Err(MailError::NotAuthorized);
+        }
+        self.remove_members(&email_address);
+        self.remove_routes(&email_address);
+
+        Ok(())
+    }
+
+    pub fn delete_mailbox(&mut self, email : &EMAIL_ADDRESS) -> Result<(), MailError> {
+        if !self.inboxes.contains_key(email) {
+            return Err(MailError::NoUserAddressFound);
+        }
+        self.profile.remove(email);
+        self.inboxes.remove(email);
+        self.sent.remove(email);
+        self.trash.remove(email);
+        self.mbox_imports.remove(email);
+        self.app_passwords.remove(email);
+        self.audit_logs.remove(email);
+        self.auto_replies.remove(email);
+        self.auto_replied.remove(email);
+        self.mail_rules.remove(email);
+        self.sender_lists.remove(email);
+        self.postage_prices.remove(email);
+        self.contacts.remove(email);
+        self.labels.remove(email);
+        self.starred.remove(email);
+        for readers in self.opened_by.values_mut() {
+            readers.remove(email);
+        }
+        self.remove_members(email);
+        self.remove_routes(email);
+
+        Ok(())
+    }
+
+    // The mailbox a call acts on and whether the principal may do `action` there. `mailbox` is the
+    // mailbox the caller selected, None means its primary one. Members act through their role,
+    // anyone else needs an active grant, and every use of a grant goes to the mailbox's audit log.
+    pub fn authorize(&mut self, principal : Principal, mailbox : Option<EMAIL_ADDRESS>, action : &MailboxAction, now : u64) -> Result<EMAIL_ADDRESS, MailError> {
+        let mailbox = match mailbox {
+            Some(mailbox) => mailbox,
+            None => self.users.get(&principal).cloned().ok_or(MailError::NoUserAddressFound)?
+        };
+        let role = self.mailbox_members
+            .get(&mailbox)
+            .and_then(|members| members.get(&principal));
+        if let Some(role) = role {
+            if !role.allows(action) {
+                return Err(MailError::NotAuthorized);
+            }
+            return Ok(mailbox);
+        }
+
+        let grant_id = self.grants
+            .get(&principal)
+            .and_then(|grants| grants.iter().find(|grant| {
+                grant.mailbox == mailbox
+                    && grant.is_active(now)
+                    && grant.scopes.iter().any(|scope| self.scope_allows(&mailbox, *scope, action))
+            }))
+            .ok_or(MailError::NotAuthorized)?
+            .id;
+        self.record_delegated_action(&mailbox, principal, grant_id, action, now);
+        Ok(mailbox)
+    }
+
+    fn scope_allows(&self, mailbox : &EMAIL_ADDRESS, scope : GrantScope, action : &MailboxAction) -> bool {
+        match (scope, action) {
+            (GrantScope::ReadInbox, MailboxAction::ListFolder(folder)) => *folder == MailFolder::Inbox,
+            (GrantScope::ReadInbox, MailboxAction::ReadMail(mail_id)) => self.folder_set(mailbox, MailFolder::Inbox).is_some_and(|inbox| inbox.contains(mail_id)),
+            (GrantScope::ReadAll, MailboxAction::ListFolder(_) | MailboxAction::ReadMail(_) | MailboxAction::ReadAll) => true,
+            (GrantScope::Send, MailboxAction::Send) => true,
+            (GrantScope::Organize, MailboxAction::Organize) => true,
+            _ => false
+        }
+    }
+
+    // Members and principals holding an active grant.
+    pub fn has_mailbox(&self, principal : Principal, now : u64) -> bool {
+        self.users.contains_key(&principal)
+            || self.grants.get(&principal).is_some_and(|grants| grants.iter().any(|grant| grant.is_active(now)))
+    }
+
+    fn record_delegated_action(&mut self, mailbox : &EMAIL_ADDRESS, principal : Principal, grant_id : u64, action : &MailboxAction, now : u64) {
+        let log = self.audit_logs.entry(mailbox.clone()).or_default();
+        log.push_back(AuditEntry {
+            timestamp: now,
+            principal,
+            grant_id,
+            action: format!("{:?}", action)
+        });
+        if log.len() > MAX_AUDIT_ENTRIES {
+            log.pop_front();
+        }
+    }
+
+    // Newest first.
+    pub fn get_audit_log(&self, mailbox : &EMAIL_ADDRESS) -> Vec<AuditEntry> {
+        self.audit_logs
+            .get(mailbox)
+            .map(|log| log.iter().rev().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn create_grant(&mut self, mailbox : &EMAIL_ADDRESS, granted_by : Principal, grantee : Principal, scopes : Vec<GrantScope>, expires_at : u64, now : u64) -> Result<u64, MailError> {
+        if !self.inboxes.contains_key(mailbox) {
+            return Err(MailError::NoUserAddressFound);
+        }
+        if grantee == Principal::anonymous() {
+            return Err(MailError::GeneralError("The anonymous principal can not hold a grant".to_string()));
+        }
+        if scopes.is_empty() {
+            return Err(MailError::GeneralError("A grant needs at least one scope".to_string()));
+        }
+        if expires_at <= now {
+            return Err(MailError::GeneralError("A grant has to expire in the future".to_string()));
+        }
+
+        let id = self.next_grant_id;
+        self.next_grant_id += 1;
+        let grants = self.grants.entry(grantee).or_insert(vec![]);
+        grants.retain(|grant| grant.is_active(now));
+        grants.push(MailboxGrant {
+            id,
+            mailbox: mailbox.clone(),
+            grantee,
+            scopes,
+            expires_at,
+            granted_by,
+            created_at: now
+        });
+        Ok(id)
+    }
+
+    pub fn revoke_grant(&mut self, mailbox : &EMAIL_ADDRESS, grant_id : u64) -> Result<(), MailError> {
+        for grants in self.grants.values_mut() {
+            if let Some(pos) = grants.iter().position(|grant| grant.id == grant_id && grant.mailbox == *mailbox) {
+                grants.remove(pos);
+                return Ok(());
+            }
+        }
+        Err(MailError::NotFound)
+    }
+
+    pub fn get_grants(&self, mailbox : &EMAIL_ADDRESS) -> Vec<MailboxGrant> {
+        let mut grants : Vec<MailboxGrant> = self.grants
+            .values()
+            .flatten()
+            .filter(|grant| grant.mailbox == *mailbox)
+            .cloned()
+            .collect();
+        grants.sort_by_key(|grant| grant.id);
+        grants
+    }
+
+    // The active grants a principal holds.
+    pub fn get_grants_of(&self, principal : Principal, now : u64) -> Vec<MailboxGrant> {
+        self.grants
+            .get(&principal)
+            .map(|grants| grants.iter().filter(|grant| grant.is_active(now)).cloned().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn get_mailboxes(&self, principal : Principal) -> Vec<MailboxAccess> {
+        let primary = self.users.get(&principal);
+        let mut mailboxes : Vec<MailboxAccess> = self.mailbox_members
+            .iter()
+            .filter_map(|(mailbox, members)| members.get(&principal).map(|role| MailboxAccess {
+                mailbox: mailbox.clone(),
+                role: *role,
+                primary: primary == Some(mailbox)
+            }))
+            .collect();
+        mailboxes.sort_by(|a, b| a.mailbox.cmp(&b.mailbox));
+        mailboxes
+    }
+
+    // Only mailboxes the principal is a member of, a grant is not enough.
+    pub fn set_primary_mailbox(&mut self, principal : Principal, mailbox : EMAIL_ADDRESS) -> Result<(), MailError> {
+        let is_member = self.mailbox_members.get(&mailbox).is_some_and(|members| members.contains_key(&principal));
+        if !is_member {
+            return Err(MailError::NotAuthorized);
+        }
+        self.users.insert(principal, mailbox);
+        Ok(())
+    }
+
+    pub fn get_mailbox_members(&self, mailbox : &EMAIL_ADDRESS) -> Vec<(Principal, MailboxRole)> {
+        self.mailbox_members
+            .get(mailbox)
+            .map(|members| members.iter().map(|(p, role)| (*p, *role)).collect())
+            .unwrap_or_default()
+    }
+
+    pub fn grant_mailbox_access(&mut self, mailbox : &EMAIL_ADDRESS, principal : Principal, role : MailboxRole) -> Result<(), MailError> {
+        if !self.inboxes.contains_key(mailbox) {
+            return Err(MailError::NoUserAddressFound);
+        }
+        if principal == Principal::anonymous() {
+            return Err(MailError::GeneralError("The anonymous principal can not be a member".to_string()));
+        }
+        if role != MailboxRole::Owner {
+            self.ensure_other_owner(mailbox, principal)?;
+        }
+        self.add_member(mailbox.clone(), principal, role);
+        Ok(())
+    }
+
+    pub fn revoke_mailbox_access(&mut self, mailbox : &EMAIL_ADDRESS, principal : Principal) -> Result<(), MailError> {
+        let is_member = self.mailbox_members.get(mailbox).is_some_and(|members| members.contains_key(&principal));
+        if !is_member {
+            return Err(MailError::NotFound);
+        }
+        self.ensure_other_owner(mailbox, principal)?;
+
+        if let Some(members) = self.mailbox_members.get_mut(mailbox) {
+            members.remove(&principal);
+        }
+        if self.users.get(&principal) == Some(mailbox) {
+            self.reassign_primary(principal);
+        }
+        Ok(())
+    }
+
+    // a mailbox never loses its last owner
+    fn ensure_other_owner(&self, mailbox : &EMAIL_ADDRESS, principal : Principal) -> Result<(), MailError> {
+        let other_owner = self.mailbox_members
+            .get(mailbox)
+            .is_some_and(|members| members.iter().any(|(p, role)| *p != principal && *role == MailboxRole::Owner));
+        if !other_owner {
+            return Err(MailError::GeneralError("A mailbox needs at least one owner".to_string()));
+        }
+        Ok(())
+    }
+
+    fn add_member(&mut self, mailbox : EMAIL_ADDRESS, principal : Principal, role : MailboxRole) {
+        self.mailbox_members.entry(mailbox.clone()).or_default().insert(principal, role);
+        self.users.entry(principal).or_insert(mailbox);
+    }
+
+    fn remove_members(&mut self, mailbox : &EMAIL_ADDRESS) {
+        for grants in self.grants.values_mut() {
+            grants.retain(|grant| grant.mailbox != *mailbox);
+        }
+        let members = self.mailbox_members.remove(mailbox).unwrap_or_default();
+        for principal in members.keys() {
+            if self.users.get(principal) == Some(mailbox) {
+                self.reassign_primary(*principal);
+            }
+        }
+    }
+
+    // the primary mailbox went away, fall back to another one the principal can reach
+    fn reassign_primary(&mut self, principal : Principal) {
+        match self.get_mailboxes(principal).first() {
+            Some(access) => self.users.insert(principal, access.mailbox.clone()),
+            None => self.users.remove(&principal)
+        };
+    }
+
+    pub fn delete_mail_for(&mut self, email : &EMAIL_ADDRESS, mail_id : MAIL_ID) -> Result<(), MailError> {
+        let inbox = self.inboxes.get_mut(email).ok_or(MailError::NoUserAddressFound)?;
+        let mut found = inbox.remove(&mail_id);
+        if let Some(labels) = self.labels.get_mut(email) {
+            for set in labels.values_mut() {
+                found |= set.remove(&mail_id);
+            }
+            labels.retain(|_, set| !set.is_empty());
+        }
+        if found {
+            self.trash.entry(email.clone()).or_default().insert(mail_id);
+        }
+
+        Ok(())
+    }
+
+    pub fn restore_mail_for(&mut self, email : &EMAIL_ADDRESS, mail_id : MAIL_ID) -> Result<(), MailError> {
+        let trash_set = self.trash.get_mut(email).ok_or(MailError::MailNotFound)?;
+        if trash_set.contains(&mail_id) {
+            trash_set.remove(&mail_id);
+            let inbox_set = self.inboxes.get_mut(email).ok_or(MailError::MailNotFound)?;
+            inbox_set.insert(mail_id);
+            Ok(())
+        } else {
+            Err(MailError::MailNotFound)
+        }
+    }
+
+    pub fn get_domain_name(&self) -> String {
+        self.config.domain_name.clone()
+    }
+
+    pub fn get_token_address(&self) -> String {
+        self.config.token_address.clone()
+    }
+
+    pub fn get_registry_address(&self) -> String {
+        self.config.registry_canister.clone()
+    }
+
+    // gets domains of receipients
+    pub fn get_receipients_domains(mail: &Mail) -> Result<Vec<String>, MailError> {
+        let receipients = mail.header.to.iter().chain(mail.header.bcc.iter().flatten()).chain(mail.header.cc.iter().flatten());
+        let mut selected_domains = vec![];
+        for to in receipients {
+            let email = EmailAddress::from_str(to).map_err(|_| MailError::InvalidAddress(to.clone()))?;
+            selected_domains.push(email.domain().to_string())
+        }
+        Ok(selected_domains)
+    }
+
+    // receipients of the mail whose address is on one of `domains`
+    pub fn get_receipients_in_domains(mail: &Mail, domains: &[String]) -> Vec<EMAIL_ADDRESS> {
+        let mut receipients = mail.header.to.clone();
+        receipients.extend(mail.header.cc.clone().unwrap_or_default());
+        receipients.extend(mail.header.bcc.clone().unwrap_or_default());
+        receipients
+            .into_iter()
+            .filter(|addr| {
+                EmailAddress::from_str(addr)
+                    .map(|email| domains.contains(&email.domain().to_string()))
+                    .unwrap_or(false)
+            })
+            .collect()
+    }
+
+    pub fn get_newsletter_subscribers(&self, newsletter_id : NEWSLETTER_ID) -> Result<Vec<EMAIL_ADDRESS>, MailError>{
+        let set = self.newsletter_subscribers.get(&newsletter_id).ok_or(MailError::NotFound)?;
+        Ok(set.keys().cloned().collect())
+    }
+
+    // Oldest subscription first.
+    pub fn get_subscribers(&self, newsletter_id : &NEWSLETTER_ID, principal : Principal) -> Result<Vec<Subscriber>, MailError> {
+        self.check_newsletter_owner(newsletter_id, principal)?;
+        let set = self.newsletter_subscribers.get(newsletter_id).ok_or(MailError::NotFound)?;
+        let mut subscribers : Vec<Subscriber> = set.iter().map(|(address, subscription)| Subscriber {
+            address: address.clone(),
+            principal: subscription.principal,
+            subscribed_at: subscription.subscribed_at,
+            paid: subscription.paid.clone()
+        }).collect();
+        subscribers.sort_by(|a, b| a.subscribed_at.cmp(&b.subscribed_at).then_with(|| a.address.cmp(&b.address)));
+        Ok(subscribers)
+    }
+
+    // Holds the subscription until the address confirms it with `token`, which is mailed to it as a
+    // link to `/confirm` under `base_url`. Subscribers are keyed by their address in lower case.
+    pub fn subscribe_to_newsletter(&mut self, newsletter_id : NEWSLETTER_ID, email_address : EMAIL_ADDRESS, p : Principal, token : &str, base_url : &str, now : u64) -> Result<(), MailError> {
+        EmailAddress::from_str(&email_address).map_err(|_| MailError::InvalidAddress(email_address.clone()))?;
+        let email_address = email_address.to_lowercase();
+        let newsletter = self.newsletter.get(&newsletter_id).cloned().ok_or(MailError::NotFound)?;
+        let subscribe_set = self.newsletter_subscribers.get(&newsletter_id).ok_or(MailError::NotFound)?;
+        if subscribe_set.contains_key(&email_address) {
+            return Err(MailError::AddressExist);
+        }
+
+        self.prune_pending_subscriptions(now);
+        // one confirmation mail per address until it expires, so subscribing can not flood a mailbox
+        if self.pending_subscriptions.values().any(|pending| pending.newsletter_id == newsletter_id && pending.address == email_address) {
+            return Err(MailError::GeneralError("A confirmation was already sent to this address".to_string()));
+        }
+        self.pending_subscriptions.insert(sha256(token), PendingSubscription {
+            newsletter_id,
+            address: email_address.clone(),
+            principal: p,
+            expires_at: now + SUBSCRIPTION_CONFIRMATION_NANOS
+        });
+
+        let sender = format!("no-reply@{}", self.config.domain_name);
+        let mut header = MailHeader {
+            from: sender.clone(),
+            timestamp: now,
+            content_type: Some("text/plain; charset=utf-8".to_string()),
+            to: vec![email_address],
+            subject: Some(format!("Confirm your subscription to {}", newsletter.title)),
+            ..Default::default()
+        };
+        header.set_header("Auto-Submitted", "auto-generated".to_string());
+        let body = format!(
+            "Someone asked to subscribe this address to {}.\r\n\r\nTo confirm, open this link within {} hours:\r\n\r\n{}/confirm?token={}\r\n\r\nIf you did not ask for it, ignore this mail and nothing will be sent to you.\r\n",
+            newsletter.title,
+            SUBSCRIPTION_CONFIRMATION_NANOS / (NANOS_PER_DAY / 24),
+            base_url,
+            http::percent_encode(token)
+        );
+        let body = Rcbytes(Arc::new(ByteBuf::from(body.into_bytes())));
+        self.pending_outbound.push((sender, Mail { correlation_id: None, header, body, reply_messages: None }));
+        Ok(())
+    }
+
+    // Activates the subscription the token was mailed for, a token works once.
+    pub fn confirm_subscription(&mut self, token : &str, now : u64) -> Result<NEWSLETTER_ID, MailError> {
+        self.prune_pending_subscriptions(now);
+        let pending = self.pending_subscriptions.remove(&sha256(token)).ok_or(MailError::NotFound)?;
+        let subscribe_set = self.newsletter_subscribers.get_mut(&pending.newsletter_id).ok_or(MailError::NotFound)?;
+        if let std::collections::hash_map::Entry::Vacant(entry) = subscribe_set.entry(pending.address) {
+            entry.insert(Subscription { principal: pending.principal, subscribed_at: now, paid: None });
+            self.record_growth(&pending.newsletter_id, true, now);
+        }
+        Ok(pending.newsletter_id)
+    }
+
+    pub fn has_newsletter_secret(&self) -> bool {
+        self.newsletter_secret.is_some()
+    }
+
+    pub fn set_newsletter_secret(&mut self, secret : [u8; 32]) {
+        self.newsletter_secret.get_or_insert(secret);
+    }
+
+    // HMAC-SHA256 of a subscriber under the newsletter secret, None until the secret is set.
+    fn unsubscribe_mac(&self, newsletter_id : &NEWSLETTER_ID, address : &EMAIL_ADDRESS) -> Option<Hmac<sha2::Sha256>> {
+        let secret = self.newsletter_secret.as_ref()?;
+        let mut mac = Hmac::<sha2::Sha256>::new_from_slice(secret).expect("HMAC takes keys of any length");
+        mac.update(format!("{}\n{}", newsletter_id, address).as_bytes());
+        Some(mac)
+    }
+
+    // "<newsletter id>.<address>.<signature>" with the parts hex encoded, so the token alone
+    // proves which subscriber it was sent to.
+    pub fn unsubscribe_token(&self, newsletter_id : &NEWSLETTER_ID, address : &EMAIL_ADDRESS) -> Option<String> {
+        let address = address.to_lowercase();
+        let signature = self.unsubscribe_mac(newsletter_id, &address)?.finalize().into_bytes();
+        Some(format!("{}.{}.{}", hex::encode(newsletter_id), hex::encode(&address), hex::encode(signature)))
+    }
+
+    fn verify_unsubscribe_token(&self, token : &str) -> Result<(NEWSLETTER_ID, EMAIL_ADDRESS), MailError> {
+        let invalid = || MailError::GeneralError("Invalid unsubscribe token".to_string());
+        let parts : Vec<&str> = token.trim().split('.').collect();
+        if parts.len() != 3 {
+            return Err(invalid());
+        }
+        let decode = |part : &str| hex::decode(part).ok().and_then(|bytes| String::from_utf8(bytes).ok());
+        let newsletter_id = decode(parts[0]).ok_or_else(invalid)?;
+        let address = decode(parts[1]).ok_or_else(invalid)?;
+        let signature = hex::decode(parts[2]).map_err(|_| invalid())?;
+        // constant time, a timing difference must not leak how much of a forged signature matched
+        self.unsubscribe_mac(&newsletter_id, &address)
+            .ok_or_else(invalid)?
+            .verify_slice(&signature)
+            .map_err(|_| invalid())?;
+        Ok((newsletter_id, address))
+    }
+
+    // Unsubscribes whoever the token was made for, no principal needed. Returns the newsletter's
+    // title.
+    pub fn unsubscribe_with_token(&mut self, token : &str, now : u64) -> Result<String, MailError> {
+        let (newsletter_id, address) = self.verify_unsubscribe_token(token)?;
+        let title = self.newsletter.get(&newsletter_id).map(|newsletter| newsletter.title.clone()).ok_or(MailError::NotFound)?;
+        let subscribe_set = self.newsletter_subscribers.get_mut(&newsletter_id).ok_or(MailError::NotFound)?;
+        if subscribe_set.remove(&address).is_some() {
+            self.record_unsubscribe(&newsletter_id, &address, true, now);
+        }
+        Ok(title)
+    }
+
+    // The copy of an issue one subscriber gets, it names the list and how to leave it (RFC 2369,
+    // RFC 8058). `base_url` is where this canister's `http_request` is served.
+    pub fn newsletter_copy(&self, newsletter_id : &NEWSLETTER_ID, issue_number : u32, mail : &Mail, address : &EMAIL_ADDRESS, base_url : &str) -> Mail {
+        let mut copy = mail.clone();
+        copy.header.to = vec![address.clone()];
+        copy.header.cc = None;
+        copy.header.bcc = None;
+        // keeps auto responders from answering the newsletter
+        copy.header.set_header("Precedence", "bulk".to_string());
+        let title = self.newsletter.get(newsletter_id).map(|newsletter| newsletter.title.replace(|c : char| c == '<' || c == '>' || c == '"' || c.is_control(), "")).unwrap_or_default();
+        copy.header.set_header("List-Id", format!("\"{}\" <{}.{}>", title, newsletter_id, self.config.domain_name));
+        if let Some(token) = self.unsubscribe_token(newsletter_id, address) {
+            copy.header.set_header("List-Unsubscribe", format!("<{}/unsubscribe?token={}>", base_url, token));
+            copy.header.set_header("List-Unsubscribe-Post", "List-Unsubscribe=One-Click".to_string());
+        }
+        copy.header.set_header(NEWSLETTER_HEADER, newsletter_id.clone());
+        copy.header.set_header(NEWSLETTER_ISSUE_HEADER, issue_number.to_string());
+        copy
+    }
+
+    // Snapshots the subscribers of the newsletter into a job, the caller runs it. A paid issue
+    // only goes to subscribers whose paid subscription is active.
+    pub fn create_send_job(&mut self, newsletter_id : &NEWSLETTER_ID, mail : Mail, tier : Tier, principal : Principal, now : u64) -> Result<u64, MailError> {
+        self.check_newsletter_owner(newsletter_id, principal)?;
+        if tier == Tier::Paid && !self.newsletter_plans.contains_key(newsletter_id) {
+            return Err(MailError::GeneralError("The newsletter has no paid tier".to_string()));
+        }
+        let mut deliveries : Vec<(String, SubscriberDelivery)> = self.newsletter_subscribers.get(newsletter_id).ok_or(MailError::NotFound)?
+            .iter()
+            .filter(|(_, subscription)| tier == Tier::Free || subscription.paid.as_ref().is_some_and(|paid| paid.is_active(now)))
+            .map(|(address, _)| address.clone())
+            .map(|address| {
+                let domain = EmailAddress::from_str(&address).map(|email| email.domain().to_lowercase());
+                let status = if domain.is_ok() { DeliveryStatus::Pending } else { DeliveryStatus::Failed("Invalid address".to_string()) };
+                (domain.unwrap_or_default(), SubscriberDelivery { address, status, attempted_at: None, read_at: None })
+            })
+            .collect();
+        deliveries.sort_by(|a, b| a.0.cmp(&b.0).then_with(|| a.1.address.cmp(&b.1.address)));
+
+        let issues = self.newsletter_issues.entry(newsletter_id.clone()).or_default();
+        let issue_number = issues.len() as u32 + 1;
+        issues.push(Issue {
+            number: issue_number,
+            subject: mail.header.subject.clone().unwrap_or_default(),
+            published_at: now,
+            content_type: mail.header.content_type.clone(),
+            body: (*mail.body.0).clone(),
+            tier
+        });
+
+        let id = self.next_send_job_id;
+        self.next_send_job_id += 1;
+        self.send_jobs.insert(id, SendJob {
+            newsletter_id: newsletter_id.clone(),
+            issue_number,
+            mail,
+            deliveries,
+            destinations: HashMap::new(),
+            created_at: now,
+            finished_at: None,
+            lease_until: 0,
+            unsubscribes: 0
+        });
+        Ok(id)
+    }
+
+    // Takes the job for a runner unless another one holds it.
+    pub fn claim_send_job(&mut self, job_id : u64, now : u64) -> bool {
+        match self.send_jobs.get_mut(&job_id) {
+            Some(job) if job.finished_at.is_none() && job.lease_until <= now => {
+                job.lease_until = now + SEND_JOB_LEASE_NANOS;
+                true
+            }
+            _ => false
+        }
+    }
+
+    pub fn release_send_job(&mut self, job_id : u64, until : u64) {
+        if let Some(job) = self.send_jobs.get_mut(&job_id) {
+            job.lease_until = until;
+        }
+    }
+
+    // Unfinished jobs whose runner is gone.
+    pub fn take_newsletter_state(&mut self) -> NewsletterState {
+        NewsletterState {
+            config: std::mem::take(&mut self.config),
+            newsletter: std::mem::take(&mut self.newsletter),
+            newsletter_owners: std::mem::take(&mut self.newsletter_owners),
+            newsletter_subscribers: std::mem::take(&mut self.newsletter_subscribers),
+            newsletter_secret: self.newsletter_secret.take(),
+            send_jobs: std::mem::take(&mut self.send_jobs),
+            next_send_job_id: self.next_send_job_id,
+            newsletter_issues: std::mem::take(&mut self.newsletter_issues)
+        }
+    }
+
+    // The runner that held a lease did not survive the upgrade, its job is picked up again on the
+    // next tick.
+    pub fn restore_newsletter_state(&mut self, state : NewsletterState) {
+        self.config = state.config;
+        self.newsletter = state.newsletter;
+        self.newsletter_owners = state.newsletter_owners;
+        self.newsletter_subscribers = state.newsletter_subscribers;
+        self.newsletter_secret = state.newsletter_secret;
+        self.send_jobs = state.send_jobs;
+        for job in self.send_jobs.values_mut() {
+            job.lease_until = 0;
+        }
+        self.next_send_job_id = state.next_send_job_id;
+        self.newsletter_issues = state.newsletter_issues;
+    }
+
+    pub fn stale_send_jobs(&self, now : u64) -> Vec<u64> {
+        self.send_jobs.iter().filter(|(_, job)| job.finished_at.is_none() && job.lease_until <= now).map(|(id, _)| *id).collect()
+    }
+
+    // The next pending subscribers on one domain and their copies of the issue, bounded by
+    // `MAX_SEND_BATCH_MAILS` and `MAX_SEND_BATCH_BYTES`. Renews the runner's lease.
+    pub fn next_send_batch(&mut self, job_id : u64, base_url : &str, now : u64) -> Option<(String, Vec<Mail>)> {
+        let job = self.send_jobs.get(&job_id)?;
+        let domain = job.deliveries.iter().find(|(_, delivery)| delivery.status == DeliveryStatus::Pending).map(|(domain, _)| domain.clone())?;
+        let mut mails = vec![];
+        let mut bytes = 0;
+        for (_, delivery) in job.deliveries.iter().filter(|(d, delivery)| *d == domain && delivery.status == DeliveryStatus::Pending) {
+            let copy = self.newsletter_copy(&job.newsletter_id, job.issue_number, &job.mail, &delivery.address, base_url);
+            bytes += copy.size();
+            if !mails.is_empty() && (mails.len() >= MAX_SEND_BATCH_MAILS || bytes > MAX_SEND_BATCH_BYTES) {
+                break;
+            }
+            mails.push(copy);
+        }
+        if let Some(job) = self.send_jobs.get_mut(&job_id) {
+            job.lease_until = now + SEND_JOB_LEASE_NANOS;
+        }
+        Some((domain, mails))
+    }
+
+    pub fn get_send_job_destination(&self, job_id : u64, domain : &str) -> Option<Destination> {
+        self.send_jobs.get(&job_id)?.destinations.get(domain).cloned()
+    }
+
+    pub fn set_send_job_destination(&mut self, job_id : u64, domain : &str, destination : Destination) {
+        if let Some(job) = self.send_jobs.get_mut(&job_id) {
+            job.destinations.insert(domain.to_string(), destination);
+        }
+    }
+
+    // Rate limited copies stay pending, everything else is final. The job is finished once
+    // nothing is pending.
+    pub fn record_deliveries(&mut self, job_id : u64, results : Vec<(EMAIL_ADDRESS, Result<(), MailError>)>, now : u64) {
+        let job = match self.send_jobs.get_mut(&job_id) {
+            Some(job) => job,
+            None => return
+        };
+        for (address, result) in results {
+            let delivery = job.deliveries.iter_mut().find(|(_, delivery)| delivery.address == address && delivery.status == DeliveryStatus::Pending);
+            if let Some((_, delivery)) = delivery {
+                delivery.attempted_at = Some(now);
+                match result {
+                    Ok(()) => delivery.status = DeliveryStatus::Delivered,
+                    Err(MailError::RateLimited { .. }) => {}
+                    Err(err) => delivery.status = DeliveryStatus::Failed(err.to_string())
+                }
+            }
+        }
+        if job.deliveries.iter().all(|(_, delivery)| delivery.status != DeliveryStatus::Pending) {
+            job.finished_at = Some(now);
+            job.lease_until = 0;
+        }
+    }
+
+    // Newest first.
+    pub fn get_send_jobs(&self, newsletter_id : &NEWSLETTER_ID, principal : Principal) -> Result<Vec<SendJobInfo>, MailError> {
+        self.check_newsletter_owner(newsletter_id, principal)?;
+        let mut jobs : Vec<SendJobInfo> = self.send_jobs.iter().filter(|(_, job)| job.newsletter_id == *newsletter_id).map(|(id, job)| job.info(*id)).collect();
+        jobs.sort_by_key(|job| cmp::Reverse(job.id));
+        Ok(jobs)
+    }
+
+    pub fn get_send_job_deliveries(&self, job_id : u64, principal : Principal) -> Result<Vec<SubscriberDelivery>, MailError> {
+        let job = self.send_jobs.get(&job_id).ok_or(MailError::NotFound)?;
+        self.check_newsletter_owner(&job.newsletter_id, principal)?;
+        Ok(job.deliveries.iter().map(|(_, delivery)| delivery.clone()).collect())
+    }
+
+    pub fn prune_pending_subscriptions(&mut self, now : u64) {
+        self.pending_subscriptions.retain(|_, pending| pending.expires_at > now);
+    }
+
+    pub fn get_newsletters(&self) -> Vec<(NEWSLETTER_ID, Newsletter)> {
+        self.newsletter.clone().into_iter().collect()
+    }
+
+    // The principal that subscribed the address and the newsletter's owner can unsubscribe it.
+    pub fn unsubscribe_to_newsletter(&mut self, newsletter_id : NEWSLETTER_ID, email_address : EMAIL_ADDRESS, p : Principal, now : u64) -> Result<(), MailError> {
+        let email_address = email_address.to_lowercase();
+        let is_owner = self.check_newsletter_owner(&newsletter_id, p).is_ok();
+        let subscribe_set = self.newsletter_subscribers.get_mut(&newsletter_id).ok_or(MailError::NotFound)?;
+        let subscription = subscribe_set.get(&email_address).ok_or(MailError::NotFound)?;
+
+        // only a subscriber leaving counts against an issue, not the owner removing one
+        let by_subscriber = subscription.principal == p;
+        if by_subscriber || is_owner {
+            subscribe_set.remove(&email_address);
+        } else {
+            return Err(MailError::NotAuthorized);
+        }
+        self.record_unsubscribe(&newsletter_id, &email_address, by_subscriber, now);
+
+        Ok(())
+    }
+
+    pub fn get_newsletter(&self, mail_id : MAIL_ID) -> Result<Newsletter, MailError> {
+        let n = self.newsletter.get(&mail_id).cloned().ok_or(MailError::NotFound)?;
+        Ok(n)
+    }
+
+
+    pub fn create_newletter(&mut self, newsletter_id : NEWSLETTER_ID, letter : Newsletter, owner : Principal) -> Result<(), MailError> {
+        letter.validate()?;
+        if self.newsletter.contains_key(&newsletter_id) {
+            return Err(MailError::InternalSystemMailCollision);
+        }
+        self.newsletter.insert(newsletter_id.clone(), letter);
+        self.newsletter_owners.insert(newsletter_id.clone(), owner);
+        self.newsletter_subscribers.insert(newsletter_id, HashMap::new());
+        Ok(())
+    }
+
+    pub fn update_newsletter(&mut self, newsletter_id : &NEWSLETTER_ID, letter : Newsletter, principal : Principal) -> Result<(), MailError> {
+        self.check_newsletter_owner(newsletter_id, principal)?;
+        letter.validate()?;
+        self.newsletter.insert(newsletter_id.clone(), letter);
+        Ok(())
+    }
+
+    // Removes the newsletter together with its subscribers.
+    pub fn delete_newsletter(&mut self, newsletter_id : &NEWSLETTER_ID, principal : Principal) -> Result<(), MailError> {
+        self.check_newsletter_owner(newsletter_id, principal)?;
+        self.newsletter.remove(newsletter_id);
+        self.newsletter_owners.remove(newsletter_id);
+        self.newsletter_subscribers.remove(newsletter_id);
+        self.pending_subscriptions.retain(|_, pending| pending.newsletter_id != *newsletter_id);
+        self.send_jobs.retain(|_, job| job.newsletter_id != *newsletter_id);
+        self.newsletter_issues.remove(newsletter_id);
+        self.newsletter_plans.remove(newsletter_id);
+        self.billing_in_progress.retain(|(id, _)| id != newsletter_id);
+        self.subscriber_growth.remove(newsletter_id);
+        Ok(())
+    }
+
+    fn record_growth(&mut self, newsletter_id : &NEWSLETTER_ID, subscribed : bool, now : u64) {
+        let day = self.subscriber_growth.entry(newsletter_id.clone()).or_default().entry(now - now % NANOS_PER_DAY).or_default();
+        if subscribed {
+            day.0 += 1;
+        } else {
+            day.1 += 1;
+        }
+    }
+
+    // Counts the unsubscribe for the day and, when the subscriber left on their own, against the
+    // last issue delivered to them.
+    fn record_unsubscribe(&mut self, newsletter_id : &NEWSLETTER_ID, address : &EMAIL_ADDRESS, by_subscriber : bool, now : u64) {
+        self.record_growth(newsletter_id, false, now);
+        if !by_subscriber {
+            return;
+        }
+        let last_job = self.send_jobs.values_mut()
+            .filter(|job| job.newsletter_id == *newsletter_id)
+            .filter(|job| job.deliveries.iter().any(|(_, delivery)| delivery.status == DeliveryStatus::Delivered && delivery.address.eq_ignore_ascii_case(address)))
+            .max_by_key(|job| job.issue_number);
+        if let Some(job) = last_job {
+            job.unsubscribes += 1;
+        }
+    }
+
+    // Marks the subscriber's copy of the issue read. `from` must be where the copy was
+    // delivered, this canister for local subscribers or the canister of the subscriber's domain.
+    pub fn record_issue_read(&mut self, newsletter_id : &NEWSLETTER_ID, issue_number : u32, address : &EMAIL_ADDRESS, from : Destination, now : u64) -> Result<(), MailError> {
+        self.mark_issue_read(newsletter_id, issue_number, address, Some(from), now)
+    }
+
+    // A read reported by another principal, either the canister the copy was delivered to or the
+    // principal that subscribed the address.
+    pub fn report_issue_read(&mut self, newsletter_id : &NEWSLETTER_ID, issue_number : u32, address : &EMAIL_ADDRESS, caller : Principal, now : u64) -> Result<(), MailError> {
+        let by_subscriber = self.newsletter_subscribers.get(newsletter_id)
+            .and_then(|subscribers| subscribers.get(&address.to_lowercase()))
+            .is_some_and(|subscription| subscription.principal == caller);
+        let from = if by_subscriber { None } else { Some(Destination::Canister(caller)) };
+        self.mark_issue_read(newsletter_id, issue_number, address, from, now)
+    }
+
+    fn mark_issue_read(&mut self, newsletter_id : &NEWSLETTER_ID, issue_number : u32, address : &EMAIL_ADDRESS, from : Option<Destination>, now : u64) -> Result<(), MailError> {
+        let job = self.send_jobs.values_mut()
+            .find(|job| job.newsletter_id == *newsletter_id && job.issue_number == issue_number)
+            .ok_or(MailError::NotFound)?;
+        let (domain, delivery) = job.deliveries.iter_mut()
+            .find(|(_, delivery)| delivery.status == DeliveryStatus::Delivered && delivery.address.eq_ignore_ascii_case(address))
+            .ok_or(MailError::NotFound)?;
+        if from.is_some_and(|from| job.destinations.get(domain.as_str()) != Some(&from)) {
+            return Err(MailError::NotAuthorized);
+        }
+        delivery.read_at.get_or_insert(now);
+        Ok(())
+    }
+
+    // Oldest issue first.
+    pub fn get_issue_metrics(&self, newsletter_id : &NEWSLETTER_ID, principal : Principal) -> Result<Vec<IssueMetrics>, MailError> {
+        self.check_newsletter_owner(newsletter_id, principal)?;
+        let jobs : HashMap<u32, &SendJob> = self.send_jobs.values().filter(|job| job.newsletter_id == *newsletter_id).map(|job| (job.issue_number, job)).collect();
+        Ok(self.newsletter_issues.get(newsletter_id).into_iter().flatten()
+            .filter_map(|issue| jobs.get(&issue.number).map(|job| job.metrics(issue)))
+            .collect())
+    }
+
+    // One entry per day with subscriptions or unsubscribes since `since`, oldest first.
+    pub fn get_subscriber_growth(&self, newsletter_id : &NEWSLETTER_ID, since : Option<u64>, principal : Principal) -> Result<Vec<SubscriberGrowth>, MailError> {
+        self.check_newsletter_owner(newsletter_id, principal)?;
+        let days = match self.subscriber_growth.get(newsletter_id) {
+            Some(days) => days,
+            None => return Ok(vec![])
+        };
+        let since = since.map_or(0, |since| since - since % NANOS_PER_DAY);
+        // walks back from today's count, so subscribers from before the days were counted add up
+        let mut subscribers = self.newsletter_subscribers.get(newsletter_id).map_or(0, |set| set.len() as u64);
+        let mut growth = vec![];
+        for (day, (subscribed, unsubscribed)) in days.range(since..).rev() {
+            growth.push(SubscriberGrowth { day: *day, subscribed: *subscribed, unsubscribed: *unsubscribed, subscribers });
+            subscribers = (subscribers + *unsubscribed as u64).saturating_sub(*subscribed as u64);
+        }
+        growth.reverse();
+        Ok(growth)
+    }
+
+    // The issues anyone can read, paid ones stay with the paid subscribers.
+    pub fn get_issues(&self, newsletter_id : &NEWSLETTER_ID) -> Result<Vec<&Issue>, MailError> {
+        if !self.newsletter.contains_key(newsletter_id) {
+            return Err(MailError::NotFound);
+        }
+        Ok(self.newsletter_issues.get(newsletter_id).into_iter().flatten().filter(|issue| issue.tier == Tier::Free).collect())
+    }
+
+    pub fn get_issue_summaries(&self, newsletter_id : &NEWSLETTER_ID) -> Result<Vec<IssueSummary>, MailError> {
+        Ok(self.get_issues(newsletter_id)?.iter().map(|issue| issue.summary()).collect())
+    }
+
+    pub fn get_issue(&self, newsletter_id : &NEWSLETTER_ID, number : u32) -> Result<Issue, MailError> {
+        self.get_issues(newsletter_id)?
+            .into_iter()
+            .find(|issue| issue.number == number)
+            .cloned()
+            .ok_or(MailError::NotFound)
+    }
+
+    pub fn get_newsletter_plan(&self, newsletter_id : &NEWSLETTER_ID) -> Option<NewsletterPlan> {
+        self.newsletter_plans.get(newsletter_id).cloned()
+    }
+
+    // `None` removes the paid tier, paid subscriptions then end with their period.
+    pub fn set_newsletter_plan(&mut self, newsletter_id : &NEWSLETTER_ID, plan : Option<NewsletterPlan>, principal : Principal) -> Result<(), MailError> {
+        self.check_newsletter_owner(newsletter_id, principal)?;
+        match plan {
+            Some(plan) => {
+                plan.validate()?;
+                self.newsletter_plans.insert(newsletter_id.clone(), plan);
+            }
+            None => {
+                self.newsletter_plans.remove(newsletter_id);
+            }
+        }
+        Ok(())
+    }
+
+    // The charge that starts a paid subscription for the subscriber's principal. A cancelled
+    // subscription that is still paid for is simply renewed again and needs no charge.
+    pub fn begin_paid_subscription(&mut self, newsletter_id : &NEWSLETTER_ID, address : &EMAIL_ADDRESS, principal : Principal, now : u64) -> Result<Option<Renewal>, MailError> {
+        let plan = self.newsletter_plans.get(newsletter_id).cloned().ok_or(MailError::NotFound)?;
+        let owner = *self.newsletter_owners.get(newsletter_id).ok_or(MailError::NotFound)?;
+        let address = &address.to_lowercase();
+        let subscription = self.newsletter_subscribers.get_mut(newsletter_id)
+            .and_then(|subscribers| subscribers.get_mut(address))
+            .ok_or(MailError::NotFound)?;
+        if subscription.principal != principal {
+            return Err(MailError::NotAuthorized);
+        }
+        if let Some(paid) = subscription.paid.as_mut() {
+            match paid.status {
+                BillingStatus::Active => return Err(MailError::AddressExist),
+                BillingStatus::Cancelled if paid.paid_until > now => {
+                    paid.status = BillingStatus::Active;
+                    return Ok(None);
+                }
+                _ => {}
+            }
+        }
+        let key = (newsletter_id.clone(), address.clone());
+        if !self.billing_in_progress.insert(key) {
+            return Err(MailError::GeneralError("A payment for this subscription is already in progress".to_string()));
+        }
+        Ok(Some(Renewal { newsletter_id: newsletter_id.clone(), address: address.clone(), payer: principal, owner, plan }))
+    }
+
+    // Stops renewing, the subscription stays paid until the end of the period.
+    pub fn cancel_paid_subscription(&mut self, newsletter_id : &NEWSLETTER_ID, address : &EMAIL_ADDRESS, principal : Principal) -> Result<(), MailError> {
+        let subscription = self.newsletter_subscribers.get_mut(newsletter_id)
+            .and_then(|subscribers| subscribers.get_mut(&address.to_lowercase()))
+            .ok_or(MailError::NotFound)?;
+        if subscription.principal != principal {
+            return Err(MailError::NotAuthorized);
+        }
+        match subscription.paid.as_mut() {
+            Some(paid) if paid.status == BillingStatus::Active => {
+                paid.status = BillingStatus::Cancelled;
+                Ok(())
+            }
+            _ => Err(MailError::NotFound)
+        }
+    }
+
+    // The paid subscriptions due for another period. Cancelled ones and those of newsletters
+    // without a paid tier fall back to free once their period is over.
+    pub fn begin_renewals(&mut self, now : u64) -> Vec<Renewal> {
+        let mut renewals = vec![];
+        for (newsletter_id, subscribers) in self.newsletter_subscribers.iter_mut() {
+            let plan = self.newsletter_plans.get(newsletter_id);
+            let owner = self.newsletter_owners.get(newsletter_id);
+            for (address, subscription) in subscribers.iter_mut() {
+                let paid = match subscription.paid.as_ref() {
+                    Some(paid) if paid.paid_until <= now => paid,
+                    _ => continue
+                };
+                match (&paid.status, plan, owner) {
+                    (BillingStatus::Active, Some(plan), Some(owner)) => {
+                        if self.billing_in_progress.insert((newsletter_id.clone(), address.clone())) {
+                            renewals.push(Renewal { newsletter_id: newsletter_id.clone(), address: address.clone(), payer: paid.payer, owner: *owner, plan: plan.clone() });
+                        }
+                    }
+                    (BillingStatus::Suspended(_), Some(_), _) => {}
+                    _ => subscription.paid = None
+                }
+            }
+        }
+        renewals
+    }
+
+    // Records the outcome of a charge from `begin_paid_subscription` or `begin_renewals`. A
+    // failed renewal suspends the subscription, a failed first charge leaves it free.
+    pub fn finish_billing(&mut self, renewal : &Renewal, result : Result<(), String>, now : u64) {
+        self.billing_in_progress.remove(&(renewal.newsletter_id.clone(), renewal.address.clone()));
+        let subscription = match self.newsletter_subscribers.get_mut(&renewal.newsletter_id).and_then(|subscribers| subscribers.get_mut(&renewal.address)) {
+            Some(subscription) => subscription,
+            None => return
+        };
+        match result {
+            Ok(()) => {
+                // a period starts when it is paid for, so a canister that was stopped does not charge for the gap
+                let start = subscription.paid.as_ref().map_or(now, |paid| cmp::max(paid.paid_until, now));
+                subscription.paid = Some(PaidSubscription {
+                    payer: renewal.payer,
+                    paid_until: start.saturating_add(renewal.plan.period_nanos()),
+                    status: BillingStatus::Active
+                });
+            }
+            Err(reason) => {
+                if let Some(paid) = subscription.paid.as_mut() {
+                    paid.status = BillingStatus::Suspended(reason);
+                }
+            }
+        }
+    }
+
+    pub fn check_newsletter_owner(&self, newsletter_id : &NEWSLETTER_ID, principal : Principal) -> Result<(), MailError> {
+        match self.newsletter_owners.get(newsletter_id) {
+            Some(owner) if *owner == principal => Ok(()),
+            Some(_) => Err(MailError::NotAuthorized),
+            None => Err(MailError::NotFound)
+        }
+    }
+
+    pub fn get_mail_transfer_agent_url(&self) -> String {
+        return self.config.mta_url.clone();
+    }
+
+    pub fn get_mta_max_response_bytes(&self) -> u64 {
+        self.config.mta_max_response_bytes.unwrap_or(DEFAULT_MTA_MAX_RESPONSE_BYTES)
+    }
+
+    pub fn get_fee_schedule(&self) -> FeeSchedule {
+        self.config.get_fee_schedule()
+    }
+
+    pub fn set_fee_schedule(&mut self, schedule : Option<FeeSchedule>) {
+        self.config.fee_schedule = schedule;
+    }
+
+    pub fn get_rate_limits(&self) -> RateLimits {
+        self.config.rate_limits.clone().unwrap_or_default()
+    }
+
+    pub fn set_rate_limits(&mut self, limits : RateLimits) -> Result<(), MailError> {
+        for limit in [&limits.per_caller, &limits.per_sender, &limits.per_receipient].into_iter().flatten() {
+            if limit.capacity == 0 || limit.refill_per_hour == 0 {
+                return Err(MailError::GeneralError("A rate limit needs a capacity and a refill rate".to_string()));
+            }
+        }
+        self.config.rate_limits = Some(limits);
+        Ok(())
+    }
+
+    fn rate_limit_of(&self, key : &RateKey) -> Option<RateLimit> {
+        let limits = self.config.rate_limits.clone().unwrap_or_default();
+        match key {
+            RateKey::Caller(_) => limits.per_caller,
+            RateKey::Sender(_) => limits.per_sender,
+            RateKey::Receipient(_) => limits.per_receipient
+        }
+    }
+
+    // tokens in the bucket at `now`, a missing bucket is full
+    fn rate_tokens(&self, key : &RateKey, limit : &RateLimit, now : u64) -> f64 {
+        match self.rate_buckets.get(key) {
+            Some(bucket) => {
+                let elapsed_hours = now.saturating_sub(bucket.updated_at) as f64 / 3_600_000_000_000.0;
+                (bucket.tokens + elapsed_hours * limit.refill_per_hour as f64).min(limit.capacity as f64)
+            },
+            None => limit.capacity as f64
+        }
+    }
+
+    // Takes a token from every bucket, or none of them when one is empty.
+    pub fn take_rate_tokens(&mut self, keys : &[RateKey], now : u64) -> Result<(), MailError> {
+        let mut taken = vec![];
+        let mut retry_after = 0;
+        for key in keys {
+            let limit = match self.rate_limit_of(key) {
+                Some(limit) => limit,
+                None => continue
+            };
+            let tokens = self.rate_tokens(key, &limit, now);
+            if tokens < 1.0 {
+                let seconds = ((1.0 - tokens) * 3600.0 / limit.refill_per_hour as f64).ceil() as u64;
+                retry_after = retry_after.max(seconds.max(1));
+            }
+            taken.push((key.clone(), tokens - 1.0));
+        }
+        if retry_after > 0 {
+            return Err(MailError::RateLimited { retry_after });
+        }
+
+        if self.rate_buckets.len() > RATE_BUCKETS_PRUNE_AT {
+            let full : Vec<RateKey> = self.rate_buckets
+                .keys()
+                .filter(|key| self.rate_limit_of(key).is_none_or(|limit| self.rate_tokens(key, &limit, now) >= limit.capacity as f64))
+                .cloned()
+                .collect();
+            for key in full {
+                self.rate_buckets.remove(&key);
+            }
+        }
+        for (key, tokens) in taken {
+            self.rate_buckets.insert(key, RateBucket { tokens, updated_at: now });
+        }
+        Ok(())
+    }
+
+    // A bucket for every local mailbox among the receipients.
+    pub fn receipient_rate_keys(&self, header : &MailHeader) -> Vec<RateKey> {
+        let mut keys = vec![];
+        let receipients = header.to.iter().chain(header.cc.iter().flatten()).chain(header.bcc.iter().flatten());
+        for receipient in receipients {
+            if let Some(mailbox) = self.resolve_receipient(receipient) {
+                let key = RateKey::Receipient(mailbox);
+                if !keys.contains(&key) {
+                    keys.push(key);
+                }
+            }
+        }
+        keys
+    }
+
+    pub fn get_rate_buckets(&self, now : u64) -> Vec<RateBucketInfo> {
+        self.rate_buckets
+            .keys()
+            .filter_map(|key| {
+                let limit = self.rate_limit_of(key)?;
+                Some(RateBucketInfo {
+                    key: key.clone(),
+                    tokens: self.rate_tokens(key, &limit, now),
+                    capacity: limit.capacity,
+                    updated_at: self.rate_buckets[key].updated_at
+                })
+            })
+            .collect()
+    }
+
+    // Refills one bucket, or all of them when no key is given.
+    pub fn reset_rate_buckets(&mut self, key : Option<RateKey>) -> Result<(), MailError> {
+        match key {
+            Some(key) => self.rate_buckets.remove(&key).map(|_| ()).ok_or(MailError::NotFound),
+            None => {
+                self.rate_buckets.clear();
+                Ok(())
+            }
+        }
+    }
+
+    pub fn get_mta_request_cycles(&self) -> u128 {
+        self.config.mta_request_cycles.unwrap_or(DEFAULT_MTA_REQUEST_CYCLES)
+    }
+
+    pub fn add_to_sent(&mut self, mail_id : MAIL_ID,user_addr : EMAIL_ADDRESS) {
+        let set = self.sent.entry(user_addr).or_insert(HashSet::new());
+        set.insert(mail_id);
+    }
+
+    pub fn get_ecdsa_public_key(&self) -> Option<EcdsaPublicKeyInfo> {
+        self.ecdsa_public_key.clone()
+    }
+
+    pub fn set_ecdsa_public_key(&mut self, key : EcdsaPublicKeyInfo) {
+        self.ecdsa_public_key = Some(key)
+    }
+
+    pub fn create_app_password(&mut self, email : &EMAIL_ADDRESS, label : String, password : &str) -> Result<(), MailError> {
+        let passwords = self.app_passwords.entry(email.clone()).or_insert(vec![]);
+        if passwords.iter().any(|p| p.label == label) {
+            return Err(MailError::GeneralError("An app password with this label exists".to_string()));
+        }
+
+        passwords.push(AppPassword { label, hash: sha256(password), created_at: time() });
+        Ok(())
+    }
+
+    pub fn revoke_app_password(&mut self, email : &EMAIL_ADDRESS, label : String) -> Result<(), MailError> {
+        let passwords = self.app_passwords.get_mut(email).ok_or(MailError::NotFound)?;
+        let len = passwords.len();
+        passwords.retain(|p| p.label != label);
+        if passwords.len() == len {
+            return Err(MailError::NotFound);
+        }
+        Ok(())
+    }
+
+    pub fn get_app_passwords(&self, email : &EMAIL_ADDRESS) -> Result<Vec<AppPasswordInfo>, MailError> {
+        let passwords = self.app_passwords.get(email).cloned().unwrap_or_default();
+        Ok(passwords.into_iter().map(|p| AppPasswordInfo { label: p.label, created_at: p.created_at }).collect())
+    }
+
+    pub fn verify_app_password(&self, email_address : &EMAIL_ADDRESS, password : &str) -> Result<(), MailError> {
+        let hash = sha256(password);
+        let passwords = self.app_passwords.get(email_address).ok_or(MailError::NotAuthorized)?;
+        if passwords.iter().any(|p| p.hash == hash) {
+            Ok(())
+        } else {
+            Err(MailError::NotAuthorized)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use futures::executor::block_on;
+
+    use super::*;
+    use crate::icrc::{Account, TokenLedger, TransferArg, TransferError, TransferFromArgs, TransferFromError};
+
+    fn outgoing_mail() -> OutgoingMail {
+        OutgoingMail {
+            id: "mail-1".to_string(),
+            rcpt_to: vec!["bob@example.com".to_string()],
+            header: MailHeader { from: "alice@dmail.ai".to_string(), to: vec!["bob@example.com".to_string()], subject: Some("Hi".to_string()), ..Default::default() },
+            body: Rcbytes(Arc::new(ByteBuf::from(b"Hello Bob".to_vec())))
+        }
+    }
+
+    #[test]
+    fn signature_covers_the_whole_outgoing_mail() {
+        use k256::ecdsa::{signature::hazmat::PrehashSigner, Signature, SigningKey};
+
+        let signing_key = SigningKey::from_slice(&[7u8; 32]).unwrap();
+        let public_key = signing_key.verifying_key().to_encoded_point(true);
+        let out = outgoing_mail();
+        let signature : Signature = signing_key.sign_prehash(&out.signing_digest().unwrap()).unwrap();
+        let sig = hex::encode(signature.to_bytes());
+        assert!(verify_signature(public_key.as_bytes(), &out.signing_digest().unwrap(), &sig).is_ok());
+
+        let mut rerouted = outgoing_mail();
+        rerouted.rcpt_to.push("eve@example.com".to_string());
+        assert!(verify_signature(public_key.as_bytes(), &rerouted.signing_digest().unwrap(), &sig).is_err());
+
+        let mut rewritten = outgoing_mail();
+        rewritten.body = Rcbytes(Arc::new(ByteBuf::from(b"Send money".to_vec())));
+        assert!(verify_signature(public_key.as_bytes(), &rewritten.signing_digest().unwrap(), &sig).is_err());
+
+        let mut resent = outgoing_mail();
+        resent.header.from = "mallory@dmail.ai".to_string();
+        assert!(verify_signature(public_key.as_bytes(), &resent.signing_digest().unwrap(), &sig).is_err());
+    }
+
+    fn ledger_with(email : &str) -> Ledger {
+        let mut ledger = Ledger::default();
+        ledger.create_user(email.to_string(), Principal::anonymous().to_text()).unwrap();
+        ledger
+    }
+
+    // A canister serving dmail.ai with alice's mailbox on it.
+    fn dmail_ledger() -> (Ledger, EMAIL_ADDRESS) {
+        let mut ledger = Ledger::default();
+        ledger.init(LedgerConfiguration { domain_name: "dmail.ai".to_string(), ..Default::default() });
+        let email = "alice@dmail.ai".to_string();
+        ledger.create_user(email.clone(), Principal::from_slice(&[1]).to_text()).unwrap();
+        (ledger, email)
+    }
+
+    fn mail_to(from : &str, to : &[&str], subject : &str) -> Mail {
+        Mail {
+            correlation_id: None,
+            header: MailHeader {
+                from: from.to_string(),
+                to: to.iter().map(|to| to.to_string()).collect(),
+                subject: Some(subject.to_string()),
+                ..Default::default()
+            },
+            body: Rcbytes(Arc::new(ByteBuf::from(b"Hello".to_vec()))),
+            reply_messages: None
+        }
+    }
+
+    fn inbox_ids(ledger : &Ledger, email : &EMAIL_ADDRESS) -> Vec<MAIL_ID> {
+        let mut ids : Vec<MAIL_ID> = ledger.get_folder_mails(email, MailFolder::Inbox, None).unwrap().into_iter().map(|data| data.mail_id).collect();
+        ids.sort();
+        ids
+    }
+
+    fn mbox_message(mbox : &mut Vec<u8>, subject : &str, body : &str) {
+        let message = format!("From: bob@dmail.ai\r\nTo: alice@dmail.ai\r\nSubject: {}\r\nDate: Tue, 14 Nov 2023 22:13:20 +0000\r\n\r\n{}\r\n", subject, body);
+        mbox::push_message(mbox, "bob@dmail.ai", 1_700_000_000 * 1_000_000_000, message.as_bytes());
+    }
+
+    #[test]
+    fn chunked_import_keeps_the_messages_around_an_oversized_one() {
+        let email = "alice@dmail.ai".to_string();
+        let mut ledger = ledger_with(&email);
+        let mut mbox = vec![];
+        mbox_message(&mut mbox, "first", "small");
+        mbox_message(&mut mbox, "huge", &"a".repeat(2 * MBOX_IMPORT_PENDING_LIMIT));
+        mbox_message(&mut mbox, "last", "small");
+
+        let chunks : Vec<&[u8]> = mbox.chunks(1_000_000).collect();
+        let (mut imported, mut failed) = (0, 0);
+        for (index, chunk) in chunks.iter().enumerate() {
+            let report = ledger.import_mbox_chunk(&email, chunk, index == chunks.len() - 1, &index.to_string()).unwrap();
+            assert!(report.pending_bytes <= MBOX_IMPORT_PENDING_LIMIT as u64);
+            imported += report.imported;
+            failed += report.failed;
+        }
+
+        assert_eq!((imported, failed), (2, 1));
+        assert!(ledger.mbox_imports.is_empty());
+        let mut subjects : Vec<String> = ledger.inboxes[&email].iter().map(|id| ledger.mails[id].header.subject.clone().unwrap()).collect();
+        subjects.sort();
+        assert_eq!(subjects, vec!["first", "last"]);
+    }
+
+    #[test]
+    fn a_recreated_mailbox_starts_empty() {
+        let email = "alice@dmail.ai".to_string();
+        let mut ledger = ledger_with(&email);
+        let mut mbox = vec![];
+        mbox_message(&mut mbox, "kept", "in the inbox");
+        ledger.import_mbox_chunk(&email, &mbox, true, "seed").unwrap();
+        ledger.add_to_sent("sent-1".to_string(), email.clone());
+        ledger.mails.insert("sent-1".to_string(), Mail { correlation_id: None, header: outgoing_mail().header, body: outgoing_mail().body, reply_messages: None });
+        // an import that was never finished
+        ledger.import_mbox_chunk(&email, b"From bob@dmail.ai Tue Nov 14 22:13:20 2023\nSubject: cut", false, "seed-2").unwrap();
+
+        ledger.delete_mailbox(&email).unwrap();
+        ledger.public_create_user(email.clone(), Principal::management_canister()).unwrap();
+
+        for folder in [MailFolder::Inbox, MailFolder::Sent, MailFolder::Trash] {
+            assert!(ledger.get_folder_mails(&email, folder, None).unwrap().is_empty());
+        }
+        let mut mbox = vec![];
+        mbox_message(&mut mbox, "new", "after the import was dropped");
+        let report = ledger.import_mbox_chunk(&email, &mbox, true, "seed-3").unwrap();
+        assert_eq!((report.imported, report.failed), (1, 0));
+    }
+
+    // An ICRC-2 ledger kept in memory, `canister` is the only spender and `down` fails every call.
+    struct MockLedger {
+        canister : Principal,
+        fee : Nat,
+        balances : RefCell<HashMap<Principal, Nat>>,
+        allowances : RefCell<HashMap<Principal, Nat>>,
+        down : Cell<bool>
+    }
+
+    impl MockLedger {
+        fn new(canister : Principal) -> Self {
+            MockLedger { canister, fee: Nat::from(10u64), balances: RefCell::default(), allowances: RefCell::default(), down: Cell::new(false) }
+        }
+
+        fn balance(&self, owner : Principal) -> Nat {
+            self.balances.borrow().get(&owner).cloned().unwrap_or(Nat::from(0u64))
+        }
+
+        fn available(&self) -> Result<(), String> {
+            if self.down.get() { Err("Canister is stopped".to_string()) } else { Ok(()) }
+        }
+    }
+
+    impl TokenLedger for MockLedger {
+        async fn fee(&self) -> Result<Nat, String> {
+            self.available()?;
+            Ok(self.fee.clone())
+        }
+
+        async fn transfer(&self, args : TransferArg) -> Result<Result<Nat, TransferError>, String> {
+            self.available()?;
+            let total = args.amount.clone() + self.fee.clone();
+            let balance = self.balance(self.canister);
+            if balance < total {
+                return Ok(Err(TransferError::InsufficientFunds { balance }));
+            }
+            let mut balances = self.balances.borrow_mut();
+            balances.insert(self.canister, balance - total);
+            *balances.entry(args.to.owner).or_insert(Nat::from(0u64)) += args.amount;
+            Ok(Ok(Nat::from(balances.len())))
+        }
+
+        async fn transfer_from(&self, args : TransferFromArgs) -> Result<Result<Nat, TransferFromError>, String> {
+            self.available()?;
+            let total = args.amount.clone() + self.fee.clone();
+            let allowance = self.allowances.borrow().get(&args.from.owner).cloned().unwrap_or(Nat::from(0u64));
+            if allowance < total {
+                return Ok(Err(TransferFromError::InsufficientAllowance { allowance }));
+            }
+            let balance = self.balance(args.from.owner);
+            if balance < total {
+                return Ok(Err(TransferFromError::InsufficientFunds { balance }));
+            }
+            self.allowances.borrow_mut().insert(args.from.owner, allowance - total.clone());
+            let mut balances = self.balances.borrow_mut();
+            balances.insert(args.from.owner, balance - total);
+            *balances.entry(args.to.owner).or_insert(Nat::from(0u64)) += args.amount;
+            Ok(Ok(Nat::from(balances.len())))
+        }
+    }
+
+    #[test]
+    fn postage_is_collected_and_withdrawn_through_the_token_ledger() {
+        let (canister, sender, owner) = (Principal::from_slice(&[1]), Principal::from_slice(&[2]), Principal::from_slice(&[3]));
+        let alice = "alice@dmail.ai".to_string();
+        let mut ledger = ledger_with(&alice);
+        ledger.set_postage_price(&alice, Some(Nat::from(100u64))).unwrap();
+        let token = MockLedger::new(canister);
+        token.balances.borrow_mut().insert(sender, Nat::from(1000u64));
+
+        let header = MailHeader { from: "bob@example.com".to_string(), to: vec![alice.clone()], ..Default::default() };
+        let due = ledger.postage_due(&header);
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0], (alice.clone(), Nat::from(100u64)));
+
+        // nothing approved yet
+        let result = block_on(icrc::collect_postage(&token, sender, canister, Nat::from(100u64)));
+        assert!(matches!(result, Err(MailError::PostageRequired { amount }) if amount == 100u64));
+        assert_eq!(token.balance(sender), 1000u64);
+
+        token.allowances.borrow_mut().insert(sender, Nat::from(150u64));
+        block_on(icrc::collect_postage(&token, sender, canister, Nat::from(100u64))).unwrap();
+        assert_eq!(token.balance(sender), 890u64);
+        assert_eq!(token.balance(canister), 100u64);
+        // what record_postage adds for a delivered mail, it reads the canister clock
+        ledger.credit_postage(&alice, Nat::from(100u64));
+
+        // the rest of the allowance does not cover a second mail
+        let result = block_on(icrc::collect_postage(&token, sender, canister, Nat::from(100u64)));
+        assert!(matches!(result, Err(MailError::PostageRequired { .. })));
+
+        ledger.debit_postage(&alice, &Nat::from(60u64)).unwrap();
+        block_on(icrc::pay_out(&token, Account::from(owner), Nat::from(60u64))).unwrap();
+        assert_eq!(token.balance(owner), 50u64);
+        assert_eq!(token.balance(canister), 40u64);
+        assert_eq!(ledger.get_postage_balance(&alice), 40u64);
+        assert!(ledger.debit_postage(&alice, &Nat::from(41u64)).is_err());
+        assert!(block_on(icrc::pay_out(&token, Account::from(owner), Nat::from(10u64))).is_err());
+    }
+
+    #[test]
+    fn a_failed_refund_keeps_the_postage() {
+        let (canister, sender) = (Principal::from_slice(&[1]), Principal::from_slice(&[2]));
+        let alice = "alice@dmail.ai".to_string();
+        let mut ledger = ledger_with(&alice);
+        let token = MockLedger::new(canister);
+        token.balances.borrow_mut().insert(canister, Nat::from(100u64));
+        ledger.credit_postage(&alice, Nat::from(100u64));
+        ledger.postage_payments.insert(alice.clone(), vec![PostagePayment { mail_id: "mail-1".to_string(), sender: "bob@example.com".to_string(), payer: sender, amount: Nat::from(100u64), paid_at: 0, refunded: false }]);
+
+        // refund_postage: the payment comes off the balance and is given back if the transfer fails
+        token.down.set(true);
+        let payment = ledger.begin_postage_refund(&alice, &"mail-1".to_string()).unwrap();
+        assert_eq!(ledger.get_postage_balance(&alice), 0u64);
+        assert!(block_on(icrc::pay_out(&token, Account::from(payment.payer), payment.amount)).is_err());
+        ledger.cancel_postage_refund(&alice, &"mail-1".to_string());
+        assert_eq!(ledger.get_postage_balance(&alice), 100u64);
+        assert!(!ledger.get_postage_payments(&alice)[0].refunded);
+
+        token.down.set(false);
+        let payment = ledger.begin_postage_refund(&alice, &"mail-1".to_string()).unwrap();
+        block_on(icrc::pay_out(&token, Account::from(payment.payer), payment.amount)).unwrap();
+        assert_eq!(token.balance(sender), 90u64);
+        assert_eq!(ledger.get_postage_balance(&alice), 0u64);
+        assert!(ledger.get_postage_payments(&alice)[0].refunded);
+        // the sender is a contact now and pays no postage
+        assert!(ledger.is_contact(&alice, &"bob@example.com".to_string()));
+    }
+
+    #[test]
+    fn fees_grow_per_started_kilobyte() {
+        assert_eq!(FeeSchedule::default().fee_for(5_000), SUBMIT_CALL_PAYMENT);
+        let schedule = FeeSchedule { base_fee: 100, per_kb_fee: 10 };
+        assert_eq!(schedule.fee_for(0), 100);
+        assert_eq!(schedule.fee_for(1), 110);
+        assert_eq!(schedule.fee_for(1024), 110);
+        assert_eq!(schedule.fee_for(1025), 120);
+        assert_eq!(schedule.fee_for(u64::MAX), 100 + 10 * (u64::MAX / 1024 + 1));
+    }
+
+    #[test]
+    fn malformed_receipients_are_errors() {
+        let header = outgoing_mail().header;
+        let mut mail = Mail { correlation_id: None, header, body: outgoing_mail().body, reply_messages: None };
+        mail.header.cc = Some(vec!["carol@dmail.ai".to_string()]);
+        assert_eq!(Ledger::get_receipients_domains(&mail).unwrap(), vec!["example.com", "dmail.ai"]);
+
+        mail.header.bcc = Some(vec!["not an address".to_string()]);
+        assert!(matches!(Ledger::get_receipients_domains(&mail), Err(MailError::InvalidAddress(address)) if address == "not an address"));
+        mail.header.bcc = None;
+        mail.header.to.push("bob@".to_string());
+        assert!(matches!(Ledger::get_receipients_domains(&mail), Err(MailError::InvalidAddress(address)) if address == "bob@"));
+    }
+
+    #[test]
+    fn invalid_principals_are_errors() {
+        let mut ledger = Ledger::default();
+        assert!(matches!(ledger.create_user("alice@dmail.ai".to_string(), "not a principal".to_string()), Err(MailError::GeneralError(_))));
+        assert!(!ledger.inboxes.contains_key("alice@dmail.ai"));
+        // nothing is configured
+        assert!(ledger.get_token_ledger().is_err());
+        assert!(Principal::from_text(ledger.get_registry_address()).is_err());
+    }
+
+    #[test]
+    fn unknown_mailboxes_are_errors() {
+        let mut ledger = Ledger::default();
+        let email = "nobody@dmail.ai".to_string();
+        assert!(matches!(ledger.get_folder_mails(&email, MailFolder::Inbox, None), Err(MailError::NoUserAddressFound)));
+        assert!(matches!(ledger.import_mbox_chunk(&email, b"", true, "seed"), Err(MailError::NoUserAddressFound)));
+        assert!(matches!(ledger.get_inbound_record(&email, "mail-1".to_string()), Err(MailError::NoUserAddressFound)));
+        assert!(matches!(ledger.delete_mailbox(&email), Err(MailError::NoUserAddressFound)));
+        let reply = MailReply { content: outgoing_mail().body, sender_address: email.clone(), principal: None, timestamp: 0 };
+        assert!(matches!(ledger.store_reply("unknown".to_string(), reply), Err(MailError::GeneralError(_))));
+    }
+
+    #[test]
+    fn unknown_newsletters_are_errors() {
+        let mut ledger = Ledger::default();
+        let reader = "bob@example.com".to_string();
+        assert!(matches!(ledger.subscribe_to_newsletter("missing".to_string(), reader.clone(), Principal::anonymous(), "token", "https://news.example", 0), Err(MailError::NotFound)));
+        assert!(matches!(ledger.subscribe_to_newsletter("missing".to_string(), "bob".to_string(), Principal::anonymous(), "token", "https://news.example", 0), Err(MailError::InvalidAddress(_))));
+        assert!(matches!(ledger.unsubscribe_to_newsletter("missing".to_string(), reader, Principal::anonymous(), 0), Err(MailError::NotFound)));
+        assert!(matches!(ledger.unsubscribe_with_token("not.a.token", 0), Err(MailError::GeneralError(_))));
+        assert!(matches!(ledger.unsubscribe_with_token("garbage", 0), Err(MailError::GeneralError(_))));
+    }
+
+    #[test]
+    fn bad_keys_are_errors() {
+        let digest = outgoing_mail().signing_digest().unwrap();
+        assert_eq!(verify_signature(b"not a key", &digest, "00"), Err("Invalid public key".to_string()));
+        let public_key = k256::ecdsa::SigningKey::from_slice(&[7u8; 32]).unwrap().verifying_key().to_encoded_point(true);
+        assert_eq!(verify_signature(public_key.as_bytes(), &digest, "zz"), Err("Signature is not valid hex".to_string()));
+        assert_eq!(verify_signature(public_key.as_bytes(), &digest, "00"), Err("Invalid signature encoding".to_string()));
+        // exchange_key has nothing to hand out before the key is fetched
+        assert!(Ledger::default().get_ecdsa_public_key().is_none());
+    }
+
+    #[test]
+    fn subscribers_are_keyed_by_their_lower_case_address() {
+        let mut ledger = Ledger::default();
+        let (owner, reader) = (Principal::from_slice(&[1]), Principal::from_slice(&[2]));
+        let id = "news".to_string();
+        ledger.create_newletter(id.clone(), Newsletter::new("News".to_string(), "Weekly news".to_string()), owner).unwrap();
+        let subscribers = |ledger : &Ledger| ledger.get_newsletter_subscribers(id.clone()).unwrap();
+
+        ledger.subscribe_to_newsletter(id.clone(), "Bob@Example.COM".to_string(), reader, "token-1", "https://news.example", 1).unwrap();
+        let again = ledger.subscribe_to_newsletter(id.clone(), "bob@example.com".to_string(), reader, "token-2", "https://news.example", 2);
+        assert!(matches!(again, Err(MailError::GeneralError(_))));
+        ledger.confirm_subscription("token-1", 3).unwrap();
+        assert_eq!(subscribers(&ledger), vec!["bob@example.com"]);
+        let again = ledger.subscribe_to_newsletter(id.clone(), "BOB@example.com".to_string(), reader, "token-3", "https://news.example", 4);
+        assert!(matches!(again, Err(MailError::AddressExist)));
+
+        ledger.unsubscribe_to_newsletter(id.clone(), "bob@EXAMPLE.com".to_string(), reader, 5).unwrap();
+        assert!(subscribers(&ledger).is_empty());
+
+        ledger.subscribe_to_newsletter(id.clone(), "bob@example.com".to_string(), reader, "token-4", "https://news.example", 6).unwrap();
+        ledger.confirm_subscription("token-4", 7).unwrap();
+        ledger.set_newsletter_secret([9u8; 32]);
+        let token = ledger.unsubscribe_token(&id, &"Bob@Example.com".to_string()).unwrap();
+        assert_eq!(ledger.unsubscribe_with_token(&token, 8).unwrap(), "News");
+        assert!(subscribers(&ledger).is_empty());
+    }
+
+    #[test]
+    fn a_half_finished_send_job_survives_an_upgrade() {
+        let mut ledger = Ledger::default();
+        ledger.init(LedgerConfiguration { domain_name: "dmail.ai".to_string(), ..Default::default() });
+        ledger.set_newsletter_secret([9u8; 32]);
+        let owner = Principal::from_slice(&[1]);
+        let id = "news".to_string();
+        ledger.create_newletter(id.clone(), Newsletter::new("News".to_string(), "Weekly news".to_string()), owner).unwrap();
+        for (n, address) in ["bob@example.com", "carol@example.com", "dave@example.org"].iter().enumerate() {
+            let token = format!("token-{}", n);
+            ledger.subscribe_to_newsletter(id.clone(), address.to_string(), owner, &token, "https://news.example", 1).unwrap();
+            ledger.confirm_subscription(&token, 2).unwrap();
+        }
+        let mail = Mail {
+            correlation_id: None,
+            header: MailHeader { from: "news@dmail.ai".to_string(), subject: Some("Issue one".to_string()), ..Default::default() },
+            body: Rcbytes(Arc::new(ByteBuf::from(b"Hello readers".to_vec()))),
+            reply_messages: None
+        };
+        let job_id = ledger.create_send_job(&id, mail, Tier::Free, owner, 3).unwrap();
+        assert!(ledger.claim_send_job(job_id, 4));
+        ledger.set_send_job_destination(job_id, "example.com", Destination::Canister(Principal::from_slice(&[7])));
+        ledger.record_deliveries(job_id, vec![("bob@example.com".to_string(), Ok(())), ("carol@example.com".to_string(), Err(MailError::NotFound))], 5);
+
+        let bytes = candid::encode_one(ledger.take_newsletter_state()).unwrap();
+        // the heap starts out empty after an upgrade
+        let mut ledger = Ledger::default();
+        ledger.restore_newsletter_state(candid::decode_one(&bytes).unwrap());
+
+        // the lease of the runner that was cut off does not hold the job back
+        assert_eq!(ledger.stale_send_jobs(6), vec![job_id]);
+        let statuses : Vec<(String, DeliveryStatus)> = ledger.get_send_job_deliveries(job_id, owner).unwrap().into_iter().map(|delivery| (delivery.address, delivery.status)).collect();
+        assert_eq!(ledger.get_send_jobs(&id, owner).unwrap().len(), 1);
+        assert_eq!(statuses, vec![
+            ("bob@example.com".to_string(), DeliveryStatus::Delivered),
+            ("carol@example.com".to_string(), DeliveryStatus::Failed(MailError::NotFound.to_string())),
+            ("dave@example.org".to_string(), DeliveryStatus::Pending)
+        ]);
+        assert_eq!(ledger.get_issue(&id, 1).unwrap().subject, "Issue one");
+        assert_eq!(ledger.get_send_job_destination(job_id, "example.com"), Some(Destination::Canister(Principal::from_slice(&[7]))));
+        let (domain, mails) = ledger.next_send_batch(job_id, "https://news.example", 7).unwrap();
+        assert_eq!(domain, "example.org");
+        assert_eq!(mails.len(), 1);
+        assert_eq!(mails[0].header.to, vec!["dave@example.org".to_string()]);
+        assert_eq!(mails[0].body.0.as_slice(), b"Hello readers");
+        assert_eq!(mails[0].header.get_header("List-Id"), Some("\"News\" <news.dmail.ai>"));
+        let token = ledger.unsubscribe_token(&id, &"dave@example.org".to_string()).unwrap();
+        assert_eq!(mails[0].header.get_header("List-Unsubscribe"), Some(format!("<https://news.example/unsubscribe?token={}>", token).as_str()));
+
+        ledger.record_deliveries(job_id, vec![("dave@example.org".to_string(), Ok(()))], 8);
+        assert!(ledger.stale_send_jobs(9).is_empty());
+        assert_eq!(ledger.create_send_job(&id, mails[0].clone(), Tier::Free, owner, 10).unwrap(), job_id + 1);
+    }
+
+    #[test]
+    fn paid_issues_are_left_out_of_the_archive() {
+        let mut ledger = Ledger::default();
+        let owner = Principal::from_slice(&[1]);
+        let id = "news".to_string();
+        ledger.create_newletter(id.clone(), Newsletter::new("News".to_string(), "Weekly news".to_string()), owner).unwrap();
+        ledger.subscribe_to_newsletter(id.clone(), "bob@example.com".to_string(), owner, "token", "https://news.example", 1).unwrap();
+        ledger.confirm_subscription("token", 2).unwrap();
+        let issue = |subject : &str| Mail {
+            correlation_id: None,
+            header: MailHeader { from: "news@dmail.ai".to_string(), subject: Some(subject.to_string()), ..Default::default() },
+            body: Rcbytes(Arc::new(ByteBuf::from(b"Hello readers".to_vec()))),
+            reply_messages: None
+        };
+
+        let paid = ledger.create_send_job(&id, issue("For paying readers"), Tier::Paid, owner, 3);
+        assert!(matches!(paid, Err(MailError::GeneralError(_))));
+        let plan = NewsletterPlan { price: Nat::from(100u64), period_seconds: MIN_BILLING_PERIOD_SECONDS };
+        ledger.set_newsletter_plan(&id, Some(plan), owner).unwrap();
+        ledger.create_send_job(&id, issue("For everyone"), Tier::Free, owner, 3).unwrap();
+        ledger.create_send_job(&id, issue("For paying readers"), Tier::Paid, owner, 4).unwrap();
+        ledger.create_send_job(&id, issue("For everyone again"), Tier::Free, owner, 5).unwrap();
+
+        let summaries : Vec<(u32, String)> = ledger.get_issue_summaries(&id).unwrap().into_iter().map(|summary| (summary.number, summary.subject)).collect();
+        assert_eq!(summaries, vec![(1, "For everyone".to_string()), (3, "For everyone again".to_string())]);
+        assert!(matches!(ledger.get_issue(&id, 2), Err(MailError::NotFound)));
+        assert!(matches!(ledger.get_issues(&"other".to_string()), Err(MailError::NotFound)));
+    }
+
+    #[test]
+    fn canister_ids_are_told_from_users() {
+        assert!(is_canister_id(&Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap()));
+        assert!(is_canister_id(&Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap()));
+        assert!(!is_canister_id(&Principal::self_authenticating([7u8; 44])));
+        assert!(!is_canister_id(&Principal::anonymous()));
+        assert!(!is_canister_id(&Principal::management_canister()));
+    }
+
+    #[test]
+    fn paid_subscriptions_are_charged_renewed_suspended_and_cancelled() {
+        let (canister, owner, reader) = (Principal::from_slice(&[9]), Principal::from_slice(&[1]), Principal::from_slice(&[2]));
+        let (id, address) = ("news".to_string(), "bob@example.com".to_string());
+        let mut ledger = Ledger::default();
+        ledger.create_newletter(id.clone(), Newsletter::new("News".to_string(), "Weekly news".to_string()), owner).unwrap();
+        ledger.subscribe_to_newsletter(id.clone(), address.clone(), reader, "token", "https://news.example", 1).unwrap();
+        ledger.confirm_subscription("token", 2).unwrap();
+        let plan = NewsletterPlan { price: Nat::from(100u64), period_seconds: MIN_BILLING_PERIOD_SECONDS };
+        let period = plan.period_nanos();
+        ledger.set_newsletter_plan(&id, Some(plan), owner).unwrap();
+        let token = MockLedger::new(canister);
+        token.balances.borrow_mut().insert(reader, Nat::from(1000u64));
+        // what the billing timer and `start_paid_subscription` do with a renewal
+        let bill = |ledger : &mut Ledger, renewal : Renewal, now : u64| {
+            let result = block_on(icrc::charge_subscription(&token, renewal.payer, renewal.owner, renewal.plan.price.clone()));
+            ledger.finish_billing(&renewal, result.as_ref().map(|_| ()).map_err(|err| err.to_string()), now);
+            result
+        };
+        let paid = |ledger : &Ledger| ledger.get_subscribers(&id, owner).unwrap().remove(0).paid;
+
+        // the first charge fails without an allowance and the subscription stays free
+        let renewal = ledger.begin_paid_subscription(&id, &address, reader, 10).unwrap().unwrap();
+        assert!(matches!(bill(&mut ledger, renewal, 10), Err(MailError::PaymentRequired { .. })));
+        assert!(paid(&ledger).is_none());
+
+        token.allowances.borrow_mut().insert(reader, Nat::from(1000u64));
+        assert!(matches!(ledger.begin_paid_subscription(&id, &address, Principal::from_slice(&[3]), 10), Err(MailError::NotAuthorized)));
+        let renewal = ledger.begin_paid_subscription(&id, &"Bob@Example.com".to_string(), reader, 10).unwrap().unwrap();
+        assert!(matches!(ledger.begin_paid_subscription(&id, &address, reader, 10), Err(MailError::GeneralError(_))));
+        bill(&mut ledger, renewal, 10).unwrap();
+        assert_eq!(paid(&ledger).unwrap().paid_until, 10 + period);
+        assert_eq!(paid(&ledger).unwrap().status, BillingStatus::Active);
+        assert_eq!(token.balance(owner), Nat::from(100u64));
+        assert_eq!(token.balance(reader), Nat::from(890u64));
+        assert!(matches!(ledger.begin_paid_subscription(&id, &address, reader, 11), Err(MailError::AddressExist)));
+
+        // renewed once the period is over, the next one starts when it is paid for
+        assert!(ledger.begin_renewals(9 + period).is_empty());
+        let renewals = ledger.begin_renewals(20 + period);
+        assert_eq!(renewals.len(), 1);
+        assert!(ledger.begin_renewals(20 + period).is_empty());
+        bill(&mut ledger, renewals[0].clone(), 20 + period).unwrap();
+        assert_eq!(paid(&ledger).unwrap().paid_until, 20 + 2 * period);
+        assert_eq!(token.balance(owner), Nat::from(200u64));
+
+        // a renewal the allowance no longer covers suspends the subscription
+        token.allowances.borrow_mut().insert(reader, Nat::from(0u64));
+        let renewals = ledger.begin_renewals(20 + 2 * period);
+        assert!(bill(&mut ledger, renewals[0].clone(), 20 + 2 * period).is_err());
+        let suspended = paid(&ledger).unwrap();
+        assert!(matches!(suspended.status, BillingStatus::Suspended(_)));
+        assert!(!suspended.is_active(20 + 2 * period));
+        assert!(ledger.begin_renewals(20 + 3 * period).is_empty());
+        assert!(paid(&ledger).is_some());
+
+        // paying again starts a new period from now
+        token.allowances.borrow_mut().insert(reader, Nat::from(1000u64));
+        let renewal = ledger.begin_paid_subscription(&id, &address, reader, 30 + 3 * period).unwrap().unwrap();
+        bill(&mut ledger, renewal, 30 + 3 * period).unwrap();
+        assert_eq!(paid(&ledger).unwrap().paid_until, 30 + 4 * period);
+        assert_eq!(paid(&ledger).unwrap().status, BillingStatus::Active);
+
+        // a cancelled subscription stays paid until the end of the period and then falls back to free
+        assert!(matches!(ledger.cancel_paid_subscription(&id, &address, owner), Err(MailError::NotAuthorized)));
+        ledger.cancel_paid_subscription(&id, &address, reader).unwrap();
+        assert_eq!(paid(&ledger).unwrap().status, BillingStatus::Cancelled);
+        assert!(paid(&ledger).unwrap().is_active(31 + 3 * period));
+        assert!(ledger.begin_paid_subscription(&id, &address, reader, 31 + 3 * period).unwrap().is_none());
+        assert_eq!(paid(&ledger).unwrap().status, BillingStatus::Active);
+        ledger.cancel_paid_subscription(&id, &address, reader).unwrap();
+        assert!(ledger.begin_renewals(30 + 4 * period).is_empty());
+        assert!(paid(&ledger).is_none());
+        assert_eq!(token.balance(owner), Nat::from(300u64));
+    }
+
+    #[test]
+    fn grants_are_scoped_expire_can_be_revoked_and_are_audited() {
+        let (owner, bot) = (Principal::from_slice(&[1]), Principal::from_slice(&[2]));
+        let email = "alice@dmail.ai".to_string();
+        let mut ledger = Ledger::default();
+        ledger.create_user(email.clone(), owner.to_text()).unwrap();
+        let inbox = MailboxAction::ListFolder(MailFolder::Inbox);
+
+        assert!(matches!(ledger.create_grant(&email, owner, bot, vec![GrantScope::ReadInbox], 10, 10), Err(MailError::GeneralError(_))));
+        let read = ledger.create_grant(&email, owner, bot, vec![GrantScope::ReadInbox], 100, 10).unwrap();
+        assert!(ledger.has_mailbox(bot, 50));
+        assert_eq!(ledger.authorize(bot, Some(email.clone()), &inbox, 50).unwrap(), email);
+        for action in [MailboxAction::ListFolder(MailFolder::Sent), MailboxAction::ReadAll, MailboxAction::Send, MailboxAction::Manage] {
+            assert!(matches!(ledger.authorize(bot, Some(email.clone()), &action, 50), Err(MailError::NotAuthorized)));
+        }
+        // a delegate always names the mailbox, it has no primary one
+        assert!(matches!(ledger.authorize(bot, None, &inbox, 50), Err(MailError::NoUserAddressFound)));
+        // members act through their role and are not audited
+        assert_eq!(ledger.authorize(owner, None, &MailboxAction::Manage, 50).unwrap(), email);
+
+        let log = ledger.get_audit_log(&email);
+        assert_eq!(log.len(), 1);
+        assert_eq!((log[0].timestamp, log[0].principal, log[0].grant_id, log[0].action.as_str()), (50, bot, read, "ListFolder(Inbox)"));
+
+        // expired
+        assert!(matches!(ledger.authorize(bot, Some(email.clone()), &inbox, 100), Err(MailError::NotAuthorized)));
+        assert!(!ledger.has_mailbox(bot, 100));
+        assert!(ledger.get_grants_of(bot, 100).is_empty());
+
+        // revoked
+        let send = ledger.create_grant(&email, owner, bot, vec![GrantScope::Send], 1_000, 100).unwrap();
+        assert_eq!(ledger.authorize(bot, Some(email.clone()), &MailboxAction::Send, 150).unwrap(), email);
+        ledger.revoke_grant(&email, send).unwrap();
+        assert!(matches!(ledger.authorize(bot, Some(email.clone()), &MailboxAction::Send, 150), Err(MailError::NotAuthorized)));
+        assert!(matches!(ledger.revoke_grant(&email, send), Err(MailError::NotFound)));
+
+        let log = ledger.get_audit_log(&email);
+        assert_eq!(log.len(), 2);
+        assert_eq!((log[0].timestamp, log[0].grant_id, log[0].action.as_str()), (150, send, "Send"));
+    }
+
+    #[test]
+    fn aliases_and_the_catch_all_deliver_to_their_mailbox() {
+        let (mut ledger, alice) = dmail_ledger();
+        ledger.add_alias("hello@dmail.ai".to_string(), alice.clone()).unwrap();
+        assert!(matches!(ledger.add_alias("hello@example.com".to_string(), alice.clone()), Err(MailError::DomainNotFound)));
+        assert!(matches!(ledger.add_alias("hello@dmail.ai".to_string(), alice.clone()), Err(MailError::AddressExist)));
+
+        ledger.submit_mail(mail_to("bob@example.com", &["hello@dmail.ai"], "alias"), "m1".to_string(), 1).unwrap();
+        // a mail to the mailbox and its alias is delivered once
+        ledger.submit_mail(mail_to("bob@example.com", &["hello@dmail.ai", "alice@dmail.ai"], "both"), "m2".to_string(), 2).unwrap();
+        assert_eq!(inbox_ids(&ledger, &alice), vec!["m1", "m2"]);
+
+        let unknown = mail_to("bob@example.com", &["nobody@dmail.ai"], "unknown");
+        assert!(matches!(ledger.submit_mail(unknown.clone(), "m3".to_string(), 3), Err(MailError::NoUserAddressFound)));
+        ledger.set_catch_all(Some(alice.clone())).unwrap();
+        ledger.submit_mail(unknown, "m3".to_string(), 3).unwrap();
+        ledger.submit_mail(mail_to("bob@example.com", &["nobody@DMAIL.ai"], "any case"), "m4".to_string(), 4).unwrap();
+        // the catch-all only takes our own domain
+        let foreign = mail_to("bob@example.com", &["nobody@example.com"], "foreign");
+        assert!(matches!(ledger.submit_mail(foreign, "m5".to_string(), 5), Err(MailError::NoUserAddressFound)));
+        assert_eq!(inbox_ids(&ledger, &alice), vec!["m1", "m2", "m3", "m4"]);
+
+        ledger.remove_alias("hello@dmail.ai".to_string()).unwrap();
+        ledger.set_catch_all(None).unwrap();
+        let alias = mail_to("bob@example.com", &["hello@dmail.ai"], "alias");
+        assert!(matches!(ledger.submit_mail(alias, "m6".to_string(), 6), Err(MailError::NoUserAddressFound)));
+    }
+
+    #[test]
+    fn forwarding_stops_at_the_hop_limit() {
+        let (mut ledger, alice) = dmail_ledger();
+        ledger.set_forwarding(&alice, Some(ForwardingRule { forward_to: vec!["alice@example.com".to_string()], keep_copy: false })).unwrap();
+
+        ledger.submit_mail(mail_to("bob@example.com", &["alice@dmail.ai"], "first"), "m1".to_string(), 1).unwrap();
+        let forwards = ledger.take_pending_outbound();
+        assert_eq!(forwards.len(), 1);
+        let (sender, forward) = &forwards[0];
+        assert_eq!(sender, &alice);
+        assert_eq!(forward.header.to, vec!["alice@example.com"]);
+        assert_eq!(forward.header.forward_hops(), 1);
+        assert_eq!(forward.header.get_header("Reply-To"), Some("bob@example.com"));
+        assert!(inbox_ids(&ledger, &alice).is_empty());
+
+        // a copy that went round the loop one hop short of the limit is forwarded once more
+        let mut looped = mail_to("bob@example.com", &["alice@dmail.ai"], "looped");
+        looped.header.set_header(FORWARD_HOPS_HEADER, (MAX_FORWARD_HOPS - 1).to_string());
+        ledger.submit_mail(looped.clone(), "m2".to_string(), 2).unwrap();
+        assert_eq!(ledger.take_pending_outbound()[0].1.header.forward_hops(), MAX_FORWARD_HOPS);
+
+        // past the limit it is kept instead of forwarded again
+        looped.header.set_header(FORWARD_HOPS_HEADER, MAX_FORWARD_HOPS.to_string());
+        ledger.submit_mail(looped, "m3".to_string(), 3).unwrap();
+        assert!(ledger.take_pending_outbound().is_empty());
+        assert_eq!(inbox_ids(&ledger, &alice), vec!["m3"]);
+    }
+
+    #[test]
+    fn a_sender_gets_one_auto_reply_per_interval() {
+        let (mut ledger, alice) = dmail_ledger();
+        let day = NANOS_PER_DAY;
+        ledger.set_auto_reply(&alice, Some(AutoReply {
+            enabled: true,
+            start: Some(10 * day),
+            end: Some(20 * day),
+            subject: "Away".to_string(),
+            body: "Back on Monday".to_string(),
+            interval_days: 3
+        })).unwrap();
+        let mut n = 0;
+        let mut deliver = |ledger : &mut Ledger, mail : Mail, now : u64| {
+            n += 1;
+            ledger.submit_mail(mail, format!("m{}", n), now).unwrap();
+            ledger.take_pending_outbound()
+        };
+
+        // before the vacation starts
+        assert!(deliver(&mut ledger, mail_to("bob@example.com", &[&alice], "early"), 9 * day).is_empty());
+
+        let replies = deliver(&mut ledger, mail_to("bob@example.com", &[&alice], "hi"), 10 * day);
+        assert_eq!(replies.len(), 1);
+        let (sender, reply) = &replies[0];
+        assert_eq!(sender, &alice);
+        assert_eq!(reply.header.to, vec!["bob@example.com"]);
+        assert_eq!(reply.header.subject.as_deref(), Some("Away"));
+        assert_eq!(reply.header.get_header("Auto-Submitted"), Some("auto-replied"));
+        // the reply is itself auto generated, another responder will not answer it
+        assert!(is_auto_generated(&reply.header));
+
+        // once per sender and interval, whatever the case of the address
+        assert!(deliver(&mut ledger, mail_to("Bob@Example.com", &[&alice], "again"), 12 * day).is_empty());
+        assert_eq!(deliver(&mut ledger, mail_to("carol@example.com", &[&alice], "hi"), 12 * day).len(), 1);
+        assert_eq!(deliver(&mut ledger, mail_to("bob@example.com", &[&alice], "later"), 13 * day).len(), 1);
+
+        // bulk, list and daemon mail is never answered, nor are forwarded copies
+        let mut bulk = mail_to("dave@example.com", &[&alice], "news");
+        bulk.header.set_header("Precedence", "bulk".to_string());
+        assert!(deliver(&mut ledger, bulk, 14 * day).is_empty());
+        let mut list = mail_to("dave@example.com", &[&alice], "list");
+        list.header.set_header("List-Id", "<list.example.com>".to_string());
+        assert!(deliver(&mut ledger, list, 14 * day).is_empty());
+        assert!(deliver(&mut ledger, mail_to("mailer-daemon@example.com", &[&alice], "bounce"), 14 * day).is_empty());
+        let mut forwarded = mail_to("dave@example.com", &[&alice], "forwarded");
+        forwarded.header.set_header(FORWARD_HOPS_HEADER, "1".to_string());
+        assert!(deliver(&mut ledger, forwarded, 14 * day).is_empty());
+
+        // after the vacation ends
+        assert!(deliver(&mut ledger, mail_to("erin@example.com", &[&alice], "late"), 20 * day).is_empty());
+    }
+
+    #[test]
+    fn allowed_senders_get_past_blocked_ones() {
+        let (mut ledger, alice) = dmail_ledger();
+        let bob = "bob@dmail.ai".to_string();
+        ledger.create_user(bob.clone(), Principal::from_slice(&[2]).to_text()).unwrap();
+        let canister = Principal::from_slice(&[7]);
+        let lists = |mode| SenderLists {
+            blocked: vec![SenderPattern::Domain("@Example.com".to_string()), SenderPattern::Canister(canister)],
+            allowed: vec![SenderPattern::Address("friend@mail.example.com".to_string())],
+            mode
+        };
+        ledger.set_sender_lists(&alice, Some(lists(BlockMode::Drop))).unwrap();
+        assert!(matches!(ledger.set_sender_lists(&alice, Some(SenderLists { blocked: vec![SenderPattern::Domain(" ".to_string())], allowed: vec![], mode: BlockMode::Drop })), Err(MailError::GeneralError(_))));
+
+        let from_canister = |from : &str| {
+            let mut mail = mail_to(from, &["alice@dmail.ai"], "hi");
+            mail.header.sender_canister_id = Some(canister.to_text());
+            mail
+        };
+        let mut n = 0;
+        let mut deliver = |ledger : &mut Ledger, mail : Mail| {
+            n += 1;
+            ledger.submit_mail(mail, format!("m{}", n), 1)
+        };
+        // the domain and its subdomains are blocked, a look-alike domain is not
+        deliver(&mut ledger, mail_to("spam@example.com", &["alice@dmail.ai"], "hi")).unwrap();
+        deliver(&mut ledger, mail_to("spam@mail.EXAMPLE.com", &["alice@dmail.ai"], "hi")).unwrap();
+        deliver(&mut ledger, mail_to("someone@notexample.com", &["alice@dmail.ai"], "hi")).unwrap();
+        deliver(&mut ledger, from_canister("carol@other.io")).unwrap();
+        // the allow list wins over the block list, also for mail relayed by a blocked canister
+        deliver(&mut ledger, mail_to("Friend@mail.example.com", &["alice@dmail.ai"], "hi")).unwrap();
+        deliver(&mut ledger, from_canister("friend@mail.example.com")).unwrap();
+        assert_eq!(inbox_ids(&ledger, &alice), vec!["m3", "m5", "m6"]);
+
+        ledger.set_sender_lists(&alice, Some(lists(BlockMode::Reject))).unwrap();
+        let rejected = deliver(&mut ledger, mail_to("spam@example.com", &["alice@dmail.ai"], "hi"));
+        assert!(matches!(rejected, Err(MailError::GeneralError(reason)) if reason.contains("Sender is blocked")));
+        // refused only when no mailbox takes it, bob still gets his copy
+        deliver(&mut ledger, mail_to("spam@example.com", &["alice@dmail.ai", "bob@dmail.ai"], "hi")).unwrap();
+        assert_eq!(inbox_ids(&ledger, &alice), vec!["m3", "m5", "m6"]);
+        assert_eq!(inbox_ids(&ledger, &bob), vec!["m8"]);
+
+        ledger.set_sender_lists(&alice, None).unwrap();
+        deliver(&mut ledger, mail_to("spam@example.com", &["alice@dmail.ai"], "hi")).unwrap();
+        assert_eq!(inbox_ids(&ledger, &alice), vec!["m3", "m5", "m6", "m9"]);
+    }
+
+    #[test]
+    fn blocked_domains_cover_their_subdomains() {
+        let (mut ledger, _) = dmail_ledger();
+        ledger.add_blocked_domain("@Spam.example".to_string()).unwrap();
+        assert!(matches!(ledger.add_blocked_domain("dmail.ai".to_string()), Err(MailError::GeneralError(_))));
+        assert!(ledger.is_domain_blocked("bob@spam.example"));
+        assert!(ledger.is_domain_blocked("bob@mail.SPAM.example"));
+        assert!(!ledger.is_domain_blocked("bob@notspam.example"));
+        assert!(!ledger.is_domain_blocked("bob@example"));
+        ledger.remove_blocked_domain("spam.example".to_string()).unwrap();
+        assert!(!ledger.is_domain_blocked("bob@spam.example"));
+        assert!(matches!(ledger.remove_blocked_domain("spam.example".to_string()), Err(MailError::NotFound)));
+    }
+
+    #[test]
+    fn rate_tokens_are_taken_all_or_nothing_and_refill() {
+        let mut ledger = Ledger::default();
+        ledger.set_rate_limits(RateLimits {
+            // one token a minute
+            per_caller: Some(RateLimit { capacity: 2, refill_per_hour: 60 }),
+            per_sender: None,
+            per_receipient: Some(RateLimit { capacity: 5, refill_per_hour: 3600 })
+        }).unwrap();
+        let (a, b) = (RateKey::Caller(Principal::from_slice(&[1])), RateKey::Caller(Principal::from_slice(&[2])));
+        let alice = RateKey::Receipient("alice@dmail.ai".to_string());
+        let tokens = |ledger : &Ledger, key : &RateKey, now : u64| ledger.get_rate_buckets(now).into_iter().find(|bucket| bucket.key == *key).map(|bucket| bucket.tokens);
+        let second = 1_000_000_000;
+
+        ledger.take_rate_tokens(&[a.clone(), alice.clone()], 0).unwrap();
+        ledger.take_rate_tokens(&[a.clone(), alice.clone()], 0).unwrap();
+        assert!(matches!(ledger.take_rate_tokens(&[a.clone(), alice.clone()], 0), Err(MailError::RateLimited { retry_after: 60 })));
+        // the refused call took nothing from the receipient's bucket
+        assert_eq!(tokens(&ledger, &alice, 0), Some(3.0));
+
+        // a full bucket next to an empty one gives up nothing either
+        assert!(matches!(ledger.take_rate_tokens(&[b.clone(), a.clone()], 0), Err(MailError::RateLimited { .. })));
+        assert_eq!(tokens(&ledger, &b, 0), None);
+        ledger.take_rate_tokens(std::slice::from_ref(&b), 0).unwrap();
+        assert_eq!(tokens(&ledger, &b, 0), Some(1.0));
+
+        // half a token after 30 seconds, the wait is rounded up to whole seconds
+        assert!(matches!(ledger.take_rate_tokens(std::slice::from_ref(&a), 30 * second), Err(MailError::RateLimited { retry_after: 30 })));
+        assert!(matches!(ledger.take_rate_tokens(std::slice::from_ref(&a), 30 * second + second / 2), Err(MailError::RateLimited { retry_after: 30 })));
+        assert!(matches!(ledger.take_rate_tokens(std::slice::from_ref(&a), 60 * second - 1), Err(MailError::RateLimited { retry_after: 1 })));
+        ledger.take_rate_tokens(std::slice::from_ref(&a), 60 * second).unwrap();
+
+        // keys without a limit get no bucket, and buckets refill up to their capacity only
+        ledger.take_rate_tokens(&[RateKey::Sender("bob@dmail.ai".to_string())], 0).unwrap();
+        assert_eq!(ledger.get_rate_buckets(0).len(), 3);
+        assert_eq!(tokens(&ledger, &alice, 3600 * second), Some(5.0));
+
+        ledger.reset_rate_buckets(Some(a.clone())).unwrap();
+        assert_eq!(tokens(&ledger, &a, 60 * second), None);
+        ledger.take_rate_tokens(std::slice::from_ref(&a), 60 * second).unwrap();
+        ledger.take_rate_tokens(&[a], 60 * second).unwrap();
+    }
+
+    #[test]
+    fn only_the_owner_updates_and_deletes_a_newsletter() {
+        let mut ledger = Ledger::default();
+        let (owner, other) = (Principal::from_slice(&[1]), Principal::from_slice(&[2]));
+        let id = "news".to_string();
+        ledger.create_newletter(id.clone(), Newsletter::new("News".to_string(), "Weekly news".to_string()), owner).unwrap();
+        ledger.subscribe_to_newsletter(id.clone(), "bob@example.com".to_string(), other, "token", "https://news.example", 1).unwrap();
+        ledger.confirm_subscription("token", 2).unwrap();
+
+        let renamed = || Newsletter::new("Daily".to_string(), "Daily news".to_string());
+        assert!(matches!(ledger.update_newsletter(&id, renamed(), other), Err(MailError::NotAuthorized)));
+        assert!(matches!(ledger.update_newsletter(&id, Newsletter::new(" ".to_string(), String::new()), owner), Err(MailError::GeneralError(_))));
+        assert_eq!(ledger.get_newsletter(id.clone()).unwrap().title, "News");
+        ledger.update_newsletter(&id, renamed(), owner).unwrap();
+        assert_eq!(ledger.get_newsletter(id.clone()).unwrap().title, "Daily");
+        assert_eq!(ledger.get_subscribers(&id, owner).unwrap().len(), 1);
+
+        assert!(matches!(ledger.delete_newsletter(&id, other), Err(MailError::NotAuthorized)));
+        ledger.delete_newsletter(&id, owner).unwrap();
+        assert!(matches!(ledger.get_newsletter(id.clone()), Err(MailError::NotFound)));
+        assert!(matches!(ledger.get_newsletter_subscribers(id.clone()), Err(MailError::NotFound)));
+        assert!(matches!(ledger.update_newsletter(&id, renamed(), owner), Err(MailError::NotFound)));
+        assert!(matches!(ledger.delete_newsletter(&id, owner), Err(MailError::NotFound)));
+        // the id is free again
+        ledger.create_newletter(id.clone(), renamed(), other).unwrap();
+    }
+
+    #[test]
+    fn each_recipient_opens_a_mail_for_the_first_time_once() {
+        let (mut ledger, alice) = dmail_ledger();
+        let carol = "carol@dmail.ai".to_string();
+        ledger.create_user(carol.clone(), Principal::from_slice(&[2]).to_text()).unwrap();
+        ledger.submit_mail(mail_to("bob@example.com", &["alice@dmail.ai", "carol@dmail.ai"], "both"), "m1".to_string(), 1).unwrap();
+
+        assert!(ledger.open_mail(&alice, "m1".to_string()).unwrap().1);
+        assert!(!ledger.open_mail(&alice, "m1".to_string()).unwrap().1);
+        // alice reading it does not count as carol's first open
+        assert!(ledger.open_mail(&carol, "m1".to_string()).unwrap().1);
+        assert!(matches!(ledger.open_mail(&alice, "m2".to_string()), Err(MailError::MailNotFound)));
+    }
+
+    #[test]
+    fn issue_metrics_count_deliveries_reads_and_unsubscribes() {
+        let mut ledger = Ledger::default();
+        let (owner, bob, carol) = (Principal::from_slice(&[1]), Principal::from_slice(&[2]), Principal::from_slice(&[3]));
+        let (example_com, stranger) = (Principal::from_slice(&[7]), Principal::from_slice(&[8]));
+        let id = "news".to_string();
+        ledger.create_newletter(id.clone(), Newsletter::new("News".to_string(), "Weekly news".to_string()), owner).unwrap();
+        for (n, (address, principal)) in [("bob@example.com", bob), ("carol@example.com", carol), ("dave@example.org", carol)].into_iter().enumerate() {
+            let token = format!("token-{}", n);
+            ledger.subscribe_to_newsletter(id.clone(), address.to_string(), principal, &token, "https://news.example", 1).unwrap();
+            ledger.confirm_subscription(&token, 2).unwrap();
+        }
+        let job_id = ledger.create_send_job(&id, mail_to("news@dmail.ai", &[], "Issue one"), Tier::Free, owner, 3).unwrap();
+        ledger.set_send_job_destination(job_id, "example.com", Destination::Canister(example_com));
+        ledger.record_deliveries(job_id, vec![("bob@example.com".to_string(), Ok(())), ("carol@example.com".to_string(), Err(MailError::NotFound))], 4);
+        assert!(matches!(ledger.get_issue_metrics(&id, bob), Err(MailError::NotAuthorized)));
+        let metrics = |ledger : &Ledger| {
+            let metrics = ledger.get_issue_metrics(&id, owner).unwrap();
+            assert_eq!(metrics.len(), 1);
+            let m = &metrics[0];
+            (m.targeted, m.delivered, m.bounced, m.pending, m.reads, m.unsubscribes)
+        };
+        assert_eq!(metrics(&ledger), (3, 1, 1, 1, 0, 0));
+
+        let bob_address = "bob@example.com".to_string();
+        assert!(matches!(ledger.report_issue_read(&id, 1, &bob_address, stranger, 5), Err(MailError::NotAuthorized)));
+        // carol subscribed other addresses, not bob's
+        assert!(matches!(ledger.report_issue_read(&id, 1, &bob_address, carol, 5), Err(MailError::NotAuthorized)));
+        assert!(matches!(ledger.report_issue_read(&id, 1, &"carol@example.com".to_string(), example_com, 5), Err(MailError::NotFound)));
+        assert!(matches!(ledger.report_issue_read(&id, 2, &bob_address, example_com, 5), Err(MailError::NotFound)));
+        ledger.report_issue_read(&id, 1, &bob_address, example_com, 5).unwrap();
+        assert_eq!(metrics(&ledger), (3, 1, 1, 1, 1, 0));
+
+        // example.org has no destination yet, only the subscriber can report
+        ledger.record_deliveries(job_id, vec![("dave@example.org".to_string(), Ok(()))], 6);
+        let dave_address = "Dave@Example.org".to_string();
+        assert!(matches!(ledger.report_issue_read(&id, 1, &dave_address, example_com, 7), Err(MailError::NotAuthorized)));
+        assert!(matches!(ledger.record_issue_read(&id, 1, &dave_address, Destination::Local, 7), Err(MailError::NotAuthorized)));
+        ledger.report_issue_read(&id, 1, &dave_address, carol, 7).unwrap();
+        assert_eq!(metrics(&ledger), (3, 2, 1, 0, 2, 0));
+
+        // only leaving on their own counts against the issue
+        ledger.unsubscribe_to_newsletter(id.clone(), bob_address, bob, NANOS_PER_DAY + 1).unwrap();
+        ledger.unsubscribe_to_newsletter(id.clone(), dave_address, owner, NANOS_PER_DAY + 2).unwrap();
+        assert_eq!(metrics(&ledger), (3, 2, 1, 0, 2, 1));
+
+        let growth : Vec<(u64, u32, u32, u64)> = ledger.get_subscriber_growth(&id, None, owner).unwrap().into_iter()
+            .map(|day| (day.day, day.subscribed, day.unsubscribed, day.subscribers))
+            .collect();
+        assert_eq!(growth, vec![(0, 3, 0, 3), (NANOS_PER_DAY, 0, 2, 1)]);
+        let since_yesterday = ledger.get_subscriber_growth(&id, Some(NANOS_PER_DAY + 5), owner).unwrap();
+        assert_eq!(since_yesterday.len(), 1);
+        assert!(matches!(ledger.get_subscriber_growth(&id, None, carol), Err(MailError::NotAuthorized)));
+    }
+}
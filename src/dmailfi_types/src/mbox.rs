@@ -0,0 +1,157 @@
+//! mboxrd framing for mailbox export and import. Messages are stored with LF line endings,
+//! each one starts with a `From ` separator line and lines that look like one are quoted with `>`.
+use crate::UtcDateTime;
+
+/// Appends one RFC 5322 message to an mbox.
+pub fn push_message(mbox: &mut Vec<u8>, sender: &str, timestamp: u64, message: &[u8]) {
+    let sender = if sender.is_empty() || sender.contains(char::is_whitespace) {
+        "MAILER-DAEMON"
+    } else {
+        sender
+    };
+    mbox.extend_from_slice(format!("From {} {}\n", sender, asctime(timestamp)).as_bytes());
+
+    let message = message.strip_suffix(b"\r\n").unwrap_or(message);
+    let message = message.strip_suffix(b"\n").unwrap_or(message);
+    for line in message.split(|b| *b == b'\n') {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if is_quoted_separator(line) {
+            mbox.push(b'>');
+        }
+        mbox.extend_from_slice(line);
+        mbox.push(b'\n');
+    }
+    // the blank line before the next separator
+    mbox.push(b'\n');
+}
+
+pub struct MboxMessage {
+    pub message: Vec<u8>,
+    // date of the `From ` line, used when the message has no Date header
+    pub timestamp: Option<u64>,
+}
+
+/// Splits the complete messages off the front of `mbox`. Unless `last` is set the final message
+/// may still be cut off by the end of the chunk, so it is left in place. Returns the messages
+/// and how many bytes of `mbox` they used.
+pub fn split_messages(mbox: &[u8], last: bool) -> (Vec<MboxMessage>, usize) {
+    let mut starts = vec![];
+    let mut line_start = 0;
+    while line_start < mbox.len() {
+        let line_end = mbox[line_start..]
+            .iter()
+            .position(|b| *b == b'\n')
+            .map(|pos| line_start + pos + 1);
+        match line_end {
+            Some(line_end) => {
+                if mbox[line_start..].starts_with(b"From ") {
+                    starts.push(line_start);
+                }
+                line_start = line_end;
+            }
+            // a separator is only trusted once its line is complete
+            None => {
+                if last && mbox[line_start..].starts_with(b"From ") {
+                    starts.push(line_start);
+                }
+                break;
+            }
+        }
+    }
+
+    let mut ends: Vec<usize> = starts.iter().skip(1).cloned().collect();
+    let consumed = if last {
+        ends.push(mbox.len());
+        mbox.len()
+    } else {
+        starts.last().cloned().unwrap_or(0)
+    };
+
+    let messages = starts
+        .iter()
+        .zip(ends.iter())
+        .map(|(start, end)| parse_message(&mbox[*start..*end]))
+        .collect();
+    (messages, consumed)
+}
+
+fn parse_message(entry: &[u8]) -> MboxMessage {
+    let separator_end = entry
+        .iter()
+        .position(|b| *b == b'\n')
+        .unwrap_or(entry.len());
+    let separator = String::from_utf8_lossy(&entry[..separator_end]);
+    let body = &entry[(separator_end + 1).min(entry.len())..];
+
+    let mut message = Vec::with_capacity(body.len());
+    for line in body.split(|b| *b == b'\n') {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let line = if line.starts_with(b">") && is_quoted_separator(&line[1..]) {
+            &line[1..]
+        } else {
+            line
+        };
+        message.extend_from_slice(line);
+        message.extend_from_slice(b"\r\n");
+    }
+    // the blank line that separated this message from the next one
+    while message.ends_with(b"\r\n\r\n") {
+        message.truncate(message.len() - 2);
+    }
+
+    MboxMessage {
+        message,
+        timestamp: separator
+            .split_whitespace()
+            .skip(2)
+            .collect::<Vec<&str>>()
+            .join(" ")
+            .parse::<AscTime>()
+            .ok()
+            .map(|time| time.0),
+    }
+}
+
+// ">*From " lines, RFC 4155 with the mboxrd quoting
+fn is_quoted_separator(line: &[u8]) -> bool {
+    let unquoted = line
+        .iter()
+        .position(|b| *b != b'>')
+        .map_or(&[][..], |pos| &line[pos..]);
+    unquoted.starts_with(b"From ")
+}
+
+// e.g. "Tue Nov 14 22:13:20 2023"
+fn asctime(timestamp: u64) -> String {
+    let date = UtcDateTime::from_nanos(timestamp);
+    let rfc2822 = date.to_rfc2822();
+    // "Tue, 14 Nov 2023 22:13:20 +0000"
+    let fields: Vec<&str> = rfc2822.split_whitespace().collect();
+    format!(
+        "{} {} {:>2} {} {}",
+        fields[0].trim_end_matches(','),
+        fields[2],
+        date.day,
+        fields[4],
+        date.year
+    )
+}
+
+struct AscTime(u64);
+
+impl std::str::FromStr for AscTime {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        // "Tue Nov 14 22:13:20 2023" has the same fields as an RFC 5322 date, in another order
+        let fields: Vec<&str> = value.split_whitespace().collect();
+        if fields.len() < 5 {
+            return Err(());
+        }
+        let date = format!(
+            "{} {} {} {} +0000",
+            fields[2], fields[1], fields[4], fields[3]
+        );
+        crate::rfc5322::parse_date(&date).map(AscTime).ok_or(())
+    }
+}
@@ -0,0 +1,953 @@
+//! Conversion between `Mail` and RFC 5322 / MIME messages.
+//!
+//! A multipart `Mail` keeps its MIME body as is, with the boundary in `content_type`, so text,
+//! html and attachments survive a round trip byte for byte. Single part bodies are stored
+//! decoded and written back as base64. Headers that have no `MailHeader` field are carried in
+//! `MailHeader::headers`.
+use std::sync::Arc;
+
+use base64::Engine;
+use serde_bytes::ByteBuf;
+
+use crate::{Mail, MailHeader, Rcbytes, UtcDateTime};
+
+pub const DEFAULT_CONTENT_TYPE: &str = "text/plain; charset=utf-8";
+
+const SENDER_CANISTER_HEADER: &str = "X-Dmail-Sender-Canister";
+const SENDER_CHANNEL_HEADER: &str = "X-Dmail-Sender-Channel";
+const RECEIPIENT_CANISTER_HEADER: &str = "X-Dmail-Receipient-Canister";
+
+// Headers written from `MailHeader` fields, custom headers with these names are dropped.
+const RESERVED_HEADERS: [&str; 14] = [
+    "from",
+    "to",
+    "cc",
+    "bcc",
+    "subject",
+    "date",
+    "message-id",
+    "mime-version",
+    "content-type",
+    "content-transfer-encoding",
+    "x-dmail-sender-canister",
+    "x-dmail-sender-channel",
+    "x-dmail-receipient-canister",
+    "return-path",
+];
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MimePart {
+    pub content_type: String,
+    pub filename: Option<String>,
+    pub content_id: Option<String>,
+    pub body: Vec<u8>,
+}
+
+/// Renders `mail` as an RFC 5322 message, Bcc included. Use `encode` to leave Bcc out when
+/// the message goes over the wire.
+pub fn to_rfc5322(mail: &Mail, message_id: &str) -> Vec<u8> {
+    encode(&mail.header, mail.body.0.as_slice(), message_id, true)
+}
+
+/// Parses an RFC 5322 message. `timestamp` is 0 when the message has no usable Date header.
+pub fn from_rfc5322(raw: &[u8]) -> Result<Mail, String> {
+    let (headers, body) = split_message(raw)?;
+
+    let from = header(&headers, "from").unwrap_or_default();
+    let (sender_name, from) = match parse_addresses(&from).into_iter().next() {
+        Some(mailbox) => (mailbox.0, mailbox.1),
+        None => return Err("Message has no From address".to_string()),
+    };
+
+    let content_type = header(&headers, "content-type");
+    let body = if is_multipart(content_type.as_deref()) {
+        body.to_vec()
+    } else {
+        decode_transfer(
+            header(&headers, "content-transfer-encoding").as_deref(),
+            body,
+        )?
+    };
+
+    let custom: Vec<(String, String)> = headers
+        .iter()
+        .filter(|(name, _)| !RESERVED_HEADERS.contains(&name.to_ascii_lowercase().as_str()))
+        .map(|(name, value)| (name.clone(), decode_words(value)))
+        .collect();
+
+    Ok(Mail {
+        correlation_id: None,
+        header: MailHeader {
+            from,
+            timestamp: header(&headers, "date")
+                .and_then(|date| parse_date(&date))
+                .unwrap_or(0),
+            content_type,
+            to: address_list(&headers, "to"),
+            subject: header(&headers, "subject").map(|subject| decode_words(&subject)),
+            cc: Some(address_list(&headers, "cc")).filter(|cc| !cc.is_empty()),
+            bcc: Some(address_list(&headers, "bcc")).filter(|bcc| !bcc.is_empty()),
+            sender_name,
+            sender_canister_id: header(&headers, SENDER_CANISTER_HEADER),
+            sender_channel: header(&headers, SENDER_CHANNEL_HEADER),
+            receipient_canister_id: header(&headers, RECEIPIENT_CANISTER_HEADER),
+            headers: Some(custom).filter(|custom| !custom.is_empty()),
+        },
+        body: Rcbytes::new(Arc::new(ByteBuf::from(body))),
+        reply_messages: None,
+    })
+}
+
+/// Renders a header and body as an RFC 5322 message. `message_id` is written without the angle
+/// brackets, e.g. `id@example.com`.
+pub fn encode(header: &MailHeader, body: &[u8], message_id: &str, include_bcc: bool) -> Vec<u8> {
+    let mut message = String::new();
+
+    let from = match &header.sender_name {
+        Some(name) => format!("{} <{}>", display_name(name), header.from),
+        None => header.from.clone(),
+    };
+    push_header(&mut message, "From", &from);
+    if !header.to.is_empty() {
+        push_header(&mut message, "To", &header.to.join(", "));
+    }
+    if let Some(cc) = header.cc.as_ref().filter(|cc| !cc.is_empty()) {
+        push_header(&mut message, "Cc", &cc.join(", "));
+    }
+    if include_bcc {
+        if let Some(bcc) = header.bcc.as_ref().filter(|bcc| !bcc.is_empty()) {
+            push_header(&mut message, "Bcc", &bcc.join(", "));
+        }
+    }
+    if let Some(subject) = &header.subject {
+        push_header(&mut message, "Subject", &encode_word(subject));
+    }
+    push_header(
+        &mut message,
+        "Date",
+        &UtcDateTime::from_nanos(header.timestamp).to_rfc2822(),
+    );
+    push_header(&mut message, "Message-ID", &format!("<{}>", message_id));
+    if let Some(canister_id) = &header.sender_canister_id {
+        push_header(&mut message, SENDER_CANISTER_HEADER, canister_id);
+    }
+    if let Some(channel) = &header.sender_channel {
+        push_header(&mut message, SENDER_CHANNEL_HEADER, channel);
+    }
+    if let Some(canister_id) = &header.receipient_canister_id {
+        push_header(&mut message, RECEIPIENT_CANISTER_HEADER, canister_id);
+    }
+    for (name, value) in header.headers.iter().flatten() {
+        if !is_header_name(name) || RESERVED_HEADERS.contains(&name.to_ascii_lowercase().as_str()) {
+            continue;
+        }
+        push_header(&mut message, name, &encode_word(value));
+    }
+
+    push_header(&mut message, "MIME-Version", "1.0");
+    let content_type = header
+        .content_type
+        .clone()
+        .unwrap_or(DEFAULT_CONTENT_TYPE.to_string());
+    push_header(&mut message, "Content-Type", &content_type);
+
+    if is_multipart(Some(&content_type)) {
+        push_header(&mut message, "Content-Transfer-Encoding", "8bit");
+        message.push_str("\r\n");
+        let mut message = message.into_bytes();
+        message.extend_from_slice(body);
+        message
+    } else {
+        push_header(&mut message, "Content-Transfer-Encoding", "base64");
+        message.push_str("\r\n");
+        push_base64(&mut message, body);
+        message.into_bytes()
+    }
+}
+
+/// Builds a MIME body from its parts. Text alone stays single part, text with html becomes
+/// multipart/alternative, and attachments wrap both in multipart/mixed. Returns the content
+/// type and the body to put in a `Mail`.
+pub fn build_multipart(
+    text: Option<&str>,
+    html: Option<&str>,
+    attachments: &[MimePart],
+) -> (String, Vec<u8>) {
+    let text_part = text.map(|text| MimePart {
+        content_type: DEFAULT_CONTENT_TYPE.to_string(),
+        filename: None,
+        content_id: None,
+        body: text.as_bytes().to_vec(),
+    });
+    let html_part = html.map(|html| MimePart {
+        content_type: "text/html; charset=utf-8".to_string(),
+        filename: None,
+        content_id: None,
+        body: html.as_bytes().to_vec(),
+    });
+
+    let content = match (text_part, html_part) {
+        (Some(text), Some(html)) => {
+            let (content_type, body) = multipart("alternative", &[text, html]);
+            Some(MimePart {
+                content_type,
+                filename: None,
+                content_id: None,
+                body,
+            })
+        }
+        (Some(part), None) | (None, Some(part)) => Some(part),
+        (None, None) => None,
+    };
+
+    match content {
+        Some(content) if attachments.is_empty() => (content.content_type, content.body),
+        content => {
+            let mut parts: Vec<MimePart> = content.into_iter().collect();
+            parts.extend(attachments.iter().cloned());
+            multipart("mixed", &parts)
+        }
+    }
+}
+
+/// Flattens a body into its leaf parts with transfer encodings removed. A single part body
+/// comes back as one part.
+pub fn parts(content_type: Option<&str>, body: &[u8]) -> Vec<MimePart> {
+    let content_type = content_type.unwrap_or(DEFAULT_CONTENT_TYPE);
+    let boundary = match parameter(content_type, "boundary") {
+        Some(boundary) if is_multipart(Some(content_type)) => boundary,
+        _ => {
+            return vec![MimePart {
+                content_type: content_type.to_string(),
+                filename: None,
+                content_id: None,
+                body: body.to_vec(),
+            }]
+        }
+    };
+
+    let mut leaves = vec![];
+    for raw_part in split_multipart(body, &boundary) {
+        let (headers, part_body) = match split_message(raw_part) {
+            Ok(split) => split,
+            Err(_) => continue,
+        };
+        let part_type =
+            header(&headers, "content-type").unwrap_or(DEFAULT_CONTENT_TYPE.to_string());
+        if is_multipart(Some(&part_type)) {
+            leaves.extend(parts(Some(&part_type), part_body));
+            continue;
+        }
+
+        let encoding = header(&headers, "content-transfer-encoding");
+        let decoded = match decode_transfer(encoding.as_deref(), part_body) {
+            Ok(decoded) => decoded,
+            Err(_) => continue,
+        };
+        let filename = header(&headers, "content-disposition")
+            .and_then(|disposition| parameter(&disposition, "filename"))
+            .or_else(|| parameter(&part_type, "name"))
+            .map(|filename| decode_words(&filename));
+        let content_id = header(&headers, "content-id")
+            .map(|id| id.trim_matches(|c| c == '<' || c == '>').to_string());
+
+        leaves.push(MimePart {
+            content_type: part_type,
+            filename,
+            content_id,
+            body: decoded,
+        });
+    }
+    leaves
+}
+
+fn multipart(subtype: &str, parts: &[MimePart]) -> (String, Vec<u8>) {
+    // derived from the content so the same parts always produce the same body, every leaf is
+    // base64 which never contains "-", so the boundary can not collide with a part
+    let mut digest = String::new();
+    for part in parts {
+        digest.push_str(&part.content_type);
+        digest.push_str(&hex::encode(crate::sha256_bytes(&part.body)));
+    }
+    let boundary = format!("dmailfi-{}", &hex::encode(crate::sha256(&digest))[..32]);
+
+    let mut body = Vec::new();
+    for part in parts {
+        body.extend_from_slice(format!("--{}\r\n", boundary).as_bytes());
+        let mut head = String::new();
+        push_header(&mut head, "Content-Type", &part.content_type);
+        if is_multipart(Some(&part.content_type)) {
+            body.extend_from_slice(head.as_bytes());
+            body.extend_from_slice(b"\r\n");
+            body.extend_from_slice(&part.body);
+            if !part.body.ends_with(b"\r\n") {
+                body.extend_from_slice(b"\r\n");
+            }
+            continue;
+        }
+
+        push_header(&mut head, "Content-Transfer-Encoding", "base64");
+        if let Some(filename) = &part.filename {
+            push_header(
+                &mut head,
+                "Content-Disposition",
+                &format!(
+                    "attachment; filename=\"{}\"",
+                    quoted(&encode_word(filename))
+                ),
+            );
+        }
+        if let Some(content_id) = &part.content_id {
+            push_header(&mut head, "Content-ID", &format!("<{}>", content_id));
+        }
+        head.push_str("\r\n");
+        push_base64(&mut head, &part.body);
+        body.extend_from_slice(head.as_bytes());
+    }
+    body.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+
+    (
+        format!("multipart/{}; boundary=\"{}\"", subtype, boundary),
+        body,
+    )
+}
+
+fn split_multipart<'a>(body: &'a [u8], boundary: &str) -> Vec<&'a [u8]> {
+    let delimiter = format!("--{}", boundary);
+    let delimiter = delimiter.as_bytes();
+
+    let mut parts = vec![];
+    let mut start: Option<usize> = None;
+    let mut line_start = 0;
+    while line_start < body.len() {
+        let line_end = find(&body[line_start..], b"\n")
+            .map(|pos| line_start + pos + 1)
+            .unwrap_or(body.len());
+        let line = trim_newline(&body[line_start..line_end]);
+
+        if line.starts_with(delimiter) {
+            if let Some(start) = start {
+                // the line break before a delimiter belongs to the delimiter, RFC 2046 section 5.1.1
+                parts.push(trim_newline(&body[start..line_start]));
+            }
+            if line[delimiter.len()..].starts_with(b"--") {
+                break;
+            }
+            start = Some(line_end);
+        }
+        line_start = line_end;
+    }
+    parts
+}
+
+type Headers = Vec<(String, String)>;
+
+// Header names keep their case so custom headers round trip, lookups ignore it.
+fn split_message(message: &[u8]) -> Result<(Headers, &[u8]), String> {
+    // a part may start with the blank line when it has no headers
+    if let Some(body) = message
+        .strip_prefix(b"\r\n")
+        .or_else(|| message.strip_prefix(b"\n"))
+    {
+        return Ok((vec![], body));
+    }
+    let (head, body) = match (find(message, b"\r\n\r\n"), find(message, b"\n\n")) {
+        (Some(crlf), Some(lf)) if lf < crlf => (&message[..lf], &message[lf + 2..]),
+        (Some(crlf), _) => (&message[..crlf], &message[crlf + 4..]),
+        (None, Some(lf)) => (&message[..lf], &message[lf + 2..]),
+        (None, None) => (message, &[][..]),
+    };
+    let head = std::str::from_utf8(head).map_err(|_| "Headers are not valid utf-8".to_string())?;
+
+    let mut headers: Headers = vec![];
+    for line in head.lines() {
+        // folded header, RFC 5322 section 2.2.3
+        if line.starts_with(' ') || line.starts_with('\t') {
+            if let Some(last) = headers.last_mut() {
+                last.1.push(' ');
+                last.1.push_str(line.trim());
+            }
+            continue;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+    }
+
+    Ok((headers, body))
+}
+
+fn header(headers: &[(String, String)], name: &str) -> Option<String> {
+    headers
+        .iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.clone())
+}
+
+fn address_list(headers: &[(String, String)], name: &str) -> Vec<String> {
+    headers
+        .iter()
+        .filter(|(n, _)| n.eq_ignore_ascii_case(name))
+        .flat_map(|(_, value)| parse_addresses(value))
+        .map(|(_, address)| address)
+        .collect()
+}
+
+/// Splits an address list into display names and addresses, e.g.
+/// `"Doe, Jane" <jane@x.io>, bob@y.io`. Group syntax is flattened.
+pub fn parse_addresses(value: &str) -> Vec<(Option<String>, String)> {
+    let mut entries = vec![];
+    let mut current = String::new();
+    let mut quoted = false;
+    let mut angle = false;
+    let mut escaped = false;
+    for c in value.chars() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            '<' if !quoted => angle = true,
+            '>' if !quoted => angle = false,
+            // "group: a@x.io, b@x.io;" the group name is not an address
+            ':' if !quoted && !angle => {
+                current.clear();
+                continue;
+            }
+            ',' | ';' if !quoted && !angle => {
+                entries.push(std::mem::take(&mut current));
+                continue;
+            }
+            _ => {}
+        }
+        current.push(c);
+    }
+    entries.push(current);
+
+    entries
+        .iter()
+        .filter_map(|entry| {
+            let entry = entry.trim();
+            let (name, address) = match (entry.rfind('<'), entry.rfind('>')) {
+                (Some(start), Some(end)) if start < end => {
+                    (entry[..start].trim(), entry[start + 1..end].trim())
+                }
+                _ => ("", entry),
+            };
+            if address.is_empty() {
+                return None;
+            }
+            let name = decode_words(unquote(name).trim());
+            Some((Some(name).filter(|n| !n.is_empty()), address.to_string()))
+        })
+        .collect()
+}
+
+// drops the quotes of a quoted string and resolves its escapes
+fn unquote(value: &str) -> String {
+    let mut output = String::new();
+    let mut escaped = false;
+    for c in value.chars() {
+        match c {
+            _ if escaped => {
+                output.push(c);
+                escaped = false;
+            }
+            '\\' => escaped = true,
+            '"' => {}
+            _ => output.push(c),
+        }
+    }
+    output
+}
+
+fn display_name(name: &str) -> String {
+    if name.is_ascii() {
+        format!("\"{}\"", quoted(name))
+    } else {
+        encode_word(name)
+    }
+}
+
+fn quoted(value: &str) -> String {
+    value.replace('\\', "").replace('"', "\\\"")
+}
+
+fn is_multipart(content_type: Option<&str>) -> bool {
+    content_type.is_some_and(|content_type| {
+        content_type
+            .trim_start()
+            .to_ascii_lowercase()
+            .starts_with("multipart/")
+    })
+}
+
+fn is_header_name(name: &str) -> bool {
+    !name.is_empty() && name.bytes().all(|b| b.is_ascii_graphic() && b != b':')
+}
+
+/// Reads a parameter such as `boundary` or `filename` from a structured header value.
+pub fn parameter(value: &str, name: &str) -> Option<String> {
+    let mut rest = value;
+    while let Some(pos) = rest.find(';') {
+        rest = &rest[pos + 1..];
+        let (key, tail) = rest.split_once('=')?;
+        let tail = tail.trim_start();
+        let (param, remaining) = if let Some(stripped) = tail.strip_prefix('"') {
+            let end = stripped.find('"').unwrap_or(stripped.len());
+            (&stripped[..end], &stripped[(end + 1).min(stripped.len())..])
+        } else {
+            let end = tail.find(';').unwrap_or(tail.len());
+            (tail[..end].trim(), &tail[end..])
+        };
+        if key.trim().eq_ignore_ascii_case(name) {
+            return Some(param.to_string());
+        }
+        rest = remaining;
+    }
+    None
+}
+
+fn decode_transfer(encoding: Option<&str>, body: &[u8]) -> Result<Vec<u8>, String> {
+    match encoding.map(|e| e.trim().to_ascii_lowercase()) {
+        Some(encoding) if encoding == "base64" => {
+            let compact: Vec<u8> = body
+                .iter()
+                .filter(|b| !b.is_ascii_whitespace())
+                .cloned()
+                .collect();
+            base64::engine::general_purpose::STANDARD
+                .decode(compact)
+                .map_err(|_| "Body is not valid base64".to_string())
+        }
+        Some(encoding) if encoding == "quoted-printable" => {
+            Ok(decode_quoted_printable(body, false))
+        }
+        _ => Ok(body.to_vec()),
+    }
+}
+
+// RFC 2045 section 6.7, `header` selects the RFC 2047 "Q" variant where "_" is a space
+fn decode_quoted_printable(input: &[u8], header: bool) -> Vec<u8> {
+    let mut output = Vec::with_capacity(input.len());
+    let mut i = 0;
+    while i < input.len() {
+        match input[i] {
+            b'=' if input[i + 1..].starts_with(b"\r\n") => i += 3,
+            b'=' if input[i + 1..].starts_with(b"\n") => i += 2,
+            b'=' if i + 2 < input.len() => {
+                match u8::from_str_radix(&String::from_utf8_lossy(&input[i + 1..i + 3]), 16) {
+                    Ok(byte) => {
+                        output.push(byte);
+                        i += 3;
+                    }
+                    Err(_) => {
+                        output.push(b'=');
+                        i += 1;
+                    }
+                }
+            }
+            b'_' if header => {
+                output.push(b' ');
+                i += 1;
+            }
+            byte => {
+                output.push(byte);
+                i += 1;
+            }
+        }
+    }
+    output
+}
+
+// RFC 2047 encoded word for non ascii values
+fn encode_word(value: &str) -> String {
+    if value.is_ascii() {
+        return value.to_string();
+    }
+    format!(
+        "=?utf-8?B?{}?=",
+        base64::engine::general_purpose::STANDARD.encode(value)
+    )
+}
+
+/// Decodes RFC 2047 encoded words, whitespace between adjacent words is dropped.
+pub fn decode_words(value: &str) -> String {
+    let mut output = String::new();
+    let mut rest = value;
+    let mut last_was_word = false;
+    while let Some(start) = rest.find("=?") {
+        let decoded = rest[start + 2..].splitn(4, '?').collect::<Vec<&str>>();
+        let word = match decoded.as_slice() {
+            [charset, encoding, text, tail] if tail.starts_with('=') => {
+                decode_word(charset, encoding, text)
+                    .map(|word| (word, charset.len() + encoding.len() + text.len() + 6))
+            }
+            _ => None,
+        };
+        match word {
+            Some((word, length)) => {
+                let between = &rest[..start];
+                if !(last_was_word && between.trim().is_empty()) {
+                    output.push_str(between);
+                }
+                output.push_str(&word);
+                rest = &rest[start + length..];
+                last_was_word = true;
+            }
+            None => {
+                output.push_str(&rest[..start + 2]);
+                rest = &rest[start + 2..];
+                last_was_word = false;
+            }
+        }
+    }
+    output.push_str(rest);
+    output
+}
+
+fn decode_word(charset: &str, encoding: &str, text: &str) -> Option<String> {
+    let bytes = match encoding {
+        "B" | "b" => base64::engine::general_purpose::STANDARD
+            .decode(text)
+            .ok()?,
+        "Q" | "q" => decode_quoted_printable(text.as_bytes(), true),
+        _ => return None,
+    };
+    let charset = charset.to_ascii_lowercase();
+    if charset == "iso-8859-1" || charset == "latin1" {
+        return Some(bytes.iter().map(|b| *b as char).collect());
+    }
+    Some(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// Parses an RFC 5322 date such as `Tue, 14 Nov 2023 22:13:20 +0100` into nanoseconds since
+/// the epoch in UTC.
+pub fn parse_date(value: &str) -> Option<u64> {
+    const MONTHS: [&str; 12] = [
+        "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+    ];
+
+    // day of week is optional and carries no information
+    let value = match value.split_once(',') {
+        Some((_, rest)) => rest,
+        None => value,
+    };
+    let mut fields = value.split_whitespace();
+    let day: u32 = fields.next()?.parse().ok()?;
+    let month = fields.next()?.to_ascii_lowercase();
+    let month = MONTHS.iter().position(|m| month.starts_with(m))? as u32 + 1;
+    let mut year: i64 = fields.next()?.parse().ok()?;
+    // obsolete two digit years, RFC 5322 section 4.3
+    if year < 50 {
+        year += 2000;
+    } else if year < 1000 {
+        year += 1900;
+    }
+
+    let mut time = fields.next()?.split(':');
+    let hour: u32 = time.next()?.parse().ok()?;
+    let minute: u32 = time.next()?.parse().ok()?;
+    let second: u32 = time.next().map_or(Some(0), |s| s.parse().ok())?;
+
+    let offset_secs: i64 = match fields.next() {
+        Some(zone) if zone.len() == 5 && (zone.starts_with('+') || zone.starts_with('-')) => {
+            // non ascii input must not split a character
+            let hours: i64 = zone.get(1..3)?.parse().ok()?;
+            let minutes: i64 = zone.get(3..5)?.parse().ok()?;
+            let offset = hours * 3600 + minutes * 60;
+            if zone.starts_with('-') {
+                -offset
+            } else {
+                offset
+            }
+        }
+        Some(zone) => match zone.to_ascii_uppercase().as_str() {
+            "EDT" => -4 * 3600,
+            "EST" | "CDT" => -5 * 3600,
+            "CST" | "MDT" => -6 * 3600,
+            "MST" | "PDT" => -7 * 3600,
+            "PST" => -8 * 3600,
+            _ => 0,
+        },
+        None => 0,
+    };
+
+    if day == 0 || day > 31 || hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+    let date = UtcDateTime {
+        year,
+        month,
+        day,
+        hour,
+        minute,
+        second,
+        weekday: 0,
+    };
+    let secs = date.to_secs() - offset_secs;
+    u64::try_from(secs).ok().map(|secs| secs * 1_000_000_000)
+}
+
+fn push_header(message: &mut String, name: &str, value: &str) {
+    message.push_str(name);
+    message.push_str(": ");
+    // header injection guard, values never span lines
+    message.push_str(&value.replace(['\r', '\n'], " "));
+    message.push_str("\r\n");
+}
+
+fn push_base64(message: &mut String, body: &[u8]) {
+    let encoded = base64::engine::general_purpose::STANDARD.encode(body);
+    for chunk in encoded.as_bytes().chunks(76) {
+        message.push_str(std::str::from_utf8(chunk).unwrap());
+        message.push_str("\r\n");
+    }
+}
+
+fn trim_newline(line: &[u8]) -> &[u8] {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    fn mail(header: MailHeader, body: &[u8]) -> Mail {
+        Mail {
+            correlation_id: None,
+            header,
+            body: Rcbytes::new(Arc::new(ByteBuf::from(body.to_vec()))),
+            reply_messages: None,
+        }
+    }
+
+    // parse -> serialize -> parse gives the same mail and the same bytes
+    fn round_trip(raw: &[u8]) -> Mail {
+        let parsed = from_rfc5322(raw).unwrap();
+        let serialized = to_rfc5322(&parsed, "id@dmail.ai");
+        let reparsed = from_rfc5322(&serialized).unwrap();
+        assert_eq!(to_rfc5322(&reparsed, "id@dmail.ai"), serialized);
+
+        let (a, b) = (&parsed.header, &reparsed.header);
+        assert_eq!(a.from, b.from);
+        assert_eq!(a.sender_name, b.sender_name);
+        assert_eq!(a.to, b.to);
+        assert_eq!(a.cc, b.cc);
+        assert_eq!(a.bcc, b.bcc);
+        assert_eq!(a.subject, b.subject);
+        assert_eq!(a.timestamp, b.timestamp);
+        // a message without Content-Type is text/plain
+        assert_eq!(
+            a.content_type.as_deref().unwrap_or(DEFAULT_CONTENT_TYPE),
+            b.content_type.as_deref().unwrap_or(DEFAULT_CONTENT_TYPE)
+        );
+        assert_eq!(a.headers, b.headers);
+        assert_eq!(parsed.body.0.as_slice(), reparsed.body.0.as_slice());
+        reparsed
+    }
+
+    #[test]
+    fn parses_dates_with_zones() {
+        assert_eq!(
+            parse_date("Tue, 14 Nov 2023 23:13:20 +0100"),
+            Some(1_700_000_000 * 1_000_000_000)
+        );
+        assert_eq!(
+            parse_date("14 Nov 2023 17:13:20 EST"),
+            Some(1_700_000_000 * 1_000_000_000)
+        );
+        assert_eq!(
+            parse_date("14 Nov 2023 22:13"),
+            Some(1_699_999_980 * 1_000_000_000)
+        );
+    }
+
+    #[test]
+    fn rejects_dates_with_non_ascii_zones() {
+        assert_eq!(parse_date("Tue, 14 Nov 2023 22:13:20 +0\u{e9}0"), None);
+        assert_eq!(
+            parse_date("Tue, 14 Nov 2023 22:13:20 \u{e9}\u{e9}\u{e9}"),
+            Some(1_700_000_000 * 1_000_000_000)
+        );
+        assert_eq!(parse_date("Tue, 14 N\u{f6}v 2023 22:13:20 +0100"), None);
+    }
+
+    #[test]
+    fn round_trips_folded_headers() {
+        let raw = b"From: \"Doe, Jane\" <jane@dmail.ai>\r\n\
+To: bob@dmail.ai,\r\n carol@dmail.ai\r\n\
+Subject: A subject that is\r\n\tfolded twice\r\n\
+Date: Tue, 14 Nov 2023 22:13:20 +0100\r\n\
+List-Id: <news.dmail.ai>\r\n\
+\r\n\
+Hello\r\n";
+        let mail = round_trip(raw);
+        assert_eq!(mail.header.from, "jane@dmail.ai");
+        assert_eq!(mail.header.sender_name.as_deref(), Some("Doe, Jane"));
+        assert_eq!(mail.header.to, vec!["bob@dmail.ai", "carol@dmail.ai"]);
+        assert_eq!(
+            mail.header.subject.as_deref(),
+            Some("A subject that is folded twice")
+        );
+        assert_eq!(mail.header.get_header("list-id"), Some("<news.dmail.ai>"));
+        assert_eq!(mail.body.0.as_slice(), b"Hello\r\n");
+    }
+
+    #[test]
+    fn round_trips_encoded_words() {
+        let raw = b"From: =?ISO-8859-1?Q?Andr=E9?= <andre@dmail.ai>\r\n\
+To: bob@dmail.ai\r\n\
+Subject: =?utf-8?B?R3LDvMOfZQ==?= =?UTF-8?Q?_aus_K=C3=B6ln?=\r\n\
+Content-Type: text/plain; charset=utf-8\r\n\
+Content-Transfer-Encoding: quoted-printable\r\n\
+\r\n\
+Sch=C3=B6n\r\n";
+        let mail = round_trip(raw);
+        assert_eq!(mail.header.sender_name.as_deref(), Some("Andr\u{e9}"));
+        assert_eq!(
+            mail.header.subject.as_deref(),
+            Some("Gr\u{fc}\u{df}e aus K\u{f6}ln")
+        );
+        assert_eq!(mail.body.0.as_slice(), "Sch\u{f6}n\r\n".as_bytes());
+    }
+
+    #[test]
+    fn round_trips_multipart() {
+        let attachment = MimePart {
+            content_type: "application/pdf".to_string(),
+            filename: Some("R\u{e9}sum\u{e9}.pdf".to_string()),
+            content_id: None,
+            body: vec![0, 1, 2, 255],
+        };
+        let (content_type, body) = build_multipart(
+            Some("Hi"),
+            Some("<p>Hi</p>"),
+            std::slice::from_ref(&attachment),
+        );
+        let header = MailHeader {
+            from: "alice@dmail.ai".to_string(),
+            timestamp: 1_700_000_000 * 1_000_000_000,
+            content_type: Some(content_type),
+            to: vec!["bob@dmail.ai".to_string()],
+            subject: Some("Files".to_string()),
+            ..MailHeader::default()
+        };
+        let raw = to_rfc5322(&mail(header, &body), "id@dmail.ai");
+
+        let mail = round_trip(&raw);
+        assert_eq!(mail.body.0.as_slice(), body.as_slice());
+        let leaves = parts(mail.header.content_type.as_deref(), mail.body.0.as_slice());
+        assert_eq!(leaves.len(), 3);
+        assert_eq!(leaves[0].body, b"Hi");
+        assert_eq!(leaves[1].body, b"<p>Hi</p>");
+        assert_eq!(leaves[2], attachment);
+    }
+
+    fn address() -> impl Strategy<Value = String> {
+        "[a-z0-9._-]{1,10}@[a-z0-9-]{1,10}\\.(io|ai|com)"
+    }
+
+    // one line of text without surrounding whitespace, ascii specials and non ascii included
+    fn text() -> impl Strategy<Value = String> {
+        "[A-Za-z0-9\"',.;:<>()!?&\u{e9}\u{fc}\u{df}\u{4e2d}-]([A-Za-z0-9 \"',.;:<>()!?&\u{e9}\u{fc}\u{df}\u{4e2d}-]{0,30}[A-Za-z0-9\"',.;:<>()!?&\u{e9}\u{fc}\u{df}\u{4e2d}-])?"
+    }
+
+    fn header() -> impl Strategy<Value = MailHeader> {
+        (
+            (address(), proptest::option::of(text())),
+            proptest::collection::vec(address(), 0..4),
+            proptest::collection::vec(address(), 0..3),
+            proptest::collection::vec(address(), 0..3),
+            proptest::option::of(text()),
+            0u64..4_000_000_000,
+            proptest::collection::vec(("X-[A-Za-z]{1,12}", text()), 0..4),
+        )
+            .prop_map(
+                |((from, sender_name), to, cc, bcc, subject, secs, headers)| MailHeader {
+                    from,
+                    sender_name,
+                    to,
+                    cc: Some(cc).filter(|cc| !cc.is_empty()),
+                    bcc: Some(bcc).filter(|bcc| !bcc.is_empty()),
+                    subject,
+                    timestamp: secs * 1_000_000_000,
+                    headers: Some(headers).filter(|headers| !headers.is_empty()),
+                    ..MailHeader::default()
+                },
+            )
+    }
+
+    // RFC 2047 "Q" encoding of every byte, ascii or not
+    fn q_word(value: &str) -> String {
+        let encoded: String = value.bytes().map(|b| format!("={:02X}", b)).collect();
+        format!("=?utf-8?Q?{}?=", encoded)
+    }
+
+    fn b_word(value: &str) -> String {
+        format!(
+            "=?UTF-8?b?{}?=",
+            base64::engine::general_purpose::STANDARD.encode(value)
+        )
+    }
+
+    proptest! {
+        #[test]
+        fn headers_and_bodies_round_trip(header in header(), body in proptest::collection::vec(any::<u8>(), 0..300)) {
+            let raw = encode(&header, &body, "id@dmail.ai", true);
+            let parsed = from_rfc5322(&raw).unwrap();
+            prop_assert_eq!(&parsed.header.from, &header.from);
+            prop_assert_eq!(&parsed.header.sender_name, &header.sender_name);
+            prop_assert_eq!(&parsed.header.to, &header.to);
+            prop_assert_eq!(&parsed.header.cc, &header.cc);
+            prop_assert_eq!(&parsed.header.bcc, &header.bcc);
+            prop_assert_eq!(&parsed.header.subject, &header.subject);
+            prop_assert_eq!(parsed.header.timestamp, header.timestamp);
+            prop_assert_eq!(&parsed.header.headers, &header.headers);
+            prop_assert_eq!(parsed.body.0.as_slice(), body.as_slice());
+            // and serializing the parsed mail gives the same bytes again
+            prop_assert_eq!(to_rfc5322(&parsed, "id@dmail.ai"), raw);
+        }
+
+        #[test]
+        fn folding_is_undone(
+            words in proptest::collection::vec("[A-Za-z0-9,.!?]{1,12}", 1..12),
+            folds in proptest::collection::vec(prop_oneof![Just(" "), Just("\r\n "), Just("\r\n\t"), Just("\n ")], 11),
+        ) {
+            let mut value = words[0].clone();
+            for (word, fold) in words[1..].iter().zip(&folds) {
+                value.push_str(fold);
+                value.push_str(word);
+            }
+            let raw = format!(
+                "From: alice@dmail.ai\r\nSubject: {}\r\nX-Folded: {}\r\n\r\nHi",
+                value, value
+            );
+            let mail = from_rfc5322(raw.as_bytes()).unwrap();
+            let unfolded = words.join(" ");
+            prop_assert_eq!(mail.header.subject.as_deref(), Some(unfolded.as_str()));
+            prop_assert_eq!(mail.header.get_header("X-Folded"), Some(unfolded.as_str()));
+        }
+
+        #[test]
+        fn encoded_words_decode(value in text(), other in text()) {
+            prop_assert_eq!(decode_words(&encode_word(&value)), value.clone());
+            prop_assert_eq!(decode_words(&q_word(&value)), value.clone());
+            // whitespace between adjacent encoded words is dropped, not around plain text
+            prop_assert_eq!(
+                decode_words(&format!("{} \t{}", b_word(&value), q_word(&other))),
+                format!("{}{}", value, other)
+            );
+            prop_assert_eq!(
+                decode_words(&format!("{} plain {}", q_word(&value), b_word(&other))),
+                format!("{} plain {}", value, other)
+            );
+        }
+    }
+}
@@ -0,0 +1,454 @@
+//! Server side filtering of incoming mail, modelled on Sieve (RFC 5228). Rules run in order and
+//! every rule whose conditions hold adds its actions. Mail goes to the inbox unless an action
+//! files, forwards, discards or rejects it, `Keep` puts it in the inbox anyway.
+use candid::CandidType;
+use serde::Deserialize;
+
+use crate::{Mail, MailHeader, EMAIL_ADDRESS};
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum MatchType {
+    Is,
+    Contains,
+    // `*` matches any run of characters and `?` a single one
+    Matches,
+}
+
+// Comparisons ignore ASCII case, like the Sieve default comparator.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TextMatch {
+    pub match_type: MatchType,
+    pub value: String,
+}
+
+impl TextMatch {
+    pub fn test(&self, text: &str) -> bool {
+        let text = text.to_ascii_lowercase();
+        let value = self.value.to_ascii_lowercase();
+        match self.match_type {
+            MatchType::Is => text == value,
+            MatchType::Contains => text.contains(&value),
+            MatchType::Matches => wildcard_match(value.as_bytes(), text.as_bytes()),
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum RuleCondition {
+    From(TextMatch),
+    // any To or Cc address
+    To(TextMatch),
+    Subject(TextMatch),
+    Header { name: String, test: TextMatch },
+    SenderChannel(TextMatch),
+    // body size in bytes
+    SizeOver(u64),
+    SizeUnder(u64),
+}
+
+impl RuleCondition {
+    pub fn holds(&self, mail: &Mail) -> bool {
+        let header = &mail.header;
+        match self {
+            RuleCondition::From(test) => test.test(&header.from),
+            RuleCondition::To(test) => receipients(header).iter().any(|to| test.test(to)),
+            RuleCondition::Subject(test) => test.test(header.subject.as_deref().unwrap_or("")),
+            RuleCondition::Header { name, test } => header_values(header, name)
+                .iter()
+                .any(|value| test.test(value)),
+            RuleCondition::SenderChannel(test) => {
+                test.test(header.sender_channel.as_deref().unwrap_or(""))
+            }
+            RuleCondition::SizeOver(size) => mail.body.0.len() as u64 > *size,
+            RuleCondition::SizeUnder(size) => (mail.body.0.len() as u64) < *size,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, PartialEq, Debug)]
+pub enum RuleAction {
+    // file under a label instead of the inbox
+    FileInto(String),
+    Keep,
+    MarkRead,
+    Star,
+    Forward(EMAIL_ADDRESS),
+    Discard,
+    // refuse the mail, the sender gets the reason back
+    Reject(String),
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct MailRule {
+    pub name: String,
+    pub enabled: bool,
+    // every condition has to hold instead of any one, a rule without conditions always fires
+    pub match_all: bool,
+    pub conditions: Vec<RuleCondition>,
+    pub actions: Vec<RuleAction>,
+    // later rules are skipped once this one fired
+    pub stop: bool,
+}
+
+impl MailRule {
+    pub fn matches(&self, mail: &Mail) -> bool {
+        if self.conditions.is_empty() {
+            return true;
+        }
+        if self.match_all {
+            self.conditions
+                .iter()
+                .all(|condition| condition.holds(mail))
+        } else {
+            self.conditions
+                .iter()
+                .any(|condition| condition.holds(mail))
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct FiredRule {
+    // position in the mailbox's rule list
+    pub index: u32,
+    pub name: String,
+}
+
+// What delivery does with a mail once the rules ran.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct RuleDisposition {
+    pub fired: Vec<FiredRule>,
+    pub inbox: bool,
+    pub labels: Vec<String>,
+    pub read: bool,
+    pub starred: bool,
+    pub forward_to: Vec<EMAIL_ADDRESS>,
+    // the mail is refused and nothing else happens
+    pub reject: Option<String>,
+}
+
+impl Default for RuleDisposition {
+    fn default() -> Self {
+        RuleDisposition {
+            fired: vec![],
+            inbox: true,
+            labels: vec![],
+            read: false,
+            starred: false,
+            forward_to: vec![],
+            reject: None,
+        }
+    }
+}
+
+impl RuleDisposition {
+    // discarded, or filtered to nowhere
+    pub fn is_dropped(&self) -> bool {
+        self.reject.is_some()
+            || (!self.inbox && self.labels.is_empty() && self.forward_to.is_empty())
+    }
+}
+
+pub fn evaluate(rules: &[MailRule], mail: &Mail) -> RuleDisposition {
+    let mut disposition = RuleDisposition::default();
+    let mut implicit_keep = true;
+    let mut explicit_keep = false;
+
+    for (index, rule) in rules.iter().enumerate() {
+        if !rule.enabled || !rule.matches(mail) {
+            continue;
+        }
+        disposition.fired.push(FiredRule {
+            index: index as u32,
+            name: rule.name.clone(),
+        });
+
+        for action in &rule.actions {
+            match action {
+                RuleAction::FileInto(label) => {
+                    if !disposition.labels.contains(label) {
+                        disposition.labels.push(label.clone());
+                    }
+                    implicit_keep = false;
+                }
+                RuleAction::Keep => explicit_keep = true,
+                RuleAction::MarkRead => disposition.read = true,
+                RuleAction::Star => disposition.starred = true,
+                RuleAction::Forward(address) => {
+                    if !disposition.forward_to.contains(address) {
+                        disposition.forward_to.push(address.clone());
+                    }
+                    implicit_keep = false;
+                }
+                RuleAction::Discard => implicit_keep = false,
+                RuleAction::Reject(reason) => {
+                    if disposition.reject.is_none() {
+                        disposition.reject = Some(reason.clone());
+                    }
+                    implicit_keep = false;
+                }
+            }
+        }
+
+        if rule.stop {
+            break;
+        }
+    }
+
+    disposition.inbox = explicit_keep || implicit_keep;
+    if disposition.reject.is_some() {
+        disposition.inbox = false;
+        disposition.labels.clear();
+        disposition.forward_to.clear();
+    }
+    disposition
+}
+
+fn receipients(header: &MailHeader) -> Vec<&str> {
+    header
+        .to
+        .iter()
+        .chain(header.cc.iter().flatten())
+        .map(|address| address.as_str())
+        .collect()
+}
+
+// The fields that have a place of their own in `MailHeader` can be tested by name as well.
+fn header_values<'a>(header: &'a MailHeader, name: &str) -> Vec<&'a str> {
+    let name = name.to_ascii_lowercase();
+    match name.as_str() {
+        "from" => vec![header.from.as_str()],
+        "to" => header.to.iter().map(|to| to.as_str()).collect(),
+        "cc" => header.cc.iter().flatten().map(|cc| cc.as_str()).collect(),
+        "subject" => header
+            .subject
+            .iter()
+            .map(|subject| subject.as_str())
+            .collect(),
+        "content-type" => header.content_type.iter().map(|c| c.as_str()).collect(),
+        _ => header
+            .headers
+            .iter()
+            .flatten()
+            .filter(|(n, _)| n.eq_ignore_ascii_case(&name))
+            .map(|(_, value)| value.as_str())
+            .collect(),
+    }
+}
+
+fn wildcard_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // where the last `*` was and the text position it is currently matched up to
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == b'?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == b'*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = star {
+            p = star_p + 1;
+            t = star_t + 1;
+            star = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|b| *b == b'*')
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use serde_bytes::ByteBuf;
+
+    use super::*;
+    use crate::Rcbytes;
+
+    fn mail(from: &str, subject: &str, body: &[u8]) -> Mail {
+        let mut header = MailHeader {
+            from: from.to_string(),
+            to: vec!["alice@dmail.ai".to_string()],
+            cc: Some(vec!["team@dmail.ai".to_string()]),
+            subject: Some(subject.to_string()),
+            ..MailHeader::default()
+        };
+        header.set_header("List-Id", "<dev.lists.example.com>".to_string());
+        Mail {
+            correlation_id: None,
+            header,
+            body: Rcbytes(Arc::new(ByteBuf::from(body.to_vec()))),
+            reply_messages: None,
+        }
+    }
+
+    fn text(match_type: MatchType, value: &str) -> TextMatch {
+        TextMatch {
+            match_type,
+            value: value.to_string(),
+        }
+    }
+
+    fn rule(name: &str, conditions: Vec<RuleCondition>, actions: Vec<RuleAction>) -> MailRule {
+        MailRule {
+            name: name.to_string(),
+            enabled: true,
+            match_all: true,
+            conditions,
+            actions,
+            stop: false,
+        }
+    }
+
+    fn fired(disposition: &RuleDisposition) -> Vec<(u32, &str)> {
+        disposition
+            .fired
+            .iter()
+            .map(|rule| (rule.index, rule.name.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn conditions_match_ignoring_case() {
+        let mail = mail("Bob@Example.com", "Weekly REPORT 42", &[0; 100]);
+        assert!(text(MatchType::Is, "bob@example.com").test(&mail.header.from));
+        assert!(!text(MatchType::Is, "bob@example").test(&mail.header.from));
+        assert!(text(MatchType::Contains, "report").test("Weekly REPORT 42"));
+        assert!(text(MatchType::Matches, "weekly*4?").test("Weekly REPORT 42"));
+        assert!(text(MatchType::Matches, "*@*.com").test("bob@example.com"));
+        assert!(!text(MatchType::Matches, "weekly?report").test("Weekly REPORT 42"));
+
+        let holds = |condition: RuleCondition| condition.holds(&mail);
+        assert!(holds(RuleCondition::To(text(
+            MatchType::Is,
+            "team@dmail.ai"
+        ))));
+        assert!(holds(RuleCondition::Header {
+            name: "list-id".to_string(),
+            test: text(MatchType::Contains, "dev.lists"),
+        }));
+        assert!(holds(RuleCondition::Header {
+            name: "From".to_string(),
+            test: text(MatchType::Matches, "*@example.com"),
+        }));
+        assert!(!holds(RuleCondition::Header {
+            name: "X-Missing".to_string(),
+            test: text(MatchType::Matches, "*"),
+        }));
+        assert!(!holds(RuleCondition::SenderChannel(text(
+            MatchType::Is,
+            "web2"
+        ))));
+        assert!(holds(RuleCondition::SizeOver(99)));
+        assert!(!holds(RuleCondition::SizeOver(100)));
+        assert!(holds(RuleCondition::SizeUnder(101)));
+
+        let from = RuleCondition::From(text(MatchType::Contains, "example"));
+        let subject = RuleCondition::Subject(text(MatchType::Contains, "invoice"));
+        let mut any = rule("any", vec![from.clone(), subject.clone()], vec![]);
+        any.match_all = false;
+        assert!(any.matches(&mail));
+        assert!(!rule("all", vec![from, subject], vec![]).matches(&mail));
+        assert!(rule("always", vec![], vec![]).matches(&mail));
+    }
+
+    #[test]
+    fn rules_fire_in_order_until_one_stops() {
+        let mail = mail("bob@example.com", "Weekly report", b"Hi");
+        let from_bob = || RuleCondition::From(text(MatchType::Is, "bob@example.com"));
+        let mut disabled = rule("disabled", vec![], vec![RuleAction::Discard]);
+        disabled.enabled = false;
+        let mut stop = rule(
+            "stop",
+            vec![from_bob()],
+            vec![RuleAction::FileInto("bob".to_string())],
+        );
+        stop.stop = true;
+        let rules = vec![
+            disabled,
+            rule(
+                "other sender",
+                vec![RuleCondition::From(text(
+                    MatchType::Is,
+                    "carol@example.com",
+                ))],
+                vec![RuleAction::Discard],
+            ),
+            rule("star", vec![from_bob()], vec![RuleAction::Star]),
+            stop,
+            rule("after stop", vec![], vec![RuleAction::Discard]),
+        ];
+
+        let disposition = evaluate(&rules, &mail);
+        assert_eq!(fired(&disposition), vec![(2, "star"), (3, "stop")]);
+        assert!(disposition.starred);
+        assert_eq!(disposition.labels, vec!["bob"]);
+        // filed, so not in the inbox, and not dropped either
+        assert!(!disposition.inbox);
+        assert!(!disposition.is_dropped());
+
+        let disposition = evaluate(&[], &mail);
+        assert!(disposition.fired.is_empty());
+        assert!(disposition.inbox && !disposition.read && !disposition.starred);
+    }
+
+    #[test]
+    fn actions_add_up() {
+        let mail = mail("bob@example.com", "Weekly report", b"Hi");
+        let forward = |to: &str| RuleAction::Forward(to.to_string());
+        let rules = vec![
+            rule(
+                "file",
+                vec![],
+                vec![
+                    RuleAction::FileInto("reports".to_string()),
+                    RuleAction::FileInto("reports".to_string()),
+                    RuleAction::MarkRead,
+                ],
+            ),
+            rule(
+                "forward",
+                vec![],
+                vec![forward("me@example.org"), forward("me@example.org")],
+            ),
+            rule("keep", vec![], vec![RuleAction::Keep]),
+        ];
+        let disposition = evaluate(&rules, &mail);
+        assert_eq!(disposition.labels, vec!["reports"]);
+        assert_eq!(disposition.forward_to, vec!["me@example.org"]);
+        assert!(disposition.read);
+        // `Keep` brings it back to the inbox after it was filed
+        assert!(disposition.inbox);
+
+        let discard = evaluate(&[rule("discard", vec![], vec![RuleAction::Discard])], &mail);
+        assert!(!discard.inbox);
+        assert!(discard.is_dropped());
+        assert!(discard.reject.is_none());
+
+        // a rejection wins over everything else, the first reason is the one returned
+        let rules = vec![
+            rule(
+                "reject",
+                vec![],
+                vec![RuleAction::Reject("No reports".to_string())],
+            ),
+            rule(
+                "more",
+                vec![],
+                vec![
+                    RuleAction::Reject("Later".to_string()),
+                    RuleAction::Keep,
+                    RuleAction::FileInto("reports".to_string()),
+                    forward("me@example.org"),
+                ],
+            ),
+        ];
+        let rejected = evaluate(&rules, &mail);
+        assert_eq!(rejected.reject.as_deref(), Some("No reports"));
+        assert!(!rejected.inbox);
+        assert!(rejected.labels.is_empty() && rejected.forward_to.is_empty());
+        assert!(rejected.is_dropped());
+    }
+}